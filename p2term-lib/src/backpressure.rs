@@ -0,0 +1,174 @@
+//! Backpressure on a session's output, see [`Capabilities::OUTPUT_ACK`].
+//!
+//! QUIC takes whatever is written to a stream into large buffers of its own, so output that
+//! the link or the client can't keep up with would pile up there, and a `Ctrl-C` would only
+//! show once all of it had arrived. Instead the client acknowledges the output it has handled
+//! with [`Frame::OutputAck`], and the server stops reading output while [`OUTPUT_WINDOW`]
+//! bytes of it are unacknowledged, which eventually blocks the shell like a slow terminal
+//! would. Both ends count output with [`output_len`], per stream.
+use crate::proto::Capabilities;
+use crate::proto::codec::Frame;
+use tokio::sync::watch;
+
+/// Output the server sends ahead of the client's acknowledgements
+pub const OUTPUT_WINDOW: usize = 256 * 1024;
+
+/// Output the client handles before acknowledging it, well below [`OUTPUT_WINDOW`] so that
+/// the server never waits on output that has already been handled
+const ACK_EVERY: u64 = OUTPUT_WINDOW as u64 / 8;
+
+/// How much of the window `frame` takes up, as sent
+#[must_use]
+pub fn output_len(frame: &Frame) -> u64 {
+    match frame {
+        Frame::Stdout(bytes) | Frame::Stderr(bytes) | Frame::CompressedStdout(bytes) => {
+            bytes.len() as u64
+        }
+        _ => 0,
+    }
+}
+
+/// The server's ends of a session's window, the acknowledgements come in on the input side
+/// and the window is waited on by the output side
+#[must_use]
+pub fn window(capabilities: Capabilities) -> (OutputAcks, OutputWindow) {
+    let (acks, acked) = watch::channel(0);
+    let acked = capabilities
+        .contains(Capabilities::OUTPUT_ACK)
+        .then_some(acked);
+    (OutputAcks(acks), OutputWindow { sent: 0, acked })
+}
+
+/// Where the server takes [`Frame::OutputAck`]
+#[derive(Debug)]
+pub struct OutputAcks(watch::Sender<u64>);
+
+impl OutputAcks {
+    pub fn ack(&self, handled: u64) {
+        self.0.send_if_modified(|acked| {
+            let newer = handled > *acked;
+            if newer {
+                *acked = handled;
+            }
+            newer
+        });
+    }
+}
+
+/// Holds back output that the client hasn't caught up with, never if it doesn't acknowledge
+/// output. Once the input side is gone the window stays open, nothing acknowledges anymore
+#[derive(Debug)]
+pub struct OutputWindow {
+    sent: u64,
+    acked: Option<watch::Receiver<u64>>,
+}
+
+impl OutputWindow {
+    /// Counts a frame that has been sent
+    pub fn sent(&mut self, frame: &Frame) {
+        self.sent += output_len(frame);
+    }
+
+    /// Whether more output may be sent
+    #[must_use]
+    pub fn is_open(&self) -> bool {
+        self.room() > 0
+    }
+
+    /// Output that may be sent before the window closes
+    #[must_use]
+    pub fn room(&self) -> usize {
+        let Some(acked) = self.acked.as_ref() else {
+            return usize::MAX;
+        };
+        if acked.has_changed().is_err() {
+            return usize::MAX;
+        }
+        let in_flight = self.sent.saturating_sub(*acked.borrow());
+        OUTPUT_WINDOW.saturating_sub(usize::try_from(in_flight).unwrap_or(usize::MAX))
+    }
+
+    /// Completes once more output may be sent, cancel safe
+    pub async fn open(&self) {
+        let Some(mut acked) = self.acked.clone() else {
+            return;
+        };
+        let sent = self.sent;
+        // An error means the input side is gone
+        let _ = acked
+            .wait_for(|acked| sent.saturating_sub(*acked) < OUTPUT_WINDOW as u64)
+            .await;
+    }
+}
+
+/// The client's ends of a session's acknowledgements, output is counted on the output side
+/// and the acknowledgements written by the input side, which owns the stream
+#[must_use]
+pub fn acks(capabilities: Capabilities) -> (HandledOutput, PendingAcks) {
+    let (handled, pending) = watch::channel(0);
+    let pending = capabilities
+        .contains(Capabilities::OUTPUT_ACK)
+        .then_some(pending);
+    (
+        HandledOutput {
+            handled: 0,
+            reported: 0,
+            pending: handled,
+        },
+        PendingAcks(pending),
+    )
+}
+
+/// Counts the output the client has handled
+#[derive(Debug)]
+pub struct HandledOutput {
+    handled: u64,
+    reported: u64,
+    pending: watch::Sender<u64>,
+}
+
+impl HandledOutput {
+    /// Counts a frame once it has been handled, like written to the terminal
+    pub fn handled(&mut self, frame: &Frame) {
+        self.handled += output_len(frame);
+        if self.handled - self.reported >= ACK_EVERY {
+            self.reported = self.handled;
+            self.pending.send_replace(self.handled);
+        }
+    }
+}
+
+/// Acknowledgements waiting to be written, there are never any if the server doesn't take
+/// them
+#[derive(Debug)]
+pub struct PendingAcks(Option<watch::Receiver<u64>>);
+
+impl PendingAcks {
+    /// Whether output is acknowledged at all, the stream has to stay open for that
+    #[must_use]
+    pub fn acknowledges(&self) -> bool {
+        self.0.is_some()
+    }
+
+    /// Completes with the next [`Frame::OutputAck`] to write, cancel safe
+    pub async fn next(&mut self) -> Frame {
+        let Some(pending) = self.0.as_mut() else {
+            return std::future::pending().await;
+        };
+        if pending.changed().await.is_err() {
+            // The output side is gone, and with it the session
+            return std::future::pending().await;
+        }
+        Frame::OutputAck(*pending.borrow_and_update())
+    }
+
+    /// The acknowledgement waiting to be written, if any, for writers that are busy with
+    /// other frames for a while
+    pub fn take(&mut self) -> Option<Frame> {
+        let pending = self.0.as_mut()?;
+        pending
+            .has_changed()
+            .unwrap_or(false)
+            .then(|| Frame::OutputAck(*pending.borrow_and_update()))
+    }
+}
@@ -1,3 +1,8 @@
+pub mod connection;
+pub mod files;
+pub mod inband;
+pub mod predict;
 pub mod runtime;
+pub mod screen;
 pub mod server_handle;
 pub mod shell_proxy;
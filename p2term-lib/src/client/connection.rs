@@ -0,0 +1,123 @@
+use crate::client::server_handle::P2TermServerHandle;
+use crate::datagram::{FLOW_ID_LEN, FlowReceiver, FlowRouter};
+use crate::proto::Capabilities;
+use anyhow::{Context, bail};
+use iroh::Endpoint;
+use iroh::endpoint::{Connection, ConnectionError, RecvStream, SendStream};
+use iroh_base::{PublicKey, SecretKey};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Once, OnceLock};
+
+/// A connection to a server that sessions can be opened on
+#[derive(Debug, Clone)]
+pub struct P2TermConnection {
+    connection: Connection,
+    /// What the server supports, shared by all sessions on the connection once known
+    server_capabilities: Arc<OnceLock<Capabilities>>,
+    /// The udp flows set up on the connection, see [`crate::datagram`]
+    flows: FlowRouter,
+    next_flow: Arc<AtomicU32>,
+    /// Datagrams are only read once the first flow is set up
+    reading_datagrams: Arc<Once>,
+}
+
+impl P2TermConnection {
+    pub async fn dial(secret_key: SecretKey, peer: PublicKey) -> anyhow::Result<Self> {
+        let ep = Endpoint::builder()
+            .alpns(vec![crate::proto::ALPN.to_vec()])
+            .secret_key(secret_key)
+            .bind()
+            .await
+            .context("failed to start client endpoint")?;
+        let connection = ep
+            .connect(peer, crate::proto::ALPN)
+            .await
+            .with_context(|| format!("failed to open connection to peer={peer}"))?;
+        Ok(Self {
+            connection,
+            server_capabilities: Arc::default(),
+            flows: FlowRouter::default(),
+            next_flow: Arc::default(),
+            reading_datagrams: Arc::new(Once::new()),
+        })
+    }
+
+    /// Opens a session on the connection, skipping the connection setup and hole punching
+    /// that [`Self::dial`] goes through
+    pub async fn open_session(&self) -> anyhow::Result<P2TermServerHandle<SendStream, RecvStream>> {
+        if let Some(capabilities) = self.server_capabilities.get()
+            && !capabilities.contains(Capabilities::MULTI_SESSION)
+        {
+            bail!("the server only serves one session per connection, upgrade the server");
+        }
+        let (send_stream, recv_stream) = self
+            .connection
+            .open_bi()
+            .await
+            .context("failed to open bidirectional stream to server")?;
+        Ok(P2TermServerHandle::on_connection(
+            send_stream,
+            recv_stream,
+            self.clone(),
+        ))
+    }
+
+    /// Accepts the next stream the server opens, those carry connections accepted by
+    /// [`crate::proto::SessionKind::ListenTcp`] sessions. `None` once the connection is closed
+    pub async fn accept_stream(&self) -> anyhow::Result<Option<(SendStream, RecvStream)>> {
+        match self.connection.accept_bi().await {
+            Ok(streams) => Ok(Some(streams)),
+            Err(
+                ConnectionError::ApplicationClosed(_)
+                | ConnectionError::ConnectionClosed(_)
+                | ConnectionError::LocallyClosed,
+            ) => Ok(None),
+            Err(e) => Err(e).context("failed to accept bidirectional stream from server"),
+        }
+    }
+
+    /// Sets up a new udp flow, for a [`crate::proto::SessionKind::ForwardUdp`] session
+    #[must_use]
+    pub fn open_flow(&self) -> FlowReceiver {
+        self.reading_datagrams.call_once(|| {
+            let connection = self.connection.clone();
+            let flows = self.flows.clone();
+            tokio::spawn(async move {
+                while let Ok(datagram) = connection.read_datagram().await {
+                    flows.route(&datagram);
+                }
+            });
+        });
+        loop {
+            let flow = self.next_flow.fetch_add(1, Ordering::Relaxed);
+            if let Ok(receiver) = self.flows.register(flow) {
+                return receiver;
+            }
+        }
+    }
+
+    /// Sends `payload` to the server on `flow`, it may get lost on the way
+    pub fn send_datagram(&self, flow: u32, payload: &[u8]) -> anyhow::Result<()> {
+        self.connection
+            .send_datagram(crate::datagram::encode(flow, payload).into())
+            .context("failed to send datagram")
+    }
+
+    /// The largest payload [`Self::send_datagram`] can carry, `None` if the server doesn't
+    /// take datagrams
+    #[must_use]
+    pub fn max_datagram_payload(&self) -> Option<usize> {
+        self.connection
+            .max_datagram_size()
+            .map(|size| size.saturating_sub(FLOW_ID_LEN))
+    }
+
+    pub(crate) fn server_capabilities(&self) -> &Arc<OnceLock<Capabilities>> {
+        &self.server_capabilities
+    }
+
+    /// Completes when the connection has closed, for whatever reason
+    pub async fn closed(&self) {
+        self.connection.closed().await;
+    }
+}
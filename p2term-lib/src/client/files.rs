@@ -0,0 +1,460 @@
+use crate::client::connection::P2TermConnection;
+use crate::proto::codec::{Frame, FrameReader, FrameWriter};
+use crate::proto::files::{
+    CHUNK_LEN, Checksum, DirEntry, FileAttrs, FileError, FileErrorKind, FileMeta, FileRequest,
+    FileResponse,
+};
+use crate::proto::{ClientOpt, SessionKind};
+use crate::streams::{ReadStream, WriteStream};
+use anyhow::{Context, bail};
+use iroh::endpoint::{RecvStream, SendStream};
+use std::path::PathBuf;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// A [`SessionKind::Files`] session, operating on files on the server one request at a time.
+/// Failed requests return a [`FileError`] that can be downcast to, the session can go on
+/// after them
+#[derive(Debug)]
+pub struct RemoteFiles<W, R> {
+    write: FrameWriter<W>,
+    read: FrameReader<R>,
+}
+
+/// What a file on the server to patch is made of, see [`RemoteFiles::signature`]
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Signature {
+    pub block_len: u32,
+    /// Hashes of consecutive blocks, the last one may be shorter than the others
+    pub blocks: Vec<Checksum>,
+    /// Whether some blocks were taken of an interrupted patch rather than the file, which
+    /// then isn't what the blocks hash to
+    pub resumed: bool,
+}
+
+impl Signature {
+    /// Whether what's read from `source` is made of these blocks, and nothing more
+    pub async fn matches<S>(&self, source: &mut S) -> std::io::Result<bool>
+    where
+        S: AsyncRead + Unpin,
+    {
+        let mut buf = vec![0u8; self.block_len as usize];
+        for hash in &self.blocks {
+            let len = read_block(source, &mut buf).await?;
+            if blake3::hash(&buf[..len]).as_bytes() != hash {
+                return Ok(false);
+            }
+        }
+        Ok(read_block(source, &mut buf).await? == 0)
+    }
+}
+
+impl RemoteFiles<SendStream, RecvStream> {
+    /// Opens a session on `connection`, relative paths start from `cwd`, or if `None`
+    /// the server user's home directory if permitted, or else the first permitted directory
+    pub async fn open(connection: &P2TermConnection, cwd: Option<PathBuf>) -> anyhow::Result<Self> {
+        let mut server_handle = connection.open_session().await?;
+        let client_opt = ClientOpt {
+            cwd,
+            session: SessionKind::Files,
+            ..ClientOpt::default()
+        };
+        server_handle.handshake(&client_opt).await?;
+        let (send, recv) = server_handle.decompose();
+        Ok(Self::new(FrameWriter::new(send), FrameReader::new(recv)))
+    }
+}
+
+impl<W, R> RemoteFiles<W, R>
+where
+    W: WriteStream,
+    R: ReadStream,
+{
+    /// Runs on a session that's already been handshaken as [`SessionKind::Files`]
+    pub fn new(write: FrameWriter<W>, read: FrameReader<R>) -> Self {
+        Self { write, read }
+    }
+
+    /// Metadata of the file at `path`, following symlinks
+    pub async fn stat(&mut self, path: &str) -> anyhow::Result<FileMeta> {
+        self.send(FileRequest::Stat {
+            path: path.to_string(),
+        })
+        .await?;
+        match self.response().await? {
+            FileResponse::Meta(meta) => Ok(meta),
+            unexpected => bail!("unexpected response to stat: {unexpected:?}"),
+        }
+    }
+
+    /// The entries of the directory at `path`, without following symlinks among them
+    pub async fn read_dir(&mut self, path: &str) -> anyhow::Result<Vec<DirEntry>> {
+        self.send(FileRequest::ReadDir {
+            path: path.to_string(),
+        })
+        .await?;
+        let mut entries = Vec::new();
+        loop {
+            match self.response().await? {
+                FileResponse::Entries(batch) => entries.extend(batch),
+                FileResponse::Done => return Ok(entries),
+                unexpected => bail!("unexpected response to read dir: {unexpected:?}"),
+            }
+        }
+    }
+
+    /// The target of the symlink at `path`
+    pub async fn read_link(&mut self, path: &str) -> anyhow::Result<String> {
+        self.send(FileRequest::ReadLink {
+            path: path.to_string(),
+        })
+        .await?;
+        match self.response().await? {
+            FileResponse::LinkTarget(target) => Ok(target),
+            unexpected => bail!("unexpected response to read link: {unexpected:?}"),
+        }
+    }
+
+    /// The absolute path `path` leads to on the server, with symlinks resolved
+    pub async fn real_path(&mut self, path: &str) -> anyhow::Result<String> {
+        self.send(FileRequest::RealPath {
+            path: path.to_string(),
+        })
+        .await?;
+        match self.response().await? {
+            FileResponse::Path(resolved) => Ok(resolved),
+            unexpected => bail!("unexpected response to real path: {unexpected:?}"),
+        }
+    }
+
+    /// The contents of the file at `path`, checked against their checksum
+    pub async fn read(&mut self, path: &str) -> anyhow::Result<Vec<u8>> {
+        let mut contents = Vec::new();
+        self.read_to(path, &mut contents, |_| {}).await?;
+        Ok(contents)
+    }
+
+    /// Replaces the file at `path` with `contents`, see [`RemoteFiles::write_from`]
+    pub async fn write(
+        &mut self,
+        path: &str,
+        create_mode: u32,
+        mut contents: &[u8],
+    ) -> anyhow::Result<()> {
+        self.write_from(path, create_mode, &mut contents, |_| {})
+            .await?;
+        Ok(())
+    }
+
+    /// Writes the contents of the file at `path` to `sink`, calling `progress` with the
+    /// bytes written so far after each chunk. Fails if the contents don't match the
+    /// checksum the server sends, `sink` may have been partially written to by then
+    pub async fn read_to<S>(
+        &mut self,
+        path: &str,
+        sink: &mut S,
+        mut progress: impl FnMut(u64),
+    ) -> anyhow::Result<u64>
+    where
+        S: AsyncWrite + Unpin,
+    {
+        self.send(FileRequest::Read {
+            path: path.to_string(),
+        })
+        .await?;
+        let mut hasher = blake3::Hasher::new();
+        let mut written = 0u64;
+        // A failing sink doesn't end the read, the rest of the contents still need to be
+        // taken off the stream
+        let mut sink_res = Ok(());
+        let checksum = loop {
+            match self.response().await? {
+                FileResponse::Data(chunk) => {
+                    hasher.update(&chunk);
+                    if sink_res.is_ok() {
+                        sink_res = sink.write_all(&chunk).await;
+                        written += chunk.len() as u64;
+                        progress(written);
+                    }
+                }
+                FileResponse::ReadDone { checksum } => break checksum,
+                unexpected => bail!("unexpected response to read: {unexpected:?}"),
+            }
+        };
+        sink_res.with_context(|| format!("failed to write contents of {path}"))?;
+        sink.flush()
+            .await
+            .with_context(|| format!("failed to write contents of {path}"))?;
+        if hasher.finalize().as_bytes() != &checksum {
+            return Err(FileError::new(
+                FileErrorKind::Corrupted,
+                format!("contents of {path} don't match their checksum"),
+            )
+            .into());
+        }
+        Ok(written)
+    }
+
+    /// Replaces the file at `path` with what's read from `source`, calling `progress` with
+    /// the bytes sent so far after each chunk. A new file is created with `create_mode`
+    /// less the server's umask, an existing one keeps its mode
+    pub async fn write_from<S>(
+        &mut self,
+        path: &str,
+        create_mode: u32,
+        source: &mut S,
+        mut progress: impl FnMut(u64),
+    ) -> anyhow::Result<u64>
+    where
+        S: AsyncRead + Unpin,
+    {
+        self.send(FileRequest::Write {
+            path: path.to_string(),
+            create_mode,
+        })
+        .await?;
+        let mut hasher = blake3::Hasher::new();
+        let mut sent = 0u64;
+        let mut buf = vec![0u8; CHUNK_LEN];
+        let read_res = loop {
+            let len = match source.read(&mut buf).await {
+                Ok(0) => break Ok(()),
+                Ok(len) => len,
+                Err(e) => break Err(e),
+            };
+            hasher.update(&buf[..len]);
+            self.send(FileRequest::Data(buf[..len].to_vec())).await?;
+            sent += len as u64;
+            progress(sent);
+        };
+        if let Err(e) = read_res {
+            // The server answers once the write is over either way
+            self.send(FileRequest::WriteAbort).await?;
+            let _ = self.response().await;
+            return Err(e).with_context(|| format!("failed to read contents for {path}"));
+        }
+        self.send(FileRequest::WriteEnd {
+            checksum: *hasher.finalize().as_bytes(),
+        })
+        .await?;
+        self.expect_done("write").await?;
+        Ok(sent)
+    }
+
+    /// Hashes of the `block_len` long blocks of what's at `path` to patch, see
+    /// [`FileRequest::Signature`]. Without blocks if there's nothing there
+    pub async fn signature(&mut self, path: &str, block_len: u32) -> anyhow::Result<Signature> {
+        self.send(FileRequest::Signature {
+            path: path.to_string(),
+            block_len,
+        })
+        .await?;
+        let mut blocks = Vec::new();
+        loop {
+            match self.response().await? {
+                FileResponse::Signature(batch) => blocks.extend(batch),
+                FileResponse::SignatureEnd { resumed } => {
+                    return Ok(Signature {
+                        block_len,
+                        blocks,
+                        resumed,
+                    });
+                }
+                unexpected => bail!("unexpected response to signature: {unexpected:?}"),
+            }
+        }
+    }
+
+    /// Replaces the file at `path` with the first `len` bytes read from `source`, sending
+    /// only the blocks whose hashes differ from `signature`, as taken of `path` with
+    /// [`RemoteFiles::signature`]. Calls `progress` with the bytes of `source` gone through
+    /// so far after each block, and returns the bytes sent. A new file is created with
+    /// `create_mode` less the server's umask, an existing one keeps its mode
+    pub async fn patch_from<S>(
+        &mut self,
+        path: &str,
+        create_mode: u32,
+        len: u64,
+        signature: &Signature,
+        source: &mut S,
+        mut progress: impl FnMut(u64),
+    ) -> anyhow::Result<u64>
+    where
+        S: AsyncRead + Unpin,
+    {
+        let block_len = signature.block_len;
+        self.send(FileRequest::Patch {
+            path: path.to_string(),
+            len,
+            block_len,
+            create_mode,
+        })
+        .await?;
+        let mut source = source.take(len);
+        let mut hasher = blake3::Hasher::new();
+        let mut done = 0u64;
+        let mut sent = 0u64;
+        let mut buf = vec![0u8; block_len as usize];
+        let mut index = 0u64;
+        let read_res = loop {
+            let block = match read_block(&mut source, &mut buf).await {
+                Ok(0) => break Ok(()),
+                Ok(block_len) => &buf[..block_len],
+                Err(e) => break Err(e),
+            };
+            hasher.update(block);
+            let unchanged = usize::try_from(index)
+                .ok()
+                .and_then(|index| signature.blocks.get(index))
+                .is_some_and(|hash| blake3::hash(block).as_bytes() == hash);
+            if !unchanged {
+                self.send(FileRequest::Block {
+                    index,
+                    data: block.to_vec(),
+                })
+                .await?;
+                sent += block.len() as u64;
+            }
+            done += block.len() as u64;
+            progress(done);
+            index += 1;
+        };
+        let read_res = read_res.and_then(|()| {
+            if done == len {
+                Ok(())
+            } else {
+                Err(std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    format!("ended after {done} of {len} bytes"),
+                ))
+            }
+        });
+        if let Err(e) = read_res {
+            self.send(FileRequest::WriteAbort).await?;
+            let _ = self.response().await;
+            return Err(e).with_context(|| format!("failed to read contents for {path}"));
+        }
+        self.send(FileRequest::WriteEnd {
+            checksum: *hasher.finalize().as_bytes(),
+        })
+        .await?;
+        self.expect_done("patch").await?;
+        Ok(sent)
+    }
+
+    /// Creates the directory at `path` with `mode` less the server's umask
+    pub async fn create_dir(&mut self, path: &str, mode: u32) -> anyhow::Result<()> {
+        self.send(FileRequest::CreateDir {
+            path: path.to_string(),
+            mode,
+        })
+        .await?;
+        self.expect_done("create dir").await
+    }
+
+    /// Creates a symlink at `path` pointing to `target`, replacing a file or symlink there
+    pub async fn symlink(&mut self, path: &str, target: &str) -> anyhow::Result<()> {
+        self.send(FileRequest::Symlink {
+            path: path.to_string(),
+            target: target.to_string(),
+        })
+        .await?;
+        self.expect_done("symlink").await
+    }
+
+    /// Sets the attributes given in `attrs` on the file at `path`
+    pub async fn set_attrs(&mut self, path: &str, attrs: FileAttrs) -> anyhow::Result<()> {
+        self.send(FileRequest::SetAttrs {
+            path: path.to_string(),
+            attrs,
+        })
+        .await?;
+        self.expect_done("set attrs").await
+    }
+
+    /// Removes the file or symlink at `path`
+    pub async fn remove(&mut self, path: &str) -> anyhow::Result<()> {
+        self.send(FileRequest::Remove {
+            path: path.to_string(),
+        })
+        .await?;
+        self.expect_done("remove").await
+    }
+
+    /// Removes the empty directory at `path`
+    pub async fn remove_dir(&mut self, path: &str) -> anyhow::Result<()> {
+        self.send(FileRequest::RemoveDir {
+            path: path.to_string(),
+        })
+        .await?;
+        self.expect_done("remove dir").await
+    }
+
+    /// Moves the entry at `from` to `to`, replacing a file at `to`
+    pub async fn rename(&mut self, from: &str, to: &str) -> anyhow::Result<()> {
+        self.send(FileRequest::Rename {
+            from: from.to_string(),
+            to: to.to_string(),
+        })
+        .await?;
+        self.expect_done("rename").await
+    }
+
+    /// Ends the session, once the server has seen to every request
+    pub async fn close(mut self) -> anyhow::Result<()> {
+        self.write.write_frame(&Frame::Close).await?;
+        self.write.shutdown().await?;
+        while let Some(frame) = self.read.read_frame().await? {
+            if frame == Frame::Close {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    async fn send(&mut self, request: FileRequest) -> anyhow::Result<()> {
+        self.write
+            .write_frame(&Frame::FileRequest(request))
+            .await
+            .context("failed to send file request")
+    }
+
+    async fn expect_done(&mut self, request: &str) -> anyhow::Result<()> {
+        match self.response().await? {
+            FileResponse::Done => Ok(()),
+            unexpected => bail!("unexpected response to {request}: {unexpected:?}"),
+        }
+    }
+
+    /// The next response, failing with the [`FileError`] if it's an error
+    async fn response(&mut self) -> anyhow::Result<FileResponse> {
+        loop {
+            let frame = self
+                .read
+                .read_frame()
+                .await
+                .context("failed to read file response")?
+                .context("server closed the session")?;
+            match frame {
+                Frame::FileResponse(FileResponse::Error(e)) => return Err(e.into()),
+                Frame::FileResponse(response) => return Ok(response),
+                Frame::Close => bail!("server closed the session"),
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Fills `buf` from `source` as far as it goes, short only at the end of `source`
+async fn read_block<S>(source: &mut S, buf: &mut [u8]) -> std::io::Result<usize>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut filled = 0;
+    while filled < buf.len() {
+        match source.read(&mut buf[filled..]).await? {
+            0 => break,
+            read => filled += read,
+        }
+    }
+    Ok(filled)
+}
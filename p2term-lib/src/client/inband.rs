@@ -0,0 +1,341 @@
+//! Transfers started from inside a session, by a program writing escape sequences to its
+//! terminal the way iTerm2 has them. A download is an OSC 1337 `File=` sequence carrying the
+//! file in base64, an upload is requested with OSC 1337 `RequestUpload=` and answered by typing
+//! the file into the session in base64, one line at a time, ended by an empty line.
+//! ZMODEM transfers aren't supported, but recognized so that they can be cancelled rather
+//! than show up as garbage.
+use base64::Engine;
+use base64::engine::general_purpose::{GeneralPurpose, GeneralPurposeConfig};
+use base64::engine::{DecodePaddingMode, general_purpose};
+
+const FILE: &[u8] = b"\x1b]1337;File=";
+const REQUEST_UPLOAD: &[u8] = b"\x1b]1337;RequestUpload=";
+/// The start of a ZMODEM hex header, `ZRQINIT` as sent by `sz`
+const ZMODEM_SEND: &[u8] = b"\x18B00";
+/// The start of a ZMODEM hex header, `ZRINIT` as sent by `rz`
+const ZMODEM_RECEIVE: &[u8] = b"\x18B01";
+const ZMODEM_PAD: &[u8] = b"**";
+/// What follows the header type, 4 flag bytes and a crc in hex, a line ending and XON
+const ZMODEM_HEADER_REST: usize = 16;
+/// Arguments longer than this aren't a transfer, and are left to the terminal
+const MAX_ARGS_LEN: usize = 4096;
+
+const BEL: u8 = 0x07;
+const ESC: u8 = 0x1b;
+const XON: u8 = 0x11;
+
+/// Typed into the session to accept an upload request, the file follows
+pub const UPLOAD_ACCEPT: &[u8] = b"ok\n";
+/// Typed into the session to turn down an upload request
+pub const UPLOAD_ABORT: &[u8] = b"abort\n";
+/// Typed into the session after the last line of an upload
+pub const UPLOAD_END: &[u8] = b"\n";
+/// How much of a file goes on each line of an upload, 76 characters once encoded
+pub const UPLOAD_LINE_LEN: usize = 57;
+/// The format of uploads, the file as it is
+pub const UPLOAD_FORMAT_RAW: &str = "raw";
+/// Makes `sz` or `rz` give up, the same as theirs when interrupted
+pub const ZMODEM_CANCEL: &[u8] =
+    b"\x18\x18\x18\x18\x18\x18\x18\x18\x18\x18\x08\x08\x08\x08\x08\x08\x08\x08\x08\x08";
+
+/// Downloads are decoded whether padded or not, line breaks are skipped before decoding
+const DOWNLOAD_ENGINE: GeneralPurpose = GeneralPurpose::new(
+    &base64::alphabet::STANDARD,
+    GeneralPurposeConfig::new().with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+
+/// What a session's output is made of, see [`OutputScanner`]
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum OutputEvent {
+    /// Output for the terminal
+    Output(Vec<u8>),
+    /// A file is being sent, its contents follow as [`OutputEvent::DownloadData`]
+    Download {
+        name: Option<String>,
+        size: Option<u64>,
+    },
+    DownloadData(Vec<u8>),
+    /// All of the file has been sent
+    DownloadEnd,
+    /// The file being sent can't be decoded, nothing more of it follows
+    DownloadFailed(String),
+    /// A program in the session waits for a file to be typed in, or for
+    /// [`UPLOAD_ABORT`]
+    UploadRequest {
+        format: Option<String>,
+    },
+    /// `sz` or `rz` was started, and waits for an answer
+    Zmodem,
+}
+
+/// Picks out transfers from a session's output as it arrives, in pieces that may split
+/// escape sequences anywhere
+#[derive(Debug, Default)]
+pub struct OutputScanner {
+    state: State,
+    /// The start of what may become an escape sequence of a transfer
+    held: Vec<u8>,
+}
+
+#[derive(Debug, Default)]
+enum State {
+    #[default]
+    Text,
+    Args {
+        upload: bool,
+        args: Vec<u8>,
+    },
+    /// An inline file, most likely an image, is left to the terminal
+    PassThrough,
+    Data {
+        encoded: Vec<u8>,
+    },
+    /// The rest of a download that failed
+    Skip,
+    /// After the `ESC` ending a sequence, which should be followed by `\`
+    End,
+    ZmodemHeader {
+        remaining: usize,
+    },
+}
+
+impl OutputScanner {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Scans the next piece of output, what may be the start of a transfer is held back
+    /// until the piece after it
+    pub fn scan(&mut self, bytes: &[u8]) -> Vec<OutputEvent> {
+        let mut input = std::mem::take(&mut self.held);
+        input.extend_from_slice(bytes);
+        let mut events = Events::default();
+        let mut pos = 0;
+        while pos < input.len() {
+            if matches!(self.state, State::Text) {
+                pos = self.text(&input, pos, &mut events);
+            } else if self.byte(input[pos], &mut events) {
+                pos += 1;
+            }
+        }
+        if let State::Data { encoded } = &mut self.state {
+            // Decoded as it arrives, only whole groups of 4 characters can be
+            let whole = encoded.len() - encoded.len() % 4;
+            let rest = encoded.split_off(whole);
+            let decoded = DOWNLOAD_ENGINE.decode(&encoded);
+            *encoded = rest;
+            match decoded {
+                Ok(data) if data.is_empty() => {}
+                Ok(data) => events.push(OutputEvent::DownloadData(data)),
+                Err(e) => {
+                    events.push(OutputEvent::DownloadFailed(format!("invalid base64: {e}")));
+                    self.state = State::Skip;
+                }
+            }
+        }
+        events.finish()
+    }
+
+    /// Passes on output up to the next escape sequence, returns where scanning goes on
+    fn text(&mut self, input: &[u8], pos: usize, events: &mut Events) -> usize {
+        let rest = &input[pos..];
+        let Some(start) = rest.iter().position(|&b| b == ESC || b == ZMODEM_SEND[0]) else {
+            events.text(rest);
+            return input.len();
+        };
+        events.text(&rest[..start]);
+        let candidate = &rest[start..];
+        let after = pos + start;
+        if candidate.starts_with(FILE) {
+            self.state = State::Args {
+                upload: false,
+                args: Vec::new(),
+            };
+            return after + FILE.len();
+        }
+        if candidate.starts_with(REQUEST_UPLOAD) {
+            self.state = State::Args {
+                upload: true,
+                args: Vec::new(),
+            };
+            return after + REQUEST_UPLOAD.len();
+        }
+        if candidate.starts_with(ZMODEM_SEND) || candidate.starts_with(ZMODEM_RECEIVE) {
+            events.strip_text_suffix(ZMODEM_PAD);
+            events.push(OutputEvent::Zmodem);
+            self.state = State::ZmodemHeader {
+                remaining: ZMODEM_HEADER_REST,
+            };
+            return after + ZMODEM_SEND.len();
+        }
+        if [FILE, REQUEST_UPLOAD, ZMODEM_SEND, ZMODEM_RECEIVE]
+            .iter()
+            .any(|sequence| sequence.starts_with(candidate))
+        {
+            self.held = candidate.to_vec();
+            return input.len();
+        }
+        events.text(&candidate[..1]);
+        after + 1
+    }
+
+    /// Scans a byte inside of a sequence, returns whether it was part of it
+    fn byte(&mut self, b: u8, events: &mut Events) -> bool {
+        match std::mem::take(&mut self.state) {
+            State::Text => unreachable!("text is scanned a piece at a time"),
+            State::Args { upload, args } => self.args(upload, args, b, events),
+            State::PassThrough => {
+                events.text(&[b]);
+                if b != BEL && b != ESC {
+                    self.state = State::PassThrough;
+                }
+            }
+            State::Data { mut encoded } => match b {
+                b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'+' | b'/' | b'=' => {
+                    encoded.push(b);
+                    self.state = State::Data { encoded };
+                }
+                b'\r' | b'\n' | b' ' => self.state = State::Data { encoded },
+                BEL | ESC => {
+                    match DOWNLOAD_ENGINE.decode(&encoded) {
+                        Ok(data) => {
+                            if !data.is_empty() {
+                                events.push(OutputEvent::DownloadData(data));
+                            }
+                            events.push(OutputEvent::DownloadEnd);
+                        }
+                        Err(e) => {
+                            events
+                                .push(OutputEvent::DownloadFailed(format!("invalid base64: {e}")));
+                        }
+                    }
+                    if b == ESC {
+                        self.state = State::End;
+                    }
+                }
+                _ => {
+                    events.push(OutputEvent::DownloadFailed(format!(
+                        "unexpected byte {b:#04x} in base64"
+                    )));
+                    self.state = State::Skip;
+                }
+            },
+            State::Skip => match b {
+                BEL => {}
+                ESC => self.state = State::End,
+                _ => self.state = State::Skip,
+            },
+            // Not a proper string terminator otherwise, the byte belongs to what follows
+            State::End => return b == b'\\',
+            State::ZmodemHeader { remaining } => {
+                if b != XON && remaining > 1 {
+                    self.state = State::ZmodemHeader {
+                        remaining: remaining - 1,
+                    };
+                }
+            }
+        }
+        true
+    }
+
+    fn args(&mut self, upload: bool, mut args: Vec<u8>, b: u8, events: &mut Events) {
+        match b {
+            BEL | ESC if upload => {
+                events.push(OutputEvent::UploadRequest {
+                    format: arg(&args, "format").map(|format| lossy(&format)),
+                });
+                if b == ESC {
+                    self.state = State::End;
+                }
+            }
+            b':' if !upload => {
+                if arg(&args, "inline").as_deref() == Some(b"1") {
+                    events.text(FILE);
+                    events.text(&args);
+                    events.text(b":");
+                    self.state = State::PassThrough;
+                    return;
+                }
+                let name = arg(&args, "name")
+                    .and_then(|name| general_purpose::STANDARD.decode(name).ok())
+                    .map(|name| lossy(&name));
+                let size = arg(&args, "size").and_then(|size| lossy(&size).parse().ok());
+                events.push(OutputEvent::Download { name, size });
+                self.state = State::Data {
+                    encoded: Vec::new(),
+                };
+            }
+            _ if b == BEL || b == ESC || args.len() >= MAX_ARGS_LEN => {
+                // Not a transfer after all
+                events.text(if upload { REQUEST_UPLOAD } else { FILE });
+                events.text(&args);
+                events.text(&[b]);
+            }
+            _ => {
+                args.push(b);
+                self.state = State::Args { upload, args };
+            }
+        }
+    }
+}
+
+/// The value of `key` in `key=value` pairs separated by `;`
+fn arg(args: &[u8], key: &str) -> Option<Vec<u8>> {
+    args.split(|&b| b == b';').find_map(|pair| {
+        let (pair_key, value) = pair.split_at(pair.iter().position(|&b| b == b'=')?);
+        (pair_key == key.as_bytes()).then(|| value[1..].to_vec())
+    })
+}
+
+fn lossy(bytes: &[u8]) -> String {
+    String::from_utf8_lossy(bytes).into_owned()
+}
+
+/// Events of one piece of output, with output coalesced in between
+#[derive(Default)]
+struct Events {
+    events: Vec<OutputEvent>,
+    text: Vec<u8>,
+}
+
+impl Events {
+    fn text(&mut self, bytes: &[u8]) {
+        self.text.extend_from_slice(bytes);
+    }
+
+    fn strip_text_suffix(&mut self, suffix: &[u8]) {
+        if self.text.ends_with(suffix) {
+            self.text.truncate(self.text.len() - suffix.len());
+        }
+    }
+
+    fn push(&mut self, event: OutputEvent) {
+        self.flush_text();
+        self.events.push(event);
+    }
+
+    fn flush_text(&mut self) {
+        if !self.text.is_empty() {
+            self.events
+                .push(OutputEvent::Output(std::mem::take(&mut self.text)));
+        }
+    }
+
+    fn finish(mut self) -> Vec<OutputEvent> {
+        self.flush_text();
+        self.events
+    }
+}
+
+/// A piece of a file to upload as lines to type into the session, `contents` should be a
+/// multiple of [`UPLOAD_LINE_LEN`] long unless it's the last piece
+#[must_use]
+pub fn upload_lines(contents: &[u8]) -> Vec<u8> {
+    let mut lines = Vec::with_capacity(contents.len().div_ceil(UPLOAD_LINE_LEN) * 77);
+    for line in contents.chunks(UPLOAD_LINE_LEN) {
+        lines.extend_from_slice(general_purpose::STANDARD.encode(line).as_bytes());
+        lines.push(b'\n');
+    }
+    lines
+}
@@ -0,0 +1,483 @@
+//! Predictive local echo the way mosh has it: what typing does to the line being edited is
+//! drawn over the terminal right away, underlined, rather than once the echo has come back
+//! from the server. Predictions are checked against a [`Screen`] kept from the session's
+//! output, and taken back if it doesn't confirm them in time.
+//!
+//! Nothing is drawn on a line until the server has echoed typing on it, so that nothing
+//! shows where the remote doesn't echo, like at password prompts. Only typing at the end of
+//! a line is predicted, together with backspace and moving the cursor over what was typed,
+//! anything else closes the line until output has caught up. Full-screen applications,
+//! which show the alternate screen, aren't predicted at all.
+use crate::client::screen::{Screen, char_width, utf8_len};
+use anyhow::bail;
+use std::collections::{BTreeMap, VecDeque};
+use std::time::Duration;
+
+/// Adaptive predictions are shown once echo takes longer than this to come back
+const SHOW_ABOVE: Duration = Duration::from_millis(30);
+/// Adaptive predictions are hidden again once echo takes less than this to come back
+const HIDE_BELOW: Duration = Duration::from_millis(20);
+/// How long predictions wait for their echo at the least, it's 3 round trips once known
+const EXPIRE_MIN: Duration = Duration::from_millis(500);
+const EXPIRE_MAX: Duration = Duration::from_secs(5);
+/// Before the round trip is known
+const EXPIRE_UNKNOWN: Duration = Duration::from_secs(1);
+
+const ESC: u8 = 0x1b;
+const UNDERLINE: &[u8] = b"\x1b[0;4m";
+const RESET: &[u8] = b"\x1b[0m";
+
+/// When predictions are drawn
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub enum PredictMode {
+    /// While echo is slow enough to notice
+    #[default]
+    Adaptive,
+    Always,
+    Never,
+}
+
+impl core::str::FromStr for PredictMode {
+    type Err = anyhow::Error;
+
+    fn from_str(mode: &str) -> Result<Self, Self::Err> {
+        match mode {
+            "adaptive" => Ok(Self::Adaptive),
+            "always" => Ok(Self::Always),
+            "never" => Ok(Self::Never),
+            _ => bail!("unknown prediction mode {mode}, expected adaptive, always or never"),
+        }
+    }
+}
+
+/// Sits between a session and the local terminal: typing goes through [`Self::input`] on
+/// its way to the session and output through [`Self::output`] on its way to the terminal,
+/// what they return is written to the terminal. Times are taken from any fixed point.
+#[derive(Debug)]
+pub struct Predictor {
+    mode: PredictMode,
+    screen: Screen,
+    /// Oldest first
+    pending: VecDeque<Prediction>,
+    line: Option<Line>,
+    /// Smoothed time from typing to its echo
+    rtt: Option<Duration>,
+    /// Whether echo is slow enough for adaptive predictions
+    slow: bool,
+    drawn: Option<Drawn>,
+}
+
+/// The line that typing is predicted on
+#[derive(Debug, Copy, Clone)]
+struct Line {
+    row_id: u64,
+    /// Where typing started
+    start: usize,
+    /// Where what was typed ends
+    end: usize,
+    /// The furthest that what was typed has reached, cells up to here are predicted
+    reach: usize,
+    cursor: usize,
+    /// Whether typing is still predicted, otherwise the line is only waiting for output to
+    /// confirm what's pending
+    open: bool,
+    /// Whether the server has echoed typing on the line
+    echoed: bool,
+}
+
+#[derive(Debug, Copy, Clone)]
+struct Prediction {
+    kind: Kind,
+    /// The cell written, or for [`Kind::Move`] where the cursor goes
+    col: usize,
+    /// Where the cursor is after it
+    cursor: usize,
+    /// The [`Screen::generation`] it was made at, output after that may confirm it
+    generation: u64,
+    at: Duration,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Kind {
+    Char(char),
+    Erase,
+    Move,
+}
+
+/// What's drawn over the terminal
+#[derive(Debug)]
+struct Drawn {
+    row: usize,
+    cols: Vec<usize>,
+    /// Where the cursor was left
+    cursor: usize,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Key {
+    Char(char),
+    Backspace,
+    Left,
+    Right,
+    Other,
+}
+
+impl Predictor {
+    /// `screen` is what the terminal shows to begin with
+    #[must_use]
+    pub fn new(mode: PredictMode, screen: Screen) -> Self {
+        Self {
+            mode,
+            screen,
+            pending: VecDeque::new(),
+            line: None,
+            rtt: None,
+            slow: false,
+            drawn: None,
+        }
+    }
+
+    /// Typing, on its way to the session
+    pub fn input(&mut self, bytes: &[u8], now: Duration) -> Vec<u8> {
+        if self.mode == PredictMode::Never {
+            return Vec::new();
+        }
+        self.expire(now);
+        for key in keys(bytes) {
+            if !self.predict(key, now) {
+                self.close_line();
+            }
+        }
+        self.redraw()
+    }
+
+    /// Output of the session, on its way to the terminal
+    pub fn output(&mut self, bytes: &[u8], now: Duration) -> Vec<u8> {
+        if self.mode == PredictMode::Never {
+            return bytes.to_vec();
+        }
+        let mut out = self.undraw();
+        out.extend_from_slice(bytes);
+        self.screen.write(bytes);
+        self.reconcile(now);
+        self.expire(now);
+        self.draw(&mut out);
+        out
+    }
+
+    /// The terminal was resized, what it shows isn't known anymore
+    pub fn resize(&mut self, cols: u16, rows: u16) -> Vec<u8> {
+        let out = self.undraw();
+        self.pending.clear();
+        self.line = None;
+        self.screen.resize(cols, rows);
+        out
+    }
+
+    /// When predictions that haven't been confirmed by then are to be taken back with
+    /// [`Self::tick`]
+    #[must_use]
+    pub fn expires_at(&self) -> Option<Duration> {
+        self.pending
+            .front()
+            .map(|prediction| prediction.at + self.expire_after())
+    }
+
+    /// Takes back predictions that output hasn't confirmed in time
+    pub fn tick(&mut self, now: Duration) -> Vec<u8> {
+        if self.expire(now) {
+            self.redraw()
+        } else {
+            Vec::new()
+        }
+    }
+
+    /// Whether typing is predicted at all, it isn't while a full-screen application runs
+    #[must_use]
+    pub fn is_active(&self) -> bool {
+        self.mode != PredictMode::Never && !self.screen.is_alternate()
+    }
+
+    /// How long echo takes to come back, once measured
+    #[must_use]
+    pub fn rtt(&self) -> Option<Duration> {
+        self.rtt
+    }
+
+    fn predict(&mut self, key: Key, now: Duration) -> bool {
+        if self.screen.is_alternate() || !self.screen.can_draw() {
+            return false;
+        }
+        let (row, col) = self.screen.cursor();
+        let Some(row_id) = self.screen.row_id(row) else {
+            return false;
+        };
+        let mut line = match self.line {
+            Some(line) if line.open && line.row_id == row_id => line,
+            Some(_) => return false,
+            None => Line {
+                row_id,
+                start: col,
+                end: col,
+                reach: col,
+                cursor: col,
+                open: true,
+                echoed: false,
+            },
+        };
+        let cursor = line.cursor;
+        let (kind, col, next) = match key {
+            // The last column is left alone, writing there makes the terminal wrap
+            Key::Char(ch)
+                if cursor == line.end
+                    && cursor + 2 <= self.screen.cols()
+                    && self.is_blank(row, line.reach.max(cursor)) =>
+            {
+                (Kind::Char(ch), cursor, cursor + 1)
+            }
+            Key::Backspace if cursor == line.end && cursor > line.start => {
+                (Kind::Erase, cursor - 1, cursor - 1)
+            }
+            Key::Left if cursor > line.start => (Kind::Move, cursor - 1, cursor - 1),
+            Key::Right if cursor < line.end => (Kind::Move, cursor + 1, cursor + 1),
+            _ => return false,
+        };
+        if kind != Kind::Move {
+            line.end = next;
+            line.reach = line.reach.max(next);
+        }
+        line.cursor = next;
+        self.line = Some(line);
+        self.pending.push_back(Prediction {
+            kind,
+            col,
+            cursor: next,
+            generation: self.screen.generation(),
+            at: now,
+        });
+        true
+    }
+
+    /// Whether the cells of a row from `col` on are known to be empty
+    fn is_blank(&self, row: usize, col: usize) -> bool {
+        (col..self.screen.cols()).all(|col| self.screen.cell(row, col) == Some(' '))
+    }
+
+    /// Nothing more is predicted on the line, the next one has to be echoed again before
+    /// its predictions are drawn
+    fn close_line(&mut self) {
+        if self.pending.is_empty() {
+            self.line = None;
+        } else if let Some(line) = self.line.as_mut() {
+            line.open = false;
+        }
+    }
+
+    /// Drops the predictions that output has confirmed
+    fn reconcile(&mut self, now: Duration) {
+        let Some(mut line) = self.line else {
+            return;
+        };
+        let row = self
+            .screen
+            .find_row(line.row_id)
+            .filter(|_| !self.screen.is_alternate());
+        let Some(row) = row else {
+            // What was predicted on is gone, or a full-screen application took over
+            self.pending.clear();
+            self.line = None;
+            return;
+        };
+        while let Some(&front) = self.pending.front() {
+            let superseded = self.pending.iter().skip(1).any(|later| match front.kind {
+                Kind::Move => true,
+                Kind::Char(_) | Kind::Erase => later.kind != Kind::Move && later.col == front.col,
+            });
+            if !superseded && !self.is_confirmed(row, &front) {
+                break;
+            }
+            self.pending.pop_front();
+            if !superseded {
+                self.measured(now.saturating_sub(front.at));
+                line.echoed = true;
+            }
+        }
+        let caught_up =
+            self.pending.is_empty() && (!line.open || self.screen.cursor() != (row, line.cursor));
+        self.line = (!caught_up).then_some(line);
+    }
+
+    fn is_confirmed(&self, row: usize, prediction: &Prediction) -> bool {
+        let written = self.screen.written(row, prediction.col) > prediction.generation;
+        match prediction.kind {
+            Kind::Char(ch) => written && self.screen.cell(row, prediction.col) == Some(ch),
+            Kind::Erase => written && self.screen.cell(row, prediction.col) == Some(' '),
+            Kind::Move => {
+                self.screen.generation() > prediction.generation
+                    && self.screen.cursor() == (row, prediction.col)
+            }
+        }
+    }
+
+    fn measured(&mut self, rtt: Duration) {
+        let rtt = self.rtt.map_or(rtt, |smoothed| (smoothed * 7 + rtt) / 8);
+        self.rtt = Some(rtt);
+        if rtt > SHOW_ABOVE {
+            self.slow = true;
+        } else if rtt < HIDE_BELOW {
+            self.slow = false;
+        }
+    }
+
+    fn expire_after(&self) -> Duration {
+        self.rtt
+            .map_or(EXPIRE_UNKNOWN, |rtt| rtt * 3)
+            .clamp(EXPIRE_MIN, EXPIRE_MAX)
+    }
+
+    /// Takes back all predictions if the oldest hasn't been confirmed in time, the line
+    /// isn't predicted on anymore
+    fn expire(&mut self, now: Duration) -> bool {
+        let expired = self
+            .expires_at()
+            .is_some_and(|expires_at| expires_at <= now);
+        if expired {
+            self.pending.clear();
+            self.line = None;
+        }
+        expired
+    }
+
+    fn redraw(&mut self) -> Vec<u8> {
+        let mut out = self.undraw();
+        self.draw(&mut out);
+        out
+    }
+
+    /// Draws the predictions over the terminal, the cursor is left where they put it
+    fn draw(&mut self, out: &mut Vec<u8>) {
+        let shown = match self.mode {
+            PredictMode::Adaptive => self.slow,
+            PredictMode::Always => true,
+            PredictMode::Never => false,
+        };
+        let Some(line) = self.line.filter(|line| shown && line.echoed) else {
+            return;
+        };
+        let (row, col) = self.screen.cursor();
+        if self.screen.find_row(line.row_id) != Some(row)
+            || self.screen.is_alternate()
+            || !self.screen.can_draw()
+        {
+            return;
+        }
+        let mut cells = BTreeMap::new();
+        let mut cursor = col;
+        for prediction in &self.pending {
+            match prediction.kind {
+                Kind::Char(ch) => cells.insert(prediction.col, ch),
+                Kind::Erase => cells.insert(prediction.col, ' '),
+                Kind::Move => None,
+            };
+            cursor = prediction.cursor;
+        }
+        // Unknown cells may be half of a wide character, which can't be drawn back
+        cells.retain(|&col, ch| self.screen.cell(row, col).is_some_and(|shown| shown != *ch));
+        if cells.is_empty() && cursor == col {
+            return;
+        }
+        let mut at = col;
+        for (&col, &ch) in &cells {
+            move_cursor(out, at, col);
+            out.extend_from_slice(if ch == ' ' { RESET } else { UNDERLINE });
+            push_char(out, ch);
+            at = col + 1;
+        }
+        restore_attributes(out, &self.screen);
+        move_cursor(out, at, cursor);
+        self.drawn = Some(Drawn {
+            row,
+            cols: cells.into_keys().collect(),
+            cursor,
+        });
+    }
+
+    /// Puts back what the terminal showed before predictions were drawn over it
+    fn undraw(&mut self) -> Vec<u8> {
+        let mut out = Vec::new();
+        let Some(drawn) = self.drawn.take() else {
+            return out;
+        };
+        out.extend_from_slice(RESET);
+        let mut at = drawn.cursor;
+        for col in drawn.cols {
+            move_cursor(&mut out, at, col);
+            push_char(&mut out, self.screen.cell(drawn.row, col).unwrap_or(' '));
+            at = col + 1;
+        }
+        restore_attributes(&mut out, &self.screen);
+        move_cursor(&mut out, at, self.screen.cursor().1);
+        out
+    }
+}
+
+fn move_cursor(out: &mut Vec<u8>, from: usize, to: usize) {
+    if to > from {
+        out.extend_from_slice(format!("\x1b[{}C", to - from).as_bytes());
+    } else if to < from {
+        out.extend_from_slice(format!("\x1b[{}D", from - to).as_bytes());
+    }
+}
+
+fn push_char(out: &mut Vec<u8>, ch: char) {
+    out.extend_from_slice(ch.encode_utf8(&mut [0; 4]).as_bytes());
+}
+
+fn restore_attributes(out: &mut Vec<u8>, screen: &Screen) {
+    out.extend_from_slice(RESET);
+    out.extend_from_slice(screen.sgr());
+}
+
+/// What typing does, as far as predictions go
+fn keys(mut bytes: &[u8]) -> Vec<Key> {
+    let mut keys = Vec::new();
+    while let Some(&first) = bytes.first() {
+        let (key, len) = match first {
+            0x7f | 0x08 => (Key::Backspace, 1),
+            ESC => escape_key(bytes),
+            0x00..=0x1f => (Key::Other, 1),
+            _ => {
+                let len = utf8_len(first).min(bytes.len());
+                let key = std::str::from_utf8(&bytes[..len])
+                    .ok()
+                    .and_then(|typed| typed.chars().next())
+                    .filter(|&ch| !ch.is_control() && char_width(ch) == 1)
+                    .map_or(Key::Other, Key::Char);
+                (key, len)
+            }
+        };
+        keys.push(key);
+        bytes = &bytes[len..];
+    }
+    keys
+}
+
+fn escape_key(bytes: &[u8]) -> (Key, usize) {
+    let len = match bytes.get(1) {
+        // Up to the final byte of the sequence
+        Some(b'[') => bytes
+            .iter()
+            .skip(2)
+            .position(|byte| (0x40..=0x7e).contains(byte))
+            .map_or(bytes.len(), |end| end + 3),
+        Some(b'O') => bytes.len().min(3),
+        Some(_) => 2,
+        None => 1,
+    };
+    let key = match &bytes[..len] {
+        b"\x1b[C" | b"\x1bOC" => Key::Right,
+        b"\x1b[D" | b"\x1bOD" => Key::Left,
+        _ => Key::Other,
+    };
+    (key, len)
+}
@@ -1,6 +1,6 @@
 use crate::client::server_handle::P2TermServerHandle;
 use crate::client::shell_proxy::ClientShellProxy;
-use crate::proto::ClientOpt;
+use crate::proto::{ClientOpt, ExitStatus};
 use crate::streams::{ReadStream, WriteStream};
 use anyhow::Context;
 
@@ -8,14 +8,14 @@ pub async fn run<W: WriteStream, R: ReadStream, S: ClientShellProxy>(
     mut server: P2TermServerHandle<W, R>,
     client_opt: &ClientOpt,
     shell_proxy: S,
-) -> anyhow::Result<()> {
-    server
+) -> anyhow::Result<Option<ExitStatus>> {
+    let capabilities = server
         .handshake(client_opt)
         .await
         .context("server handshake failed")?;
-    let (send, recv) = server.decompose();
+    let (write, read) = server.into_frames();
     shell_proxy
-        .run(send, recv)
+        .run(write, read, capabilities)
         .await
         .context("failed to run shell proxy")
 }
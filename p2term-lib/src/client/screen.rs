@@ -0,0 +1,848 @@
+//! A model of a terminal's screen, kept by following the output written to it, see
+//! [`Screen`]. Only what's needed to draw over the terminal is kept: characters without
+//! their attributes, the cursor, and the modes that change where output goes. Sequences
+//! that aren't understood are skipped.
+
+/// Sequences longer than this aren't recorded, they're still parsed to their end
+const MAX_SEQUENCE: usize = 64;
+/// Attributes kept to restore them, past this they're considered lost until reset
+const MAX_SGR: usize = 1024;
+const TAB_WIDTH: usize = 8;
+
+const BS: u8 = 0x08;
+const HT: u8 = 0x09;
+const LF: u8 = 0x0a;
+const VT: u8 = 0x0b;
+const FF: u8 = 0x0c;
+const CR: u8 = 0x0d;
+const SO: u8 = 0x0e;
+const SI: u8 = 0x0f;
+const CAN: u8 = 0x18;
+const SUB: u8 = 0x1a;
+const ESC: u8 = 0x1b;
+const BEL: u8 = 0x07;
+
+#[derive(Debug, Copy, Clone)]
+struct Cell {
+    /// `None` if unknown, like before anything was written to it, or when it holds the
+    /// right half of a wide character
+    ch: Option<char>,
+    /// The [`Screen::generation`] that last wrote the cell
+    written: u64,
+}
+
+impl Cell {
+    const UNKNOWN: Cell = Cell {
+        ch: None,
+        written: 0,
+    };
+
+    fn blank(generation: u64) -> Self {
+        Self {
+            ch: Some(' '),
+            written: generation,
+        }
+    }
+}
+
+/// A line of the screen, rows keep their id when scrolled so that it can be told where
+/// they went
+#[derive(Debug, Clone)]
+struct Row {
+    id: u64,
+    cells: Vec<Cell>,
+}
+
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+enum State {
+    #[default]
+    Ground,
+    Escape,
+    Csi,
+    /// An OSC, DCS, SOS, PM or APC string, ended by ST or for OSC also by BEL
+    String,
+    StringEscape,
+}
+
+/// Modes that change where output goes
+#[derive(Debug, Copy, Clone)]
+struct Modes {
+    autowrap: bool,
+    /// Rows are counted from the top margin
+    origin: bool,
+    /// Characters are inserted rather than written over what's there
+    insert: bool,
+}
+
+/// Whether characters show as something else, only the DEC line drawing set is told apart
+#[derive(Debug, Default, Copy, Clone)]
+struct Charset {
+    line_drawing: bool,
+    shifted: bool,
+}
+
+/// What `DECSC` saves
+#[derive(Debug, Clone)]
+struct SavedCursor {
+    row: usize,
+    col: usize,
+    sgr: Vec<u8>,
+    charset: Charset,
+}
+
+/// The primary screen while the alternate one is shown
+#[derive(Debug)]
+struct Primary {
+    grid: Vec<Row>,
+    saved: Option<SavedCursor>,
+}
+
+/// What a terminal shows, as far as can be told from its output
+#[derive(Debug)]
+pub struct Screen {
+    cols: usize,
+    rows: usize,
+    grid: Vec<Row>,
+    row: usize,
+    col: usize,
+    /// The cursor is past the last column, the next character goes on the next line
+    wrap_pending: bool,
+    top: usize,
+    bottom: usize,
+    modes: Modes,
+    charset: Charset,
+    /// The `SGR` sequences since attributes were last reset, replaying them restores the
+    /// attributes in use. `None` if too many to keep
+    sgr: Option<Vec<u8>>,
+    saved: Option<SavedCursor>,
+    primary: Option<Primary>,
+    generation: u64,
+    /// Rows made so far, which numbers their ids
+    rows_made: u64,
+    state: State,
+    sequence: Vec<u8>,
+    utf8: Vec<u8>,
+}
+
+impl Screen {
+    /// A screen that nothing is known about, the cursor is assumed to be on its last line
+    #[must_use]
+    pub fn new(cols: u16, rows: u16) -> Self {
+        let cols = usize::from(cols.max(1));
+        let rows = usize::from(rows.max(1));
+        Self {
+            cols,
+            rows,
+            grid: Vec::new(),
+            row: rows - 1,
+            col: 0,
+            wrap_pending: false,
+            top: 0,
+            bottom: rows - 1,
+            modes: Modes {
+                autowrap: true,
+                origin: false,
+                insert: false,
+            },
+            charset: Charset::default(),
+            sgr: Some(Vec::new()),
+            saved: None,
+            primary: None,
+            generation: 0,
+            rows_made: 0,
+            state: State::Ground,
+            sequence: Vec::new(),
+            utf8: Vec::new(),
+        }
+        .with_grid(Cell::UNKNOWN)
+    }
+
+    /// A screen of a terminal that was just opened, empty with the cursor at the top
+    #[must_use]
+    pub fn blank(cols: u16, rows: u16) -> Self {
+        let mut screen = Self::new(cols, rows).with_grid(Cell::blank(0));
+        screen.row = 0;
+        screen
+    }
+
+    /// The terminal was resized, what it shows may have been rearranged so none of it is
+    /// trusted anymore
+    pub fn resize(&mut self, cols: u16, rows: u16) {
+        self.cols = usize::from(cols.max(1));
+        self.rows = usize::from(rows.max(1));
+        self.grid = self.new_grid(Cell::UNKNOWN);
+        if self.primary.is_some() {
+            let grid = self.new_grid(Cell::UNKNOWN);
+            if let Some(primary) = self.primary.as_mut() {
+                primary.grid = grid;
+            }
+        }
+        self.row = self.row.min(self.rows - 1);
+        self.col = self.col.min(self.cols - 1);
+        self.wrap_pending = false;
+        self.top = 0;
+        self.bottom = self.rows - 1;
+    }
+
+    /// Follows output written to the terminal, which may end anywhere in a sequence
+    pub fn write(&mut self, bytes: &[u8]) {
+        self.generation += 1;
+        for &byte in bytes {
+            self.advance(byte);
+        }
+    }
+
+    /// The cursor as `(row, col)`, from the top left
+    #[must_use]
+    pub fn cursor(&self) -> (usize, usize) {
+        (self.row, self.col)
+    }
+
+    /// The character in a cell, `None` if unknown
+    #[must_use]
+    pub fn cell(&self, row: usize, col: usize) -> Option<char> {
+        self.grid.get(row)?.cells.get(col)?.ch
+    }
+
+    /// The [`Self::generation`] that last wrote a cell, erasing it included
+    #[must_use]
+    pub fn written(&self, row: usize, col: usize) -> u64 {
+        self.grid
+            .get(row)
+            .and_then(|row| row.cells.get(col))
+            .map_or(0, |cell| cell.written)
+    }
+
+    /// Counts the calls to [`Self::write`]
+    #[must_use]
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// An id for what a row shows, which stays with it when scrolled
+    #[must_use]
+    pub fn row_id(&self, row: usize) -> Option<u64> {
+        self.grid.get(row).map(|row| row.id)
+    }
+
+    /// Where a row is now, `None` once it has been scrolled off or isn't shown anymore
+    #[must_use]
+    pub fn find_row(&self, id: u64) -> Option<usize> {
+        self.grid.iter().position(|row| row.id == id)
+    }
+
+    #[must_use]
+    pub fn cols(&self) -> usize {
+        self.cols
+    }
+
+    /// Whether the alternate screen is shown, as it is by full-screen applications
+    #[must_use]
+    pub fn is_alternate(&self) -> bool {
+        self.primary.is_some()
+    }
+
+    /// Whether characters written at the cursor show up as they are and the cursor can be
+    /// put back where it was, so that something can be drawn over the terminal and erased
+    /// again without output noticing
+    #[must_use]
+    pub fn can_draw(&self) -> bool {
+        self.state == State::Ground
+            && self.utf8.is_empty()
+            && !self.wrap_pending
+            && self.modes.autowrap
+            && !self.modes.origin
+            && !self.modes.insert
+            && !self.charset.line_drawing
+            && !self.charset.shifted
+            && self.sgr.is_some()
+    }
+
+    /// The sequences that restore the attributes in use, after resetting them
+    #[must_use]
+    pub fn sgr(&self) -> &[u8] {
+        self.sgr.as_deref().unwrap_or_default()
+    }
+
+    fn with_grid(mut self, cell: Cell) -> Self {
+        self.grid = self.new_grid(cell);
+        self
+    }
+
+    fn new_grid(&mut self, cell: Cell) -> Vec<Row> {
+        (0..self.rows).map(|_| self.new_row(cell)).collect()
+    }
+
+    fn new_row(&mut self, cell: Cell) -> Row {
+        self.rows_made += 1;
+        Row {
+            id: self.rows_made,
+            cells: vec![cell; self.cols],
+        }
+    }
+
+    fn advance(&mut self, byte: u8) {
+        match self.state {
+            State::Ground => self.ground(byte),
+            State::Escape => self.escape(byte),
+            State::Csi => self.csi(byte),
+            State::String => match byte {
+                ESC => self.state = State::StringEscape,
+                BEL | CAN | SUB => self.state = State::Ground,
+                _ => {}
+            },
+            State::StringEscape => {
+                if byte == b'\\' {
+                    self.state = State::Ground;
+                } else {
+                    // Another sequence cuts the string short
+                    self.sequence.clear();
+                    self.state = State::Escape;
+                    self.advance(byte);
+                }
+            }
+        }
+    }
+
+    fn ground(&mut self, byte: u8) {
+        if byte >= 0x80 {
+            self.utf8.push(byte);
+            let len = utf8_len(self.utf8[0]);
+            if self.utf8.len() < len {
+                return;
+            }
+            let ch = std::str::from_utf8(&self.utf8)
+                .ok()
+                .and_then(|s| s.chars().next())
+                .unwrap_or(char::REPLACEMENT_CHARACTER);
+            self.utf8.clear();
+            self.print(ch);
+            return;
+        }
+        if !self.utf8.is_empty() {
+            // A sequence cut short
+            self.utf8.clear();
+            self.print(char::REPLACEMENT_CHARACTER);
+        }
+        if byte < 0x20 || byte == 0x7f {
+            self.control(byte);
+        } else {
+            self.print(char::from(byte));
+        }
+    }
+
+    fn control(&mut self, byte: u8) {
+        match byte {
+            BS => {
+                self.col = self.col.saturating_sub(1);
+                self.wrap_pending = false;
+            }
+            HT => {
+                self.col = ((self.col / TAB_WIDTH + 1) * TAB_WIDTH).min(self.cols - 1);
+                self.wrap_pending = false;
+            }
+            LF | VT | FF => {
+                self.linefeed();
+                self.wrap_pending = false;
+            }
+            CR => {
+                self.col = 0;
+                self.wrap_pending = false;
+            }
+            SO => self.charset.shifted = true,
+            SI => self.charset.shifted = false,
+            ESC => {
+                self.sequence.clear();
+                self.state = State::Escape;
+            }
+            CAN | SUB => self.state = State::Ground,
+            _ => {}
+        }
+    }
+
+    fn print(&mut self, ch: char) {
+        let width = char_width(ch);
+        if width == 0 {
+            return;
+        }
+        if self.wrap_pending {
+            self.col = 0;
+            self.linefeed();
+            self.wrap_pending = false;
+        }
+        if self.col + width > self.cols {
+            if self.modes.autowrap {
+                self.col = 0;
+                self.linefeed();
+            } else {
+                self.col = self.cols.saturating_sub(width);
+            }
+        }
+        if self.modes.insert {
+            self.insert_cells(width);
+        }
+        let generation = self.generation;
+        let cells = &mut self.grid[self.row].cells;
+        cells[self.col] = Cell {
+            ch: Some(ch),
+            written: generation,
+        };
+        if let Some(right) = cells.get_mut(self.col + 1).filter(|_| width == 2) {
+            *right = Cell {
+                ch: None,
+                written: generation,
+            };
+        }
+        if self.col + width >= self.cols {
+            self.col = self.cols - 1;
+            self.wrap_pending = self.modes.autowrap;
+        } else {
+            self.col += width;
+        }
+    }
+
+    fn escape(&mut self, byte: u8) {
+        match byte {
+            // Intermediates, like choosing a character set
+            0x20..=0x2f => {
+                self.sequence.push(byte);
+                return;
+            }
+            CAN | SUB => {
+                self.state = State::Ground;
+                return;
+            }
+            ESC => {
+                self.sequence.clear();
+                return;
+            }
+            0x00..=0x1f => {
+                self.control(byte);
+                return;
+            }
+            _ => {}
+        }
+        self.state = State::Ground;
+        match (self.sequence.as_slice(), byte) {
+            (b"", b'[') => {
+                self.state = State::Csi;
+            }
+            (b"", b']' | b'P' | b'X' | b'^' | b'_') => self.state = State::String,
+            (b"", b'7') => self.save_cursor(),
+            (b"", b'8') => self.restore_cursor(),
+            (b"", b'D') => {
+                self.linefeed();
+                self.wrap_pending = false;
+            }
+            (b"", b'E') => {
+                self.linefeed();
+                self.col = 0;
+                self.wrap_pending = false;
+            }
+            (b"", b'M') => {
+                self.reverse_index();
+                self.wrap_pending = false;
+            }
+            (b"", b'c') => self.reset(),
+            (b"(", b'0') => self.charset.line_drawing = true,
+            (b"(", _) => self.charset.line_drawing = false,
+            _ => {}
+        }
+        self.sequence.clear();
+    }
+
+    fn csi(&mut self, byte: u8) {
+        match byte {
+            0x20..=0x3f if self.sequence.len() < MAX_SEQUENCE => self.sequence.push(byte),
+            0x20..=0x3f => {}
+            0x40..=0x7e => {
+                self.state = State::Ground;
+                if self.sequence.len() < MAX_SEQUENCE {
+                    self.csi_dispatch(byte);
+                }
+                self.sequence.clear();
+            }
+            ESC => {
+                self.sequence.clear();
+                self.state = State::Escape;
+            }
+            CAN | SUB => self.state = State::Ground,
+            0x00..=0x1f => self.control(byte),
+            _ => {}
+        }
+    }
+
+    fn csi_dispatch(&mut self, action: u8) {
+        let (private, rest) = match self.sequence.first() {
+            Some(&marker @ (b'?' | b'>' | b'<' | b'=')) => (Some(marker), &self.sequence[1..]),
+            _ => (None, self.sequence.as_slice()),
+        };
+        let has_intermediates = rest.iter().any(|byte| (0x20..=0x2f).contains(byte));
+        let params = parse_params(rest);
+        if has_intermediates {
+            return;
+        }
+        if private == Some(b'?') {
+            match action {
+                b'h' => self.set_private_modes(&params, true),
+                b'l' => self.set_private_modes(&params, false),
+                _ => {}
+            }
+            return;
+        }
+        if private.is_some() {
+            return;
+        }
+        let n = param(&params, 0, 1);
+        match action {
+            b'A' => self.cursor_up(n),
+            b'B' | b'e' => self.cursor_down(n),
+            b'C' | b'a' => self.set_col(self.col.saturating_add(n)),
+            b'D' => self.set_col(self.col.saturating_sub(n)),
+            b'E' => {
+                self.cursor_down(n);
+                self.col = 0;
+            }
+            b'F' => {
+                self.cursor_up(n);
+                self.col = 0;
+            }
+            b'G' | b'`' => self.set_col(n - 1),
+            b'H' | b'f' => {
+                self.set_row(param(&params, 0, 1) - 1);
+                self.set_col(param(&params, 1, 1) - 1);
+            }
+            b'd' => self.set_row(n - 1),
+            b'J' => self.erase_display(param(&params, 0, 0)),
+            b'K' => self.erase_line(param(&params, 0, 0)),
+            b'X' => {
+                let end = self.col.saturating_add(n).min(self.cols);
+                self.erase_cells(self.row, self.col, end);
+            }
+            b'P' => self.delete_cells(n),
+            b'@' => self.insert_cells(n),
+            b'L' => self.insert_lines(n),
+            b'M' => self.delete_lines(n),
+            b'S' => self.scroll_up(self.top, n),
+            b'T' => self.scroll_down(self.top, n),
+            b'm' => self.record_sgr(&params),
+            b'r' => {
+                let top = param(&params, 0, 1) - 1;
+                let bottom = param(&params, 1, self.rows).min(self.rows) - 1;
+                if top < bottom {
+                    self.top = top;
+                    self.bottom = bottom;
+                    self.home();
+                }
+            }
+            b's' if params.is_empty() => self.save_cursor(),
+            b'u' => self.restore_cursor(),
+            b'h' | b'l' if params.contains(&4) => self.modes.insert = action == b'h',
+            _ => {}
+        }
+        if action != b'm' {
+            self.wrap_pending = false;
+        }
+    }
+
+    fn set_private_modes(&mut self, params: &[usize], set: bool) {
+        for &mode in params {
+            match mode {
+                6 => {
+                    self.modes.origin = set;
+                    self.home();
+                }
+                7 => self.modes.autowrap = set,
+                47 | 1047 | 1049 => self.alternate(mode, set),
+                _ => {}
+            }
+        }
+    }
+
+    fn alternate(&mut self, mode: usize, show: bool) {
+        if show == self.is_alternate() {
+            return;
+        }
+        if show {
+            if mode == 1049 {
+                self.save_cursor();
+            }
+            let blank = self.new_grid(Cell::blank(self.generation));
+            let grid = std::mem::replace(&mut self.grid, blank);
+            self.primary = Some(Primary {
+                grid,
+                saved: self.saved.clone(),
+            });
+        } else if let Some(primary) = self.primary.take() {
+            self.grid = primary.grid;
+            self.saved = primary.saved;
+            if mode == 1049 {
+                self.restore_cursor();
+            }
+        }
+    }
+
+    fn record_sgr(&mut self, params: &[usize]) {
+        let sequence = [b"\x1b[", self.sequence.as_slice(), b"m"].concat();
+        if params.first().is_none_or(|&first| first == 0) {
+            // Starts with a reset, so it's all that's needed to restore the attributes
+            self.sgr = Some(sequence);
+        } else if let Some(sgr) = self.sgr.as_mut() {
+            sgr.extend_from_slice(&sequence);
+            if sgr.len() > MAX_SGR {
+                self.sgr = None;
+            }
+        }
+    }
+
+    fn save_cursor(&mut self) {
+        self.saved = Some(SavedCursor {
+            row: self.row,
+            col: self.col,
+            sgr: self.sgr().to_vec(),
+            charset: self.charset,
+        });
+    }
+
+    fn restore_cursor(&mut self) {
+        let saved = self.saved.clone().unwrap_or(SavedCursor {
+            row: 0,
+            col: 0,
+            sgr: Vec::new(),
+            charset: Charset::default(),
+        });
+        self.row = saved.row.min(self.rows - 1);
+        self.col = saved.col.min(self.cols - 1);
+        self.sgr = Some(saved.sgr);
+        self.charset = saved.charset;
+        self.wrap_pending = false;
+    }
+
+    fn reset(&mut self) {
+        let (cols, rows) = (self.cols, self.rows);
+        let (generation, rows_made) = (self.generation, self.rows_made);
+        *self = Self {
+            cols,
+            rows,
+            row: 0,
+            col: 0,
+            top: 0,
+            bottom: rows - 1,
+            generation,
+            rows_made,
+            ..Self::new(1, 1)
+        };
+        self.grid = self.new_grid(Cell::blank(generation));
+    }
+
+    fn home(&mut self) {
+        self.row = if self.modes.origin { self.top } else { 0 };
+        self.col = 0;
+    }
+
+    fn set_col(&mut self, col: usize) {
+        self.col = col.min(self.cols - 1);
+    }
+
+    fn set_row(&mut self, row: usize) {
+        self.row = if self.modes.origin {
+            self.top.saturating_add(row).min(self.bottom)
+        } else {
+            row.min(self.rows - 1)
+        };
+    }
+
+    fn cursor_up(&mut self, n: usize) {
+        let limit = if self.row >= self.top { self.top } else { 0 };
+        self.row = self.row.saturating_sub(n).max(limit);
+    }
+
+    fn cursor_down(&mut self, n: usize) {
+        let limit = if self.row <= self.bottom {
+            self.bottom
+        } else {
+            self.rows - 1
+        };
+        self.row = self.row.saturating_add(n).min(limit);
+    }
+
+    fn linefeed(&mut self) {
+        if self.row == self.bottom {
+            self.scroll_up(self.top, 1);
+        } else if self.row < self.rows - 1 {
+            self.row += 1;
+        }
+    }
+
+    fn reverse_index(&mut self) {
+        if self.row == self.top {
+            self.scroll_down(self.top, 1);
+        } else {
+            self.row = self.row.saturating_sub(1);
+        }
+    }
+
+    /// Moves the rows from `from` to the bottom margin up, blank rows come in at the bottom
+    fn scroll_up(&mut self, from: usize, n: usize) {
+        if from > self.bottom {
+            return;
+        }
+        let n = n.min(self.bottom + 1 - from);
+        self.grid.drain(from..from + n);
+        for _ in 0..n {
+            let blank = self.new_row(Cell::blank(self.generation));
+            self.grid.insert(self.bottom + 1 - n, blank);
+        }
+    }
+
+    /// Moves the rows from `from` to the bottom margin down, blank rows come in at `from`
+    fn scroll_down(&mut self, from: usize, n: usize) {
+        if from > self.bottom {
+            return;
+        }
+        let n = n.min(self.bottom + 1 - from);
+        self.grid.drain(self.bottom + 1 - n..=self.bottom);
+        for _ in 0..n {
+            let blank = self.new_row(Cell::blank(self.generation));
+            self.grid.insert(from, blank);
+        }
+    }
+
+    fn insert_lines(&mut self, n: usize) {
+        if (self.top..=self.bottom).contains(&self.row) {
+            self.scroll_down(self.row, n);
+            self.col = 0;
+        }
+    }
+
+    fn delete_lines(&mut self, n: usize) {
+        if (self.top..=self.bottom).contains(&self.row) {
+            self.scroll_up(self.row, n);
+            self.col = 0;
+        }
+    }
+
+    fn insert_cells(&mut self, n: usize) {
+        let n = n.min(self.cols - self.col);
+        let blank = Cell::blank(self.generation);
+        let cells = &mut self.grid[self.row].cells;
+        cells.truncate(self.cols - n);
+        cells.splice(self.col..self.col, std::iter::repeat_n(blank, n));
+        // Everything that moved is written anew
+        for cell in &mut cells[self.col..] {
+            cell.written = self.generation;
+        }
+    }
+
+    fn delete_cells(&mut self, n: usize) {
+        let n = n.min(self.cols - self.col);
+        let blank = Cell::blank(self.generation);
+        let cells = &mut self.grid[self.row].cells;
+        cells.drain(self.col..self.col + n);
+        cells.extend(std::iter::repeat_n(blank, n));
+        for cell in &mut cells[self.col..] {
+            cell.written = self.generation;
+        }
+    }
+
+    fn erase_cells(&mut self, row: usize, from: usize, to: usize) {
+        let blank = Cell::blank(self.generation);
+        if let Some(row) = self.grid.get_mut(row) {
+            row.cells[from.min(to)..to].fill(blank);
+        }
+    }
+
+    fn erase_line(&mut self, mode: usize) {
+        match mode {
+            0 => self.erase_cells(self.row, self.col, self.cols),
+            1 => self.erase_cells(self.row, 0, self.col + 1),
+            2 => self.erase_cells(self.row, 0, self.cols),
+            _ => {}
+        }
+    }
+
+    fn erase_display(&mut self, mode: usize) {
+        let rows = match mode {
+            0 => {
+                self.erase_line(0);
+                self.row + 1..self.rows
+            }
+            1 => {
+                self.erase_line(1);
+                0..self.row
+            }
+            2 => 0..self.rows,
+            _ => return,
+        };
+        for row in rows {
+            self.erase_cells(row, 0, self.cols);
+        }
+    }
+}
+
+/// The columns a character takes up, roughly as terminals have it: combining characters
+/// take none, East Asian wide ones and emoji two
+#[must_use]
+pub fn char_width(ch: char) -> usize {
+    match u32::from(ch) {
+        0x0300..=0x036f
+        | 0x1ab0..=0x1aff
+        | 0x1dc0..=0x1dff
+        | 0x200b..=0x200f
+        | 0x20d0..=0x20ff
+        | 0xfe00..=0xfe0f
+        | 0xfe20..=0xfe2f => 0,
+        0x1100..=0x115f
+        | 0x2e80..=0x303e
+        | 0x3041..=0x33ff
+        | 0x3400..=0x4dbf
+        | 0x4e00..=0x9fff
+        | 0xa000..=0xa4cf
+        | 0xac00..=0xd7a3
+        | 0xf900..=0xfaff
+        | 0xfe30..=0xfe4f
+        | 0xff00..=0xff60
+        | 0xffe0..=0xffe6
+        | 0x1f300..=0x1f64f
+        | 0x1f900..=0x1f9ff
+        | 0x20000..=0x3fffd => 2,
+        _ => 1,
+    }
+}
+
+/// The length of a UTF-8 sequence from its first byte, invalid ones end right away
+pub(crate) fn utf8_len(first: u8) -> usize {
+    match first {
+        0xc0..=0xdf => 2,
+        0xe0..=0xef => 3,
+        0xf0..=0xf7 => 4,
+        _ => 1,
+    }
+}
+
+/// Numeric parameters of a `CSI` sequence, sub-parameters are skipped
+fn parse_params(bytes: &[u8]) -> Vec<usize> {
+    if bytes.is_empty() {
+        return Vec::new();
+    }
+    bytes
+        .split(|&byte| byte == b';')
+        .map(|param| {
+            param
+                .iter()
+                .take_while(|byte| byte.is_ascii_digit())
+                .fold(0usize, |n, digit| {
+                    n.saturating_mul(10)
+                        .saturating_add(usize::from(digit - b'0'))
+                })
+        })
+        .collect()
+}
+
+/// A parameter, where 0 and missing ones are the default
+fn param(params: &[usize], index: usize, default: usize) -> usize {
+    match params.get(index) {
+        Some(&n) if n > 0 => n,
+        _ => default,
+    }
+}
@@ -1,14 +1,25 @@
-use crate::proto::ClientOpt;
+use crate::client::connection::P2TermConnection;
+use crate::proto::codec::{FrameReader, FrameWriter};
+use crate::proto::{
+    Capabilities, ClientOpt, HELLO, HelloStatus, Keepalive, MIN_PROTOCOL_VERSION, ServerHello,
+    WELCOME, decode_handshake, is_compatible_version, peek_version, read_handshake,
+    write_handshake,
+};
 use crate::streams::{ReadStream, WriteStream};
-use anyhow::Context;
-use iroh::Endpoint;
+use anyhow::{Context, bail};
 use iroh::endpoint::{RecvStream, SendStream};
 use iroh_base::{PublicKey, SecretKey};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::sync::{Arc, OnceLock};
 
 pub struct P2TermServerHandle<W, R> {
     send_stream: W,
     recv_stream: R,
+    /// The connection the session runs on, if more sessions can be opened on it
+    connection: Option<P2TermConnection>,
+    /// What the server supports, shared by all sessions on the connection once known
+    server_capabilities: Arc<OnceLock<Capabilities>>,
+    /// How the session is kept alive, once negotiated
+    keepalive: Option<Keepalive>,
 }
 
 impl<W, R> P2TermServerHandle<W, R> {
@@ -16,30 +27,44 @@ impl<W, R> P2TermServerHandle<W, R> {
         Self {
             send_stream: w,
             recv_stream: r,
+            connection: None,
+            server_capabilities: Arc::default(),
+            keepalive: None,
         }
     }
+
+    pub(crate) fn on_connection(w: W, r: R, connection: P2TermConnection) -> Self {
+        Self {
+            send_stream: w,
+            recv_stream: r,
+            server_capabilities: connection.server_capabilities().clone(),
+            connection: Some(connection),
+            keepalive: None,
+        }
+    }
+
+    /// The connection the session runs on, if more sessions can be opened on it
+    pub fn connection(&self) -> Option<&P2TermConnection> {
+        self.connection.as_ref()
+    }
 }
 
 impl P2TermServerHandle<SendStream, RecvStream> {
     pub async fn connect(secret_key: SecretKey, peer: PublicKey) -> anyhow::Result<Self> {
-        let ep = Endpoint::builder()
-            .alpns(vec![crate::proto::ALPN.to_vec()])
-            .secret_key(secret_key)
-            .bind()
+        P2TermConnection::dial(secret_key, peer)
+            .await?
+            .open_session()
             .await
-            .context("failed to start client endpoint")?;
-        let con = ep
-            .connect(peer, crate::proto::ALPN)
-            .await
-            .with_context(|| format!("failed to open connection to peer={peer}"))?;
-        let (send_stream, recv_stream) = con
-            .open_bi()
+    }
+
+    /// Opens another session over the same connection, skipping the connection setup
+    /// and hole punching that [`Self::connect`] goes through
+    pub async fn open_session(&self) -> anyhow::Result<Self> {
+        self.connection
+            .as_ref()
+            .context("this session is not running on a connection that can open more sessions")?
+            .open_session()
             .await
-            .context("failed to open bidirectional stream to server")?;
-        Ok(Self {
-            send_stream,
-            recv_stream,
-        })
     }
 }
 
@@ -48,25 +73,63 @@ where
     W: WriteStream,
     R: ReadStream,
 {
-    pub async fn handshake(&mut self, client_opt: &ClientOpt) -> anyhow::Result<()> {
-        let bytes = postcard::to_allocvec(client_opt).context("failed to serialize client opt")?;
-        let bytes_len: u16 = bytes
-            .len()
-            .try_into()
-            .with_context(|| format!("client opt len too large {}", bytes.len()))?;
-        // Write vectored is a good candidate here, but for some reason
-        // it only manages the first buffer every time in practice
-        self.send_stream.write_u16_le(bytes_len).await?;
-        self.send_stream.write_all(&bytes).await?;
-        let mut resp_buf = [0u8; crate::proto::WELCOME.len()];
-        self.recv_stream
-            .read_exact(&mut resp_buf)
+    /// Sends the client's hello, returning the capabilities negotiated for the session
+    pub async fn handshake(&mut self, client_opt: &ClientOpt) -> anyhow::Result<Capabilities> {
+        write_handshake(&mut self.send_stream, HELLO, client_opt)
+            .await
+            .context("failed to send client hello")?;
+        let bytes = read_handshake(&mut self.recv_stream, WELCOME)
             .await
-            .context("failed to read server welcome message")?;
-        Ok(())
+            .context(
+                "failed to read server welcome message, the server may be running an incompatible version",
+            )?;
+        let version = peek_version(&bytes)?;
+        if !is_compatible_version(version) {
+            bail!(
+                "server speaks protocol version {version}, oldest supported by this client is {MIN_PROTOCOL_VERSION}, upgrade the server"
+            );
+        }
+        let server_hello: ServerHello = decode_handshake(&bytes)?;
+        let _ = self.server_capabilities.set(server_hello.capabilities);
+        if let HelloStatus::Rejected(rejection) = server_hello.status {
+            return Err(rejection.into());
+        }
+        if client_opt.version < server_hello.min_version {
+            bail!(
+                "client speaks protocol version {}, oldest supported by the server is {}, upgrade the client",
+                client_opt.version,
+                server_hello.min_version
+            );
+        }
+        let capabilities = client_opt
+            .capabilities
+            .intersection(server_hello.capabilities);
+        let required = client_opt.required_capabilities();
+        if !capabilities.contains(required) {
+            bail!(
+                "the server does not support the requested session (missing capabilities {:#x}), upgrade the server",
+                required
+                    .intersection(Capabilities::from_bits(!capabilities.bits()))
+                    .bits()
+            );
+        }
+        if capabilities.contains(Capabilities::KEEPALIVE) {
+            self.keepalive = server_hello.keepalive;
+        }
+        Ok(capabilities)
     }
 
     pub fn decompose(self) -> (W, R) {
         (self.send_stream, self.recv_stream)
     }
+
+    /// The session's streams framed, keeping the session alive if that was negotiated.
+    /// Whoever writes to the session sends [`crate::proto::codec::Frame::Keepalive`] when
+    /// [`FrameWriter::keepalive_due`] completes
+    pub fn into_frames(self) -> (FrameWriter<W>, FrameReader<R>) {
+        (
+            FrameWriter::new(self.send_stream).with_keepalive(self.keepalive),
+            FrameReader::new(self.recv_stream).with_keepalive(self.keepalive),
+        )
+    }
 }
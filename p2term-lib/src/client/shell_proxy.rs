@@ -1,8 +1,16 @@
+use crate::proto::codec::{FrameReader, FrameWriter};
+use crate::proto::{Capabilities, ExitStatus};
 use crate::streams::{ReadStream, WriteStream};
 use std::fmt::Debug;
 
 pub trait ClientShellProxy: Debug {
-    fn run<W, R>(self, write: W, read: R) -> impl Future<Output = anyhow::Result<()>>
+    /// Runs the session, returning the remote exit status if the server sent one
+    fn run<W, R>(
+        self,
+        write: FrameWriter<W>,
+        read: FrameReader<R>,
+        capabilities: Capabilities,
+    ) -> impl Future<Output = anyhow::Result<Option<ExitStatus>>>
     where
         W: WriteStream,
         R: ReadStream;
@@ -0,0 +1,101 @@
+//! Compression of a session's output, see [`Capabilities::COMPRESSION`].
+//!
+//! Each session stream carries one zstd stream, which the sender flushes after every burst
+//! of output so that nothing waits on more output to arrive. Later bursts are compressed
+//! against what was sent before, which is what makes redraws of a full screen cheap.
+use crate::proto::Capabilities;
+use crate::proto::codec::{FRAME_MAX_LEN, Frame};
+use anyhow::{Context, bail};
+use std::io::Write;
+use zstd::stream::raw::{Decoder, InBuffer, Operation, OutBuffer};
+
+/// Fast enough to not be noticed while typing, while still shrinking terminal output a lot
+const LEVEL: i32 = 3;
+
+/// Output decoded at a time
+const DECODE_BUF_LEN: usize = 64 * 1024;
+
+/// Turns a session's output into frames, compressed if that was negotiated
+pub struct OutputEncoder {
+    zstd: Option<zstd::stream::write::Encoder<'static, Vec<u8>>>,
+}
+
+impl core::fmt::Debug for OutputEncoder {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("OutputEncoder")
+            .field("compressed", &self.zstd.is_some())
+            .finish()
+    }
+}
+
+impl OutputEncoder {
+    pub fn new(capabilities: Capabilities) -> anyhow::Result<Self> {
+        let zstd = if capabilities.contains(Capabilities::COMPRESSION) {
+            Some(
+                zstd::stream::write::Encoder::new(Vec::new(), LEVEL)
+                    .context("failed to create zstd encoder")?,
+            )
+        } else {
+            None
+        };
+        Ok(Self { zstd })
+    }
+
+    /// A frame of a burst of output, which may be at most [`FRAME_MAX_LEN`] long
+    pub fn frame(&mut self, output: &[u8]) -> anyhow::Result<Frame> {
+        let Some(zstd) = self.zstd.as_mut() else {
+            return Ok(Frame::Stdout(output.to_vec()));
+        };
+        if output.len() > FRAME_MAX_LEN {
+            bail!("output burst of {} bytes is too large", output.len());
+        }
+        zstd.write_all(output)
+            .context("failed to compress output")?;
+        // Ends the burst, everything written so far can be decoded from what's been sent
+        zstd.flush().context("failed to compress output")?;
+        Ok(Frame::CompressedStdout(std::mem::take(zstd.get_mut())))
+    }
+}
+
+/// Decodes [`Frame::CompressedStdout`] of a session
+pub struct OutputDecoder {
+    zstd: Decoder<'static>,
+}
+
+impl core::fmt::Debug for OutputDecoder {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("OutputDecoder").finish_non_exhaustive()
+    }
+}
+
+impl OutputDecoder {
+    pub fn new() -> anyhow::Result<Self> {
+        Ok(Self {
+            zstd: Decoder::new().context("failed to create zstd decoder")?,
+        })
+    }
+
+    /// The output in the next compressed frame, which like an uncompressed one may be at
+    /// most [`FRAME_MAX_LEN`] long
+    pub fn decode(&mut self, compressed: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let mut input = InBuffer::around(compressed);
+        let mut buf = vec![0u8; DECODE_BUF_LEN];
+        let mut output = Vec::new();
+        loop {
+            let mut out = OutBuffer::around(buf.as_mut_slice());
+            self.zstd
+                .run(&mut input, &mut out)
+                .context("failed to decompress output")?;
+            let decoded = out.pos();
+            output.extend_from_slice(&buf[..decoded]);
+            if output.len() > FRAME_MAX_LEN {
+                bail!("compressed output decodes to more than {FRAME_MAX_LEN} bytes");
+            }
+            // Everything that can be decoded is once the input is used up without
+            // filling the buffer
+            if input.pos() == compressed.len() && decoded < buf.len() {
+                return Ok(output);
+            }
+        }
+    }
+}
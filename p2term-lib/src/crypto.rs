@@ -1,6 +1,6 @@
 use crate::convert::{Bytes32Convert, HexConvert};
 use anyhow::Context;
-use rand_core::SeedableRng;
+use rand_core::{RngCore, SeedableRng};
 use std::path::Path;
 
 #[must_use]
@@ -9,6 +9,14 @@ pub fn generate_secret_key() -> iroh_base::SecretKey {
     iroh_base::SecretKey::generate(&mut rng)
 }
 
+#[must_use]
+pub fn generate_resume_token() -> crate::proto::ResumeToken {
+    let mut rng = rand_chacha::ChaCha20Rng::from_os_rng();
+    let mut token = [0u8; 32];
+    rng.fill_bytes(&mut token);
+    crate::proto::ResumeToken(token)
+}
+
 pub fn any_secret_key(
     hex_input: Option<&str>,
     file: Option<&Path>,
@@ -0,0 +1,115 @@
+//! Unreliable datagrams multiplexed over a connection.
+//!
+//! [`crate::proto::SessionKind::ForwardUdp`] sessions carry their payloads as QUIC datagrams
+//! rather than on the session stream, each prefixed with the session's flow id as a
+//! little-endian `u32`. The session stream only sets the flow up and ends it.
+//! Datagrams for a flow that isn't set up are dropped, as are those arriving faster than the
+//! flow takes them, like a congested udp socket would.
+use rustc_hash::FxHashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use tokio::sync::mpsc;
+
+pub const FLOW_ID_LEN: usize = 4;
+
+/// Datagrams queued per flow before further ones are dropped
+const FLOW_QUEUE: usize = 128;
+
+#[must_use]
+pub fn encode(flow: u32, payload: &[u8]) -> Vec<u8> {
+    let mut datagram = Vec::with_capacity(FLOW_ID_LEN + payload.len());
+    datagram.extend_from_slice(&flow.to_le_bytes());
+    datagram.extend_from_slice(payload);
+    datagram
+}
+
+#[must_use]
+pub fn decode(datagram: &[u8]) -> Option<(u32, &[u8])> {
+    let (flow, payload) = datagram.split_first_chunk::<FLOW_ID_LEN>()?;
+    Some((u32::from_le_bytes(*flow), payload))
+}
+
+/// Hands the datagrams received on one connection to the flows set up on it
+#[derive(Debug, Clone, Default)]
+pub struct FlowRouter {
+    flows: Arc<Mutex<FxHashMap<u32, mpsc::Sender<Vec<u8>>>>>,
+    /// Flows set up at once before further ones are refused, unlimited if `None`
+    max_flows: Option<usize>,
+}
+
+impl FlowRouter {
+    /// A router taking at most `max_flows` flows at once
+    #[must_use]
+    pub fn limited(max_flows: usize) -> Self {
+        Self {
+            flows: Arc::default(),
+            max_flows: Some(max_flows),
+        }
+    }
+
+    fn flows(&self) -> MutexGuard<'_, FxHashMap<u32, mpsc::Sender<Vec<u8>>>> {
+        self.flows
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+
+    /// Sets up `flow`, failing if it already is or the router is full. The flow is taken
+    /// down with the receiver
+    pub fn register(&self, flow: u32) -> anyhow::Result<FlowReceiver> {
+        let mut flows = self.flows();
+        if flows.contains_key(&flow) {
+            anyhow::bail!("udp flow {flow} is already in use");
+        }
+        if let Some(max) = self.max_flows
+            && flows.len() >= max
+        {
+            anyhow::bail!("the connection already has the maximum of {max} udp flows");
+        }
+        let (send, datagrams) = mpsc::channel(FLOW_QUEUE);
+        flows.insert(flow, send);
+        Ok(FlowReceiver {
+            flow,
+            datagrams,
+            router: self.clone(),
+        })
+    }
+
+    pub fn route(&self, datagram: &[u8]) {
+        let Some((flow, payload)) = decode(datagram) else {
+            tracing::debug!("dropping datagram without a flow id");
+            return;
+        };
+        let Some(send) = self.flows().get(&flow).cloned() else {
+            tracing::debug!("dropping datagram for unknown flow {flow}");
+            return;
+        };
+        if send.try_send(payload.to_vec()).is_err() {
+            tracing::debug!("dropping datagram for flow {flow}, it's not keeping up");
+        }
+    }
+}
+
+/// The datagrams of one flow, as routed by [`FlowRouter`]
+#[derive(Debug)]
+pub struct FlowReceiver {
+    flow: u32,
+    datagrams: mpsc::Receiver<Vec<u8>>,
+    router: FlowRouter,
+}
+
+impl FlowReceiver {
+    #[must_use]
+    pub fn flow(&self) -> u32 {
+        self.flow
+    }
+
+    /// The next datagram's payload, without the flow id
+    pub async fn recv(&mut self) -> Option<Vec<u8>> {
+        self.datagrams.recv().await
+    }
+}
+
+impl Drop for FlowReceiver {
+    fn drop(&mut self) {
+        self.router.flows().remove(&self.flow);
+    }
+}
@@ -0,0 +1,203 @@
+//! Applying [`crate::proto::SessionKind::Files`] operations to a local filesystem, shared by
+//! the server serving requests and the client copying files from it.
+//!
+//! Files and symlinks are written next to where they go under a temporary name and renamed
+//! into place once complete, so that an interrupted or corrupted copy never replaces what was
+//! there.
+use crate::proto::files::FileAttrs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll};
+use tokio::io::AsyncWrite;
+
+/// Disambiguates temporary names within the process
+static NEXT_TEMP: AtomicU64 = AtomicU64::new(0);
+
+fn temp_path(target: &Path) -> io::Result<PathBuf> {
+    let name = target.file_name().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} does not name a file", target.display()),
+        )
+    })?;
+    let mut temp_name = std::ffi::OsString::from(".");
+    temp_name.push(name);
+    temp_name.push(format!(
+        ".p2term-{}-{}",
+        std::process::id(),
+        NEXT_TEMP.fetch_add(1, Ordering::Relaxed)
+    ));
+    Ok(target.with_file_name(temp_name))
+}
+
+/// A file being written under a temporary name, replacing `target` on [`Self::finish`]
+/// and removed if dropped before that
+#[derive(Debug)]
+pub struct PartialFile {
+    file: tokio::fs::File,
+    temp: PathBuf,
+    target: PathBuf,
+    finished: bool,
+}
+
+impl PartialFile {
+    /// Starts writing a file to replace `target`. A new file gets `create_mode` less the
+    /// umask, a file already at `target` keeps its mode
+    pub async fn create(target: &Path, create_mode: u32) -> io::Result<Self> {
+        let existing = tokio::fs::symlink_metadata(target)
+            .await
+            .ok()
+            .filter(std::fs::Metadata::is_file);
+        let temp = temp_path(target)?;
+        let mut options = tokio::fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        options.mode(create_mode);
+        #[cfg(not(unix))]
+        let _ = create_mode;
+        let file = options.open(&temp).await?;
+        let partial = Self {
+            file,
+            temp,
+            target: target.to_path_buf(),
+            finished: false,
+        };
+        if let Some(existing) = existing {
+            partial.file.set_permissions(existing.permissions()).await?;
+        }
+        Ok(partial)
+    }
+
+    /// Replaces the target with the written file
+    pub async fn finish(mut self) -> io::Result<()> {
+        use tokio::io::AsyncWriteExt;
+        self.file.flush().await?;
+        tokio::fs::rename(&self.temp, &self.target).await?;
+        self.finished = true;
+        Ok(())
+    }
+}
+
+impl AsyncWrite for PartialFile {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.file).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.file).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.file).poll_shutdown(cx)
+    }
+}
+
+impl Drop for PartialFile {
+    fn drop(&mut self) {
+        if !self.finished {
+            let _ = std::fs::remove_file(&self.temp);
+        }
+    }
+}
+
+/// Creates the directory at `path` with `mode` less the umask
+pub async fn create_dir(path: &Path, mode: u32) -> io::Result<()> {
+    let mut builder = tokio::fs::DirBuilder::new();
+    #[cfg(unix)]
+    builder.mode(mode);
+    #[cfg(not(unix))]
+    let _ = mode;
+    builder.create(path).await
+}
+
+/// Creates a symlink at `path` pointing to `target`, replacing a file or symlink there
+#[cfg(unix)]
+pub async fn symlink(path: &Path, target: &str) -> io::Result<()> {
+    let temp = temp_path(path)?;
+    tokio::fs::symlink(target, &temp).await?;
+    if let Err(e) = tokio::fs::rename(&temp, path).await {
+        let _ = tokio::fs::remove_file(&temp).await;
+        return Err(e);
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub async fn symlink(_path: &Path, _target: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "symlinks are not supported on this platform",
+    ))
+}
+
+/// Sets the attributes given in `attrs` on the file at `path`, symlinks are left as they are
+pub async fn set_attrs(path: &Path, attrs: FileAttrs) -> io::Result<()> {
+    match open_entry(path).await? {
+        Some(file) => set_file_attrs(&file, attrs).await,
+        None => Ok(()),
+    }
+}
+
+/// Opens the entry at `path` to set its attributes on, without following a symlink there,
+/// `None` if it is one. Entries that can't be read are opened to write instead
+#[cfg(unix)]
+pub async fn open_entry(path: &Path) -> io::Result<Option<tokio::fs::File>> {
+    let options = |write: bool| {
+        let mut options = tokio::fs::OpenOptions::new();
+        options
+            .read(!write)
+            .write(write)
+            .custom_flags(libc::O_NOFOLLOW | libc::O_NONBLOCK);
+        options
+    };
+    let opened = match options(false).open(path).await {
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied => options(true).open(path).await,
+        opened => opened,
+    };
+    match opened {
+        Ok(file) => Ok(Some(file)),
+        Err(e) if e.raw_os_error() == Some(libc::ELOOP) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(not(unix))]
+pub async fn open_entry(path: &Path) -> io::Result<Option<tokio::fs::File>> {
+    if tokio::fs::symlink_metadata(path).await?.is_symlink() {
+        return Ok(None);
+    }
+    tokio::fs::File::open(path).await.map(Some)
+}
+
+/// Sets the attributes given in `attrs` on an opened file
+pub async fn set_file_attrs(file: &tokio::fs::File, attrs: FileAttrs) -> io::Result<()> {
+    if let Some(mode) = attrs.mode {
+        let current = file.metadata().await?.permissions();
+        file.set_permissions(permissions(current, mode)).await?;
+    }
+    if let Some(mtime) = attrs.mtime {
+        let file = file.try_clone().await?.into_std().await;
+        tokio::task::spawn_blocking(move || file.set_modified(mtime.to_system_time()))
+            .await
+            .map_err(io::Error::other)??;
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn permissions(_current: std::fs::Permissions, mode: u32) -> std::fs::Permissions {
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::Permissions::from_mode(mode)
+}
+
+#[cfg(not(unix))]
+fn permissions(mut current: std::fs::Permissions, mode: u32) -> std::fs::Permissions {
+    current.set_readonly(mode & 0o222 == 0);
+    current
+}
@@ -0,0 +1,122 @@
+//! Carrying a socket's bytes over a forwarding session.
+//!
+//! Forwarding reuses the shell frames: the client sends the socket's bytes as
+//! [`Frame::Stdin`] and finishes with [`Frame::StdinEof`], the server answers with
+//! [`Frame::Stdout`] and finishes with [`Frame::Close`], so that each direction can be shut
+//! down on its own like a tcp half-close.
+//! A server dialing the target sends [`Frame::ForwardConnected`] once it's reached, or the
+//! reason it failed as [`Frame::Stderr`].
+use crate::proto::codec::{Frame, FrameReader, FrameWriter};
+use anyhow::{Context, bail};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Bytes read from the socket per frame
+const CHUNK_LEN: usize = 16 * 1024;
+
+/// A tcp or unix socket, whichever a forward was set up with
+pub trait Socket: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> Socket for T {}
+
+/// Which end of the forwarding session a socket is spliced onto
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ForwardSide {
+    Client,
+    Server,
+}
+
+impl ForwardSide {
+    fn data(self, bytes: Vec<u8>) -> Frame {
+        match self {
+            ForwardSide::Client => Frame::Stdin(bytes),
+            ForwardSide::Server => Frame::Stdout(bytes),
+        }
+    }
+
+    fn eof(self) -> Frame {
+        match self {
+            ForwardSide::Client => Frame::StdinEof,
+            ForwardSide::Server => Frame::Close,
+        }
+    }
+}
+
+/// Carries bytes between `socket` and the session until both directions have finished,
+/// or either fails
+pub async fn splice<S, W, R>(
+    socket: S,
+    write: FrameWriter<W>,
+    read: FrameReader<R>,
+    side: ForwardSide,
+) -> anyhow::Result<()>
+where
+    S: AsyncRead + AsyncWrite,
+    W: AsyncWrite + Unpin,
+    R: AsyncRead + Unpin,
+{
+    let (socket_read, socket_write) = tokio::io::split(socket);
+    tokio::try_join!(
+        socket_to_session(socket_read, write, side),
+        session_to_socket(read, socket_write, side),
+    )?;
+    Ok(())
+}
+
+async fn socket_to_session<S, W>(
+    mut socket: S,
+    mut write: FrameWriter<W>,
+    side: ForwardSide,
+) -> anyhow::Result<()>
+where
+    S: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0u8; CHUNK_LEN];
+    loop {
+        let read_bytes = socket
+            .read(&mut buf)
+            .await
+            .context("failed to read from forwarded socket")?;
+        if read_bytes == 0 {
+            return write.write_frame(&side.eof()).await;
+        }
+        write
+            .write_frame(&side.data(buf[..read_bytes].to_vec()))
+            .await?;
+    }
+}
+
+async fn session_to_socket<R, S>(
+    mut read: FrameReader<R>,
+    mut socket: S,
+    side: ForwardSide,
+) -> anyhow::Result<()>
+where
+    R: AsyncRead + Unpin,
+    S: AsyncWrite + Unpin,
+{
+    loop {
+        let Some(frame) = read.read_frame().await? else {
+            break;
+        };
+        match (side, frame) {
+            (ForwardSide::Client, Frame::Stdout(bytes))
+            | (ForwardSide::Server, Frame::Stdin(bytes)) => socket
+                .write_all(&bytes)
+                .await
+                .context("failed to write to forwarded socket")?,
+            (ForwardSide::Client, Frame::Stderr(reason)) => {
+                bail!("{}", String::from_utf8_lossy(&reason));
+            }
+            (_, Frame::Close | Frame::StdinEof) => break,
+            (_, Frame::Keepalive) | (ForwardSide::Client, Frame::ForwardConnected) => {}
+            (_, unexpected) => {
+                tracing::debug!("ignoring unexpected frame on forwarding session: {unexpected:?}");
+            }
+        }
+    }
+    socket
+        .shutdown()
+        .await
+        .context("failed to shut down forwarded socket")
+}
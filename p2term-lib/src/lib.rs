@@ -1,8 +1,15 @@
+pub mod backpressure;
 #[cfg(feature = "client")]
 pub mod client;
+#[cfg(feature = "compression")]
+pub mod compression;
 pub mod convert;
 pub mod crypto;
+pub mod datagram;
 pub mod error;
+#[cfg(feature = "fs")]
+pub mod files;
+pub mod forward;
 pub mod proto;
 #[cfg(feature = "server")]
 pub mod server;
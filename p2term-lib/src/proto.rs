@@ -1,4 +1,11 @@
+pub mod codec;
+pub mod files;
+
+use anyhow::{Context, bail};
+use iroh_base::PublicKey;
 use std::path::PathBuf;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 pub const ALPN: &[u8] = b"p2term-proto";
 
@@ -9,9 +16,625 @@ pub const DEFAULT_TERM: &str = "xterm-256color";
 
 pub const OPT_MAX_LEN: usize = 4096;
 
-#[derive(Debug, Default, serde::Deserialize, serde::Serialize)]
+/// Protocol version spoken by this build, bumped on every wire format change
+pub const PROTOCOL_VERSION: u16 = 1;
+
+/// Oldest peer protocol version this build can talk to
+pub const MIN_PROTOCOL_VERSION: u16 = 1;
+
+/// Zero bytes appended to handshake messages before decoding, see [`decode_handshake`].
+/// Enough for every fixed field of [`ClientOpt`] and [`ServerHello`] to be missing
+const HANDSHAKE_PADDING: usize = 64;
+
+/// Sent by the client after [`HELLO`].
+/// The fields are fixed, each decodes as its default from a zero byte so that hellos from
+/// older peers, which stop early, still decode. New fields go into [`Self::extensions`],
+/// which older peers skip.
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
 pub struct ClientOpt {
+    pub version: u16,
+    pub capabilities: Capabilities,
     pub shell: Option<String>,
     pub cwd: Option<PathBuf>,
     pub term: Option<String>,
+    pub size: Option<TermSize>,
+    pub session: SessionKind,
+    /// Run the session without a pty, keeping stdout and stderr apart
+    pub no_pty: bool,
+    /// Keep the session running on the server when the client disconnects, so that it can
+    /// be attached to again with [`SessionKind::Attach`]
+    pub persist: bool,
+    /// A name for a persistent session, unique among the server's sessions
+    pub session_name: Option<String>,
+    /// How [`SessionKind::Attach`] shares the session with other attached clients
+    pub attach_mode: AttachMode,
+    /// Keep the session's pty for a grace period if the connection is lost, so that
+    /// [`SessionKind::Resume`] can pick it up again
+    pub resumable: bool,
+    pub extensions: Extensions,
+}
+
+impl Default for ClientOpt {
+    fn default() -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            capabilities: Capabilities::SUPPORTED,
+            shell: None,
+            cwd: None,
+            term: None,
+            size: None,
+            session: SessionKind::Shell,
+            no_pty: false,
+            persist: false,
+            session_name: None,
+            attach_mode: AttachMode::Takeover,
+            resumable: false,
+            extensions: Extensions::default(),
+        }
+    }
+}
+
+impl ClientOpt {
+    /// Capabilities the server must support to run this session as requested,
+    /// an older server would otherwise silently ignore the fields it doesn't know
+    #[must_use]
+    pub fn required_capabilities(&self) -> Capabilities {
+        let mut required = Capabilities::NONE;
+        if self.no_pty || matches!(self.session, SessionKind::Exec(_)) {
+            required = required | Capabilities::EXEC;
+        }
+        if self.persist || matches!(self.session, SessionKind::Attach(_) | SessionKind::List) {
+            required = required | Capabilities::PERSIST;
+        }
+        if self.attach_mode != AttachMode::Takeover {
+            required = required | Capabilities::SHARED;
+        }
+        // Asking for a resumable session is fine with any server, it's just not resumable then
+        if matches!(self.session, SessionKind::Resume { .. }) {
+            required = required | Capabilities::RESUME;
+        }
+        if matches!(self.session, SessionKind::ForwardTcp { .. }) {
+            required = required | Capabilities::TCP_FORWARD;
+        }
+        if matches!(self.session, SessionKind::ListenTcp { .. }) {
+            required = required | Capabilities::REMOTE_TCP_FORWARD;
+        }
+        if matches!(
+            self.session,
+            SessionKind::ForwardUnix { .. } | SessionKind::ListenUnix { .. }
+        ) {
+            required = required | Capabilities::UNIX_FORWARD;
+        }
+        if matches!(self.session, SessionKind::ForwardUdp { .. }) {
+            required = required | Capabilities::UDP_FORWARD;
+        }
+        if matches!(self.session, SessionKind::Files) {
+            required = required | Capabilities::FILES;
+        }
+        required
+    }
+}
+
+/// What the server runs for a session
+#[derive(Debug, Clone, Default, Eq, PartialEq, serde::Deserialize, serde::Serialize)]
+pub enum SessionKind {
+    /// The requested shell, or the server's default, as a login shell
+    #[default]
+    Shell,
+    /// A single command, the first element is the program and the rest its arguments. It's
+    /// run with the requested shell, or the server's default, as `shell -c <command>`
+    Exec(Vec<String>),
+    /// Attach to a persistent session by id or name
+    Attach(String),
+    /// List the persistent sessions the client may attach to, answered with
+    /// [`codec::Frame::SessionList`]
+    List,
+    /// Pick a session up again after losing the connection, replaying output from `offset`,
+    /// see [`codec::Frame::ResumeToken`] and [`codec::Frame::OutputOffset`]
+    Resume { token: ResumeToken, offset: u64 },
+    /// Open a tcp connection from the server to `host:port` and carry its bytes,
+    /// see [`crate::forward`]
+    ForwardTcp { host: String, port: u16 },
+    /// Listen on `host:port` on the server, `port` 0 picks any free port, and carry every
+    /// accepted connection back to the client on a stream the server opens, starting with
+    /// [`codec::Frame::Forwarded`]. The session lasts as long as the listener
+    ListenTcp { host: String, port: u16 },
+    /// Connect to the unix socket at `path` on the server, like [`SessionKind::ForwardTcp`]
+    ForwardUnix { path: String },
+    /// Listen on a unix socket created at `path` on the server, like [`SessionKind::ListenTcp`]
+    /// with the streams starting with [`codec::Frame::ForwardedUnix`]. The socket is removed
+    /// when the session ends
+    ListenUnix { path: String },
+    /// Send udp datagrams from the server to `host:port`, carried as QUIC datagrams of
+    /// `flow`, a number the client picks that's unique among its flows on the connection,
+    /// see [`crate::datagram`]. The server answers with [`codec::Frame::ForwardConnected`]
+    /// once its socket is set up, the flow lasts until either end closes the session
+    ForwardUdp { host: String, port: u16, flow: u32 },
+    /// Operate on files on the server, relative paths start from [`ClientOpt::cwd`],
+    /// see [`files`]
+    Files,
+}
+
+impl SessionKind {
+    /// Whether the session runs a shell or command a user interacts with
+    #[must_use]
+    pub fn is_interactive(&self) -> bool {
+        matches!(
+            self,
+            SessionKind::Shell
+                | SessionKind::Exec(_)
+                | SessionKind::Attach(_)
+                | SessionKind::Resume { .. }
+        )
+    }
+}
+
+/// Identifies a session to resume, handed out to clients attached to it
+#[derive(Copy, Clone, Eq, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct ResumeToken(pub [u8; 32]);
+
+impl core::fmt::Debug for ResumeToken {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        // Anyone holding the token with access to the session can take it over
+        f.write_str("ResumeToken(..)")
+    }
+}
+
+/// How a client attaches to a persistent session that others may be attached to
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq, serde::Deserialize, serde::Serialize)]
+pub enum AttachMode {
+    /// Detach the other clients that may write to the session, watchers stay
+    #[default]
+    Takeover,
+    /// Write to the session alongside the clients already attached
+    Join,
+    /// Only see the output, input from the client is discarded
+    Watch,
+}
+
+/// A persistent session kept by the server
+#[derive(Debug, Clone, Eq, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct SessionInfo {
+    pub id: u64,
+    pub name: Option<String>,
+    /// The peer that started the session
+    pub owner: PublicKey,
+    /// What the session runs, the program followed by its arguments
+    pub command: Vec<String>,
+    /// Seconds since the unix epoch
+    pub created_at: u64,
+    /// Whether a client is currently attached
+    pub attached: bool,
+}
+
+/// Which [`SessionKind::ListenTcp`] listener accepted a connection the server forwards
+#[derive(Debug, Clone, Eq, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct ForwardedTcp {
+    /// The host and port the listener was requested with
+    pub host: String,
+    pub port: u16,
+    /// The address the connection came from
+    pub origin: String,
+}
+
+/// Which [`SessionKind::ListenUnix`] listener accepted a connection the server forwards
+#[derive(Debug, Clone, Eq, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct ForwardedUnix {
+    /// The path the listener was requested with
+    pub path: String,
+}
+
+/// Sent by the server after [`WELCOME`], extended under the same rules as [`ClientOpt`]
+#[derive(Debug, Clone, Eq, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct ServerHello {
+    pub version: u16,
+    pub min_version: u16,
+    pub capabilities: Capabilities,
+    pub status: HelloStatus,
+    /// How the session is kept alive, if [`Capabilities::KEEPALIVE`] was negotiated
+    pub keepalive: Option<Keepalive>,
+    pub extensions: Extensions,
+}
+
+/// Handshake fields added after the fixed ones, each tagged and carried with its length.
+/// A peer skips the ones it doesn't know and finds none of those it isn't sent, so new
+/// fields of any type can be added without older peers misreading them
+#[derive(Debug, Clone, Default, Eq, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct Extensions(Vec<(u16, Vec<u8>)>);
+
+impl Extensions {
+    /// Sets the field `tag` to `value`, replacing whatever it was
+    /// # Errors
+    /// `value` can't be serialized
+    pub fn insert<T: serde::Serialize>(&mut self, tag: u16, value: &T) -> anyhow::Result<()> {
+        let bytes = postcard::to_allocvec(value)
+            .with_context(|| format!("failed to serialize handshake extension {tag}"))?;
+        self.0.retain(|(existing, _)| *existing != tag);
+        self.0.push((tag, bytes));
+        Ok(())
+    }
+
+    /// The field `tag`, `None` if the peer didn't send it
+    /// # Errors
+    /// The peer sent it, but not as a `T`
+    pub fn get<T: serde::de::DeserializeOwned>(&self, tag: u16) -> anyhow::Result<Option<T>> {
+        self.0
+            .iter()
+            .find(|(existing, _)| *existing == tag)
+            .map(|(_, bytes)| {
+                postcard::from_bytes(bytes)
+                    .with_context(|| format!("failed to parse handshake extension {tag}"))
+            })
+            .transpose()
+    }
+}
+
+impl Default for ServerHello {
+    fn default() -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            min_version: MIN_PROTOCOL_VERSION,
+            capabilities: Capabilities::SUPPORTED,
+            status: HelloStatus::Accepted,
+            keepalive: None,
+            extensions: Extensions::default(),
+        }
+    }
+}
+
+impl ServerHello {
+    #[must_use]
+    pub fn rejected(rejection: Rejection) -> Self {
+        Self {
+            status: HelloStatus::Rejected(rejection),
+            ..Self::default()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, serde::Deserialize, serde::Serialize)]
+pub enum HelloStatus {
+    Accepted,
+    Rejected(Rejection),
+}
+
+/// Why the server refused a session, with a human-readable explanation
+#[derive(Debug, Clone, Eq, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct Rejection {
+    pub reason: RejectReason,
+    pub message: String,
+}
+
+impl Rejection {
+    pub fn new(reason: RejectReason, message: impl Into<String>) -> Self {
+        Self {
+            reason,
+            message: message.into(),
+        }
+    }
+}
+
+impl core::fmt::Display for Rejection {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "server rejected session ({}): {}",
+            self.reason, self.message
+        )
+    }
+}
+
+impl core::error::Error for Rejection {}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, serde::Deserialize, serde::Serialize)]
+pub enum RejectReason {
+    DeniedPeer,
+    DisallowedShell,
+    InvalidCwd,
+    ServerBusy,
+    VersionMismatch,
+    SessionNotFound,
+    SessionNameTaken,
+    /// The combination of options can't be served
+    InvalidRequest,
+    /// The peer may watch the session, but not write to it
+    ReadOnly,
+    /// The server's policy doesn't let the peer forward there
+    ForwardDenied,
+    /// The server's policy doesn't let the peer access files there
+    FilesDenied,
+}
+
+impl core::fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(match self {
+            RejectReason::DeniedPeer => "peer not allowed",
+            RejectReason::DisallowedShell => "shell not allowed",
+            RejectReason::InvalidCwd => "invalid working directory",
+            RejectReason::ServerBusy => "server busy",
+            RejectReason::VersionMismatch => "protocol version mismatch",
+            RejectReason::SessionNotFound => "session not found",
+            RejectReason::SessionNameTaken => "session name taken",
+            RejectReason::ReadOnly => "session is read-only for this peer",
+            RejectReason::InvalidRequest => "invalid request",
+            RejectReason::ForwardDenied => "forwarding not permitted",
+            RejectReason::FilesDenied => "file access not permitted",
+        })
+    }
+}
+
+/// Optional protocol features, a session uses the intersection of what both peers support
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct Capabilities(u64);
+
+impl Capabilities {
+    pub const NONE: Self = Self(0);
+    /// The client sends [`codec::Frame::Resize`] when its terminal changes size
+    pub const RESIZE: Self = Self(1);
+    /// The server sends [`codec::Frame::ExitStatus`] before closing the session
+    pub const EXIT_STATUS: Self = Self(1 << 1);
+    /// The server runs [`SessionKind::Exec`] and sessions without a pty,
+    /// and treats the client finishing its stream as stdin EOF
+    pub const EXEC: Self = Self(1 << 2);
+    /// The server delivers [`codec::Frame::Signal`] to the session's foreground process group,
+    /// and the client reports stdin EOF with [`codec::Frame::StdinEof`] so that signals can
+    /// still be sent after it
+    pub const SIGNAL: Self = Self(1 << 3);
+    /// The server keeps accepting sessions on a connection, rather than serving only the first
+    pub const MULTI_SESSION: Self = Self(1 << 4);
+    /// The server keeps sessions with [`ClientOpt::persist`] alive, and serves
+    /// [`SessionKind::Attach`] and [`SessionKind::List`]
+    pub const PERSIST: Self = Self(1 << 5);
+    /// The server lets several clients attach to a persistent session at once,
+    /// see [`AttachMode`]
+    pub const SHARED: Self = Self(1 << 6);
+    /// The server keeps [`ClientOpt::resumable`] sessions for a grace period after losing
+    /// the connection, and serves [`SessionKind::Resume`]
+    pub const RESUME: Self = Self(1 << 7);
+    /// The server serves [`SessionKind::ForwardTcp`]
+    pub const TCP_FORWARD: Self = Self(1 << 8);
+    /// The server serves [`SessionKind::ListenTcp`]
+    pub const REMOTE_TCP_FORWARD: Self = Self(1 << 9);
+    /// The server serves [`SessionKind::ForwardUnix`] and [`SessionKind::ListenUnix`]
+    pub const UNIX_FORWARD: Self = Self(1 << 10);
+    /// The server serves [`SessionKind::ForwardUdp`]
+    pub const UDP_FORWARD: Self = Self(1 << 11);
+    /// The server serves [`SessionKind::Files`]
+    pub const FILES: Self = Self(1 << 12);
+    /// The server sends a shell's output compressed as [`codec::Frame::CompressedStdout`].
+    /// Not part of [`Self::SUPPORTED`], either end offers it only if its [`Compression`]
+    /// setting allows it
+    pub const COMPRESSION: Self = Self(1 << 13);
+    /// Both ends of a shell session send [`codec::Frame::Keepalive`] when they have
+    /// nothing else to send, and take the other end as gone once it has been silent for
+    /// too long, see [`Keepalive`]. Not part of [`Self::SUPPORTED`], offered only by clients
+    /// that send them
+    pub const KEEPALIVE: Self = Self(1 << 14);
+    /// The client acknowledges a shell's output with [`codec::Frame::OutputAck`] and the
+    /// server holds output back while too much of it is unacknowledged, see
+    /// [`crate::backpressure`]. Not part of [`Self::SUPPORTED`], offered only by clients
+    /// that acknowledge
+    pub const OUTPUT_ACK: Self = Self(1 << 15);
+    /// Everything this build supports
+    pub const SUPPORTED: Self = Self(
+        Self::RESIZE.0
+            | Self::EXIT_STATUS.0
+            | Self::EXEC.0
+            | Self::SIGNAL.0
+            | Self::MULTI_SESSION.0
+            | Self::PERSIST.0
+            | Self::SHARED.0
+            | Self::RESUME.0
+            | Self::TCP_FORWARD.0
+            | Self::REMOTE_TCP_FORWARD.0
+            | Self::UNIX_FORWARD.0
+            | Self::UDP_FORWARD.0
+            | Self::FILES.0,
+    );
+
+    #[must_use]
+    pub const fn from_bits(bits: u64) -> Self {
+        Self(bits)
+    }
+
+    #[must_use]
+    pub const fn bits(self) -> u64 {
+        self.0
+    }
+
+    #[must_use]
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    #[must_use]
+    pub const fn intersection(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+}
+
+impl core::ops::BitOr for Capabilities {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self::Output {
+        Self(self.0 | rhs.0)
+    }
+}
+
+/// Whether a peer compresses output when the other end allows it too,
+/// see [`Capabilities::COMPRESSION`]
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    /// A zstd stream, flushed after every burst of output
+    #[default]
+    Zstd,
+    None,
+}
+
+impl Compression {
+    /// The capabilities to offer with this setting
+    #[must_use]
+    pub fn offered(self, capabilities: Capabilities) -> Capabilities {
+        match self {
+            Compression::Zstd => capabilities | Capabilities::COMPRESSION,
+            Compression::None => capabilities,
+        }
+    }
+}
+
+impl core::str::FromStr for Compression {
+    type Err = anyhow::Error;
+
+    fn from_str(compression: &str) -> Result<Self, Self::Err> {
+        match compression {
+            "zstd" => Ok(Self::Zstd),
+            "none" => Ok(Self::None),
+            _ => bail!("unknown compression {compression}, expected zstd or none"),
+        }
+    }
+}
+
+/// How often the ends of a session send [`codec::Frame::Keepalive`], chosen by the server
+#[derive(Debug, Copy, Clone, Eq, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct Keepalive {
+    /// Longest time between two frames sent by either end
+    pub interval: Duration,
+    /// Keepalives missed in a row before the other end is taken as gone
+    pub max_missed: u32,
+}
+
+impl Keepalive {
+    /// How long the other end may be silent
+    #[must_use]
+    pub fn timeout(&self) -> Duration {
+        self.interval.saturating_mul(self.max_missed.max(1))
+    }
+}
+
+/// Whether a peer announcing `peer_version` can be talked to
+#[must_use]
+pub fn is_compatible_version(peer_version: u16) -> bool {
+    peer_version >= MIN_PROTOCOL_VERSION
+}
+
+/// Reads the version of a handshake message without decoding the rest,
+/// which may not be decodable if the peer's version is incompatible
+pub fn peek_version(bytes: &[u8]) -> anyhow::Result<u16> {
+    let (version, _rest) =
+        postcard::take_from_bytes::<u16>(bytes).context("failed to parse protocol version")?;
+    Ok(version)
+}
+
+/// Decodes a handshake message, fixed fields missing at the end because the peer is
+/// older are decoded from zeroes, and so are its [`Extensions`]. Anything after those,
+/// from a newer peer, is ignored
+pub fn decode_handshake<T: serde::de::DeserializeOwned>(bytes: &[u8]) -> anyhow::Result<T> {
+    let mut padded = Vec::with_capacity(bytes.len() + HANDSHAKE_PADDING);
+    padded.extend_from_slice(bytes);
+    padded.resize(bytes.len() + HANDSHAKE_PADDING, 0);
+    postcard::from_bytes(&padded).context("failed to parse handshake message")
+}
+
+pub async fn write_handshake<W, T>(write: &mut W, magic: &[u8; 8], msg: &T) -> anyhow::Result<()>
+where
+    W: AsyncWrite + Unpin,
+    T: serde::Serialize,
+{
+    let bytes = postcard::to_allocvec(msg).context("failed to serialize handshake message")?;
+    let bytes_len: u16 = bytes
+        .len()
+        .try_into()
+        .with_context(|| format!("handshake message len too large {}", bytes.len()))?;
+    // Write vectored is a good candidate here, but for some reason
+    // it only manages the first buffer every time in practice
+    write.write_all(magic).await?;
+    write.write_u16_le(bytes_len).await?;
+    write.write_all(&bytes).await?;
+    Ok(())
+}
+
+/// Reads a handshake message preceded by `magic`, returning its raw bytes
+pub async fn read_handshake<R>(read: &mut R, magic: &[u8; 8]) -> anyhow::Result<Vec<u8>>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut magic_buf = [0u8; 8];
+    read.read_exact(&mut magic_buf)
+        .await
+        .context("failed to read handshake magic")?;
+    if &magic_buf != magic {
+        bail!(
+            "unexpected handshake magic {:?}, the peer is likely running an incompatible version",
+            String::from_utf8_lossy(&magic_buf)
+        );
+    }
+    let msg_len = read
+        .read_u16_le()
+        .await
+        .context("failed to read handshake message length")? as usize;
+    if msg_len > OPT_MAX_LEN {
+        bail!("read an oversized handshake message len of {msg_len}");
+    }
+    let mut buf = vec![0u8; msg_len];
+    read.read_exact(&mut buf)
+        .await
+        .context("failed to read handshake message")?;
+    Ok(buf)
+}
+
+/// Size of the client's terminal, pixel dimensions are 0 if unknown
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct TermSize {
+    pub rows: u16,
+    pub cols: u16,
+    pub pixel_width: u16,
+    pub pixel_height: u16,
+}
+
+/// A signal, named rather than numbered since numbers differ between platforms
+#[derive(Debug, Copy, Clone, Eq, PartialEq, serde::Deserialize, serde::Serialize)]
+pub enum Signal {
+    Hup,
+    Int,
+    Quit,
+    Kill,
+    Term,
+    /// A platform specific signal number
+    Other(i32),
+}
+
+impl Signal {
+    /// The signal number, the named signals share numbers across unix platforms
+    #[must_use]
+    pub fn number(self) -> i32 {
+        match self {
+            Signal::Hup => 1,
+            Signal::Int => 2,
+            Signal::Quit => 3,
+            Signal::Kill => 9,
+            Signal::Term => 15,
+            Signal::Other(num) => num,
+        }
+    }
+
+    #[must_use]
+    pub fn from_number(num: i32) -> Self {
+        match num {
+            1 => Signal::Hup,
+            2 => Signal::Int,
+            3 => Signal::Quit,
+            9 => Signal::Kill,
+            15 => Signal::Term,
+            num => Signal::Other(num),
+        }
+    }
+}
+
+/// How a remote process exited
+#[derive(Debug, Copy, Clone, Eq, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct ExitStatus {
+    pub code: u32,
+    /// Set if the process was terminated by a signal
+    pub signal: Option<Signal>,
 }
@@ -0,0 +1,405 @@
+//! Framing used on a session stream after the handshake.
+//!
+//! Every frame is written as `[kind: u8][len: u32 le][payload]`.
+//! Data frames carry their payload as raw bytes, control frames carry a postcard-encoded
+//! payload.
+//! Readers skip frame kinds they do not know, so new kinds can be added without breaking
+//! older peers.
+use crate::proto::files::{FileRequest, FileResponse};
+use crate::proto::{
+    ExitStatus, ForwardedTcp, ForwardedUnix, Keepalive, ResumeToken, SessionInfo, Signal, TermSize,
+};
+use anyhow::{Context, bail};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::Instant;
+
+pub const FRAME_HEADER_LEN: usize = 5;
+
+pub const FRAME_MAX_LEN: usize = 1024 * 1024;
+
+const KIND_STDIN: u8 = 0;
+const KIND_STDOUT: u8 = 1;
+const KIND_STDERR: u8 = 2;
+const KIND_RESIZE: u8 = 3;
+const KIND_SIGNAL: u8 = 4;
+const KIND_EXIT_STATUS: u8 = 5;
+const KIND_KEEPALIVE: u8 = 6;
+const KIND_CLOSE: u8 = 7;
+const KIND_STDIN_EOF: u8 = 8;
+const KIND_SESSION: u8 = 9;
+const KIND_SESSION_LIST: u8 = 10;
+const KIND_RESUME_TOKEN: u8 = 11;
+const KIND_OUTPUT_OFFSET: u8 = 12;
+const KIND_FORWARD_LISTENING: u8 = 13;
+const KIND_FORWARDED: u8 = 14;
+const KIND_FORWARD_CONNECTED: u8 = 15;
+const KIND_FORWARDED_UNIX: u8 = 16;
+const KIND_FILE_REQUEST: u8 = 17;
+const KIND_FILE_RESPONSE: u8 = 18;
+const KIND_COMPRESSED_STDOUT: u8 = 19;
+const KIND_OUTPUT_ACK: u8 = 20;
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Frame {
+    Stdin(Vec<u8>),
+    Stdout(Vec<u8>),
+    Stderr(Vec<u8>),
+    Resize(TermSize),
+    Signal(Signal),
+    ExitStatus(ExitStatus),
+    Keepalive,
+    /// The sender will not send anything more on this session
+    Close,
+    /// The client has no more input, the stream stays open for control frames
+    StdinEof,
+    /// The persistent session the client is attached to, sent before any output
+    Session(SessionInfo),
+    SessionList(Vec<SessionInfo>),
+    /// Resumes the session with [`crate::proto::SessionKind::Resume`] if the connection is lost
+    ResumeToken(ResumeToken),
+    /// The offset into the session's output of the next [`Frame::Stdout`] byte, sent when
+    /// output doesn't continue where the client would otherwise expect
+    OutputOffset(u64),
+    /// The port a [`crate::proto::SessionKind::ListenTcp`] session is listening on,
+    /// 0 for [`crate::proto::SessionKind::ListenUnix`]
+    ForwardListening(u16),
+    /// Starts a stream the server opened for a connection its listener accepted
+    Forwarded(ForwardedTcp),
+    /// The server reached the target of a [`crate::proto::SessionKind::ForwardTcp`] or
+    /// [`crate::proto::SessionKind::ForwardUnix`] session
+    ForwardConnected,
+    /// Starts a stream the server opened for a connection its unix socket listener accepted
+    ForwardedUnix(ForwardedUnix),
+    /// A request of a [`crate::proto::SessionKind::Files`] session
+    FileRequest(FileRequest),
+    /// An answer to a [`Frame::FileRequest`]
+    FileResponse(FileResponse),
+    /// The next piece of a zstd stream of the session's output, decoding to what would
+    /// otherwise have been sent as [`Frame::Stdout`], see [`crate::proto::Capabilities::COMPRESSION`]
+    CompressedStdout(Vec<u8>),
+    /// The client has handled this much of the output on the stream, see
+    /// [`crate::proto::Capabilities::OUTPUT_ACK`]
+    OutputAck(u64),
+}
+
+impl Frame {
+    fn kind(&self) -> u8 {
+        match self {
+            Frame::Stdin(_) => KIND_STDIN,
+            Frame::Stdout(_) => KIND_STDOUT,
+            Frame::Stderr(_) => KIND_STDERR,
+            Frame::Resize(_) => KIND_RESIZE,
+            Frame::Signal(_) => KIND_SIGNAL,
+            Frame::ExitStatus(_) => KIND_EXIT_STATUS,
+            Frame::Keepalive => KIND_KEEPALIVE,
+            Frame::Close => KIND_CLOSE,
+            Frame::StdinEof => KIND_STDIN_EOF,
+            Frame::Session(_) => KIND_SESSION,
+            Frame::SessionList(_) => KIND_SESSION_LIST,
+            Frame::ResumeToken(_) => KIND_RESUME_TOKEN,
+            Frame::OutputOffset(_) => KIND_OUTPUT_OFFSET,
+            Frame::ForwardListening(_) => KIND_FORWARD_LISTENING,
+            Frame::Forwarded(_) => KIND_FORWARDED,
+            Frame::ForwardConnected => KIND_FORWARD_CONNECTED,
+            Frame::ForwardedUnix(_) => KIND_FORWARDED_UNIX,
+            Frame::FileRequest(_) => KIND_FILE_REQUEST,
+            Frame::FileResponse(_) => KIND_FILE_RESPONSE,
+            Frame::CompressedStdout(_) => KIND_COMPRESSED_STDOUT,
+            Frame::OutputAck(_) => KIND_OUTPUT_ACK,
+        }
+    }
+}
+
+/// Appends the encoded frame to `buf`
+pub fn encode_frame(frame: &Frame, buf: &mut Vec<u8>) -> anyhow::Result<()> {
+    let start = buf.len();
+    buf.push(frame.kind());
+    buf.extend_from_slice(&[0u8; 4]);
+    match frame {
+        Frame::Stdin(data)
+        | Frame::Stdout(data)
+        | Frame::Stderr(data)
+        | Frame::CompressedStdout(data) => {
+            buf.extend_from_slice(data);
+        }
+        Frame::Resize(size) => {
+            encode_payload(size, buf).context("failed to serialize resize frame")?;
+        }
+        Frame::Signal(signal) => {
+            encode_payload(signal, buf).context("failed to serialize signal frame")?;
+        }
+        Frame::ExitStatus(status) => {
+            encode_payload(status, buf).context("failed to serialize exit status frame")?;
+        }
+        Frame::Session(info) => {
+            encode_payload(info, buf).context("failed to serialize session frame")?;
+        }
+        Frame::SessionList(list) => {
+            encode_payload(list, buf).context("failed to serialize session list frame")?;
+        }
+        Frame::ResumeToken(token) => {
+            encode_payload(token, buf).context("failed to serialize resume token frame")?;
+        }
+        Frame::OutputOffset(offset) => {
+            encode_payload(offset, buf).context("failed to serialize output offset frame")?;
+        }
+        Frame::OutputAck(handled) => {
+            encode_payload(handled, buf).context("failed to serialize output ack frame")?;
+        }
+        Frame::ForwardListening(port) => {
+            encode_payload(port, buf).context("failed to serialize forward listening frame")?;
+        }
+        Frame::Forwarded(forwarded) => {
+            encode_payload(forwarded, buf).context("failed to serialize forwarded frame")?;
+        }
+        Frame::ForwardedUnix(forwarded) => {
+            encode_payload(forwarded, buf).context("failed to serialize forwarded unix frame")?;
+        }
+        Frame::FileRequest(request) => {
+            encode_payload(request, buf).context("failed to serialize file request frame")?;
+        }
+        Frame::FileResponse(response) => {
+            encode_payload(response, buf).context("failed to serialize file response frame")?;
+        }
+        Frame::Keepalive | Frame::Close | Frame::StdinEof | Frame::ForwardConnected => {}
+    }
+    let payload_len = buf.len() - start - FRAME_HEADER_LEN;
+    if payload_len > FRAME_MAX_LEN {
+        buf.truncate(start);
+        bail!("frame payload too large {payload_len}");
+    }
+    let len = u32::try_from(payload_len).context("frame len does not fit in u32")?;
+    buf[start + 1..start + FRAME_HEADER_LEN].copy_from_slice(&len.to_le_bytes());
+    Ok(())
+}
+
+fn encode_payload<T: serde::Serialize>(value: &T, buf: &mut Vec<u8>) -> postcard::Result<()> {
+    *buf = postcard::to_extend(value, core::mem::take(buf))?;
+    Ok(())
+}
+
+/// Decodes a frame payload, `None` if the kind is unknown
+pub fn decode_frame(kind: u8, payload: &[u8]) -> anyhow::Result<Option<Frame>> {
+    let frame = match kind {
+        KIND_STDIN => Frame::Stdin(payload.to_vec()),
+        KIND_STDOUT => Frame::Stdout(payload.to_vec()),
+        KIND_STDERR => Frame::Stderr(payload.to_vec()),
+        KIND_COMPRESSED_STDOUT => Frame::CompressedStdout(payload.to_vec()),
+        KIND_RESIZE => {
+            Frame::Resize(postcard::from_bytes(payload).context("failed to parse resize frame")?)
+        }
+        KIND_SIGNAL => {
+            Frame::Signal(postcard::from_bytes(payload).context("failed to parse signal frame")?)
+        }
+        KIND_EXIT_STATUS => Frame::ExitStatus(
+            postcard::from_bytes(payload).context("failed to parse exit status frame")?,
+        ),
+        KIND_KEEPALIVE => Frame::Keepalive,
+        KIND_CLOSE => Frame::Close,
+        KIND_STDIN_EOF => Frame::StdinEof,
+        KIND_SESSION => {
+            Frame::Session(postcard::from_bytes(payload).context("failed to parse session frame")?)
+        }
+        KIND_SESSION_LIST => Frame::SessionList(
+            postcard::from_bytes(payload).context("failed to parse session list frame")?,
+        ),
+        KIND_RESUME_TOKEN => Frame::ResumeToken(
+            postcard::from_bytes(payload).context("failed to parse resume token frame")?,
+        ),
+        KIND_OUTPUT_OFFSET => Frame::OutputOffset(
+            postcard::from_bytes(payload).context("failed to parse output offset frame")?,
+        ),
+        KIND_OUTPUT_ACK => Frame::OutputAck(
+            postcard::from_bytes(payload).context("failed to parse output ack frame")?,
+        ),
+        KIND_FORWARD_LISTENING => Frame::ForwardListening(
+            postcard::from_bytes(payload).context("failed to parse forward listening frame")?,
+        ),
+        KIND_FORWARDED => Frame::Forwarded(
+            postcard::from_bytes(payload).context("failed to parse forwarded frame")?,
+        ),
+        KIND_FORWARD_CONNECTED => Frame::ForwardConnected,
+        KIND_FORWARDED_UNIX => Frame::ForwardedUnix(
+            postcard::from_bytes(payload).context("failed to parse forwarded unix frame")?,
+        ),
+        KIND_FILE_REQUEST => Frame::FileRequest(
+            postcard::from_bytes(payload).context("failed to parse file request frame")?,
+        ),
+        KIND_FILE_RESPONSE => Frame::FileResponse(
+            postcard::from_bytes(payload).context("failed to parse file response frame")?,
+        ),
+        _ => return Ok(None),
+    };
+    Ok(Some(frame))
+}
+
+#[derive(Debug)]
+pub struct FrameWriter<W> {
+    inner: W,
+    buf: Vec<u8>,
+    keepalive: Option<KeepaliveTimer>,
+}
+
+/// When the last frame was written, for sending [`Frame::Keepalive`] in time
+#[derive(Debug, Copy, Clone)]
+struct KeepaliveTimer {
+    interval: Duration,
+    last_write: Instant,
+}
+
+impl<W> FrameWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            buf: Vec::new(),
+            keepalive: None,
+        }
+    }
+
+    /// Has [`Self::keepalive_due`] complete whenever nothing has been written for the
+    /// keepalive interval
+    #[must_use]
+    pub fn with_keepalive(mut self, keepalive: Option<Keepalive>) -> Self {
+        self.keepalive = keepalive.map(|keepalive| KeepaliveTimer {
+            interval: keepalive.interval,
+            last_write: Instant::now(),
+        });
+        self
+    }
+
+    /// Completes once a [`Frame::Keepalive`] should be written, never without keepalives.
+    /// Doesn't borrow the writer, so that it can be raced against what writes to it
+    pub fn keepalive_due(&self) -> impl Future<Output = ()> + Send + 'static {
+        let due = self
+            .keepalive
+            .map(|timer| timer.last_write + timer.interval);
+        async move {
+            match due {
+                Some(due) => tokio::time::sleep_until(due).await,
+                None => std::future::pending().await,
+            }
+        }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W> FrameWriter<W>
+where
+    W: AsyncWrite + Unpin,
+{
+    pub async fn write_frame(&mut self, frame: &Frame) -> anyhow::Result<()> {
+        self.buf.clear();
+        encode_frame(frame, &mut self.buf)?;
+        self.inner
+            .write_all(&self.buf)
+            .await
+            .context("failed to write frame")?;
+        if let Some(timer) = self.keepalive.as_mut() {
+            timer.last_write = Instant::now();
+        }
+        Ok(())
+    }
+
+    /// Closes the underlying stream for writing
+    pub async fn shutdown(&mut self) -> anyhow::Result<()> {
+        self.inner
+            .shutdown()
+            .await
+            .context("failed to shut down frame writer")
+    }
+}
+
+#[derive(Debug)]
+pub struct FrameReader<R> {
+    inner: R,
+    buf: Vec<u8>,
+    keepalive: Option<(Keepalive, Instant)>,
+}
+
+impl<R> FrameReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            buf: Vec::new(),
+            keepalive: None,
+        }
+    }
+
+    /// Fails reading once the peer has missed too many keepalives, rather than waiting
+    /// forever on a peer that's gone without closing the stream
+    #[must_use]
+    pub fn with_keepalive(mut self, keepalive: Option<Keepalive>) -> Self {
+        self.keepalive = keepalive.map(|keepalive| (keepalive, Instant::now()));
+        self
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R> FrameReader<R>
+where
+    R: AsyncRead + Unpin,
+{
+    /// Reads the next known frame, `None` if the stream ended cleanly between frames
+    pub async fn read_frame(&mut self) -> anyhow::Result<Option<Frame>> {
+        let Some((keepalive, last_read)) = self.keepalive else {
+            return self.read_known_frame().await;
+        };
+        // Timed from the last frame rather than from this call, which is raced and dropped
+        // in selects
+        let deadline = last_read + keepalive.timeout();
+        match tokio::time::timeout_at(deadline, self.read_known_frame()).await {
+            Ok(read) => {
+                if let Some((_, last_read)) = self.keepalive.as_mut() {
+                    *last_read = Instant::now();
+                }
+                read
+            }
+            Err(_elapsed) => bail!(
+                "peer missed {} keepalives, nothing received for {:?}",
+                keepalive.max_missed,
+                keepalive.timeout()
+            ),
+        }
+    }
+
+    async fn read_known_frame(&mut self) -> anyhow::Result<Option<Frame>> {
+        loop {
+            let kind = match self.inner.read_u8().await {
+                Ok(kind) => kind,
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        std::io::ErrorKind::UnexpectedEof | std::io::ErrorKind::NotConnected
+                    ) =>
+                {
+                    return Ok(None);
+                }
+                Err(e) => return Err(e).context("failed to read frame kind"),
+            };
+            let len = self
+                .inner
+                .read_u32_le()
+                .await
+                .context("failed to read frame length")? as usize;
+            if len > FRAME_MAX_LEN {
+                bail!("read an oversized frame len of {len}");
+            }
+            self.buf.resize(len, 0);
+            self.inner
+                .read_exact(&mut self.buf)
+                .await
+                .context("failed to read frame payload")?;
+            if let Some(frame) = decode_frame(kind, &self.buf)? {
+                return Ok(Some(frame));
+            }
+            tracing::debug!("skipping frame of unknown kind={kind}, len={len}");
+        }
+    }
+}
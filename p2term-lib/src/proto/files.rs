@@ -0,0 +1,278 @@
+//! Messages of a [`crate::proto::SessionKind::Files`] session.
+//!
+//! The client sends [`FileRequest`]s as [`crate::proto::codec::Frame::FileRequest`] and the
+//! server answers each in order with [`FileResponse`]s as
+//! [`crate::proto::codec::Frame::FileResponse`], so requests may be pipelined.
+//! Paths are the server's, relative ones start from the session's working directory.
+//! Every path is checked against the directories the server permits the peer, after
+//! resolving symlinks in it.
+use std::time::{Duration, SystemTime};
+
+/// Bytes of file contents carried per [`FileRequest::Data`] and [`FileResponse::Data`]
+pub const CHUNK_LEN: usize = 64 * 1024;
+
+/// Directory entries carried per [`FileResponse::Entries`]
+pub const ENTRIES_PER_BATCH: usize = 256;
+
+/// Block hashes carried per [`FileResponse::Signature`]
+pub const BLOCKS_PER_BATCH: usize = 1024;
+
+/// The largest block a [`FileRequest::Signature`] may split files into
+pub const MAX_BLOCK_LEN: u32 = 256 * 1024;
+
+/// A blake3 hash of a file's contents, checked by whoever receives them
+pub type Checksum = [u8; 32];
+
+#[derive(Debug, Clone, Eq, PartialEq, serde::Deserialize, serde::Serialize)]
+pub enum FileRequest {
+    /// Metadata of the file at `path`, following symlinks, answered with
+    /// [`FileResponse::Meta`]
+    Stat { path: String },
+    /// The entries of the directory at `path`, answered with [`FileResponse::Entries`]
+    /// batches finished by [`FileResponse::Done`]. Entries are not followed if symlinks
+    ReadDir { path: String },
+    /// The target of the symlink at `path`, answered with [`FileResponse::LinkTarget`]
+    ReadLink { path: String },
+    /// The contents of the file at `path`, answered with [`FileResponse::Data`] chunks
+    /// finished by [`FileResponse::ReadDone`]
+    Read { path: String },
+    /// Replaces the file at `path` with the contents sent in [`FileRequest::Data`] chunks
+    /// that follow, finished by [`FileRequest::WriteEnd`] or [`FileRequest::WriteAbort`],
+    /// and only then answered with [`FileResponse::Done`]. The file is replaced once all
+    /// of it arrived intact. A new file is created with `create_mode` less the server's
+    /// umask, an existing one keeps its mode
+    Write { path: String, create_mode: u32 },
+    /// Contents of the file being written
+    Data(Vec<u8>),
+    /// Finishes the file being written, `checksum` covers all of its contents
+    WriteEnd { checksum: Checksum },
+    /// Discards the file being written, the file at its path is left as it was
+    WriteAbort,
+    /// Creates the directory at `path` with `mode` less the server's umask, answered with
+    /// [`FileResponse::Done`]
+    CreateDir { path: String, mode: u32 },
+    /// Creates a symlink at `path` pointing to `target`, replacing a file or symlink
+    /// already there, answered with [`FileResponse::Done`]
+    Symlink { path: String, target: String },
+    /// Sets the attributes given in `attrs` on the file at `path`, answered with
+    /// [`FileResponse::Done`]. Symlinks are left as they are
+    SetAttrs { path: String, attrs: FileAttrs },
+    /// Removes the file or symlink at `path`, answered with [`FileResponse::Done`]
+    Remove { path: String },
+    /// Removes the empty directory at `path`, answered with [`FileResponse::Done`]
+    RemoveDir { path: String },
+    /// Moves the entry at `from` to `to`, replacing a file at `to` the way `rename(2)`
+    /// does, answered with [`FileResponse::Done`]. The permitted directories themselves
+    /// can't be moved or removed
+    Rename { from: String, to: String },
+    /// The absolute path `path` leads to with symlinks resolved, answered with
+    /// [`FileResponse::Path`]
+    RealPath { path: String },
+    /// Hashes of the `block_len` long blocks of what's at `path` to patch, answered with
+    /// [`FileResponse::Signature`] batches finished by [`FileResponse::SignatureEnd`].
+    /// Where an earlier [`FileRequest::Patch`] of `path` was interrupted, the blocks it got
+    /// are hashed in place of the file's, so that patching resumes from them
+    Signature { path: String, block_len: u32 },
+    /// Replaces the file at `path` with one `len` long, made of the [`FileRequest::Block`]s
+    /// that follow and, for blocks not sent, what the [`FileRequest::Signature`] with the
+    /// same `block_len` was taken of. Finished and answered like [`FileRequest::Write`],
+    /// but an interrupted patch is kept to resume from
+    Patch {
+        path: String,
+        len: u64,
+        block_len: u32,
+        create_mode: u32,
+    },
+    /// A block of the file being patched, sent in increasing `index` order
+    Block { index: u64, data: Vec<u8> },
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, serde::Deserialize, serde::Serialize)]
+pub enum FileResponse {
+    /// The request succeeded, or all of its batches have been sent
+    Done,
+    Meta(FileMeta),
+    Entries(Vec<DirEntry>),
+    LinkTarget(String),
+    /// Contents of the file being read
+    Data(Vec<u8>),
+    /// Finishes the file being read, `checksum` covers all of its contents
+    ReadDone {
+        checksum: Checksum,
+    },
+    /// The request failed, ending any batches or chunks it was answered with so far
+    Error(FileError),
+    Path(String),
+    /// Hashes of consecutive blocks, the last one may be shorter than the others
+    Signature(Vec<Checksum>),
+    /// All blocks of a signature have been sent, `resumed` if some were taken of an
+    /// interrupted patch rather than the file
+    SignatureEnd {
+        resumed: bool,
+    },
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, serde::Deserialize, serde::Serialize)]
+pub enum FileKind {
+    File,
+    Dir,
+    Symlink,
+    /// Sockets, fifos and devices, which can't be copied
+    Other,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct FileMeta {
+    pub kind: FileKind,
+    pub len: u64,
+    /// Permission bits, as in `st_mode & 0o7777`
+    pub mode: u32,
+    pub mtime: Timestamp,
+}
+
+impl From<&std::fs::Metadata> for FileMeta {
+    fn from(meta: &std::fs::Metadata) -> Self {
+        let file_type = meta.file_type();
+        let kind = if file_type.is_symlink() {
+            FileKind::Symlink
+        } else if file_type.is_dir() {
+            FileKind::Dir
+        } else if file_type.is_file() {
+            FileKind::File
+        } else {
+            FileKind::Other
+        };
+        Self {
+            kind,
+            len: meta.len(),
+            mode: permission_bits(meta),
+            mtime: meta
+                .modified()
+                .map_or(Timestamp::default(), Timestamp::from_system_time),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn permission_bits(meta: &std::fs::Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    meta.permissions().mode() & 0o7777
+}
+
+#[cfg(not(unix))]
+fn permission_bits(meta: &std::fs::Metadata) -> u32 {
+    if meta.permissions().readonly() {
+        0o444
+    } else {
+        0o644
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct DirEntry {
+    pub name: String,
+    pub meta: FileMeta,
+}
+
+/// Attributes to change with [`FileRequest::SetAttrs`], those left `None` are kept
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct FileAttrs {
+    /// Permission bits, set as given regardless of the umask
+    pub mode: Option<u32>,
+    pub mtime: Option<Timestamp>,
+}
+
+/// Time since the unix epoch, negative for times before it
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct Timestamp {
+    pub secs: i64,
+    pub nanos: u32,
+}
+
+impl Timestamp {
+    #[must_use]
+    pub fn from_system_time(time: SystemTime) -> Self {
+        match time.duration_since(SystemTime::UNIX_EPOCH) {
+            Ok(since) => Self {
+                secs: i64::try_from(since.as_secs()).unwrap_or(i64::MAX),
+                nanos: since.subsec_nanos(),
+            },
+            Err(e) => {
+                let before = e.duration();
+                let secs = i64::try_from(before.as_secs()).unwrap_or(i64::MAX);
+                // Nanos count forward, from the second before
+                if before.subsec_nanos() == 0 {
+                    Self {
+                        secs: -secs,
+                        nanos: 0,
+                    }
+                } else {
+                    Self {
+                        secs: -secs - 1,
+                        nanos: 1_000_000_000 - before.subsec_nanos(),
+                    }
+                }
+            }
+        }
+    }
+
+    #[must_use]
+    pub fn to_system_time(self) -> SystemTime {
+        let nanos = Duration::from_nanos(u64::from(self.nanos));
+        if self.secs >= 0 {
+            SystemTime::UNIX_EPOCH + Duration::from_secs(self.secs.unsigned_abs()) + nanos
+        } else {
+            SystemTime::UNIX_EPOCH - Duration::from_secs(self.secs.unsigned_abs()) + nanos
+        }
+    }
+}
+
+/// Why a [`FileRequest`] failed, with a human-readable explanation
+#[derive(Debug, Clone, Eq, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct FileError {
+    pub kind: FileErrorKind,
+    pub message: String,
+}
+
+impl FileError {
+    pub fn new(kind: FileErrorKind, message: impl Into<String>) -> Self {
+        Self {
+            kind,
+            message: message.into(),
+        }
+    }
+
+    /// Why the operation on `path` failed, as told by the os
+    #[must_use]
+    pub fn io(path: &str, e: &std::io::Error) -> Self {
+        let kind = match e.kind() {
+            std::io::ErrorKind::NotFound => FileErrorKind::NotFound,
+            std::io::ErrorKind::PermissionDenied => FileErrorKind::PermissionDenied,
+            std::io::ErrorKind::AlreadyExists => FileErrorKind::AlreadyExists,
+            _ => FileErrorKind::Other,
+        };
+        Self::new(kind, format!("{path}: {e}"))
+    }
+}
+
+impl core::fmt::Display for FileError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl core::error::Error for FileError {}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, serde::Deserialize, serde::Serialize)]
+pub enum FileErrorKind {
+    NotFound,
+    PermissionDenied,
+    AlreadyExists,
+    /// The path is outside the directories the server permits the peer
+    NotPermitted,
+    /// The contents didn't match their checksum
+    Corrupted,
+    /// The requests didn't follow the protocol
+    InvalidRequest,
+    Other,
+}
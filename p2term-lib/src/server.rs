@@ -4,4 +4,6 @@ pub mod connection;
 pub mod connection_handler;
 pub mod router;
 pub mod runtime;
+pub mod sessions;
 pub mod shell_proxy;
+pub mod timeouts;
@@ -1,9 +1,17 @@
-use crate::proto::ClientOpt;
+use crate::proto::{
+    Capabilities, ClientOpt, HELLO, Keepalive, MIN_PROTOCOL_VERSION, RejectReason, Rejection,
+    ServerHello, WELCOME, decode_handshake, is_compatible_version, peek_version, read_handshake,
+    write_handshake,
+};
 use crate::streams::{ReadStream, WriteStream};
 use anyhow::{Context, bail};
 use iroh_base::EndpointId;
 use std::fmt::Debug;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+
+/// How long a rejected client gets to read the rejection before the stream is dropped
+const REJECT_LINGER: Duration = Duration::from_secs(5);
 
 #[derive(Debug)]
 pub struct P2TermClientHandle<W, R> {
@@ -27,34 +35,68 @@ where
     W: WriteStream,
     R: ReadStream,
 {
+    /// Reads the client's hello, rejecting the client if its protocol version is incompatible
     pub(crate) async fn recv_hello(&mut self) -> anyhow::Result<ClientOpt> {
-        let opt_len = self
-            .read_stream
-            .read_u16_le()
+        let bytes = read_handshake(&mut self.read_stream, HELLO)
             .await
-            .context("failed to read client opt length")? as usize;
-        let mut buf = [0u8; crate::proto::OPT_MAX_LEN];
-        if opt_len > crate::proto::OPT_MAX_LEN {
-            bail!(
-                "read an oversized client opt len of {opt_len} for peer={}",
-                self.peer
-            )
+            .with_context(|| format!("failed to read client hello from peer={}", self.peer))?;
+        let version = peek_version(&bytes)?;
+        if !is_compatible_version(version) {
+            let rejection = Rejection::new(
+                RejectReason::VersionMismatch,
+                format!(
+                    "client speaks protocol version {version}, oldest supported by the server is {MIN_PROTOCOL_VERSION}"
+                ),
+            );
+            self.reject(&rejection).await?;
+            bail!("rejected peer={}: {rejection}", self.peer);
         }
-        // Checked above, some day this will be compiled out, just keep the safety
-        let sect = buf
-            .get_mut(..opt_len)
-            .with_context(|| format!("opt len buf out bounds from peer={}", self.peer))?;
-        self.read_stream
-            .read_exact(sect)
+        decode_handshake(&bytes)
+            .with_context(|| format!("failed to parse client opt from peer={}", self.peer))
+    }
+
+    /// Accepts the session offering `capabilities`, returning the capabilities negotiated
+    /// with the client. `keepalive` is sent along if [`Capabilities::KEEPALIVE`] is offered
+    pub(crate) async fn accept_hello(
+        &mut self,
+        client_opt: &ClientOpt,
+        capabilities: Capabilities,
+        keepalive: Option<Keepalive>,
+    ) -> anyhow::Result<Capabilities> {
+        let server_hello = ServerHello {
+            capabilities,
+            keepalive: keepalive.filter(|_| capabilities.contains(Capabilities::KEEPALIVE)),
+            ..ServerHello::default()
+        };
+        write_handshake(&mut self.write_stream, WELCOME, &server_hello)
             .await
-            .context("failed to read client opt")?;
-        let opt = postcard::from_bytes(&buf[..opt_len])
-            .with_context(|| format!("failed to parse client opt from peer={}", self.peer))?;
+            .with_context(|| format!("failed to send welcome message to peer={}", self.peer))?;
+        Ok(client_opt
+            .capabilities
+            .intersection(server_hello.capabilities))
+    }
+
+    /// Tells the client why its session was refused, then waits a while for the
+    /// client to hang up so that the answer isn't lost when the connection closes
+    pub(crate) async fn reject(&mut self, rejection: &Rejection) -> anyhow::Result<()> {
+        write_handshake(
+            &mut self.write_stream,
+            WELCOME,
+            &ServerHello::rejected(rejection.clone()),
+        )
+        .await
+        .with_context(|| format!("failed to send rejection to peer={}", self.peer))?;
         self.write_stream
-            .write_all(crate::proto::WELCOME)
+            .shutdown()
             .await
-            .with_context(|| format!("failed to send welcome message to peer={}", self.peer))?;
-        Ok(opt)
+            .with_context(|| format!("failed to finish rejection to peer={}", self.peer))?;
+        let mut sink = tokio::io::sink();
+        let _ = tokio::time::timeout(
+            REJECT_LINGER,
+            tokio::io::copy(&mut self.read_stream, &mut sink),
+        )
+        .await;
+        Ok(())
     }
 
     pub(crate) fn decompose(self) -> (W, R) {
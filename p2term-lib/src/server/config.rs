@@ -1,10 +1,25 @@
 use crate::convert::HexConvert;
 use crate::crypto::{any_secret_key, generate_secret_key};
-use crate::proto::ClientOpt;
-use anyhow::{Context, bail};
+use crate::proto::{
+    Capabilities, ClientOpt, Compression, Keepalive, RejectReason, Rejection, SessionKind,
+};
+use anyhow::Context;
 use iroh::{PublicKey, SecretKey};
-use rustc_hash::FxHashSet;
-use std::path::PathBuf;
+use rustc_hash::{FxHashMap, FxHashSet};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Output kept per persistent session for replaying on attach, unless configured
+pub const DEFAULT_SCROLLBACK_BYTES: usize = 256 * 1024;
+
+/// How long a resumable session survives a lost connection, unless configured
+pub const DEFAULT_RESUME_GRACE: Duration = Duration::from_mins(1);
+
+/// How shell sessions are kept alive, unless configured
+pub const DEFAULT_KEEPALIVE: Keepalive = Keepalive {
+    interval: Duration::from_secs(15),
+    max_missed: 4,
+};
 
 #[derive(Debug, serde::Deserialize)]
 struct P2TermdTomlCfg {
@@ -13,6 +28,40 @@ struct P2TermdTomlCfg {
     allowed_peers: Option<Vec<String>>,
     default_shell: Option<String>,
     allowed_shells: Option<Vec<String>>,
+    max_sessions: Option<usize>,
+    scrollback_bytes: Option<usize>,
+    detached_session_timeout_secs: Option<u64>,
+    resume_grace_secs: Option<u64>,
+    /// Attaching peer to the peers whose sessions it may attach to
+    attach_grants: Option<FxHashMap<String, Vec<String>>>,
+    /// Watching peer to the peers whose sessions it may watch read-only
+    watch_grants: Option<FxHashMap<String, Vec<String>>>,
+    /// Forwarding peer to the `host:port` targets it may open connections to
+    permit_open: Option<FxHashMap<String, Vec<String>>>,
+    /// Forwarding peer to the `host:port` addresses it may have the server listen on
+    permit_listen: Option<FxHashMap<String, Vec<String>>>,
+    allow_non_loopback_listen: Option<bool>,
+    /// Forwarding peer to the unix socket paths it may connect to
+    permit_open_unix: Option<FxHashMap<String, Vec<String>>>,
+    /// Forwarding peer to the unix socket paths it may have the server listen on
+    permit_listen_unix: Option<FxHashMap<String, Vec<String>>>,
+    /// Forwarding peer to the `host:port` targets it may send udp datagrams to
+    permit_open_udp: Option<FxHashMap<String, Vec<String>>>,
+    /// Peer to the directories whose files it may access
+    permit_files: Option<FxHashMap<String, Vec<String>>>,
+    compression: Option<Compression>,
+    keepalive_interval_secs: Option<u64>,
+    keepalive_max_missed: Option<u32>,
+    idle_timeout_secs: Option<u64>,
+    max_session_duration_secs: Option<u64>,
+    /// Peer to the timeouts overriding the global ones for its sessions
+    peer_timeouts: Option<FxHashMap<String, PeerTimeoutsTomlCfg>>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct PeerTimeoutsTomlCfg {
+    idle_timeout_secs: Option<u64>,
+    max_session_duration_secs: Option<u64>,
 }
 
 #[derive(Debug)]
@@ -36,16 +85,371 @@ pub struct P2TermdCfg {
     pub secret_key: SecretKey,
     pub access: P2TermdAccess,
     pub shell_cfg: ShellCfg,
+    /// Sessions served concurrently before new ones are rejected as busy, unlimited if `None`
+    pub max_sessions: Option<usize>,
 }
 
 #[derive(Debug)]
 pub struct ShellCfg {
     pub default_shell: String,
     pub allowed_shells: Vec<String>,
+    pub persistence: PersistenceCfg,
+    pub forwarding: ForwardCfg,
+    pub files: FileCfg,
+    /// Whether shell output is compressed for clients that allow it
+    pub compression: Compression,
+    pub timeouts: TimeoutCfg,
+}
+
+/// How shell sessions are kept alive, and how long they may run
+#[derive(Debug)]
+pub struct TimeoutCfg {
+    /// Offered to clients that send keepalives, they're not used if `None`
+    pub keepalive: Option<Keepalive>,
+    /// Limits for peers without limits of their own
+    pub limits: SessionLimits,
+    /// Peer to the limits for its sessions, replacing the global ones
+    pub peer_limits: FxHashMap<PublicKey, SessionLimits>,
+}
+
+/// How long a shell session may run, it's warned about and then hung up once over a limit
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct SessionLimits {
+    /// How long a session may go without input from its clients or output, forever if `None`
+    pub idle_timeout: Option<Duration>,
+    /// How long a session may run at all, forever if `None`
+    pub max_duration: Option<Duration>,
+}
+
+impl Default for TimeoutCfg {
+    fn default() -> Self {
+        Self {
+            keepalive: Some(DEFAULT_KEEPALIVE),
+            limits: SessionLimits::default(),
+            peer_limits: FxHashMap::default(),
+        }
+    }
+}
+
+impl TimeoutCfg {
+    /// The limits for the sessions `peer` starts
+    #[must_use]
+    pub fn limits(&self, peer: &PublicKey) -> SessionLimits {
+        self.peer_limits.get(peer).copied().unwrap_or(self.limits)
+    }
+}
+
+/// How sessions started with [`ClientOpt::persist`] are kept
+#[derive(Debug)]
+pub struct PersistenceCfg {
+    /// Output kept per session for replaying on attach
+    pub scrollback_bytes: usize,
+    /// How long a session may stay detached before it's hung up, forever if `None`
+    pub detached_timeout: Option<Duration>,
+    /// How long a [`ClientOpt::resumable`] session is kept after losing its client's connection
+    pub resume_grace: Duration,
+    /// Attaching peer to the peers whose sessions it may attach to, besides its own
+    pub attach_grants: FxHashMap<PublicKey, FxHashSet<PublicKey>>,
+    /// Watching peer to the peers whose sessions it may watch read-only
+    pub watch_grants: FxHashMap<PublicKey, FxHashSet<PublicKey>>,
+}
+
+/// What a peer may do with a persistent session
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
+pub enum SessionAccess {
+    /// See the output, input is discarded
+    Watch,
+    /// Send input, resize and signal, as the owner does
+    Write,
+}
+
+impl Default for PersistenceCfg {
+    fn default() -> Self {
+        Self {
+            scrollback_bytes: DEFAULT_SCROLLBACK_BYTES,
+            detached_timeout: None,
+            resume_grace: DEFAULT_RESUME_GRACE,
+            attach_grants: FxHashMap::default(),
+            watch_grants: FxHashMap::default(),
+        }
+    }
+}
+
+impl PersistenceCfg {
+    /// What `peer` may do with a session started by `owner`, `None` if it may not even see it
+    #[must_use]
+    pub fn access(&self, peer: &PublicKey, owner: &PublicKey) -> Option<SessionAccess> {
+        let granted = |grants: &FxHashMap<PublicKey, FxHashSet<PublicKey>>| {
+            grants
+                .get(peer)
+                .is_some_and(|owners| owners.contains(owner))
+        };
+        if peer == owner || granted(&self.attach_grants) {
+            Some(SessionAccess::Write)
+        } else if granted(&self.watch_grants) {
+            Some(SessionAccess::Watch)
+        } else {
+            None
+        }
+    }
+}
+
+/// Where peers may forward connections to, nothing is permitted unless configured
+#[derive(Debug, Default)]
+pub struct ForwardCfg {
+    /// Forwarding peer to the targets it may open tcp connections to
+    pub permit_open: FxHashMap<PublicKey, Vec<PermitOpen>>,
+    /// Forwarding peer to the addresses it may have the server listen on for it
+    pub permit_listen: FxHashMap<PublicKey, Vec<PermitOpen>>,
+    /// Whether listening on addresses other than loopback is allowed, exposing the
+    /// peer's forwards to the server's network
+    pub allow_non_loopback_listen: bool,
+    /// Forwarding peer to the unix sockets it may connect to
+    pub permit_open_unix: FxHashMap<PublicKey, Vec<PermitPath>>,
+    /// Forwarding peer to the unix socket paths it may have the server listen on for it
+    pub permit_listen_unix: FxHashMap<PublicKey, Vec<PermitPath>>,
+    /// Forwarding peer to the targets it may send udp datagrams to
+    pub permit_open_udp: FxHashMap<PublicKey, Vec<PermitOpen>>,
+}
+
+impl ForwardCfg {
+    /// Checks the forwarding sessions the policy covers, other sessions pass
+    pub fn admit(&self, peer: &PublicKey, client_opt: &ClientOpt) -> Result<(), Rejection> {
+        match &client_opt.session {
+            SessionKind::ForwardTcp { host, port }
+                if !permitted(&self.permit_open, peer, host, *port) =>
+            {
+                return Err(Rejection::new(
+                    RejectReason::ForwardDenied,
+                    format!("peer {peer} may not open connections to {host}:{port}"),
+                ));
+            }
+            SessionKind::ListenTcp { host, port } => {
+                if !permitted(&self.permit_listen, peer, host, *port) {
+                    return Err(Rejection::new(
+                        RejectReason::ForwardDenied,
+                        format!("peer {peer} may not listen on {host}:{port}"),
+                    ));
+                }
+                if !self.allow_non_loopback_listen && !is_loopback(host) {
+                    return Err(Rejection::new(
+                        RejectReason::ForwardDenied,
+                        format!(
+                            "listening on {host}, which is not a loopback address, is not allowed"
+                        ),
+                    ));
+                }
+            }
+            SessionKind::ForwardUnix { path }
+                if !permitted_path(&self.permit_open_unix, peer, path) =>
+            {
+                return Err(Rejection::new(
+                    RejectReason::ForwardDenied,
+                    format!("peer {peer} may not connect to unix socket {path}"),
+                ));
+            }
+            SessionKind::ListenUnix { path }
+                if !permitted_path(&self.permit_listen_unix, peer, path) =>
+            {
+                return Err(Rejection::new(
+                    RejectReason::ForwardDenied,
+                    format!("peer {peer} may not listen on unix socket {path}"),
+                ));
+            }
+            SessionKind::ForwardUdp { host, port, .. }
+                if !permitted(&self.permit_open_udp, peer, host, *port) =>
+            {
+                return Err(Rejection::new(
+                    RejectReason::ForwardDenied,
+                    format!("peer {peer} may not send udp datagrams to {host}:{port}"),
+                ));
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+fn permitted_path(
+    permits: &FxHashMap<PublicKey, Vec<PermitPath>>,
+    peer: &PublicKey,
+    path: &str,
+) -> bool {
+    permits
+        .get(peer)
+        .is_some_and(|paths| paths.iter().any(|permit| permit.permits(path)))
+}
+
+fn permitted(
+    permits: &FxHashMap<PublicKey, Vec<PermitOpen>>,
+    peer: &PublicKey,
+    host: &str,
+    port: u16,
+) -> bool {
+    permits
+        .get(peer)
+        .is_some_and(|targets| targets.iter().any(|target| target.permits(host, port)))
+}
+
+fn is_loopback(host: &str) -> bool {
+    host.eq_ignore_ascii_case("localhost")
+        || host
+            .parse::<std::net::IpAddr>()
+            .is_ok_and(|addr| addr.is_loopback())
+}
+
+/// A `host:port` pattern from `permit_open`, `permit_listen` or `permit_open_udp`, either part may be `*` to
+/// match anything. Hosts are matched as the client names them, not by what they resolve to.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct PermitOpen {
+    pub host: Option<String>,
+    pub port: Option<u16>,
+}
+
+impl PermitOpen {
+    pub fn parse(target: &str) -> anyhow::Result<Self> {
+        let (host, port) = target
+            .rsplit_once(':')
+            .with_context(|| format!("permit target {target} is not host:port"))?;
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() {
+            anyhow::bail!("permit target {target} has an empty host");
+        }
+        let port = match port {
+            "*" => None,
+            port => Some(
+                port.parse()
+                    .with_context(|| format!("invalid port in permit target {target}"))?,
+            ),
+        };
+        Ok(Self {
+            host: (host != "*").then(|| host.to_ascii_lowercase()),
+            port,
+        })
+    }
+
+    #[must_use]
+    pub fn permits(&self, host: &str, port: u16) -> bool {
+        self.host
+            .as_deref()
+            .is_none_or(|permitted| permitted.eq_ignore_ascii_case(host))
+            && self.port.is_none_or(|permitted| permitted == port)
+    }
+}
+
+/// A unix socket path from `permit_open_unix` or `permit_listen_unix`, ending it with `/*`
+/// matches any socket directly inside the directory. Only absolute paths without `.` or `..`
+/// are matched, so that a permitted directory can't be walked out of
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct PermitPath {
+    pub path: String,
+    pub any_in_dir: bool,
+}
+
+impl PermitPath {
+    pub fn parse(pattern: &str) -> anyhow::Result<Self> {
+        let (path, any_in_dir) = match pattern.strip_suffix("/*") {
+            Some(dir) => (dir, true),
+            None => (pattern, false),
+        };
+        if !is_normalized_path(path) {
+            anyhow::bail!("permitted path {pattern} is not absolute or contains `.` or `..`");
+        }
+        Ok(Self {
+            path: path.to_string(),
+            any_in_dir,
+        })
+    }
+
+    #[must_use]
+    pub fn permits(&self, path: &str) -> bool {
+        if !is_normalized_path(path) {
+            return false;
+        }
+        if !self.any_in_dir {
+            return path == self.path;
+        }
+        path.strip_prefix(self.path.as_str())
+            .and_then(|rest| rest.strip_prefix('/'))
+            .is_some_and(|name| !name.contains('/'))
+    }
+}
+
+fn is_normalized_path(path: &str) -> bool {
+    path.strip_prefix('/').is_some_and(|rest| {
+        rest.split('/')
+            .all(|part| !part.is_empty() && part != "." && part != "..")
+    })
+}
+
+/// Where peers may access files with [`SessionKind::Files`], nowhere unless configured
+#[derive(Debug, Default)]
+pub struct FileCfg {
+    /// Peer to the absolute directories it may access files in, including subdirectories.
+    /// Paths are checked after resolving symlinks, so links can't lead out of them
+    pub permit_files: FxHashMap<PublicKey, Vec<PathBuf>>,
+}
+
+impl FileCfg {
+    /// Checks that a [`SessionKind::Files`] session may access anything, and starts
+    /// within the permitted directories, other sessions pass
+    pub fn admit(&self, peer: &PublicKey, client_opt: &ClientOpt) -> Result<(), Rejection> {
+        if client_opt.session != SessionKind::Files {
+            return Ok(());
+        }
+        let roots = self.roots(peer);
+        if roots.is_empty() {
+            return Err(Rejection::new(
+                RejectReason::FilesDenied,
+                format!("peer {peer} may not access files"),
+            ));
+        }
+        if let Some(cwd) = client_opt.cwd.as_ref()
+            && !cwd
+                .canonicalize()
+                .is_ok_and(|cwd| roots.iter().any(|root| is_within(&cwd, root)))
+        {
+            return Err(Rejection::new(
+                RejectReason::FilesDenied,
+                format!(
+                    "{} is outside the directories peer {peer} may access",
+                    cwd.display()
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    /// The directories `peer` may access files in
+    #[must_use]
+    pub fn roots(&self, peer: &PublicKey) -> &[PathBuf] {
+        self.permit_files.get(peer).map_or(&[], Vec::as_slice)
+    }
+}
+
+/// Whether the resolved `path` is `root` or inside it, `root` is resolved here
+fn is_within(path: &Path, root: &Path) -> bool {
+    root.canonicalize().is_ok_and(|root| path.starts_with(root))
+}
+
+fn parse_permitted_dir(dir: &str) -> anyhow::Result<PathBuf> {
+    let dir = PathBuf::from(dir);
+    if !dir.is_absolute() {
+        anyhow::bail!("permitted directory {} is not absolute", dir.display());
+    }
+    Ok(dir)
 }
 
 impl ShellCfg {
-    fn from_overrides(default_shell: Option<String>, mut allowed_shells: Vec<String>) -> Self {
+    fn from_overrides(
+        default_shell: Option<String>,
+        mut allowed_shells: Vec<String>,
+        persistence: PersistenceCfg,
+        forwarding: ForwardCfg,
+        files: FileCfg,
+        compression: Compression,
+        timeouts: TimeoutCfg,
+    ) -> Self {
         let default_shell = establish_default_shell(default_shell);
         if !allowed_shells.contains(&default_shell) {
             allowed_shells.push(default_shell.clone());
@@ -53,14 +457,74 @@ impl ShellCfg {
         Self {
             default_shell,
             allowed_shells,
+            persistence,
+            forwarding,
+            files,
+            compression,
+            timeouts,
         }
     }
 
-    pub fn validate_opt(&self, client_opt: &ClientOpt) -> anyhow::Result<()> {
+    /// What the server offers to clients in its hello for a session of `kind`
+    #[must_use]
+    pub fn capabilities(&self, kind: &SessionKind) -> Capabilities {
+        let mut capabilities = if cfg!(feature = "compression") {
+            self.compression.offered(Capabilities::SUPPORTED)
+        } else {
+            // Without the feature there's nothing to compress with
+            Capabilities::SUPPORTED
+        };
+        if kind.is_interactive() {
+            capabilities = capabilities | Capabilities::OUTPUT_ACK;
+            // Only shell sessions send keepalives while there's nothing else to send
+            if self.timeouts.keepalive.is_some() {
+                capabilities = capabilities | Capabilities::KEEPALIVE;
+            }
+        }
+        capabilities
+    }
+
+    /// What a shell or exec session runs: the requested shell, or the default, as a login
+    /// shell, or with the command passed to it with `-c` the way sshd does it, so that exec
+    /// is held to the allowed shells as well. `None` for sessions that don't run anything
+    #[must_use]
+    pub fn command(&self, client_opt: &ClientOpt) -> Option<Vec<String>> {
+        let shell = client_opt
+            .shell
+            .clone()
+            .unwrap_or_else(|| self.default_shell.clone());
+        match &client_opt.session {
+            SessionKind::Shell => Some(vec![shell, "-l".to_string()]),
+            SessionKind::Exec(argv) => Some(vec![shell, "-c".to_string(), shell_join(argv)]),
+            _ => None,
+        }
+    }
+
+    pub fn validate_opt(&self, client_opt: &ClientOpt) -> Result<(), Rejection> {
         if let Some(shell) = client_opt.shell.as_ref()
             && !self.allowed_shells.contains(shell)
         {
-            bail!("disallowed shell: {shell} in client opt")
+            return Err(Rejection::new(
+                RejectReason::DisallowedShell,
+                format!(
+                    "shell {shell} is not allowed, allowed shells are: {}",
+                    self.allowed_shells.join(", ")
+                ),
+            ));
+        }
+        if let Some(cwd) = client_opt.cwd.as_ref()
+            && !cwd.is_dir()
+        {
+            return Err(Rejection::new(
+                RejectReason::InvalidCwd,
+                format!("{} is not a directory on the server", cwd.display()),
+            ));
+        }
+        if (client_opt.persist || client_opt.resumable) && client_opt.no_pty {
+            return Err(Rejection::new(
+                RejectReason::InvalidRequest,
+                "persistent and resumable sessions need a pty",
+            ));
         }
         Ok(())
     }
@@ -71,7 +535,16 @@ impl Default for P2TermdCfg {
         Self {
             secret_key: generate_secret_key(),
             access: P2TermdAccess::Any,
-            shell_cfg: ShellCfg::from_overrides(None, vec![]),
+            shell_cfg: ShellCfg::from_overrides(
+                None,
+                vec![],
+                PersistenceCfg::default(),
+                ForwardCfg::default(),
+                FileCfg::default(),
+                Compression::default(),
+                TimeoutCfg::default(),
+            ),
+            max_sessions: None,
         }
     }
 }
@@ -91,11 +564,90 @@ impl P2TermdCfg {
             shell_cfg: ShellCfg::from_overrides(
                 toml_cfg.default_shell,
                 toml_cfg.allowed_shells.unwrap_or_default(),
+                PersistenceCfg {
+                    scrollback_bytes: toml_cfg
+                        .scrollback_bytes
+                        .unwrap_or(DEFAULT_SCROLLBACK_BYTES),
+                    detached_timeout: toml_cfg
+                        .detached_session_timeout_secs
+                        .map(Duration::from_secs),
+                    resume_grace: toml_cfg
+                        .resume_grace_secs
+                        .map_or(DEFAULT_RESUME_GRACE, Duration::from_secs),
+                    attach_grants: create_grants(toml_cfg.attach_grants)?,
+                    watch_grants: create_grants(toml_cfg.watch_grants)?,
+                },
+                ForwardCfg {
+                    permit_open: create_permits(toml_cfg.permit_open, PermitOpen::parse)?,
+                    permit_listen: create_permits(toml_cfg.permit_listen, PermitOpen::parse)?,
+                    allow_non_loopback_listen: toml_cfg.allow_non_loopback_listen.unwrap_or(false),
+                    permit_open_unix: create_permits(toml_cfg.permit_open_unix, PermitPath::parse)?,
+                    permit_listen_unix: create_permits(
+                        toml_cfg.permit_listen_unix,
+                        PermitPath::parse,
+                    )?,
+                    permit_open_udp: create_permits(toml_cfg.permit_open_udp, PermitOpen::parse)?,
+                },
+                FileCfg {
+                    permit_files: create_permits(toml_cfg.permit_files, parse_permitted_dir)?,
+                },
+                toml_cfg.compression.unwrap_or_default(),
+                TimeoutCfg {
+                    keepalive: create_keepalive(
+                        toml_cfg.keepalive_interval_secs,
+                        toml_cfg.keepalive_max_missed,
+                    ),
+                    limits: SessionLimits {
+                        idle_timeout: limit(toml_cfg.idle_timeout_secs),
+                        max_duration: limit(toml_cfg.max_session_duration_secs),
+                    },
+                    peer_limits: create_peer_limits(
+                        toml_cfg.peer_timeouts,
+                        toml_cfg.idle_timeout_secs,
+                        toml_cfg.max_session_duration_secs,
+                    )?,
+                },
             ),
+            max_sessions: toml_cfg.max_sessions,
         })
     }
 }
 
+/// Keepalives are turned off with an interval of 0
+fn create_keepalive(interval_secs: Option<u64>, max_missed: Option<u32>) -> Option<Keepalive> {
+    let interval = interval_secs.map_or(DEFAULT_KEEPALIVE.interval, Duration::from_secs);
+    (!interval.is_zero()).then(|| Keepalive {
+        interval,
+        max_missed: max_missed.unwrap_or(DEFAULT_KEEPALIVE.max_missed).max(1),
+    })
+}
+
+/// A limit of 0 is no limit, so that a peer can be exempted from a global one
+fn limit(secs: Option<u64>) -> Option<Duration> {
+    secs.filter(|&secs| secs > 0).map(Duration::from_secs)
+}
+
+/// What a peer doesn't set falls back to the global settings
+fn create_peer_limits(
+    peer_timeouts: Option<FxHashMap<String, PeerTimeoutsTomlCfg>>,
+    idle_timeout_secs: Option<u64>,
+    max_session_duration_secs: Option<u64>,
+) -> anyhow::Result<FxHashMap<PublicKey, SessionLimits>> {
+    let mut parsed = FxHashMap::default();
+    for (peer, timeouts) in peer_timeouts.unwrap_or_default() {
+        let limits = SessionLimits {
+            idle_timeout: limit(timeouts.idle_timeout_secs.or(idle_timeout_secs)),
+            max_duration: limit(
+                timeouts
+                    .max_session_duration_secs
+                    .or(max_session_duration_secs),
+            ),
+        };
+        parsed.insert(parse_peer(&peer)?, limits);
+    }
+    Ok(parsed)
+}
+
 fn establish_default_shell(default_shell: Option<String>) -> String {
     default_shell
         .or_else(|| std::env::var("SHELL").ok())
@@ -107,6 +659,25 @@ fn establish_default_shell(default_shell: Option<String>) -> String {
         })
 }
 
+/// Joins `argv` into a command line a posix shell splits back into the same arguments
+fn shell_join(argv: &[String]) -> String {
+    let quoted: Vec<String> = argv
+        .iter()
+        .map(|arg| {
+            let plain = !arg.is_empty()
+                && arg
+                    .chars()
+                    .all(|ch| ch.is_ascii_alphanumeric() || "-_./:=@,+%".contains(ch));
+            if plain {
+                arg.clone()
+            } else {
+                format!("'{}'", arg.replace('\'', "'\\''"))
+            }
+        })
+        .collect();
+    quoted.join(" ")
+}
+
 fn create_access(allowed_peers: Option<Vec<String>>) -> anyhow::Result<P2TermdAccess> {
     let allowed_peers = allowed_peers.unwrap_or_default();
     if allowed_peers.is_empty() {
@@ -115,10 +686,43 @@ fn create_access(allowed_peers: Option<Vec<String>>) -> anyhow::Result<P2TermdAc
     }
     let mut allowed = FxHashSet::default();
     for peer in allowed_peers {
-        allowed.insert(
-            PublicKey::try_from_hex(peer.as_bytes())
-                .with_context(|| format!("invalid peer public key hex: {peer}"))?,
-        );
+        allowed.insert(parse_peer(&peer)?);
     }
     Ok(P2TermdAccess::AllowedNodes(allowed))
 }
+
+fn create_grants(
+    grants: Option<FxHashMap<String, Vec<String>>>,
+) -> anyhow::Result<FxHashMap<PublicKey, FxHashSet<PublicKey>>> {
+    let mut parsed = FxHashMap::default();
+    for (peer, owners) in grants.unwrap_or_default() {
+        let peer = parse_peer(&peer)?;
+        let owners = owners
+            .iter()
+            .map(|owner| parse_peer(owner))
+            .collect::<anyhow::Result<FxHashSet<_>>>()?;
+        parsed.insert(peer, owners);
+    }
+    Ok(parsed)
+}
+
+fn create_permits<T>(
+    permits: Option<FxHashMap<String, Vec<String>>>,
+    parse: fn(&str) -> anyhow::Result<T>,
+) -> anyhow::Result<FxHashMap<PublicKey, Vec<T>>> {
+    let mut parsed = FxHashMap::default();
+    for (peer, targets) in permits.unwrap_or_default() {
+        let peer = parse_peer(&peer)?;
+        let targets = targets
+            .iter()
+            .map(|target| parse(target))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        parsed.insert(peer, targets);
+    }
+    Ok(parsed)
+}
+
+fn parse_peer(peer: &str) -> anyhow::Result<PublicKey> {
+    PublicKey::try_from_hex(peer.as_bytes())
+        .with_context(|| format!("invalid peer public key hex: {peer}"))
+}
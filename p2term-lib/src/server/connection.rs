@@ -1,14 +1,22 @@
 use crate::server::client_handle::P2TermClientHandle;
 use anyhow::Context;
-use iroh::endpoint::{Connection, RecvStream, SendStream};
+use iroh::endpoint::{Connection, ConnectionError, RecvStream, SendStream};
 use iroh_base::PublicKey;
 
-pub trait P2TermServerConnection<W, R>: Send {
+pub trait P2TermServerConnection<W, R>: Clone + Send + Sync + 'static {
     fn peer(&self) -> iroh::PublicKey;
+    /// Accepts the next session opened on this connection, `None` once the peer has
+    /// closed the connection
     fn accept(
         &self,
         peer: PublicKey,
-    ) -> impl Future<Output = anyhow::Result<P2TermClientHandle<W, R>>> + Send;
+    ) -> impl Future<Output = anyhow::Result<Option<P2TermClientHandle<W, R>>>> + Send;
+    /// Opens a stream to the peer, for connections the server accepted on the peer's behalf
+    fn open(&self) -> impl Future<Output = anyhow::Result<(W, R)>> + Send;
+    /// Sends an unreliable datagram to the peer, see [`crate::datagram`]
+    fn send_datagram(&self, datagram: Vec<u8>) -> anyhow::Result<()>;
+    /// Receives the next datagram from the peer, failing once the connection is closed
+    fn read_datagram(&self) -> impl Future<Output = anyhow::Result<Vec<u8>>> + Send;
 }
 
 impl P2TermServerConnection<SendStream, RecvStream> for Connection {
@@ -19,11 +27,32 @@ impl P2TermServerConnection<SendStream, RecvStream> for Connection {
     async fn accept(
         &self,
         peer: PublicKey,
-    ) -> anyhow::Result<P2TermClientHandle<SendStream, RecvStream>> {
-        let (send, recv) = self
-            .accept_bi()
+    ) -> anyhow::Result<Option<P2TermClientHandle<SendStream, RecvStream>>> {
+        match self.accept_bi().await {
+            Ok((send, recv)) => Ok(Some(P2TermClientHandle::new(peer, send, recv))),
+            Err(
+                ConnectionError::ApplicationClosed(_)
+                | ConnectionError::ConnectionClosed(_)
+                | ConnectionError::LocallyClosed,
+            ) => Ok(None),
+            Err(e) => Err(e).context("failed to accept bidirectional connection from client"),
+        }
+    }
+
+    async fn open(&self) -> anyhow::Result<(SendStream, RecvStream)> {
+        self.open_bi()
+            .await
+            .context("failed to open bidirectional stream to client")
+    }
+
+    fn send_datagram(&self, datagram: Vec<u8>) -> anyhow::Result<()> {
+        Connection::send_datagram(self, datagram.into()).context("failed to send datagram")
+    }
+
+    async fn read_datagram(&self) -> anyhow::Result<Vec<u8>> {
+        let datagram = Connection::read_datagram(self)
             .await
-            .context("failed to accept bidirectional connection from client")?;
-        Ok(P2TermClientHandle::new(peer, send, recv))
+            .context("failed to read datagram")?;
+        Ok(datagram.to_vec())
     }
 }
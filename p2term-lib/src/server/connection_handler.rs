@@ -1,14 +1,27 @@
+use crate::datagram::FlowRouter;
 use crate::error::unpack;
+use crate::proto::codec::{FrameReader, FrameWriter};
+use crate::proto::{Capabilities, ClientOpt, RejectReason, Rejection, SessionKind};
+use crate::server::client_handle::P2TermClientHandle;
 use crate::server::config::{P2TermdAccess, ShellCfg};
 use crate::server::connection::P2TermServerConnection;
 use crate::server::shell_proxy::ServerShellProxy;
 use crate::streams::{ReadStream, WriteStream};
-use anyhow::Context;
+use anyhow::{Context, bail};
 use iroh::endpoint::Connection;
 use iroh::protocol::{AcceptError, ProtocolHandler};
 use iroh_base::PublicKey;
 use std::fmt::Debug;
-use std::marker::PhantomData;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Udp flows a connection may have at once, each holds a socket on the server
+const MAX_UDP_FLOWS: usize = 256;
+
+/// How long a denied peer is given to say hello and read why it was rejected, so that one
+/// which never does can't hold on to the server
+const REJECT_TIMEOUT: Duration = Duration::from_secs(10);
 
 pub trait ConnectionHandler: Sized + Debug + Send + Sync + 'static {
     fn serve<W, R>(
@@ -22,19 +35,67 @@ pub trait ConnectionHandler: Sized + Debug + Send + Sync + 'static {
 
 #[derive(Debug)]
 pub struct P2TermConnectionHandler<S> {
+    state: Arc<HandlerState<S>>,
+}
+
+/// Shared by every session the handler is serving
+#[derive(Debug)]
+struct HandlerState<S> {
     access: P2TermdAccess,
     shell_cfg: ShellCfg,
-    _pd: PhantomData<S>,
+    session_slots: Option<Arc<Semaphore>>,
+    shell: S,
 }
 
 impl<S> P2TermConnectionHandler<S> {
     #[must_use]
-    pub fn new(access: P2TermdAccess, shell_cfg: ShellCfg) -> Self {
+    pub fn new(
+        access: P2TermdAccess,
+        shell_cfg: ShellCfg,
+        max_sessions: Option<usize>,
+        shell: S,
+    ) -> Self {
         Self {
-            access,
-            shell_cfg,
-            _pd: PhantomData,
+            state: Arc::new(HandlerState {
+                access,
+                shell_cfg,
+                session_slots: max_sessions.map(|max| Arc::new(Semaphore::new(max))),
+                shell,
+            }),
+        }
+    }
+}
+
+impl<S> HandlerState<S> {
+    /// Checks whether the peer may start a session with the given options, the returned
+    /// permit must be held for as long as the session runs. Attaching to or resuming a kept
+    /// session doesn't take a slot, the kept session holds its own
+    fn admit(
+        &self,
+        peer: &PublicKey,
+        client_opt: &ClientOpt,
+    ) -> Result<Option<OwnedSemaphorePermit>, Rejection> {
+        if !self.access.is_allowed(peer) {
+            return Err(Rejection::new(
+                RejectReason::DeniedPeer,
+                format!("peer {peer} is not in the server's allowed peers"),
+            ));
+        }
+        self.shell_cfg.validate_opt(client_opt)?;
+        self.shell_cfg.forwarding.admit(peer, client_opt)?;
+        self.shell_cfg.files.admit(peer, client_opt)?;
+        let Some(slots) = &self.session_slots else {
+            return Ok(None);
+        };
+        if let SessionKind::Attach(_) | SessionKind::Resume { .. } = client_opt.session {
+            return Ok(None);
         }
+        slots.clone().try_acquire_owned().map(Some).map_err(|_e| {
+            Rejection::new(
+                RejectReason::ServerBusy,
+                "the server is serving its maximum number of sessions, try again later",
+            )
+        })
     }
 }
 
@@ -52,36 +113,196 @@ where
         R: ReadStream,
     {
         let peer = connection.peer();
-        if !self.access.is_allowed(&peer) {
+        if !self.state.access.is_allowed(&peer) {
+            // The first session gets told why, then the connection is dropped
+            let rejected = tokio::time::timeout(
+                REJECT_TIMEOUT,
+                accept_session::<W, R, S, _>(&connection, peer, &self.state),
+            )
+            .await;
+            match rejected {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => tracing::debug!("failed to reject peer={peer}: {}", unpack(&*e)),
+                Err(_elapsed) => tracing::debug!("timed out rejecting peer={peer}"),
+            }
             tracing::warn!("rejected connection from peer={peer}");
             return Err(AcceptError::NotAllowed {
                 meta: Default::default(),
             });
         }
         tracing::info!("accepted connection from peer={peer}");
-        if let Err(e) = serve_client::<W, R, S>(connection, peer, &self.shell_cfg).await {
-            tracing::warn!(
-                "failed to serve client connection to peer={peer}: {}",
-                unpack(&*e)
-            );
-        }
+        serve_sessions::<W, R, S, _>(&connection, peer, &self.state).await;
+        tracing::info!("connection from peer={peer} closed");
         Ok(())
     }
 }
 
-async fn serve_client<W: WriteStream, R: ReadStream, S: ServerShellProxy>(
-    connection: impl P2TermServerConnection<W, R>,
+/// Serves every session the peer opens on the connection concurrently,
+/// until the connection closes and the sessions have finished
+async fn serve_sessions<W, R, S, C>(connection: &C, peer: PublicKey, state: &Arc<HandlerState<S>>)
+where
+    W: WriteStream,
+    R: ReadStream,
+    S: ServerShellProxy,
+    C: P2TermServerConnection<W, R>,
+{
+    let mut sessions = tokio::task::JoinSet::new();
+    let flows = FlowRouter::limited(MAX_UDP_FLOWS);
+    let mut datagrams_open = true;
+    loop {
+        tokio::select! {
+            accepted = connection.accept(peer) => match accepted {
+                Ok(Some(client)) => {
+                    sessions.spawn(serve_session::<W, R, S, C>(
+                        client,
+                        peer,
+                        connection.clone(),
+                        flows.clone(),
+                        state.clone(),
+                    ));
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    tracing::warn!("failed to accept session from peer={peer}: {}", unpack(&*e));
+                    break;
+                }
+            },
+            Some(joined) = sessions.join_next() => log_session_end(peer, joined),
+            datagram = connection.read_datagram(), if datagrams_open => match datagram {
+                Ok(datagram) => flows.route(&datagram),
+                Err(e) => {
+                    tracing::debug!("stopped reading datagrams from peer={peer}: {}", unpack(&*e));
+                    datagrams_open = false;
+                }
+            },
+        }
+    }
+    while let Some(joined) = sessions.join_next().await {
+        log_session_end(peer, joined);
+    }
+}
+
+fn log_session_end(peer: PublicKey, joined: Result<anyhow::Result<()>, tokio::task::JoinError>) {
+    match joined {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => tracing::warn!(
+            "failed to serve client session to peer={peer}: {}",
+            unpack(&*e)
+        ),
+        Err(e) => tracing::error!("client session task for peer={peer} failed: {e}"),
+    }
+}
+
+async fn accept_session<W, R, S, C>(
+    connection: &C,
     peer: PublicKey,
-    shell_cfg: &ShellCfg,
-) -> anyhow::Result<()> {
-    let mut client = connection
+    state: &Arc<HandlerState<S>>,
+) -> anyhow::Result<()>
+where
+    W: WriteStream,
+    R: ReadStream,
+    S: ServerShellProxy,
+    C: P2TermServerConnection<W, R>,
+{
+    let client = connection
         .accept(peer)
         .await
-        .context("failed to accept client")?;
+        .context("failed to accept client")?
+        .context("connection closed before a session was opened")?;
+    serve_session::<W, R, S, C>(
+        client,
+        peer,
+        connection.clone(),
+        FlowRouter::default(),
+        state.clone(),
+    )
+    .await
+}
+
+async fn serve_session<W, R, S, C>(
+    mut client: P2TermClientHandle<W, R>,
+    peer: PublicKey,
+    connection: C,
+    flows: FlowRouter,
+    state: Arc<HandlerState<S>>,
+) -> anyhow::Result<()>
+where
+    W: WriteStream,
+    R: ReadStream,
+    S: ServerShellProxy,
+    C: P2TermServerConnection<W, R>,
+{
     let client_opt = client.recv_hello().await?;
-    shell_cfg.validate_opt(&client_opt)?;
+    let admitted = state.admit(&peer, &client_opt).and_then(|permit| {
+        state
+            .shell
+            .admit(&peer, &state.shell_cfg, &client_opt)
+            .map(|()| permit)
+    });
+    let flow = match &client_opt.session {
+        SessionKind::ForwardUdp { flow, .. } => flows
+            .register(*flow)
+            .map(Some)
+            .map_err(|e| Rejection::new(RejectReason::InvalidRequest, e.to_string())),
+        _ => Ok(None),
+    };
+    let (permit, flow) = match admitted.and_then(|permit| flow.map(|flow| (permit, flow))) {
+        Ok(admitted) => admitted,
+        Err(rejection) => {
+            client.reject(&rejection).await?;
+            bail!("rejected peer={peer}: {rejection}");
+        }
+    };
+    let capabilities = client
+        .accept_hello(
+            &client_opt,
+            state.shell_cfg.capabilities(&client_opt.session),
+            state.shell_cfg.timeouts.keepalive,
+        )
+        .await?;
     let (write, read) = client.decompose();
-    S::run::<W, R>(write, read, shell_cfg, client_opt).await
+    if let Some(flow) = flow {
+        return state
+            .shell
+            .forward_udp(
+                FrameWriter::new(write),
+                FrameReader::new(read),
+                peer,
+                connection,
+                flow,
+                client_opt,
+            )
+            .await;
+    }
+    if let SessionKind::ListenTcp { .. } | SessionKind::ListenUnix { .. } = client_opt.session {
+        return state
+            .shell
+            .listen(
+                FrameWriter::new(write),
+                FrameReader::new(read),
+                peer,
+                connection,
+                client_opt,
+            )
+            .await;
+    }
+    let keepalive = state
+        .shell_cfg
+        .timeouts
+        .keepalive
+        .filter(|_| capabilities.contains(Capabilities::KEEPALIVE));
+    state
+        .shell
+        .run::<W, R>(
+            FrameWriter::new(write).with_keepalive(keepalive),
+            FrameReader::new(read).with_keepalive(keepalive),
+            peer,
+            &state.shell_cfg,
+            client_opt,
+            capabilities,
+            permit,
+        )
+        .await
 }
 
 impl<S> ProtocolHandler for P2TermConnectionHandler<S>
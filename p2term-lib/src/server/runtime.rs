@@ -5,6 +5,7 @@ use crate::server::shell_proxy::ServerShellProxy;
 
 pub async fn run<Router, S>(
     config: P2TermdCfg,
+    shell: S,
     mut router: Router,
     mut stop_receiver: tokio::sync::mpsc::Receiver<()>,
 ) -> anyhow::Result<()>
@@ -12,7 +13,8 @@ where
     Router: P2TermRouter,
     S: ServerShellProxy,
 {
-    let handler = P2TermConnectionHandler::new(config.access, config.shell_cfg);
+    let handler =
+        P2TermConnectionHandler::new(config.access, config.shell_cfg, config.max_sessions, shell);
     router.start::<S>(config.secret_key, handler).await?;
     if stop_receiver.recv().await.is_none() {
         tracing::warn!("recieved ungraceful stop (sender dropped), exiting immediately");
@@ -0,0 +1,191 @@
+//! Bookkeeping for sessions that are kept around for clients to attach to, see [`PersistenceCfg`]
+use crate::proto::{AttachMode, RejectReason, Rejection};
+use crate::server::config::{PersistenceCfg, SessionAccess};
+use iroh_base::PublicKey;
+use std::collections::{BTreeMap, VecDeque};
+
+/// A bounded byte buffer of the latest output, addressed by absolute offsets into
+/// everything the session has output
+#[derive(Debug)]
+pub struct Scrollback {
+    buf: VecDeque<u8>,
+    capacity: usize,
+    /// Offset of the first byte in `buf`
+    start: u64,
+}
+
+impl Scrollback {
+    #[must_use]
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            buf: VecDeque::new(),
+            capacity,
+            start: 0,
+        }
+    }
+
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.buf.extend(bytes);
+        let excess = self.buf.len().saturating_sub(self.capacity);
+        self.buf.drain(..excess);
+        self.start += excess as u64;
+    }
+
+    /// Offset of the oldest byte kept
+    #[must_use]
+    pub fn start(&self) -> u64 {
+        self.start
+    }
+
+    /// Offset of the byte after the latest one
+    #[must_use]
+    pub fn end(&self) -> u64 {
+        self.start + self.buf.len() as u64
+    }
+
+    /// Whether `len` more bytes fit without dropping output from `keep` onwards,
+    /// always if nothing from there is left unread
+    #[must_use]
+    pub fn has_room(&self, len: usize, keep: Option<u64>) -> bool {
+        let Some(keep) = keep else {
+            return true;
+        };
+        let unread = self.end() - keep.clamp(self.start, self.end());
+        unread == 0 || unread + len as u64 <= self.capacity as u64
+    }
+
+    /// Up to `limit` bytes from `cursor` onwards, or from wherever the kept output starts
+    /// or ends if `cursor` is outside of it, and the offset they start at
+    pub fn read_from(&self, cursor: &mut u64, limit: usize) -> (u64, Vec<u8>) {
+        let offset = (*cursor).clamp(self.start, self.end());
+        let skip = usize::try_from(offset - self.start).unwrap_or(usize::MAX);
+        let bytes: Vec<u8> = self.buf.iter().skip(skip).take(limit).copied().collect();
+        *cursor = offset + bytes.len() as u64;
+        (offset, bytes)
+    }
+}
+
+/// What `peer` gets to do with the `found` session, owned by whoever `owner` says, in `mode`.
+/// Sessions that don't exist aren't told apart from ones the peer may not see.
+/// # Errors
+/// The session isn't there for `peer`, or it may only watch it and asked for more
+pub fn check_access<S>(
+    found: Option<S>,
+    owner: impl FnOnce(&S) -> &PublicKey,
+    target: &str,
+    peer: &PublicKey,
+    cfg: &PersistenceCfg,
+    mode: AttachMode,
+) -> Result<(S, SessionAccess), Rejection> {
+    let (session, access) = found
+        .and_then(|session| {
+            let access = cfg.access(peer, owner(&session))?;
+            Some((session, access))
+        })
+        .ok_or_else(|| {
+            Rejection::new(
+                RejectReason::SessionNotFound,
+                format!("no session {target} to attach to"),
+            )
+        })?;
+    match mode {
+        AttachMode::Watch => Ok((session, SessionAccess::Watch)),
+        AttachMode::Takeover | AttachMode::Join if access == SessionAccess::Write => {
+            Ok((session, access))
+        }
+        AttachMode::Takeover | AttachMode::Join => Err(Rejection::new(
+            RejectReason::ReadOnly,
+            format!("session {target} may only be watched by this peer"),
+        )),
+    }
+}
+
+/// The clients attached to a session, output is fanned out to all of them and input is
+/// merged from those that may write
+#[derive(Debug, Default)]
+pub struct Attachments {
+    clients: BTreeMap<u64, ClientState>,
+    /// How far into the output each client has read
+    cursors: BTreeMap<u64, u64>,
+    last: u64,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ClientState {
+    Attached(SessionAccess),
+    /// Detached by another client taking over the session, but not yet gone
+    Replaced,
+}
+
+impl Attachments {
+    /// Attaches a client with `access`, replacing the writing clients if taking over,
+    /// returns the id of the attachment
+    pub fn attach(&mut self, access: SessionAccess, mode: AttachMode) -> u64 {
+        if mode == AttachMode::Takeover {
+            for state in self.clients.values_mut() {
+                if *state == ClientState::Attached(SessionAccess::Write) {
+                    *state = ClientState::Replaced;
+                }
+            }
+        }
+        self.last += 1;
+        self.clients
+            .insert(self.last, ClientState::Attached(access));
+        self.last
+    }
+
+    /// Detaches a client, returns the id of the latest attachment if no clients are left
+    /// attached after it, `None` if some are or it wasn't attached
+    pub fn detach(&mut self, attachment: u64) -> Option<u64> {
+        self.cursors.remove(&attachment);
+        if self.clients.remove(&attachment).is_none() || self.attached() > 0 {
+            return None;
+        }
+        Some(self.last)
+    }
+
+    #[must_use]
+    pub fn state(&self, attachment: u64) -> Option<ClientState> {
+        self.clients.get(&attachment).copied()
+    }
+
+    /// Whether the input, resizes and signals from `attachment` should reach the pty,
+    /// watchers and replaced clients only get the output
+    #[must_use]
+    pub fn may_write(&self, attachment: u64) -> bool {
+        self.state(attachment) == Some(ClientState::Attached(SessionAccess::Write))
+    }
+
+    /// Records that `attachment` has read the output up to `cursor`
+    pub fn progress(&mut self, attachment: u64, cursor: u64) {
+        if self.clients.contains_key(&attachment) {
+            self.cursors.insert(attachment, cursor);
+        }
+    }
+
+    /// How far the slowest client that may write has read, the output from there on is
+    /// kept for it. Watchers aren't waited for, they skip what they fall behind on
+    #[must_use]
+    pub fn slowest_writer(&self) -> Option<u64> {
+        self.cursors
+            .iter()
+            .filter(|&(&attachment, _)| self.may_write(attachment))
+            .map(|(_, &cursor)| cursor)
+            .min()
+    }
+
+    /// How many clients are attached, not counting the replaced ones
+    #[must_use]
+    pub fn attached(&self) -> usize {
+        self.clients
+            .values()
+            .filter(|state| matches!(state, ClientState::Attached(_)))
+            .count()
+    }
+
+    /// Whether nobody has attached since `last` was returned from [`Self::detach`]
+    #[must_use]
+    pub fn detached_since(&self, last: u64) -> bool {
+        self.attached() == 0 && self.last == last
+    }
+}
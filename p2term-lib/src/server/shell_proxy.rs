@@ -1,16 +1,80 @@
-use crate::proto::ClientOpt;
+use crate::datagram::FlowReceiver;
+use crate::proto::codec::{FrameReader, FrameWriter};
+use crate::proto::{Capabilities, ClientOpt, Rejection};
 use crate::server::config::ShellCfg;
+use crate::server::connection::P2TermServerConnection;
 use crate::streams::{ReadStream, WriteStream};
+use iroh_base::PublicKey;
 use std::fmt::Debug;
+use tokio::sync::OwnedSemaphorePermit;
 
+/// Serves the sessions the connection handler has accepted, kept by the handler for as long
+/// as it runs and shared by all of its connections
 pub trait ServerShellProxy: Debug + Send + Sync + 'static {
+    /// Checks a session the server's generic checks have let through, before it's accepted,
+    /// for what only the implementation can know about, like its persistent sessions
+    fn admit(
+        &self,
+        _peer: &PublicKey,
+        _shell_cfg: &ShellCfg,
+        _client_opt: &ClientOpt,
+    ) -> Result<(), Rejection> {
+        Ok(())
+    }
+
+    /// Serves a shell, command or attached session, `slot` counts it toward the server's
+    /// `max_sessions` and is held by whatever keeps the session running, past the return
+    /// if it's kept for reattaching
+    #[allow(clippy::too_many_arguments)]
     fn run<W, R>(
-        write: W,
-        read: R,
+        &self,
+        write: FrameWriter<W>,
+        read: FrameReader<R>,
+        peer: PublicKey,
         shell_cfg: &ShellCfg,
         client_opt: ClientOpt,
+        capabilities: Capabilities,
+        slot: Option<OwnedSemaphorePermit>,
     ) -> impl Future<Output = anyhow::Result<()>> + Send
     where
         W: WriteStream,
         R: ReadStream;
+
+    /// Serves [`crate::proto::SessionKind::ListenTcp`] and
+    /// [`crate::proto::SessionKind::ListenUnix`], the accepted connections are carried
+    /// back to the peer on streams opened with `connection`
+    fn listen<W, R, C>(
+        &self,
+        _write: FrameWriter<W>,
+        _read: FrameReader<R>,
+        _peer: PublicKey,
+        _connection: C,
+        _client_opt: ClientOpt,
+    ) -> impl Future<Output = anyhow::Result<()>> + Send
+    where
+        W: WriteStream,
+        R: ReadStream,
+        C: P2TermServerConnection<W, R>,
+    {
+        async { anyhow::bail!("remote forwarding is not implemented by this server") }
+    }
+
+    /// Serves [`crate::proto::SessionKind::ForwardUdp`], `flow` receives the client's
+    /// datagrams and replies are sent with `connection`
+    fn forward_udp<W, R, C>(
+        &self,
+        _write: FrameWriter<W>,
+        _read: FrameReader<R>,
+        _peer: PublicKey,
+        _connection: C,
+        _flow: FlowReceiver,
+        _client_opt: ClientOpt,
+    ) -> impl Future<Output = anyhow::Result<()>> + Send
+    where
+        W: WriteStream,
+        R: ReadStream,
+        C: P2TermServerConnection<W, R>,
+    {
+        async { anyhow::bail!("udp forwarding is not implemented by this server") }
+    }
 }
@@ -0,0 +1,155 @@
+//! Ends shell sessions that have been idle or running for too long, see [`SessionLimits`]
+use crate::proto::codec::Frame;
+use crate::server::config::SessionLimits;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// How long before a session is ended its clients are warned, at most half of the limit
+const WARN_AHEAD: Duration = Duration::from_mins(1);
+
+/// Keeps track of a session against its limits
+#[derive(Debug)]
+pub struct SessionTimer {
+    limits: SessionLimits,
+    started: Instant,
+    last_active: Mutex<Instant>,
+}
+
+/// Why a session is ended
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Expiry {
+    /// There was neither input nor output for this long
+    Idle(Duration),
+    /// The session has run for this long
+    MaxDuration(Duration),
+}
+
+impl Expiry {
+    fn limit(self) -> Duration {
+        match self {
+            Expiry::Idle(limit) | Expiry::MaxDuration(limit) => limit,
+        }
+    }
+
+    fn warning(self, remaining: Duration) -> String {
+        let remaining = human_duration(remaining);
+        match self {
+            Expiry::Idle(_) => format!(
+                "session is idle, it will be closed in {remaining} unless there's input or output"
+            ),
+            Expiry::MaxDuration(limit) => format!(
+                "session will be closed in {remaining}, it may run for at most {}",
+                human_duration(limit)
+            ),
+        }
+    }
+}
+
+impl core::fmt::Display for Expiry {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Expiry::Idle(limit) => write!(f, "idle for {}", human_duration(*limit)),
+            Expiry::MaxDuration(limit) => {
+                write!(f, "ran for its maximum of {}", human_duration(*limit))
+            }
+        }
+    }
+}
+
+impl SessionTimer {
+    #[must_use]
+    pub fn new(limits: SessionLimits) -> Self {
+        let now = Instant::now();
+        Self {
+            limits,
+            started: now,
+            last_active: Mutex::new(now),
+        }
+    }
+
+    /// Whether there's anything to time, [`Self::expired`] never completes otherwise
+    #[must_use]
+    pub fn is_limited(&self) -> bool {
+        self.limits != SessionLimits::default()
+    }
+
+    /// Input from a client or output of the session, restarting the idle timeout
+    pub fn active(&self) {
+        if self.limits.idle_timeout.is_some() {
+            *self
+                .last_active
+                .lock()
+                .unwrap_or_else(std::sync::PoisonError::into_inner) = Instant::now();
+        }
+    }
+
+    /// Completes once the session is over a limit, having called `warn` with a warning for
+    /// its clients ahead of that
+    pub async fn expired(&self, mut warn: impl FnMut(String)) -> Expiry {
+        // The end that has been warned about, an idle session is warned again after
+        // activity has moved its end
+        let mut warned = None;
+        loop {
+            let Some((end, expiry)) = self.next_end() else {
+                return std::future::pending().await;
+            };
+            let now = Instant::now();
+            if end <= now {
+                return expiry;
+            }
+            let warn_at = end - WARN_AHEAD.min(expiry.limit() / 2);
+            if now >= warn_at && warned != Some(end) {
+                warn(expiry.warning(end - now));
+                warned = Some(end);
+            }
+            // Ends only move later, so waking up early just finds the end moved
+            let wake = if warned == Some(end) { end } else { warn_at };
+            tokio::time::sleep_until(wake).await;
+        }
+    }
+
+    fn next_end(&self) -> Option<(Instant, Expiry)> {
+        let last_active = *self
+            .last_active
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        let idle = self
+            .limits
+            .idle_timeout
+            .map(|limit| (last_active + limit, Expiry::Idle(limit)));
+        let max_duration = self
+            .limits
+            .max_duration
+            .map(|limit| (self.started + limit, Expiry::MaxDuration(limit)));
+        idle.into_iter()
+            .chain(max_duration)
+            .min_by_key(|(end, _)| *end)
+    }
+}
+
+/// A message from the server to the user, written to the terminal between the output
+#[must_use]
+pub fn notice(msg: &str) -> Frame {
+    Frame::Stderr(format!("\r\n[p2term] {msg}\r\n").into_bytes())
+}
+
+/// Like `1h 30m`, down to seconds, rounded up
+fn human_duration(duration: Duration) -> String {
+    let secs = duration.as_secs() + u64::from(duration.subsec_nanos() > 0);
+    let parts = [
+        (secs / 3600, "h"),
+        (secs % 3600 / 60, "m"),
+        (secs % 60, "s"),
+    ];
+    let human: Vec<String> = parts
+        .iter()
+        .filter(|(amount, _)| *amount > 0)
+        .map(|(amount, unit)| format!("{amount}{unit}"))
+        .collect();
+    if human.is_empty() {
+        "0s".to_string()
+    } else {
+        human.join(" ")
+    }
+}
@@ -0,0 +1,77 @@
+use p2term_lib::backpressure::{OUTPUT_WINDOW, acks, output_len, window};
+use p2term_lib::proto::Capabilities;
+use p2term_lib::proto::codec::Frame;
+use std::time::Duration;
+
+const CHUNK: usize = 16 * 1024;
+
+fn output() -> Frame {
+    Frame::CompressedStdout(vec![0; CHUNK])
+}
+
+#[tokio::test]
+async fn test_window_closes_until_acknowledged() {
+    let (acks, mut window) = window(Capabilities::OUTPUT_ACK);
+    let mut sent = 0;
+    while window.is_open() {
+        let frame = output();
+        sent += output_len(&frame);
+        window.sent(&frame);
+    }
+    assert_eq!(OUTPUT_WINDOW as u64, sent);
+    let opened = tokio::time::timeout(Duration::from_millis(20), window.open()).await;
+    assert!(opened.is_err());
+    acks.ack(CHUNK as u64);
+    tokio::time::timeout(Duration::from_secs(5), window.open())
+        .await
+        .unwrap();
+    assert_eq!(CHUNK, window.room());
+    // Acknowledgements only move forward
+    acks.ack(0);
+    assert_eq!(CHUNK, window.room());
+}
+
+#[tokio::test]
+async fn test_window_opens_once_input_is_gone() {
+    let (acks, mut window) = window(Capabilities::OUTPUT_ACK);
+    window.sent(&Frame::Stdout(vec![0; OUTPUT_WINDOW]));
+    assert!(!window.is_open());
+    drop(acks);
+    assert!(window.is_open());
+    tokio::time::timeout(Duration::from_secs(5), window.open())
+        .await
+        .unwrap();
+}
+
+#[test]
+fn test_window_always_open_without_acks() {
+    let (_acks, mut window) = window(Capabilities::SUPPORTED);
+    window.sent(&Frame::Stdout(vec![0; OUTPUT_WINDOW * 2]));
+    assert!(window.is_open());
+}
+
+#[tokio::test]
+async fn test_client_acknowledges_before_the_window_closes() {
+    let (mut handled, mut pending) = acks(Capabilities::OUTPUT_ACK);
+    assert!(pending.acknowledges());
+    let (server_acks, mut window) = window(Capabilities::OUTPUT_ACK);
+    let mut total = 0;
+    for _ in 0..(OUTPUT_WINDOW / CHUNK) * 4 {
+        assert!(window.is_open(), "stalled after {total} bytes");
+        let frame = output();
+        window.sent(&frame);
+        // Control frames aren't counted
+        handled.handled(&Frame::Keepalive);
+        handled.handled(&frame);
+        total += CHUNK;
+        if let Some(Frame::OutputAck(acked)) = pending.take() {
+            server_acks.ack(acked);
+        }
+    }
+    let (mut handled, mut pending) = acks(Capabilities::SUPPORTED);
+    assert!(!pending.acknowledges());
+    handled.handled(&Frame::Stdout(vec![0; OUTPUT_WINDOW]));
+    assert_eq!(None, pending.take());
+    let next = tokio::time::timeout(Duration::from_millis(20), pending.next()).await;
+    assert!(next.is_err());
+}
@@ -0,0 +1,211 @@
+use p2term_lib::crypto::generate_secret_key;
+use p2term_lib::proto::codec::{FRAME_MAX_LEN, Frame, FrameReader, FrameWriter, encode_frame};
+use p2term_lib::proto::files::{
+    DirEntry, FileAttrs, FileError, FileErrorKind, FileKind, FileMeta, FileRequest, FileResponse,
+    Timestamp,
+};
+use p2term_lib::proto::{
+    ExitStatus, ForwardedTcp, ForwardedUnix, Keepalive, ResumeToken, SessionInfo, Signal, TermSize,
+};
+use std::time::{Duration, Instant};
+
+fn all_frames() -> Vec<Frame> {
+    vec![
+        Frame::Stdin(b"ls -la\r".to_vec()),
+        Frame::Stdout(vec![0, 1, 2, 255, 0x1b, b'[']),
+        Frame::Stderr(Vec::new()),
+        Frame::CompressedStdout(vec![0x28, 0xb5, 0x2f, 0xfd]),
+        Frame::Resize(TermSize {
+            rows: 50,
+            cols: 200,
+            pixel_width: 1600,
+            pixel_height: 900,
+        }),
+        Frame::Signal(Signal::Int),
+        Frame::Signal(Signal::Other(31)),
+        Frame::ExitStatus(ExitStatus {
+            code: 1,
+            signal: Some(Signal::Term),
+        }),
+        Frame::Keepalive,
+        Frame::Close,
+        Frame::StdinEof,
+        Frame::Session(session_info()),
+        Frame::SessionList(vec![session_info(), session_info()]),
+        Frame::SessionList(Vec::new()),
+        Frame::ResumeToken(ResumeToken([7; 32])),
+        Frame::OutputOffset(u64::MAX - 1),
+        Frame::OutputAck(256 * 1024),
+        Frame::ForwardListening(8080),
+        Frame::ForwardConnected,
+        Frame::Forwarded(ForwardedTcp {
+            host: "127.0.0.1".to_string(),
+            port: 0,
+            origin: "[::1]:51234".to_string(),
+        }),
+        Frame::ForwardedUnix(ForwardedUnix {
+            path: "/run/user/1000/forward.sock".to_string(),
+        }),
+        Frame::FileRequest(FileRequest::Write {
+            path: "src/main.rs".to_string(),
+            create_mode: 0o644,
+        }),
+        Frame::FileRequest(FileRequest::Data(vec![0; 1024])),
+        Frame::FileRequest(FileRequest::WriteEnd { checksum: [3; 32] }),
+        Frame::FileRequest(FileRequest::SetAttrs {
+            path: "/tmp".to_string(),
+            attrs: FileAttrs {
+                mode: Some(0o1777),
+                mtime: Some(Timestamp {
+                    secs: -1,
+                    nanos: 999_999_999,
+                }),
+            },
+        }),
+        Frame::FileResponse(FileResponse::Entries(vec![DirEntry {
+            name: "target".to_string(),
+            meta: FileMeta {
+                kind: FileKind::Dir,
+                len: 4096,
+                mode: 0o755,
+                mtime: Timestamp {
+                    secs: 1_700_000_000,
+                    nanos: 5,
+                },
+            },
+        }])),
+        Frame::FileRequest(FileRequest::Rename {
+            from: "notes.txt".to_string(),
+            to: "archive/notes.txt".to_string(),
+        }),
+        Frame::FileResponse(FileResponse::ReadDone { checksum: [9; 32] }),
+        Frame::FileResponse(FileResponse::Path("/home/user".to_string())),
+        Frame::FileRequest(FileRequest::Patch {
+            path: "target/release/p2termd".to_string(),
+            len: 12_345_678,
+            block_len: 65536,
+            create_mode: 0o755,
+        }),
+        Frame::FileRequest(FileRequest::Block {
+            index: 42,
+            data: vec![1; 65536],
+        }),
+        Frame::FileResponse(FileResponse::Signature(vec![[1; 32], [2; 32]])),
+        Frame::FileResponse(FileResponse::SignatureEnd { resumed: true }),
+        Frame::FileResponse(FileResponse::Error(FileError::new(
+            FileErrorKind::NotPermitted,
+            "/etc/shadow: outside the directories this peer may access",
+        ))),
+    ]
+}
+
+fn session_info() -> SessionInfo {
+    SessionInfo {
+        id: 7,
+        name: Some("build".to_string()),
+        owner: generate_secret_key().public(),
+        command: vec!["/bin/bash".to_string(), "-l".to_string()],
+        created_at: 1_700_000_000,
+        attached: false,
+    }
+}
+
+#[tokio::test]
+async fn round_trips_all_frames() {
+    let frames = all_frames();
+    let mut writer = FrameWriter::new(Vec::new());
+    for frame in &frames {
+        writer.write_frame(frame).await.unwrap();
+    }
+    let bytes = writer.into_inner();
+    let mut reader = FrameReader::new(bytes.as_slice());
+    for expected in frames {
+        assert_eq!(Some(expected), reader.read_frame().await.unwrap());
+    }
+    assert_eq!(None, reader.read_frame().await.unwrap());
+}
+
+#[tokio::test]
+async fn skips_unknown_frame_kinds() {
+    let mut bytes = vec![200u8];
+    bytes.extend_from_slice(&3u32.to_le_bytes());
+    bytes.extend_from_slice(b"new");
+    encode_frame(&Frame::Stdout(b"known".to_vec()), &mut bytes).unwrap();
+    let mut reader = FrameReader::new(bytes.as_slice());
+    assert_eq!(
+        Some(Frame::Stdout(b"known".to_vec())),
+        reader.read_frame().await.unwrap()
+    );
+    assert_eq!(None, reader.read_frame().await.unwrap());
+}
+
+#[tokio::test]
+async fn rejects_oversized_frames() {
+    let mut bytes = Vec::new();
+    assert!(encode_frame(&Frame::Stdout(vec![0; FRAME_MAX_LEN + 1]), &mut bytes).is_err());
+    assert!(bytes.is_empty());
+    let mut bytes = vec![1u8];
+    bytes.extend_from_slice(&u32::try_from(FRAME_MAX_LEN + 1).unwrap().to_le_bytes());
+    let mut reader = FrameReader::new(bytes.as_slice());
+    assert!(reader.read_frame().await.is_err());
+}
+
+#[tokio::test]
+async fn errors_on_truncated_frame() {
+    let mut bytes = Vec::new();
+    encode_frame(&Frame::Stdin(b"truncated".to_vec()), &mut bytes).unwrap();
+    bytes.truncate(bytes.len() - 1);
+    let mut reader = FrameReader::new(bytes.as_slice());
+    assert!(reader.read_frame().await.is_err());
+}
+
+#[tokio::test]
+async fn keepalives_keep_a_quiet_stream_open() {
+    let keepalive = Some(Keepalive {
+        interval: Duration::from_millis(20),
+        max_missed: 3,
+    });
+    let (client, server) = tokio::io::duplex(1024);
+    let mut writer = FrameWriter::new(client).with_keepalive(keepalive);
+    let mut reader = FrameReader::new(server).with_keepalive(keepalive);
+    let sending = tokio::spawn(async move {
+        for _ in 0..5 {
+            writer.keepalive_due().await;
+            writer.write_frame(&Frame::Keepalive).await.unwrap();
+        }
+        // Still open, but gone quiet
+        writer
+    });
+    for _ in 0..5 {
+        assert_eq!(Some(Frame::Keepalive), reader.read_frame().await.unwrap());
+    }
+    let started = Instant::now();
+    let err = reader.read_frame().await.unwrap_err();
+    assert!(err.to_string().contains("missed 3 keepalives"), "{err}");
+    assert!(started.elapsed() >= Duration::from_millis(60));
+    drop(sending.await.unwrap());
+}
+
+#[tokio::test]
+async fn keepalives_only_due_when_quiet() {
+    let (client, _server) = tokio::io::duplex(1024);
+    let mut writer = FrameWriter::new(client).with_keepalive(Some(Keepalive {
+        interval: Duration::from_millis(50),
+        max_missed: 1,
+    }));
+    tokio::time::sleep(Duration::from_millis(30)).await;
+    writer
+        .write_frame(&Frame::Stdin(b"x".to_vec()))
+        .await
+        .unwrap();
+    // Writing pushed it back by a whole interval
+    let written = Instant::now();
+    writer.keepalive_due().await;
+    assert!(written.elapsed() >= Duration::from_millis(45));
+    let never = FrameWriter::new(tokio::io::sink()).keepalive_due();
+    assert!(
+        tokio::time::timeout(Duration::from_millis(20), never)
+            .await
+            .is_err()
+    );
+}
@@ -0,0 +1,60 @@
+use p2term_lib::compression::{OutputDecoder, OutputEncoder};
+use p2term_lib::proto::Capabilities;
+use p2term_lib::proto::codec::{FRAME_MAX_LEN, Frame};
+
+fn screen(frame: u8) -> Vec<u8> {
+    let mut screen = b"\x1b[H\x1b[2J".to_vec();
+    for row in 0..50 {
+        screen.extend(format!("{row:>4} | some log line with a counter at {frame}\r\n").bytes());
+    }
+    screen
+}
+
+#[test]
+fn test_uncompressed_unless_negotiated() {
+    let mut encoder = OutputEncoder::new(Capabilities::SUPPORTED).unwrap();
+    assert_eq!(
+        Frame::Stdout(b"plain".to_vec()),
+        encoder.frame(b"plain").unwrap()
+    );
+}
+
+#[test]
+fn test_every_burst_decodes_on_its_own() {
+    let mut encoder = OutputEncoder::new(Capabilities::COMPRESSION).unwrap();
+    let mut decoder = OutputDecoder::new().unwrap();
+    let mut sizes = Vec::new();
+    for frame in 0..10 {
+        let burst = screen(frame);
+        let Frame::CompressedStdout(compressed) = encoder.frame(&burst).unwrap() else {
+            panic!("expected a compressed frame");
+        };
+        sizes.push(compressed.len());
+        // Nothing is held back until the next burst
+        assert_eq!(burst, decoder.decode(&compressed).unwrap());
+    }
+    assert!(sizes[0] < screen(0).len());
+    // Redraws of almost the same screen compress against the ones before
+    assert!(sizes[9] * 3 < sizes[0], "{sizes:?}");
+    let Frame::CompressedStdout(keystroke) = encoder.frame(b"a").unwrap() else {
+        panic!("expected a compressed frame");
+    };
+    assert_eq!(b"a".as_slice(), decoder.decode(&keystroke).unwrap());
+}
+
+#[test]
+fn test_oversized_decoded_output_rejected() {
+    let mut encoder = OutputEncoder::new(Capabilities::COMPRESSION).unwrap();
+    let mut bomb = Vec::new();
+    for _ in 0..2 {
+        let Frame::CompressedStdout(compressed) = encoder.frame(&vec![0u8; FRAME_MAX_LEN]).unwrap()
+        else {
+            panic!("expected a compressed frame");
+        };
+        bomb.extend(compressed);
+    }
+    assert!(bomb.len() < FRAME_MAX_LEN);
+    let mut decoder = OutputDecoder::new().unwrap();
+    let err = decoder.decode(&bomb).unwrap_err();
+    assert!(err.to_string().contains("decodes to more than"), "{err}");
+}
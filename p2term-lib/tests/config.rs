@@ -0,0 +1,269 @@
+use p2term_lib::crypto::generate_secret_key;
+use p2term_lib::proto::{ClientOpt, Keepalive, RejectReason, SessionKind};
+use p2term_lib::server::config::{
+    DEFAULT_KEEPALIVE, FileCfg, ForwardCfg, P2TermdCfg, PermitOpen, PermitPath, PersistenceCfg,
+    SessionAccess,
+};
+use std::time::Duration;
+
+fn forward_to(host: &str, port: u16) -> SessionKind {
+    SessionKind::ForwardTcp {
+        host: host.to_string(),
+        port,
+    }
+}
+
+#[test]
+fn test_session_access_grants() {
+    let owner = generate_secret_key().public();
+    let writer = generate_secret_key().public();
+    let watcher = generate_secret_key().public();
+    let stranger = generate_secret_key().public();
+    let mut cfg = PersistenceCfg::default();
+    cfg.attach_grants
+        .insert(writer, std::iter::once(owner).collect());
+    cfg.watch_grants
+        .insert(watcher, std::iter::once(owner).collect());
+    assert_eq!(Some(SessionAccess::Write), cfg.access(&owner, &owner));
+    assert_eq!(Some(SessionAccess::Write), cfg.access(&writer, &owner));
+    assert_eq!(Some(SessionAccess::Watch), cfg.access(&watcher, &owner));
+    assert_eq!(None, cfg.access(&stranger, &owner));
+    // Grants only cover the listed owners
+    assert_eq!(None, cfg.access(&owner, &writer));
+}
+
+#[test]
+fn test_permit_open_targets() {
+    let exact = PermitOpen::parse("LocalHost:5432").unwrap();
+    assert!(exact.permits("localhost", 5432));
+    assert!(!exact.permits("localhost", 5433));
+    assert!(!exact.permits("127.0.0.1", 5432));
+    let any_port = PermitOpen::parse("db.internal:*").unwrap();
+    assert!(any_port.permits("db.internal", 1));
+    let any_host = PermitOpen::parse("*:443").unwrap();
+    assert!(any_host.permits("example.com", 443));
+    assert!(!any_host.permits("example.com", 80));
+    let v6 = PermitOpen::parse("[::1]:22").unwrap();
+    assert!(v6.permits("::1", 22));
+    assert!(PermitOpen::parse("localhost").is_err());
+    assert!(PermitOpen::parse(":22").is_err());
+    assert!(PermitOpen::parse("localhost:http").is_err());
+}
+
+#[test]
+fn test_permit_open_per_peer() {
+    let forwarder = generate_secret_key().public();
+    let stranger = generate_secret_key().public();
+    let mut cfg = ForwardCfg::default();
+    cfg.permit_open.insert(
+        forwarder,
+        vec![PermitOpen::parse("localhost:5432").unwrap()],
+    );
+    let opt = |host: &str, port| ClientOpt {
+        session: forward_to(host, port),
+        ..ClientOpt::default()
+    };
+    assert!(cfg.admit(&forwarder, &opt("localhost", 5432)).is_ok());
+    let denied = cfg.admit(&forwarder, &opt("localhost", 22)).unwrap_err();
+    assert_eq!(RejectReason::ForwardDenied, denied.reason);
+    let denied = cfg.admit(&stranger, &opt("localhost", 5432)).unwrap_err();
+    assert_eq!(RejectReason::ForwardDenied, denied.reason);
+    // Other sessions aren't the forwarding policy's business
+    assert!(cfg.admit(&stranger, &ClientOpt::default()).is_ok());
+}
+
+#[test]
+fn test_permit_files_per_peer() {
+    let permitted = generate_secret_key().public();
+    let stranger = generate_secret_key().public();
+    let root = std::env::temp_dir();
+    let mut cfg = FileCfg::default();
+    cfg.permit_files.insert(permitted, vec![root.clone()]);
+    let opt = |cwd: Option<&str>| ClientOpt {
+        session: SessionKind::Files,
+        cwd: cwd.map(std::path::PathBuf::from),
+        ..ClientOpt::default()
+    };
+    assert!(cfg.admit(&permitted, &opt(None)).is_ok());
+    assert!(cfg.admit(&permitted, &opt(root.to_str())).is_ok());
+    let denied = cfg.admit(&permitted, &opt(Some("/"))).unwrap_err();
+    assert_eq!(RejectReason::FilesDenied, denied.reason);
+    // Resolved before it's checked
+    let escaping = root.join("..");
+    let denied = cfg.admit(&permitted, &opt(escaping.to_str())).unwrap_err();
+    assert_eq!(RejectReason::FilesDenied, denied.reason);
+    let denied = cfg.admit(&stranger, &opt(None)).unwrap_err();
+    assert_eq!(RejectReason::FilesDenied, denied.reason);
+    assert!(cfg.admit(&stranger, &ClientOpt::default()).is_ok());
+}
+
+#[test]
+fn test_timeouts_from_toml() {
+    let exempt = generate_secret_key();
+    let stricter = generate_secret_key();
+    let toml = format!(
+        r#"
+secret_key_hex = "{}"
+allowed_peers = []
+keepalive_interval_secs = 10
+idle_timeout_secs = 600
+max_session_duration_secs = 3600
+
+[peer_timeouts."{}"]
+idle_timeout_secs = 0
+
+[peer_timeouts."{}"]
+max_session_duration_secs = 60
+"#,
+        hex::encode(generate_secret_key().to_bytes()),
+        exempt.public(),
+        stricter.public(),
+    );
+    let cfg = P2TermdCfg::config_from_toml(toml.as_bytes()).unwrap();
+    let timeouts = &cfg.shell_cfg.timeouts;
+    assert_eq!(
+        Some(Keepalive {
+            interval: Duration::from_secs(10),
+            max_missed: DEFAULT_KEEPALIVE.max_missed,
+        }),
+        timeouts.keepalive
+    );
+    let everyone = timeouts.limits(&generate_secret_key().public());
+    assert_eq!(Some(Duration::from_mins(10)), everyone.idle_timeout);
+    assert_eq!(Some(Duration::from_hours(1)), everyone.max_duration);
+    let exempt = timeouts.limits(&exempt.public());
+    assert_eq!(None, exempt.idle_timeout);
+    assert_eq!(Some(Duration::from_hours(1)), exempt.max_duration);
+    let stricter = timeouts.limits(&stricter.public());
+    assert_eq!(Some(Duration::from_mins(10)), stricter.idle_timeout);
+    assert_eq!(Some(Duration::from_mins(1)), stricter.max_duration);
+
+    let off = P2TermdCfg::config_from_toml(
+        format!(
+            "secret_key_hex = \"{}\"\nallowed_peers = []\nkeepalive_interval_secs = 0",
+            hex::encode(generate_secret_key().to_bytes())
+        )
+        .as_bytes(),
+    )
+    .unwrap();
+    assert_eq!(None, off.shell_cfg.timeouts.keepalive);
+}
+
+#[test]
+fn test_permit_listen_loopback_only() {
+    let forwarder = generate_secret_key().public();
+    let mut cfg = ForwardCfg::default();
+    cfg.permit_listen.insert(
+        forwarder,
+        vec![
+            PermitOpen::parse("*:8080").unwrap(),
+            PermitOpen::parse("127.0.0.1:*").unwrap(),
+        ],
+    );
+    let opt = |host: &str, port| ClientOpt {
+        session: SessionKind::ListenTcp {
+            host: host.to_string(),
+            port,
+        },
+        ..ClientOpt::default()
+    };
+    assert!(cfg.admit(&forwarder, &opt("127.0.0.1", 0)).is_ok());
+    assert!(cfg.admit(&forwarder, &opt("localhost", 8080)).is_ok());
+    assert!(cfg.admit(&forwarder, &opt("::1", 8080)).is_ok());
+    let denied = cfg.admit(&forwarder, &opt("localhost", 8081)).unwrap_err();
+    assert_eq!(RejectReason::ForwardDenied, denied.reason);
+    // Permitted by `permit_listen`, but exposed beyond the server
+    let denied = cfg.admit(&forwarder, &opt("0.0.0.0", 8080)).unwrap_err();
+    assert_eq!(RejectReason::ForwardDenied, denied.reason);
+    assert!(denied.message.contains("loopback"), "{denied}");
+    cfg.allow_non_loopback_listen = true;
+    assert!(cfg.admit(&forwarder, &opt("0.0.0.0", 8080)).is_ok());
+}
+
+#[test]
+fn test_permit_unix_paths() {
+    let exact = PermitPath::parse("/var/run/docker.sock").unwrap();
+    assert!(exact.permits("/var/run/docker.sock"));
+    assert!(!exact.permits("/var/run/docker.sock2"));
+    assert!(!exact.permits("/var/run/../run/docker.sock"));
+    let dir = PermitPath::parse("/run/user/1000/*").unwrap();
+    assert!(dir.permits("/run/user/1000/ssh-agent.sock"));
+    assert!(!dir.permits("/run/user/1000"));
+    assert!(!dir.permits("/run/user/1000/"));
+    assert!(!dir.permits("/run/user/1000/nested/agent.sock"));
+    assert!(!dir.permits("/run/user/1000/.."));
+    assert!(!dir.permits("/run/user/10000/agent.sock"));
+    assert!(PermitPath::parse("docker.sock").is_err());
+    assert!(PermitPath::parse("/run/../etc/*").is_err());
+    assert!(PermitPath::parse("/*").is_err());
+}
+
+#[test]
+fn test_permit_unix_per_peer() {
+    let forwarder = generate_secret_key().public();
+    let stranger = generate_secret_key().public();
+    let mut cfg = ForwardCfg::default();
+    cfg.permit_open_unix.insert(
+        forwarder,
+        vec![PermitPath::parse("/var/run/docker.sock").unwrap()],
+    );
+    cfg.permit_listen_unix.insert(
+        forwarder,
+        vec![PermitPath::parse("/tmp/forwards/*").unwrap()],
+    );
+    let forward = |path: &str| ClientOpt {
+        session: SessionKind::ForwardUnix {
+            path: path.to_string(),
+        },
+        ..ClientOpt::default()
+    };
+    let listen = |path: &str| ClientOpt {
+        session: SessionKind::ListenUnix {
+            path: path.to_string(),
+        },
+        ..ClientOpt::default()
+    };
+    assert!(
+        cfg.admit(&forwarder, &forward("/var/run/docker.sock"))
+            .is_ok()
+    );
+    assert!(
+        cfg.admit(&forwarder, &listen("/tmp/forwards/agent.sock"))
+            .is_ok()
+    );
+    // Open and listen permits are kept apart
+    let denied = cfg
+        .admit(&forwarder, &forward("/tmp/forwards/agent.sock"))
+        .unwrap_err();
+    assert_eq!(RejectReason::ForwardDenied, denied.reason);
+    let denied = cfg
+        .admit(&forwarder, &listen("/var/run/docker.sock"))
+        .unwrap_err();
+    assert_eq!(RejectReason::ForwardDenied, denied.reason);
+    let denied = cfg
+        .admit(&stranger, &forward("/var/run/docker.sock"))
+        .unwrap_err();
+    assert_eq!(RejectReason::ForwardDenied, denied.reason);
+}
+
+#[test]
+fn test_permit_open_udp_apart_from_tcp() {
+    let forwarder = generate_secret_key().public();
+    let mut cfg = ForwardCfg::default();
+    cfg.permit_open
+        .insert(forwarder, vec![PermitOpen::parse("localhost:53").unwrap()]);
+    let udp = |port| ClientOpt {
+        session: SessionKind::ForwardUdp {
+            host: "localhost".to_string(),
+            port,
+            flow: 7,
+        },
+        ..ClientOpt::default()
+    };
+    let denied = cfg.admit(&forwarder, &udp(53)).unwrap_err();
+    assert_eq!(RejectReason::ForwardDenied, denied.reason);
+    cfg.permit_open_udp
+        .insert(forwarder, vec![PermitOpen::parse("localhost:53").unwrap()]);
+    assert!(cfg.admit(&forwarder, &udp(53)).is_ok());
+    assert!(cfg.admit(&forwarder, &udp(54)).is_err());
+}
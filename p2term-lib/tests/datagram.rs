@@ -0,0 +1,47 @@
+use p2term_lib::datagram::{FLOW_ID_LEN, FlowRouter, decode, encode};
+
+#[test]
+fn test_datagram_roundtrip() {
+    let datagram = encode(0xdead_beef, b"payload");
+    assert_eq!(FLOW_ID_LEN + b"payload".len(), datagram.len());
+    assert_eq!(Some((0xdead_beef, &b"payload"[..])), decode(&datagram));
+    assert_eq!(Some((3, &b""[..])), decode(&encode(3, b"")));
+    assert_eq!(None, decode(&[1, 2, 3]));
+}
+
+#[tokio::test]
+async fn test_datagrams_routed_to_their_flow() {
+    let router = FlowRouter::default();
+    let mut first = router.register(1).unwrap();
+    let mut second = router.register(2).unwrap();
+    assert!(router.register(1).is_err());
+    router.route(&encode(2, b"to second"));
+    router.route(&encode(1, b"to first"));
+    // Unknown flows and datagrams too short for a flow id are dropped
+    router.route(&encode(3, b"to nobody"));
+    router.route(&[1]);
+    assert_eq!(Some(b"to first".to_vec()), first.recv().await);
+    assert_eq!(Some(b"to second".to_vec()), second.recv().await);
+    assert_eq!(1, first.flow());
+}
+
+#[tokio::test]
+async fn test_flow_taken_down_with_receiver() {
+    let router = FlowRouter::default();
+    let flow = router.register(5).unwrap();
+    drop(flow);
+    let mut flow = router.register(5).unwrap();
+    router.route(&encode(5, b"again"));
+    assert_eq!(Some(b"again".to_vec()), flow.recv().await);
+}
+
+#[test]
+fn test_limited_router_refuses_flows_beyond_its_limit() {
+    let router = FlowRouter::limited(2);
+    let first = router.register(1).unwrap();
+    let _second = router.register(2).unwrap();
+    let err = router.register(3).unwrap_err();
+    assert!(err.to_string().contains("maximum of 2"), "{err}");
+    drop(first);
+    router.register(3).unwrap();
+}
@@ -0,0 +1,317 @@
+use p2term_lib::client::files::{RemoteFiles, Signature};
+use p2term_lib::files::PartialFile;
+use p2term_lib::proto::codec::{Frame, FrameReader, FrameWriter};
+use p2term_lib::proto::files::{FileError, FileErrorKind, FileRequest, FileResponse, Timestamp};
+use p2term_lib::streams::{ReadStream, WriteStream};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, DuplexStream, ReadBuf};
+
+#[derive(Debug)]
+struct Pipe(DuplexStream);
+
+impl AsyncRead for Pipe {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Pipe {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
+
+impl ReadStream for Pipe {}
+impl WriteStream for Pipe {}
+
+/// A client on one end, and the server's end of its session
+fn session() -> (
+    RemoteFiles<Pipe, Pipe>,
+    FrameWriter<Pipe>,
+    FrameReader<Pipe>,
+) {
+    let (to_server, from_client) = tokio::io::duplex(256 * 1024);
+    let (to_client, from_server) = tokio::io::duplex(256 * 1024);
+    let client = RemoteFiles::new(
+        FrameWriter::new(Pipe(to_server)),
+        FrameReader::new(Pipe(from_server)),
+    );
+    (
+        client,
+        FrameWriter::new(Pipe(to_client)),
+        FrameReader::new(Pipe(from_client)),
+    )
+}
+
+fn scratch_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("p2term-files-{}-{name}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[test]
+fn test_timestamp_round_trip() {
+    for time in [
+        SystemTime::UNIX_EPOCH + Duration::new(1_700_000_000, 123_456_789),
+        SystemTime::UNIX_EPOCH,
+        SystemTime::UNIX_EPOCH - Duration::new(1, 250_000_000),
+        SystemTime::UNIX_EPOCH - Duration::from_hours(24),
+    ] {
+        assert_eq!(time, Timestamp::from_system_time(time).to_system_time());
+    }
+    let before_epoch =
+        Timestamp::from_system_time(SystemTime::UNIX_EPOCH - Duration::from_millis(1500));
+    assert_eq!(-2, before_epoch.secs);
+    assert_eq!(500_000_000, before_epoch.nanos);
+}
+
+#[tokio::test]
+async fn test_partial_file_replaces_target_when_finished() {
+    let dir = scratch_dir("finished");
+    let target = dir.join("file");
+    std::fs::write(&target, b"old").unwrap();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&target, std::fs::Permissions::from_mode(0o600)).unwrap();
+    }
+    let mut partial = PartialFile::create(&target, 0o644).await.unwrap();
+    partial.write_all(b"new contents").await.unwrap();
+    // Nothing is replaced until finished
+    assert_eq!(b"old".as_slice(), std::fs::read(&target).unwrap());
+    partial.finish().await.unwrap();
+    assert_eq!(b"new contents".as_slice(), std::fs::read(&target).unwrap());
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = std::fs::metadata(&target).unwrap().permissions().mode();
+        assert_eq!(0o600, mode & 0o777, "an existing file keeps its mode");
+    }
+    assert_eq!(1, std::fs::read_dir(&dir).unwrap().count());
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_partial_file_dropped_leaves_target() {
+    let dir = scratch_dir("dropped");
+    let target = dir.join("file");
+    std::fs::write(&target, b"old").unwrap();
+    let mut partial = PartialFile::create(&target, 0o644).await.unwrap();
+    partial.write_all(b"interrupted").await.unwrap();
+    drop(partial);
+    assert_eq!(b"old".as_slice(), std::fs::read(&target).unwrap());
+    assert_eq!(1, std::fs::read_dir(&dir).unwrap().count());
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_set_attrs_leaves_symlinks_and_what_they_lead_to() {
+    use p2term_lib::proto::files::FileAttrs;
+    use std::os::unix::fs::PermissionsExt;
+    let dir = scratch_dir("attrs");
+    let file = dir.join("file");
+    std::fs::write(&file, b"contents").unwrap();
+    std::fs::set_permissions(&file, std::fs::Permissions::from_mode(0o600)).unwrap();
+    let link = dir.join("link");
+    std::os::unix::fs::symlink(&file, &link).unwrap();
+    let attrs = FileAttrs {
+        mode: Some(0o644),
+        mtime: None,
+    };
+    p2term_lib::files::set_attrs(&link, attrs).await.unwrap();
+    let mode = std::fs::metadata(&file).unwrap().permissions().mode();
+    assert_eq!(0o600, mode & 0o777);
+    // A file that can't be read is still changed
+    std::fs::set_permissions(&file, std::fs::Permissions::from_mode(0o200)).unwrap();
+    p2term_lib::files::set_attrs(&file, attrs).await.unwrap();
+    let mode = std::fs::metadata(&file).unwrap().permissions().mode();
+    assert_eq!(0o644, mode & 0o777);
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_read_checks_contents() {
+    let (mut client, mut server_write, mut server_read) = session();
+    let server = tokio::spawn(async move {
+        for contents in [b"intact".as_slice(), b"corrupted".as_slice()] {
+            let Some(Frame::FileRequest(FileRequest::Read { .. })) =
+                server_read.read_frame().await.unwrap()
+            else {
+                panic!("expected a read request");
+            };
+            server_write
+                .write_frame(&Frame::FileResponse(FileResponse::Data(contents.to_vec())))
+                .await
+                .unwrap();
+            server_write
+                .write_frame(&Frame::FileResponse(FileResponse::ReadDone {
+                    checksum: *blake3::hash(b"intact").as_bytes(),
+                }))
+                .await
+                .unwrap();
+        }
+    });
+    let mut received = Vec::new();
+    let len = client
+        .read_to("intact", &mut received, |_| {})
+        .await
+        .unwrap();
+    assert_eq!(6, len);
+    assert_eq!(b"intact".as_slice(), received);
+    let err = client
+        .read_to("corrupted", &mut Vec::new(), |_| {})
+        .await
+        .unwrap_err();
+    let err = err.downcast_ref::<FileError>().unwrap();
+    assert_eq!(FileErrorKind::Corrupted, err.kind);
+    server.await.unwrap();
+}
+
+#[tokio::test]
+async fn test_write_sends_contents_with_checksum() {
+    let (mut client, mut server_write, mut server_read) = session();
+    let contents = vec![9u8; 200_000];
+    let expected = contents.clone();
+    let server = tokio::spawn(async move {
+        let Some(Frame::FileRequest(FileRequest::Write { path, create_mode })) =
+            server_read.read_frame().await.unwrap()
+        else {
+            panic!("expected a write request");
+        };
+        assert_eq!("dir/file", path);
+        assert_eq!(0o640, create_mode);
+        let mut received = Vec::new();
+        let checksum = loop {
+            match server_read.read_frame().await.unwrap() {
+                Some(Frame::FileRequest(FileRequest::Data(chunk))) => received.extend(chunk),
+                Some(Frame::FileRequest(FileRequest::WriteEnd { checksum })) => break checksum,
+                other => panic!("unexpected frame during write: {other:?}"),
+            }
+        };
+        assert_eq!(expected, received);
+        assert_eq!(*blake3::hash(&received).as_bytes(), checksum);
+        server_write
+            .write_frame(&Frame::FileResponse(FileResponse::Error(FileError::new(
+                FileErrorKind::NotPermitted,
+                "dir/file: outside the directories this peer may access",
+            ))))
+            .await
+            .unwrap();
+    });
+    let mut progress = Vec::new();
+    let err = client
+        .write_from("dir/file", 0o640, &mut contents.as_slice(), |sent| {
+            progress.push(sent);
+        })
+        .await
+        .unwrap_err();
+    assert_eq!(
+        FileErrorKind::NotPermitted,
+        err.downcast_ref::<FileError>().unwrap().kind
+    );
+    assert_eq!(Some(&200_000), progress.last());
+    server.await.unwrap();
+}
+
+#[tokio::test]
+async fn test_signature_matches_only_the_same_contents() {
+    let signature = Signature {
+        block_len: 4,
+        blocks: [b"aaaa".as_slice(), b"bbbb", b"cc"]
+            .map(|block| *blake3::hash(block).as_bytes())
+            .to_vec(),
+        resumed: false,
+    };
+    assert!(
+        signature
+            .matches(&mut b"aaaabbbbcc".as_slice())
+            .await
+            .unwrap()
+    );
+    assert!(
+        !signature
+            .matches(&mut b"aaaabbbbc".as_slice())
+            .await
+            .unwrap()
+    );
+    assert!(
+        !signature
+            .matches(&mut b"aaaabbbbccd".as_slice())
+            .await
+            .unwrap()
+    );
+    assert!(
+        !signature
+            .matches(&mut b"aaaaBbbbcc".as_slice())
+            .await
+            .unwrap()
+    );
+}
+
+#[tokio::test]
+async fn test_patch_sends_only_changed_blocks() {
+    let (mut client, mut server_write, mut server_read) = session();
+    let contents = b"aaaaBBBBccd".to_vec();
+    let expected = contents.clone();
+    let server = tokio::spawn(async move {
+        let Some(Frame::FileRequest(FileRequest::Patch { len, block_len, .. })) =
+            server_read.read_frame().await.unwrap()
+        else {
+            panic!("expected a patch request");
+        };
+        assert_eq!(11, len);
+        assert_eq!(4, block_len);
+        let mut sent = Vec::new();
+        let checksum = loop {
+            match server_read.read_frame().await.unwrap() {
+                Some(Frame::FileRequest(FileRequest::Block { index, data })) => {
+                    sent.push((index, data));
+                }
+                Some(Frame::FileRequest(FileRequest::WriteEnd { checksum })) => break checksum,
+                other => panic!("unexpected frame during patch: {other:?}"),
+            }
+        };
+        assert_eq!(vec![(1, b"BBBB".to_vec()), (2, b"ccd".to_vec())], sent);
+        assert_eq!(*blake3::hash(&expected).as_bytes(), checksum);
+        server_write
+            .write_frame(&Frame::FileResponse(FileResponse::Done))
+            .await
+            .unwrap();
+    });
+    let signature = Signature {
+        block_len: 4,
+        blocks: [b"aaaa".as_slice(), b"bbbb", b"cc"]
+            .map(|block| *blake3::hash(block).as_bytes())
+            .to_vec(),
+        resumed: false,
+    };
+    // Only as much as the patch is long is read
+    let mut source = b"aaaaBBBBccdTRAILING".as_slice();
+    let sent = client
+        .patch_from("file", 0o644, 11, &signature, &mut source, |_| {})
+        .await
+        .unwrap();
+    assert_eq!(7, sent);
+    server.await.unwrap();
+}
@@ -0,0 +1,70 @@
+use p2term_lib::forward::{ForwardSide, splice};
+use p2term_lib::proto::codec::{Frame, FrameReader, FrameWriter};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+/// Splices both ends of a forwarding session, returning the client and server side sockets
+/// as seen by the local application and the forwarding target
+fn spliced() -> (tokio::io::DuplexStream, tokio::io::DuplexStream) {
+    let (to_server, from_client) = tokio::io::duplex(1024);
+    let (to_client, from_server) = tokio::io::duplex(1024);
+    let (application, client_socket) = tokio::io::duplex(1024);
+    let (target, server_socket) = tokio::io::duplex(1024);
+    tokio::spawn(splice(
+        client_socket,
+        FrameWriter::new(to_server),
+        FrameReader::new(from_server),
+        ForwardSide::Client,
+    ));
+    tokio::spawn(splice(
+        server_socket,
+        FrameWriter::new(to_client),
+        FrameReader::new(from_client),
+        ForwardSide::Server,
+    ));
+    (application, target)
+}
+
+#[tokio::test]
+async fn test_forward_carries_both_directions() {
+    let (mut application, mut target) = spliced();
+    let request = vec![7u8; 100_000];
+    let write_request = async {
+        application.write_all(&request).await.unwrap();
+        // Half-close, the response still has to come through
+        application.shutdown().await.unwrap();
+    };
+    let answer = async {
+        let mut received = Vec::new();
+        target.read_to_end(&mut received).await.unwrap();
+        target.write_all(b"answer").await.unwrap();
+        target.shutdown().await.unwrap();
+        received
+    };
+    let ((), received) = tokio::join!(write_request, answer);
+    assert_eq!(request, received);
+    let mut response = Vec::new();
+    application.read_to_end(&mut response).await.unwrap();
+    assert_eq!(b"answer".as_slice(), response);
+}
+
+#[tokio::test]
+async fn test_forward_reports_unreachable_target() {
+    let (to_server, _from_client) = tokio::io::duplex(1024);
+    let (mut to_client, from_server) = tokio::io::duplex(1024);
+    let (_application, client_socket) = tokio::io::duplex(1024);
+    let mut server = FrameWriter::new(&mut to_client);
+    server
+        .write_frame(&Frame::Stderr(b"connection refused".to_vec()))
+        .await
+        .unwrap();
+    server.write_frame(&Frame::Close).await.unwrap();
+    let err = splice(
+        client_socket,
+        FrameWriter::new(to_server),
+        FrameReader::new(from_server),
+        ForwardSide::Client,
+    )
+    .await
+    .unwrap_err();
+    assert!(err.to_string().contains("connection refused"), "{err}");
+}
@@ -0,0 +1,152 @@
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use p2term_lib::client::inband::{OutputEvent, OutputScanner, UPLOAD_LINE_LEN, upload_lines};
+
+/// Scans `output` in pieces of `piece_len`, with consecutive output and data merged so that
+/// the result doesn't depend on where the pieces were split
+fn scan(output: &[u8], piece_len: usize) -> Vec<OutputEvent> {
+    let mut scanner = OutputScanner::new();
+    let mut events: Vec<OutputEvent> = Vec::new();
+    for piece in output.chunks(piece_len) {
+        for event in scanner.scan(piece) {
+            match (events.last_mut(), event) {
+                (Some(OutputEvent::Output(merged)), OutputEvent::Output(bytes))
+                | (Some(OutputEvent::DownloadData(merged)), OutputEvent::DownloadData(bytes)) => {
+                    merged.extend(bytes);
+                }
+                (_, event) => events.push(event),
+            }
+        }
+    }
+    events
+}
+
+fn download(name: &str, contents: &[u8], terminator: &str) -> Vec<u8> {
+    format!(
+        "\x1b]1337;File=name={};size={}:{}{terminator}",
+        STANDARD.encode(name),
+        contents.len(),
+        STANDARD.encode(contents)
+    )
+    .into_bytes()
+}
+
+#[test]
+fn test_output_passes_through() {
+    let output = b"plain \x1b[1mbold\x1b[0m \x1b]0;title\x07 \x18 **done**".to_vec();
+    for piece_len in 1..output.len() {
+        assert_eq!(
+            vec![OutputEvent::Output(output.clone())],
+            scan(&output, piece_len)
+        );
+    }
+}
+
+#[test]
+fn test_download_split_anywhere() {
+    let contents: Vec<u8> = (0..=255).cycle().take(1000).collect();
+    let mut output = b"before ".to_vec();
+    output.extend(download("dir/file.bin", &contents, "\x1b\\"));
+    output.extend(b" after");
+    let expected = vec![
+        OutputEvent::Output(b"before ".to_vec()),
+        OutputEvent::Download {
+            name: Some("dir/file.bin".to_string()),
+            size: Some(1000),
+        },
+        OutputEvent::DownloadData(contents),
+        OutputEvent::DownloadEnd,
+        OutputEvent::Output(b" after".to_vec()),
+    ];
+    for piece_len in [1, 2, 3, 5, 7, 64, 4096] {
+        assert_eq!(expected, scan(&output, piece_len), "pieces of {piece_len}");
+    }
+}
+
+#[test]
+fn test_download_with_line_breaks() {
+    let contents = vec![7u8; 200];
+    let encoded = STANDARD.encode(&contents);
+    let (first, second) = encoded.split_at(76);
+    let output = format!("\x1b]1337;File=inline=0:{first}\r\n{second}\r\n\x07");
+    assert_eq!(
+        vec![
+            OutputEvent::Download {
+                name: None,
+                size: None
+            },
+            OutputEvent::DownloadData(contents),
+            OutputEvent::DownloadEnd,
+        ],
+        scan(output.as_bytes(), 16)
+    );
+}
+
+#[test]
+fn test_invalid_download_is_skipped() {
+    let output = b"\x1b]1337;File=size=3:QUJD!garbage\x07after";
+    assert_eq!(
+        vec![
+            OutputEvent::Download {
+                name: None,
+                size: Some(3)
+            },
+            OutputEvent::DownloadFailed("unexpected byte 0x21 in base64".to_string()),
+            OutputEvent::Output(b"after".to_vec()),
+        ],
+        scan(output, 4)
+    );
+}
+
+#[test]
+fn test_inline_file_left_to_the_terminal() {
+    let output = b"\x1b]1337;File=inline=1;size=3:QUJD\x07after".to_vec();
+    assert_eq!(vec![OutputEvent::Output(output.clone())], scan(&output, 3));
+}
+
+#[test]
+fn test_upload_request() {
+    let output = b"$ \x1b]1337;RequestUpload=format=raw\x1b\\";
+    for piece_len in [1, 5, 64] {
+        assert_eq!(
+            vec![
+                OutputEvent::Output(b"$ ".to_vec()),
+                OutputEvent::UploadRequest {
+                    format: Some("raw".to_string())
+                },
+            ],
+            scan(output, piece_len)
+        );
+    }
+}
+
+#[test]
+fn test_zmodem_header_swallowed() {
+    for header in [
+        b"**\x18B00000000000000\r\x8a\x11".as_slice(),
+        b"**\x18B0100000023be50\r\x8a\x11".as_slice(),
+    ] {
+        let mut output = b"rz\r".to_vec();
+        output.extend(header);
+        output.extend(b"prompt$ ");
+        assert_eq!(
+            vec![
+                OutputEvent::Output(b"rz\r".to_vec()),
+                OutputEvent::Zmodem,
+                OutputEvent::Output(b"prompt$ ".to_vec()),
+            ],
+            scan(&output, 64)
+        );
+    }
+}
+
+#[test]
+fn test_upload_lines() {
+    let contents: Vec<u8> = (0..=255).cycle().take(UPLOAD_LINE_LEN * 3 + 10).collect();
+    let lines = upload_lines(&contents);
+    let lines = std::str::from_utf8(&lines).unwrap();
+    assert_eq!(4, lines.lines().count());
+    assert!(lines.lines().take(3).all(|line| line.len() == 76));
+    let decoded = STANDARD.decode(lines.replace('\n', "")).unwrap();
+    assert_eq!(contents, decoded);
+}
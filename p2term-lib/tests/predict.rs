@@ -0,0 +1,250 @@
+use p2term_lib::client::predict::{PredictMode, Predictor};
+use p2term_lib::client::screen::Screen;
+use std::time::Duration;
+
+const COLS: u16 = 40;
+const ROWS: u16 = 5;
+
+/// A session through a predictor, with what the local terminal shows kept in a screen of
+/// its own
+struct Session {
+    predictor: Predictor,
+    terminal: Screen,
+}
+
+impl Session {
+    fn new(mode: PredictMode) -> Self {
+        Self {
+            predictor: Predictor::new(mode, Screen::blank(COLS, ROWS)),
+            terminal: Screen::blank(COLS, ROWS),
+        }
+    }
+
+    fn typed(&mut self, bytes: &[u8], at_ms: u64) {
+        let out = self.predictor.input(bytes, ms(at_ms));
+        self.terminal.write(&out);
+    }
+
+    fn output(&mut self, bytes: &[u8], at_ms: u64) {
+        let out = self.predictor.output(bytes, ms(at_ms));
+        self.terminal.write(&out);
+    }
+
+    fn tick(&mut self, at_ms: u64) {
+        let out = self.predictor.tick(ms(at_ms));
+        self.terminal.write(&out);
+    }
+
+    /// A row of the terminal, without trailing spaces
+    fn row(&self, row: usize) -> String {
+        let cols = usize::from(COLS);
+        let line: String = (0..cols)
+            .map(|col| self.terminal.cell(row, col).unwrap_or('?'))
+            .collect();
+        line.trim_end().to_string()
+    }
+
+    /// Types `typed` one key at a time, echoed `rtt_ms` later, starting at `at_ms`
+    fn type_echoed(&mut self, typed: &str, at_ms: u64, rtt_ms: u64) -> u64 {
+        let mut at = at_ms;
+        for ch in typed.chars() {
+            self.typed(ch.to_string().as_bytes(), at);
+            self.output(ch.to_string().as_bytes(), at + rtt_ms);
+            at += rtt_ms + 1;
+        }
+        at
+    }
+}
+
+fn ms(ms: u64) -> Duration {
+    Duration::from_millis(ms)
+}
+
+#[test]
+fn test_typing_shows_once_the_line_echoes() {
+    let mut session = Session::new(PredictMode::Always);
+    session.output(b"$ ", 0);
+    // Nothing is known about echo on the line yet
+    session.typed(b"l", 10);
+    assert_eq!("$", session.row(0));
+    session.output(b"l", 110);
+    session.typed(b"s", 120);
+    assert_eq!("$ ls", session.row(0));
+    assert_eq!((0, 4), session.terminal.cursor());
+    // The server's echo takes the place of the prediction
+    session.output(b"s", 220);
+    assert_eq!("$ ls", session.row(0));
+    assert_eq!((0, 4), session.terminal.cursor());
+    assert_eq!(Some(ms(100)), session.predictor.rtt());
+    // Backspace and moving over what was typed are predicted too
+    session.typed(b"\x7f", 230);
+    assert_eq!("$ l", session.row(0));
+    assert_eq!((0, 3), session.terminal.cursor());
+    session.typed(b"\x1b[D", 231);
+    assert_eq!((0, 2), session.terminal.cursor());
+    session.output(b"\x08 \x08", 330);
+    session.output(b"\x08", 331);
+    assert_eq!("$ l", session.row(0));
+    assert_eq!((0, 2), session.terminal.cursor());
+    // Running the command leaves the line alone until it's echoed again
+    session.output(b"\r\nfile\r\n$ ", 400);
+    session.typed(b"x", 410);
+    assert_eq!("$", session.row(2));
+    assert_eq!(None, session.predictor.tick(ms(5000)).first());
+}
+
+#[test]
+fn test_prediction_is_underlined_and_keeps_attributes() {
+    let mut predictor = Predictor::new(PredictMode::Always, Screen::blank(COLS, ROWS));
+    predictor.output(b"\x1b[1;32m$ ", ms(0));
+    predictor.input(b"a", ms(1));
+    predictor.output(b"a", ms(100));
+    let drawn = predictor.input(b"b", ms(101));
+    let drawn = String::from_utf8(drawn).unwrap();
+    assert!(drawn.starts_with("\x1b[0;4mb"), "{drawn:?}");
+    assert!(drawn.ends_with("\x1b[0m\x1b[1;32m"), "{drawn:?}");
+    let echoed = predictor.output(b"b", ms(200));
+    let echoed = String::from_utf8(echoed).unwrap();
+    // Taken back before the echo, which is then written as it came
+    assert!(echoed.ends_with("\x1b[1Db"), "{echoed:?}");
+}
+
+#[test]
+fn test_nothing_shows_without_echo() {
+    let mut session = Session::new(PredictMode::Always);
+    session.output(b"Password: ", 0);
+    session.typed(b"s", 10);
+    session.typed(b"ecret", 20);
+    assert_eq!("Password:", session.row(0));
+    session.tick(2000);
+    session.typed(b"!", 2010);
+    assert_eq!("Password:", session.row(0));
+    assert_eq!((0, 10), session.terminal.cursor());
+}
+
+#[test]
+fn test_unconfirmed_prediction_is_taken_back() {
+    let mut session = Session::new(PredictMode::Always);
+    session.output(b"> ", 0);
+    let at = session.type_echoed("ab", 10, 100);
+    session.typed(b"c", at);
+    assert_eq!("> abc", session.row(0));
+    // The echo never comes, something else is written instead
+    session.output(b"X", at + 100);
+    assert_eq!("> abc", session.row(0));
+    let expires_at = session.predictor.expires_at().unwrap();
+    session.tick(u64::try_from(expires_at.as_millis()).unwrap());
+    assert_eq!("> abX", session.row(0));
+    assert_eq!((0, 5), session.terminal.cursor());
+    assert_eq!(None, session.predictor.expires_at());
+    // The line has to echo again before anything shows
+    session.typed(b"d", at + 2000);
+    assert_eq!("> abX", session.row(0));
+}
+
+#[test]
+fn test_adaptive_predictions_follow_echo_latency() {
+    let mut session = Session::new(PredictMode::Adaptive);
+    session.output(b"$ ", 0);
+    let at = session.type_echoed("ab", 10, 5);
+    session.typed(b"c", at);
+    assert_eq!("$ ab", session.row(0));
+    session.output(b"c", at + 5);
+    let mut at = at + 10;
+    // Echo slows down
+    for _ in 0..20 {
+        at = session.type_echoed("d", at, 150);
+    }
+    session.typed(b"e", at);
+    assert!(session.row(0).ends_with('e'), "{}", session.row(0));
+    let rtt = session.predictor.rtt().unwrap();
+    assert!(rtt > ms(100), "{rtt:?}");
+}
+
+#[test]
+fn test_full_screen_applications_are_not_predicted() {
+    let mut session = Session::new(PredictMode::Always);
+    session.output(b"$ ", 0);
+    let at = session.type_echoed("vi", 10, 100);
+    session.output(b"\r\n\x1b[?1049h\x1b[H\x1b[2J~", at);
+    assert!(!session.predictor.is_active());
+    session.typed(b"i", at + 10);
+    session.typed(b"x", at + 20);
+    assert_eq!("~", session.row(0));
+    assert_eq!((0, 1), session.terminal.cursor());
+    session.output(b"\x1b[?1049l$ ", at + 100);
+    assert!(session.predictor.is_active());
+    assert_eq!("$ vi", session.row(0));
+    assert_eq!("$", session.row(1));
+}
+
+#[test]
+fn test_unknown_screen_is_predicted_on_once_written() {
+    let mut predictor = Predictor::new(PredictMode::Always, Screen::new(COLS, ROWS));
+    // What's right of the cursor isn't known to be empty
+    predictor.output(b"$ ", ms(0));
+    predictor.input(b"a", ms(1));
+    predictor.output(b"a", ms(100));
+    assert!(predictor.input(b"b", ms(101)).is_empty());
+    predictor.output(b"b\r\n$ ", ms(200));
+    predictor.input(b"c", ms(201));
+    predictor.output(b"c", ms(300));
+    let drawn = predictor.input(b"d", ms(301));
+    assert_eq!(b"\x1b[0;4md\x1b[0m".to_vec(), drawn);
+}
+
+#[test]
+fn test_never_passes_output_through() {
+    let mut predictor = Predictor::new(PredictMode::Never, Screen::blank(COLS, ROWS));
+    assert_eq!(b"$ ".to_vec(), predictor.output(b"$ ", ms(0)));
+    assert!(predictor.input(b"a", ms(1)).is_empty());
+    assert_eq!(b"a".to_vec(), predictor.output(b"a", ms(100)));
+    assert!(predictor.input(b"b", ms(101)).is_empty());
+    assert!(!predictor.is_active());
+}
+
+#[test]
+fn test_screen_follows_scrolling_and_editing() {
+    let mut screen = Screen::blank(10, 3);
+    let first = screen.row_id(0).unwrap();
+    screen.write(b"one\r\ntwo\r\nthree\r\nfour");
+    assert_eq!(None, screen.find_row(first));
+    assert_eq!((2, 4), screen.cursor());
+    assert_eq!(Some('t'), screen.cell(0, 0));
+    // Erasing, inserting and deleting cells
+    screen.write(b"\x1b[1;2H\x1b[K\x1b[3;1H\x1b[2@\x1b[1P");
+    assert_eq!(Some(' '), screen.cell(0, 1));
+    assert_eq!(Some(' '), screen.cell(2, 0));
+    assert_eq!(Some('f'), screen.cell(2, 1));
+    // Wrapping at the last column, wide characters take two
+    screen.write(b"\x1b[2;9H\xe4\xb8\xadx");
+    assert_eq!(Some('\u{4e2d}'), screen.cell(1, 8));
+    assert_eq!(None, screen.cell(1, 9));
+    assert_eq!(Some('x'), screen.cell(2, 0));
+    // Sequences split anywhere, strings skipped
+    screen.write(b"\x1b]0;ti");
+    screen.write(b"tle\x07\x1b[");
+    screen.write(b"5G");
+    assert_eq!((2, 4), screen.cursor());
+    assert!(screen.can_draw());
+}
+
+#[test]
+fn test_screen_tracks_attributes_and_modes() {
+    let mut screen = Screen::new(10, 3);
+    assert_eq!(None, screen.cell(0, 0));
+    screen.write(b"\x1b[1m\x1b[31m");
+    assert_eq!(b"\x1b[1m\x1b[31m", screen.sgr());
+    screen.write(b"\x1b[0;4m");
+    assert_eq!(b"\x1b[0;4m", screen.sgr());
+    screen.write(b"\x1b[4h");
+    assert!(!screen.can_draw());
+    screen.write(b"\x1b[4l\x1b(0");
+    assert!(!screen.can_draw());
+    screen.write(b"\x1b(B");
+    assert!(screen.can_draw());
+    screen.write(b"\x1b[?1049h");
+    assert!(screen.is_alternate());
+    screen.write(b"\x1b[?1049l");
+    assert!(!screen.is_alternate());
+}
@@ -3,29 +3,105 @@ use iroh_base::{PublicKey, SecretKey};
 use p2term_lib::client::server_handle::P2TermServerHandle;
 use p2term_lib::client::shell_proxy::ClientShellProxy;
 use p2term_lib::crypto::generate_secret_key;
-use p2term_lib::proto::ClientOpt;
+use p2term_lib::proto::codec::{Frame, FrameReader, FrameWriter};
+use p2term_lib::proto::{
+    AttachMode, Capabilities, ClientOpt, Compression, ExitStatus, Extensions, HELLO, HelloStatus,
+    Keepalive, PROTOCOL_VERSION, RejectReason, Rejection, ResumeToken, ServerHello, SessionKind,
+    Signal, TermSize, WELCOME, decode_handshake, peek_version, read_handshake, write_handshake,
+};
 use p2term_lib::server::client_handle::P2TermClientHandle;
-use p2term_lib::server::config::{P2TermdCfg, ShellCfg};
+use p2term_lib::server::config::{
+    DEFAULT_KEEPALIVE, P2TermdAccess, P2TermdCfg, PermitOpen, ShellCfg,
+};
 use p2term_lib::server::connection::P2TermServerConnection;
-use p2term_lib::server::connection_handler::P2TermConnectionHandler;
+use p2term_lib::server::connection_handler::{ConnectionHandler, P2TermConnectionHandler};
 use p2term_lib::server::router::P2TermRouter;
 use p2term_lib::server::shell_proxy::ServerShellProxy;
 use p2term_lib::streams::{ReadStream, WriteStream};
+use rustc_hash::FxHashSet;
 use std::io::Error;
 use std::pin::Pin;
-use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 use std::task::{Context, Poll};
+use std::time::Duration;
 use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::OwnedSemaphorePermit;
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 struct NoopShell;
 
+/// Echoes the client's input back, then exits with the code in the last input byte
+#[derive(Debug, Default)]
+struct ExitShell;
+
+impl ServerShellProxy for ExitShell {
+    async fn run<W, R>(
+        &self,
+        mut write: FrameWriter<W>,
+        mut read: FrameReader<R>,
+        _peer: PublicKey,
+        _shell_cfg: &ShellCfg,
+        _client_opt: ClientOpt,
+        _capabilities: Capabilities,
+        _slot: Option<OwnedSemaphorePermit>,
+    ) -> anyhow::Result<()>
+    where
+        W: WriteStream,
+        R: ReadStream,
+    {
+        let Some(Frame::Stdin(input)) = read.read_frame().await? else {
+            anyhow::bail!("expected stdin frame");
+        };
+        let code = u32::from(*input.last().context("empty input")?);
+        write.write_frame(&Frame::Stdout(input)).await?;
+        write
+            .write_frame(&Frame::ExitStatus(ExitStatus { code, signal: None }))
+            .await?;
+        write.write_frame(&Frame::Close).await
+    }
+}
+
+/// Sends its input, if any, then expects it echoed back before the exit status
+#[derive(Debug)]
+struct SendInputClient(Vec<u8>);
+
+impl ClientShellProxy for SendInputClient {
+    async fn run<W, R>(
+        self,
+        mut write: FrameWriter<W>,
+        mut read: FrameReader<R>,
+        _capabilities: Capabilities,
+    ) -> anyhow::Result<Option<ExitStatus>>
+    where
+        W: WriteStream,
+        R: ReadStream,
+    {
+        if !self.0.is_empty() {
+            write.write_frame(&Frame::Stdin(self.0.clone())).await?;
+        }
+        let mut status = None;
+        loop {
+            match read.read_frame().await? {
+                Some(Frame::Stdout(output)) => assert_eq!(self.0, output),
+                Some(Frame::ExitStatus(exit)) => status = Some(exit),
+                Some(Frame::Close) | None => return Ok(status),
+                Some(unexpected) => anyhow::bail!("unexpected frame {unexpected:?}"),
+            }
+        }
+    }
+}
+
 impl ServerShellProxy for NoopShell {
     async fn run<W, R>(
-        _write: W,
-        _read: R,
+        &self,
+        _write: FrameWriter<W>,
+        _read: FrameReader<R>,
+        _peer: PublicKey,
         _shell_cfg: &ShellCfg,
         _client_opt: ClientOpt,
+        _capabilities: Capabilities,
+        _slot: Option<OwnedSemaphorePermit>,
     ) -> anyhow::Result<()>
     where
         W: WriteStream,
@@ -36,12 +112,17 @@ impl ServerShellProxy for NoopShell {
 }
 
 impl ClientShellProxy for NoopShell {
-    async fn run<W, R>(self, _write: W, _read: R) -> anyhow::Result<()>
+    async fn run<W, R>(
+        self,
+        _write: FrameWriter<W>,
+        _read: FrameReader<R>,
+        _capabilities: Capabilities,
+    ) -> anyhow::Result<Option<ExitStatus>>
     where
         W: WriteStream,
         R: ReadStream,
     {
-        Ok(())
+        Ok(None)
     }
 }
 
@@ -49,9 +130,11 @@ struct DummyRouter {
     incoming_connections: Option<tokio::sync::mpsc::UnboundedReceiver<DummyConnection>>,
 }
 
+#[derive(Clone)]
 struct DummyConnection {
     secret_key: SecretKey,
-    channels: Mutex<Option<DummyConnectionChannels>>,
+    /// One per session the client opens, in the order they're accepted
+    channels: Arc<Mutex<Vec<DummyConnectionChannels>>>,
 }
 
 struct DummyConnectionChannels {
@@ -62,6 +145,7 @@ struct DummyConnectionChannels {
 #[derive(Debug)]
 struct MpscByteReceiverStream {
     inner: tokio::sync::mpsc::UnboundedReceiver<Vec<u8>>,
+    pending: Vec<u8>,
 }
 
 impl AsyncRead for MpscByteReceiverStream {
@@ -70,9 +154,17 @@ impl AsyncRead for MpscByteReceiverStream {
         cx: &mut Context<'_>,
         buf: &mut ReadBuf<'_>,
     ) -> Poll<std::io::Result<()>> {
+        if !self.pending.is_empty() {
+            let take = self.pending.len().min(buf.remaining());
+            buf.put_slice(&self.pending[..take]);
+            self.pending.drain(..take);
+            return Poll::Ready(Ok(()));
+        }
         match self.inner.poll_recv(cx) {
             Poll::Ready(Some(v)) => {
-                buf.put_slice(&v);
+                let take = v.len().min(buf.remaining());
+                buf.put_slice(&v[..take]);
+                self.pending.extend_from_slice(&v[take..]);
                 Poll::Ready(Ok(()))
             }
             Poll::Ready(None) => Poll::Ready(Ok(())),
@@ -114,7 +206,10 @@ fn mpsc_pair() -> (MpscByteSenderStream, MpscByteReceiverStream) {
     let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
     (
         MpscByteSenderStream { inner: tx },
-        MpscByteReceiverStream { inner: rx },
+        MpscByteReceiverStream {
+            inner: rx,
+            pending: Vec::new(),
+        },
     )
 }
 
@@ -126,18 +221,30 @@ impl P2TermServerConnection<MpscByteSenderStream, MpscByteReceiverStream> for Du
     async fn accept(
         &self,
         peer: PublicKey,
-    ) -> anyhow::Result<P2TermClientHandle<MpscByteSenderStream, MpscByteReceiverStream>> {
-        let channels = self
-            .channels
-            .lock()
-            .unwrap()
-            .take()
-            .context("no channels available")?;
-        Ok(P2TermClientHandle::new(
+    ) -> anyhow::Result<Option<P2TermClientHandle<MpscByteSenderStream, MpscByteReceiverStream>>>
+    {
+        let mut channels = self.channels.lock().unwrap();
+        if channels.is_empty() {
+            return Ok(None);
+        }
+        let channels = channels.remove(0);
+        Ok(Some(P2TermClientHandle::new(
             peer,
             channels.server_send,
             channels.server_recv,
-        ))
+        )))
+    }
+
+    async fn open(&self) -> anyhow::Result<(MpscByteSenderStream, MpscByteReceiverStream)> {
+        anyhow::bail!("dummy connections don't open streams to the client")
+    }
+
+    fn send_datagram(&self, _datagram: Vec<u8>) -> anyhow::Result<()> {
+        anyhow::bail!("dummy connections don't carry datagrams")
+    }
+
+    async fn read_datagram(&self) -> anyhow::Result<Vec<u8>> {
+        std::future::pending().await
     }
 }
 
@@ -156,12 +263,14 @@ impl P2TermRouter for DummyRouter {
             .context("empty incoming connections channel")?;
         tokio::task::spawn(async move {
             while let Some(peer) = con_recv.recv().await {
-                p2term_lib::server::connection_handler::ConnectionHandler::serve::<
+                if let Err(e) = p2term_lib::server::connection_handler::ConnectionHandler::serve::<
                     MpscByteSenderStream,
                     MpscByteReceiverStream,
                 >(&handler, peer)
                 .await
-                .unwrap();
+                {
+                    eprintln!("connection handler returned error: {e}");
+                }
             }
         });
         Ok(())
@@ -172,36 +281,1097 @@ impl P2TermRouter for DummyRouter {
     }
 }
 
-#[tokio::test]
-async fn test_protocol() {
-    let (client_send, server_recv) = mpsc_pair();
-    let (server_send, client_recv) = mpsc_pair();
-    let handle = P2TermServerHandle::new(client_send, client_recv);
-    let cfg = P2TermdCfg::default();
+async fn run_session<S, C>(opt: ClientOpt, client: C) -> anyhow::Result<Option<ExitStatus>>
+where
+    S: ServerShellProxy + Default,
+    C: ClientShellProxy,
+{
+    run_session_with_cfg::<S, C>(P2TermdCfg::default(), opt, client).await
+}
+
+async fn run_session_with_cfg<S, C>(
+    cfg: P2TermdCfg,
+    opt: ClientOpt,
+    client: C,
+) -> anyhow::Result<Option<ExitStatus>>
+where
+    S: ServerShellProxy + Default,
+    C: ClientShellProxy,
+{
+    let (mut handles, server_task) = start_server::<S>(cfg, 1);
+    let status = p2term_lib::client::runtime::run(handles.remove(0), &opt, client).await;
+    server_task.await.unwrap().unwrap();
+    status
+}
+
+type DummyServerHandle = P2TermServerHandle<MpscByteSenderStream, MpscByteReceiverStream>;
+
+/// Starts a server with a single connection carrying `sessions` sessions,
+/// returning a client handle for each
+fn start_server<S: ServerShellProxy + Default>(
+    cfg: P2TermdCfg,
+    sessions: usize,
+) -> (
+    Vec<DummyServerHandle>,
+    tokio::task::JoinHandle<anyhow::Result<()>>,
+) {
+    start_server_for::<S>(cfg, sessions, generate_secret_key())
+}
+
+/// Like [`start_server`], with the client connecting as `client_key`
+fn start_server_for<S: ServerShellProxy + Default>(
+    cfg: P2TermdCfg,
+    sessions: usize,
+    client_key: SecretKey,
+) -> (
+    Vec<DummyServerHandle>,
+    tokio::task::JoinHandle<anyhow::Result<()>>,
+) {
+    let mut handles = Vec::new();
+    let mut channels = Vec::new();
+    for _ in 0..sessions {
+        let (client_send, server_recv) = mpsc_pair();
+        let (server_send, client_recv) = mpsc_pair();
+        handles.push(P2TermServerHandle::new(client_send, client_recv));
+        channels.push(DummyConnectionChannels {
+            server_send,
+            server_recv,
+        });
+    }
     let (incoming_send, incoming_recv) = tokio::sync::mpsc::unbounded_channel();
     let router = DummyRouter {
         incoming_connections: Some(incoming_recv),
     };
     incoming_send
         .send(DummyConnection {
-            secret_key: generate_secret_key(),
-            channels: Mutex::new(Some(DummyConnectionChannels {
-                server_send,
-                server_recv,
-            })),
+            secret_key: client_key,
+            channels: Arc::new(Mutex::new(channels)),
         })
         .unwrap();
     let (finished_sig_send, finished_sig_recv) = tokio::sync::mpsc::channel(2);
-    let opt = ClientOpt::default();
-    let client_task = tokio::task::spawn(async move {
-        p2term_lib::client::runtime::run(handle, &opt, NoopShell).await
-    });
-    let server_task = tokio::task::spawn(p2term_lib::server::runtime::run::<_, NoopShell>(
+    let server_task = tokio::task::spawn(p2term_lib::server::runtime::run::<_, S>(
         cfg,
+        S::default(),
         router,
         finished_sig_recv,
     ));
     finished_sig_send.try_send(()).unwrap();
-    client_task.await.unwrap().unwrap();
+    (handles, server_task)
+}
+
+#[tokio::test]
+async fn test_protocol() {
+    run_session::<NoopShell, _>(ClientOpt::default(), NoopShell)
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_exit_status() {
+    let status = run_session::<ExitShell, _>(ClientOpt::default(), SendInputClient(vec![b'a', 3]))
+        .await
+        .unwrap();
+    assert_eq!(
+        Some(ExitStatus {
+            code: 3,
+            signal: None
+        }),
+        status
+    );
+}
+
+/// Reports the negotiated capabilities as the exit code
+#[derive(Debug, Default)]
+struct CapabilityShell;
+
+impl ServerShellProxy for CapabilityShell {
+    async fn run<W, R>(
+        &self,
+        mut write: FrameWriter<W>,
+        _read: FrameReader<R>,
+        _peer: PublicKey,
+        _shell_cfg: &ShellCfg,
+        _client_opt: ClientOpt,
+        capabilities: Capabilities,
+        _slot: Option<OwnedSemaphorePermit>,
+    ) -> anyhow::Result<()>
+    where
+        W: WriteStream,
+        R: ReadStream,
+    {
+        let code = u32::try_from(capabilities.bits())?;
+        write
+            .write_frame(&Frame::ExitStatus(ExitStatus { code, signal: None }))
+            .await?;
+        write.write_frame(&Frame::Close).await
+    }
+}
+
+#[tokio::test]
+async fn test_concurrent_sessions_on_one_connection() {
+    let (mut handles, server_task) = start_server::<ExitShell>(P2TermdCfg::default(), 2);
+    let second = handles.pop().unwrap();
+    let first = handles.pop().unwrap();
+    let opt = ClientOpt::default();
+    // The first session only gets its input after the second has finished,
+    // so they can only both finish if they're served concurrently
+    let (first_input_send, first_input_recv) = tokio::sync::oneshot::channel();
+    let (first_status, second_status) = tokio::join!(
+        p2term_lib::client::runtime::run(first, &opt, DelayedInputClient(first_input_recv)),
+        async {
+            let status =
+                p2term_lib::client::runtime::run(second, &opt, SendInputClient(vec![b'b', 2]))
+                    .await;
+            first_input_send.send(vec![b'a', 1]).unwrap();
+            status
+        }
+    );
+    assert_eq!(1, first_status.unwrap().unwrap().code);
+    assert_eq!(2, second_status.unwrap().unwrap().code);
+    server_task.await.unwrap().unwrap();
+}
+
+/// Sends its input once it arrives, then behaves like [`SendInputClient`]
+#[derive(Debug)]
+struct DelayedInputClient(tokio::sync::oneshot::Receiver<Vec<u8>>);
+
+impl ClientShellProxy for DelayedInputClient {
+    async fn run<W, R>(
+        self,
+        write: FrameWriter<W>,
+        read: FrameReader<R>,
+        capabilities: Capabilities,
+    ) -> anyhow::Result<Option<ExitStatus>>
+    where
+        W: WriteStream,
+        R: ReadStream,
+    {
+        let input = self.0.await?;
+        SendInputClient(input).run(write, read, capabilities).await
+    }
+}
+
+#[tokio::test]
+async fn test_capabilities_negotiated() {
+    let opt = ClientOpt {
+        capabilities: Capabilities::RESIZE | Capabilities::from_bits(1 << 63),
+        ..ClientOpt::default()
+    };
+    let status = run_session::<CapabilityShell, _>(opt, SendInputClient(Vec::new()))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(Capabilities::RESIZE.bits(), u64::from(status.code));
+}
+
+#[tokio::test]
+async fn test_compression_needs_both_ends() {
+    let negotiated = async |client: Compression, server: Compression| {
+        let mut cfg = P2TermdCfg::default();
+        cfg.shell_cfg.compression = server;
+        let opt = ClientOpt {
+            capabilities: client.offered(Capabilities::SUPPORTED),
+            ..ClientOpt::default()
+        };
+        let status =
+            run_session_with_cfg::<CapabilityShell, _>(cfg, opt, SendInputClient(Vec::new()))
+                .await
+                .unwrap()
+                .unwrap();
+        Capabilities::from_bits(u64::from(status.code)).contains(Capabilities::COMPRESSION)
+    };
+    assert!(negotiated(Compression::Zstd, Compression::Zstd).await);
+    assert!(!negotiated(Compression::None, Compression::Zstd).await);
+    assert!(!negotiated(Compression::Zstd, Compression::None).await);
+    // Not offered unless asked for
+    assert!(!Capabilities::SUPPORTED.contains(Capabilities::COMPRESSION));
+}
+
+#[tokio::test]
+async fn test_keepalive_only_for_shell_sessions() {
+    let negotiated = async |session: SessionKind, keepalive: Option<Keepalive>| {
+        let mut cfg = P2TermdCfg::default();
+        cfg.shell_cfg.timeouts.keepalive = keepalive;
+        let opt = ClientOpt {
+            session,
+            capabilities: Capabilities::SUPPORTED | Capabilities::KEEPALIVE,
+            ..ClientOpt::default()
+        };
+        let status =
+            run_session_with_cfg::<CapabilityShell, _>(cfg, opt, SendInputClient(Vec::new()))
+                .await
+                .unwrap()
+                .unwrap();
+        Capabilities::from_bits(u64::from(status.code)).contains(Capabilities::KEEPALIVE)
+    };
+    assert!(negotiated(SessionKind::Shell, Some(DEFAULT_KEEPALIVE)).await);
+    assert!(
+        negotiated(
+            SessionKind::Exec(vec!["true".to_string()]),
+            Some(DEFAULT_KEEPALIVE)
+        )
+        .await
+    );
+    assert!(!negotiated(SessionKind::List, Some(DEFAULT_KEEPALIVE)).await);
+    assert!(!negotiated(SessionKind::Shell, None).await);
+}
+
+#[tokio::test]
+async fn test_output_ack_only_for_shell_sessions() {
+    let negotiated = async |session: SessionKind| {
+        let opt = ClientOpt {
+            session,
+            capabilities: Capabilities::SUPPORTED | Capabilities::OUTPUT_ACK,
+            ..ClientOpt::default()
+        };
+        let status = run_session::<CapabilityShell, _>(opt, SendInputClient(Vec::new()))
+            .await
+            .unwrap()
+            .unwrap();
+        Capabilities::from_bits(u64::from(status.code)).contains(Capabilities::OUTPUT_ACK)
+    };
+    assert!(negotiated(SessionKind::Shell).await);
+    assert!(negotiated(SessionKind::Attach("main".to_string())).await);
+    assert!(!negotiated(SessionKind::List).await);
+}
+
+/// Sends keepalives until the client has been silent for too long, then exits with 42
+#[derive(Debug, Default)]
+struct KeepaliveShell;
+
+impl ServerShellProxy for KeepaliveShell {
+    async fn run<W, R>(
+        &self,
+        mut write: FrameWriter<W>,
+        mut read: FrameReader<R>,
+        _peer: PublicKey,
+        _shell_cfg: &ShellCfg,
+        _client_opt: ClientOpt,
+        _capabilities: Capabilities,
+        _slot: Option<OwnedSemaphorePermit>,
+    ) -> anyhow::Result<()>
+    where
+        W: WriteStream,
+        R: ReadStream,
+    {
+        loop {
+            tokio::select! {
+                read = read.read_frame() => match read {
+                    Ok(Some(_)) => {}
+                    Ok(None) => anyhow::bail!("client finished its stream"),
+                    Err(_) => break,
+                },
+                () = write.keepalive_due() => write.write_frame(&Frame::Keepalive).await?,
+            }
+        }
+        write
+            .write_frame(&Frame::ExitStatus(ExitStatus {
+                code: 42,
+                signal: None,
+            }))
+            .await?;
+        write.write_frame(&Frame::Close).await
+    }
+}
+
+/// Sends nothing, counting the keepalives it gets until the session exits
+#[derive(Debug)]
+struct SilentClient(Arc<AtomicUsize>);
+
+impl ClientShellProxy for SilentClient {
+    async fn run<W, R>(
+        self,
+        _write: FrameWriter<W>,
+        mut read: FrameReader<R>,
+        _capabilities: Capabilities,
+    ) -> anyhow::Result<Option<ExitStatus>>
+    where
+        W: WriteStream,
+        R: ReadStream,
+    {
+        let mut status = None;
+        while let Some(frame) = read.read_frame().await? {
+            match frame {
+                Frame::Keepalive => {
+                    self.0.fetch_add(1, Ordering::Relaxed);
+                }
+                Frame::ExitStatus(exit) => status = Some(exit),
+                Frame::Close => break,
+                _ => {}
+            }
+        }
+        Ok(status)
+    }
+}
+
+#[tokio::test]
+async fn test_silent_client_is_taken_as_gone() {
+    let mut cfg = P2TermdCfg::default();
+    cfg.shell_cfg.timeouts.keepalive = Some(Keepalive {
+        interval: Duration::from_millis(20),
+        max_missed: 3,
+    });
+    let opt = ClientOpt {
+        capabilities: Capabilities::SUPPORTED | Capabilities::KEEPALIVE,
+        ..ClientOpt::default()
+    };
+    let keepalives = Arc::new(AtomicUsize::new(0));
+    let status =
+        run_session_with_cfg::<KeepaliveShell, _>(cfg, opt, SilentClient(keepalives.clone()))
+            .await
+            .unwrap()
+            .unwrap();
+    assert_eq!(42, status.code);
+    // Received by the client all along, which didn't time out itself
+    assert!(keepalives.load(Ordering::Relaxed) >= 2);
+}
+
+#[tokio::test]
+async fn test_incompatible_server_version_rejected() {
+    let (client_send, mut server_recv) = mpsc_pair();
+    let (mut server_send, client_recv) = mpsc_pair();
+    let mut handle = P2TermServerHandle::new(client_send, client_recv);
+    write_handshake(
+        &mut server_send,
+        WELCOME,
+        &ServerHello {
+            version: 0,
+            ..ServerHello::default()
+        },
+    )
+    .await
+    .unwrap();
+    let err = handle.handshake(&ClientOpt::default()).await.unwrap_err();
+    assert!(err.to_string().contains("protocol version 0"), "{err}");
+    let hello = read_handshake(&mut server_recv, HELLO).await.unwrap();
+    assert_eq!(PROTOCOL_VERSION, peek_version(&hello).unwrap());
+}
+
+#[tokio::test]
+async fn test_client_too_old_rejected() {
+    let (client_send, _server_recv) = mpsc_pair();
+    let (mut server_send, client_recv) = mpsc_pair();
+    let mut handle = P2TermServerHandle::new(client_send, client_recv);
+    write_handshake(
+        &mut server_send,
+        WELCOME,
+        &ServerHello {
+            version: PROTOCOL_VERSION + 1,
+            min_version: PROTOCOL_VERSION + 1,
+            ..ServerHello::default()
+        },
+    )
+    .await
+    .unwrap();
+    let err = handle.handshake(&ClientOpt::default()).await.unwrap_err();
+    assert!(err.to_string().contains("upgrade the client"), "{err}");
+}
+
+/// The hellos as first sent with a protocol version, before any fields were added to them
+#[derive(serde::Serialize, serde::Deserialize)]
+struct FirstClientOpt {
+    version: u16,
+    capabilities: Capabilities,
+    shell: Option<String>,
+    cwd: Option<std::path::PathBuf>,
+    term: Option<String>,
+    size: Option<TermSize>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct FirstServerHello {
+    version: u16,
+    min_version: u16,
+    capabilities: Capabilities,
+}
+
+#[test]
+fn test_first_versioned_hellos_decode() {
+    let bytes = postcard::to_allocvec(&FirstClientOpt {
+        version: 1,
+        capabilities: Capabilities::RESIZE,
+        shell: Some("/bin/sh".to_string()),
+        cwd: None,
+        term: Some("xterm".to_string()),
+        size: Some(TermSize {
+            rows: 24,
+            cols: 80,
+            pixel_width: 0,
+            pixel_height: 0,
+        }),
+    })
+    .unwrap();
+    let opt: ClientOpt = decode_handshake(&bytes).unwrap();
+    assert_eq!(Some("/bin/sh"), opt.shell.as_deref());
+    assert_eq!(Capabilities::RESIZE, opt.capabilities);
+    assert!(opt.cwd.is_none());
+    assert_eq!(Some("xterm"), opt.term.as_deref());
+    assert_eq!(Some(80), opt.size.map(|size| size.cols));
+    assert_eq!(SessionKind::Shell, opt.session);
+    assert!(!opt.no_pty);
+    assert!(!opt.persist);
+    assert!(opt.session_name.is_none());
+    assert_eq!(AttachMode::Takeover, opt.attach_mode);
+    assert!(!opt.resumable);
+    assert_eq!(Extensions::default(), opt.extensions);
+
+    let bytes = postcard::to_allocvec(&FirstServerHello {
+        version: 1,
+        min_version: 1,
+        capabilities: Capabilities::RESIZE | Capabilities::EXIT_STATUS,
+    })
+    .unwrap();
+    let hello: ServerHello = decode_handshake(&bytes).unwrap();
+    assert_eq!(
+        ServerHello {
+            version: 1,
+            min_version: 1,
+            capabilities: Capabilities::RESIZE | Capabilities::EXIT_STATUS,
+            ..ServerHello::default()
+        },
+        hello
+    );
+}
+
+#[test]
+fn test_fixed_hello_fields_decode_from_the_version_alone() {
+    let bytes = postcard::to_allocvec(&PROTOCOL_VERSION).unwrap();
+    let opt: ClientOpt = decode_handshake(&bytes).unwrap();
+    assert_eq!(SessionKind::Shell, opt.session);
+    let hello: ServerHello = decode_handshake(&bytes).unwrap();
+    assert_eq!(HelloStatus::Accepted, hello.status);
+    assert!(hello.keepalive.is_none());
+}
+
+#[test]
+fn test_hello_extensions_skipped_by_older_peers() {
+    let mut opt = ClientOpt {
+        shell: Some("/bin/sh".to_string()),
+        ..ClientOpt::default()
+    };
+    // Of a kind that wouldn't decode from zeroes
+    opt.extensions
+        .insert(7, &vec!["a".to_string(), "b".to_string()])
+        .unwrap();
+    opt.extensions.insert(8, &[1u8; 32]).unwrap();
+    let bytes = postcard::to_allocvec(&opt).unwrap();
+    let older: FirstClientOpt = decode_handshake(&bytes).unwrap();
+    assert_eq!(Some("/bin/sh"), older.shell.as_deref());
+    let decoded: ClientOpt = decode_handshake(&bytes).unwrap();
+    assert_eq!(
+        Some(vec!["a".to_string(), "b".to_string()]),
+        decoded.extensions.get::<Vec<String>>(7).unwrap()
+    );
+    assert_eq!(Some([1u8; 32]), decoded.extensions.get(8).unwrap());
+    assert_eq!(None, decoded.extensions.get::<u64>(9).unwrap());
+    assert!(decoded.extensions.get::<[u8; 32]>(7).is_err());
+}
+
+/// Exits with the number of exec arguments, or 255 for other sessions, plus 1000 without a pty
+#[derive(Debug, Default)]
+struct SessionKindShell;
+
+impl ServerShellProxy for SessionKindShell {
+    async fn run<W, R>(
+        &self,
+        mut write: FrameWriter<W>,
+        _read: FrameReader<R>,
+        _peer: PublicKey,
+        _shell_cfg: &ShellCfg,
+        client_opt: ClientOpt,
+        _capabilities: Capabilities,
+        _slot: Option<OwnedSemaphorePermit>,
+    ) -> anyhow::Result<()>
+    where
+        W: WriteStream,
+        R: ReadStream,
+    {
+        let mut code = match client_opt.session {
+            SessionKind::Exec(argv) => u32::try_from(argv.len())?,
+            _ => 255,
+        };
+        if client_opt.no_pty {
+            code += 1000;
+        }
+        write
+            .write_frame(&Frame::ExitStatus(ExitStatus { code, signal: None }))
+            .await?;
+        write.write_frame(&Frame::Close).await
+    }
+}
+
+#[tokio::test]
+async fn test_exec_session_requested() {
+    let opt = ClientOpt {
+        session: SessionKind::Exec(vec!["ls".to_string(), "-la".to_string()]),
+        no_pty: true,
+        ..ClientOpt::default()
+    };
+    let status = run_session::<SessionKindShell, _>(opt, SendInputClient(Vec::new()))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(1002, status.code);
+}
+
+/// A server where peers only get `git-shell`
+fn restricted_cfg() -> P2TermdCfg {
+    let mut cfg = P2TermdCfg::default();
+    cfg.shell_cfg.default_shell = "/usr/bin/git-shell".to_string();
+    cfg.shell_cfg.allowed_shells = vec!["/usr/bin/git-shell".to_string()];
+    cfg
+}
+
+#[test]
+fn test_exec_runs_through_the_shell() {
+    let cfg = restricted_cfg();
+    let opt = ClientOpt {
+        session: SessionKind::Exec(vec![
+            "/bin/sh".to_string(),
+            "-c".to_string(),
+            "id; echo 'hi'".to_string(),
+        ]),
+        ..ClientOpt::default()
+    };
+    assert_eq!(
+        Some(vec![
+            "/usr/bin/git-shell".to_string(),
+            "-c".to_string(),
+            "/bin/sh -c 'id; echo '\\''hi'\\'''".to_string(),
+        ]),
+        cfg.shell_cfg.command(&opt)
+    );
+    assert_eq!(
+        Some(vec!["/usr/bin/git-shell".to_string(), "-l".to_string()]),
+        cfg.shell_cfg.command(&ClientOpt::default())
+    );
+    let files = ClientOpt {
+        session: SessionKind::Files,
+        ..ClientOpt::default()
+    };
+    assert_eq!(None, cfg.shell_cfg.command(&files));
+}
+
+#[tokio::test]
+async fn test_exec_with_restricted_shell_rejected() {
+    let opt = ClientOpt {
+        session: SessionKind::Exec(vec!["id".to_string()]),
+        shell: Some("/bin/sh".to_string()),
+        no_pty: true,
+        ..ClientOpt::default()
+    };
+    let rejection = expect_rejection(restricted_cfg(), opt).await;
+    assert_eq!(RejectReason::DisallowedShell, rejection.reason);
+}
+
+/// Expects the client to give up on `opt` when the server only offers `capabilities`
+async fn expect_needs_upgrade(capabilities: Capabilities, opt: ClientOpt) {
+    let (client_send, _server_recv) = mpsc_pair();
+    let (mut server_send, client_recv) = mpsc_pair();
+    let mut handle = P2TermServerHandle::new(client_send, client_recv);
+    write_handshake(
+        &mut server_send,
+        WELCOME,
+        &ServerHello {
+            capabilities,
+            ..ServerHello::default()
+        },
+    )
+    .await
+    .unwrap();
+    let err = handle.handshake(&opt).await.unwrap_err();
+    assert!(
+        err.to_string().contains("upgrade the server"),
+        "{:?}: {err}",
+        opt.session
+    );
+}
+
+#[tokio::test]
+async fn test_sessions_require_server_support() {
+    let older = Capabilities::RESIZE | Capabilities::EXIT_STATUS;
+    let sessions = [
+        SessionKind::Exec(vec!["true".to_string()]),
+        SessionKind::Attach("1".to_string()),
+        SessionKind::Resume {
+            token: ResumeToken([1; 32]),
+            offset: 0,
+        },
+        forward_to("localhost", 5432),
+        SessionKind::Files,
+    ];
+    for session in sessions {
+        let opt = ClientOpt {
+            session,
+            ..ClientOpt::default()
+        };
+        expect_needs_upgrade(older, opt).await;
+    }
+    // Watching needs more than attaching does
+    let opt = ClientOpt {
+        session: SessionKind::Attach("1".to_string()),
+        attach_mode: AttachMode::Watch,
+        ..ClientOpt::default()
+    };
+    expect_needs_upgrade(older | Capabilities::PERSIST, opt).await;
+}
+
+async fn expect_rejection(cfg: P2TermdCfg, opt: ClientOpt) -> Rejection {
+    let err = run_session_with_cfg::<NoopShell, _>(cfg, opt, NoopShell)
+        .await
+        .unwrap_err();
+    err.downcast_ref::<Rejection>()
+        .cloned()
+        .unwrap_or_else(|| panic!("expected rejection, got: {err:?}"))
+}
+
+fn forward_to(host: &str, port: u16) -> SessionKind {
+    SessionKind::ForwardTcp {
+        host: host.to_string(),
+        port,
+    }
+}
+
+#[tokio::test]
+async fn test_forward_denied_by_default() {
+    let opt = ClientOpt {
+        session: forward_to("localhost", 5432),
+        ..ClientOpt::default()
+    };
+    let rejection = expect_rejection(P2TermdCfg::default(), opt).await;
+    assert_eq!(RejectReason::ForwardDenied, rejection.reason);
+}
+
+#[tokio::test]
+async fn test_files_denied_by_default() {
+    let opt = ClientOpt {
+        session: SessionKind::Files,
+        ..ClientOpt::default()
+    };
+    let rejection = expect_rejection(P2TermdCfg::default(), opt).await;
+    assert_eq!(RejectReason::FilesDenied, rejection.reason);
+}
+
+#[tokio::test]
+async fn test_udp_forward_denied_by_default() {
+    let opt = ClientOpt {
+        session: SessionKind::ForwardUdp {
+            host: "localhost".to_string(),
+            port: 53,
+            flow: 0,
+        },
+        ..ClientOpt::default()
+    };
+    let rejection = expect_rejection(P2TermdCfg::default(), opt).await;
+    assert_eq!(RejectReason::ForwardDenied, rejection.reason);
+}
+
+#[tokio::test]
+async fn test_unix_forward_denied_by_default() {
+    let opt = ClientOpt {
+        session: SessionKind::ForwardUnix {
+            path: "/var/run/docker.sock".to_string(),
+        },
+        ..ClientOpt::default()
+    };
+    let rejection = expect_rejection(P2TermdCfg::default(), opt).await;
+    assert_eq!(RejectReason::ForwardDenied, rejection.reason);
+}
+
+/// Answers listening sessions with the port it was asked for plus one
+#[derive(Debug, Default)]
+struct ListenShell;
+
+impl ServerShellProxy for ListenShell {
+    async fn run<W, R>(
+        &self,
+        _write: FrameWriter<W>,
+        _read: FrameReader<R>,
+        _peer: PublicKey,
+        _shell_cfg: &ShellCfg,
+        _client_opt: ClientOpt,
+        _capabilities: Capabilities,
+        _slot: Option<OwnedSemaphorePermit>,
+    ) -> anyhow::Result<()>
+    where
+        W: WriteStream,
+        R: ReadStream,
+    {
+        anyhow::bail!("listening sessions should be served by listen")
+    }
+
+    async fn listen<W, R, C>(
+        &self,
+        mut write: FrameWriter<W>,
+        _read: FrameReader<R>,
+        _peer: PublicKey,
+        connection: C,
+        client_opt: ClientOpt,
+    ) -> anyhow::Result<()>
+    where
+        W: WriteStream,
+        R: ReadStream,
+        C: P2TermServerConnection<W, R>,
+    {
+        let SessionKind::ListenTcp { port, .. } = client_opt.session else {
+            anyhow::bail!("not a listening session");
+        };
+        // Streams back to the client are opened on the session's connection
+        assert!(connection.open().await.is_err());
+        write.write_frame(&Frame::ForwardListening(port + 1)).await
+    }
+}
+
+#[derive(Debug)]
+struct ListeningClient;
+
+impl ClientShellProxy for ListeningClient {
+    async fn run<W, R>(
+        self,
+        _write: FrameWriter<W>,
+        mut read: FrameReader<R>,
+        _capabilities: Capabilities,
+    ) -> anyhow::Result<Option<ExitStatus>>
+    where
+        W: WriteStream,
+        R: ReadStream,
+    {
+        let frame = read.read_frame().await?;
+        assert_eq!(Some(Frame::ForwardListening(8081)), frame);
+        Ok(None)
+    }
+}
+
+#[tokio::test]
+async fn test_listen_session_served_by_listen() {
+    let client_key = generate_secret_key();
+    let mut cfg = P2TermdCfg::default();
+    cfg.shell_cfg.forwarding.permit_listen.insert(
+        client_key.public(),
+        vec![PermitOpen::parse("localhost:8080").unwrap()],
+    );
+    let opt = ClientOpt {
+        session: SessionKind::ListenTcp {
+            host: "localhost".to_string(),
+            port: 8080,
+        },
+        ..ClientOpt::default()
+    };
+    let (mut handles, server_task) = start_server_for::<ListenShell>(cfg, 1, client_key);
+    p2term_lib::client::runtime::run(handles.remove(0), &opt, ListeningClient)
+        .await
+        .unwrap();
+    server_task.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn test_remote_forward_denied_by_default() {
+    let opt = ClientOpt {
+        session: SessionKind::ListenTcp {
+            host: "localhost".to_string(),
+            port: 8080,
+        },
+        ..ClientOpt::default()
+    };
+    let rejection = expect_rejection(P2TermdCfg::default(), opt).await;
+    assert_eq!(RejectReason::ForwardDenied, rejection.reason);
+}
+
+#[tokio::test]
+async fn test_denied_peer_rejected() {
+    let mut allowed = FxHashSet::default();
+    allowed.insert(generate_secret_key().public());
+    let cfg = P2TermdCfg {
+        access: P2TermdAccess::AllowedNodes(allowed),
+        ..P2TermdCfg::default()
+    };
+    let rejection = expect_rejection(cfg, ClientOpt::default()).await;
+    assert_eq!(RejectReason::DeniedPeer, rejection.reason);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_silent_denied_peer_is_dropped() {
+    let mut allowed = FxHashSet::default();
+    allowed.insert(generate_secret_key().public());
+    let cfg = P2TermdCfg::default();
+    let handler = P2TermConnectionHandler::<NoopShell>::new(
+        P2TermdAccess::AllowedNodes(allowed),
+        cfg.shell_cfg,
+        None,
+        NoopShell,
+    );
+    // Opens a session and never says hello
+    let (_client_send, server_recv) = mpsc_pair();
+    let (server_send, _client_recv) = mpsc_pair();
+    let connection = DummyConnection {
+        secret_key: generate_secret_key(),
+        channels: Arc::new(Mutex::new(vec![DummyConnectionChannels {
+            server_send,
+            server_recv,
+        }])),
+    };
+    let served = tokio::time::timeout(
+        Duration::from_mins(1),
+        ConnectionHandler::serve::<MpscByteSenderStream, MpscByteReceiverStream>(
+            &handler, connection,
+        ),
+    )
+    .await
+    .expect("denied peer held on to the server");
+    assert!(served.is_err());
+}
+
+#[tokio::test]
+async fn test_disallowed_shell_rejected() {
+    let opt = ClientOpt {
+        shell: Some("/not/a/shell".to_string()),
+        ..ClientOpt::default()
+    };
+    let rejection = expect_rejection(P2TermdCfg::default(), opt).await;
+    assert_eq!(RejectReason::DisallowedShell, rejection.reason);
+    assert!(rejection.message.contains("/not/a/shell"));
+}
+
+#[tokio::test]
+async fn test_invalid_cwd_rejected() {
+    let opt = ClientOpt {
+        cwd: Some("/not/a/directory".into()),
+        ..ClientOpt::default()
+    };
+    let rejection = expect_rejection(P2TermdCfg::default(), opt).await;
+    assert_eq!(RejectReason::InvalidCwd, rejection.reason);
+}
+
+#[tokio::test]
+async fn test_busy_server_rejected() {
+    let cfg = P2TermdCfg {
+        max_sessions: Some(0),
+        ..P2TermdCfg::default()
+    };
+    let rejection = expect_rejection(cfg, ClientOpt::default()).await;
+    assert_eq!(RejectReason::ServerBusy, rejection.reason);
+}
+
+#[tokio::test]
+async fn test_incompatible_client_version_rejected() {
+    let opt = ClientOpt {
+        version: 0,
+        ..ClientOpt::default()
+    };
+    let rejection = expect_rejection(P2TermdCfg::default(), opt).await;
+    assert_eq!(RejectReason::VersionMismatch, rejection.reason);
+}
+
+#[tokio::test]
+async fn test_persist_without_pty_rejected() {
+    let opt = ClientOpt {
+        persist: true,
+        no_pty: true,
+        ..ClientOpt::default()
+    };
+    let rejection = expect_rejection(P2TermdCfg::default(), opt).await;
+    assert_eq!(RejectReason::InvalidRequest, rejection.reason);
+}
+
+/// Knows of no sessions to attach to
+#[derive(Debug, Default)]
+struct NoSessionsShell;
+
+impl ServerShellProxy for NoSessionsShell {
+    fn admit(
+        &self,
+        _peer: &PublicKey,
+        _shell_cfg: &ShellCfg,
+        client_opt: &ClientOpt,
+    ) -> Result<(), Rejection> {
+        match &client_opt.session {
+            SessionKind::Attach(target) => Err(Rejection::new(
+                RejectReason::SessionNotFound,
+                format!("no session {target}"),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    async fn run<W, R>(
+        &self,
+        write: FrameWriter<W>,
+        read: FrameReader<R>,
+        peer: PublicKey,
+        shell_cfg: &ShellCfg,
+        client_opt: ClientOpt,
+        capabilities: Capabilities,
+        slot: Option<OwnedSemaphorePermit>,
+    ) -> anyhow::Result<()>
+    where
+        W: WriteStream,
+        R: ReadStream,
+    {
+        ServerShellProxy::run(
+            &NoopShell,
+            write,
+            read,
+            peer,
+            shell_cfg,
+            client_opt,
+            capabilities,
+            slot,
+        )
+        .await
+    }
+}
+
+#[tokio::test]
+async fn test_shell_admission_rejects() {
+    let opt = ClientOpt {
+        session: SessionKind::Attach("build".to_string()),
+        ..ClientOpt::default()
+    };
+    let err = run_session_with_cfg::<NoSessionsShell, _>(P2TermdCfg::default(), opt, NoopShell)
+        .await
+        .unwrap_err();
+    let rejection = err.downcast_ref::<Rejection>().expect("rejection");
+    assert_eq!(RejectReason::SessionNotFound, rejection.reason);
+    assert_eq!("no session build", rejection.message);
+}
+
+#[tokio::test]
+async fn test_attaching_takes_no_session_slot() {
+    // The kept session holds the slot, a full server still lets clients attach to it
+    let cfg = P2TermdCfg {
+        max_sessions: Some(0),
+        ..P2TermdCfg::default()
+    };
+    let opt = ClientOpt {
+        session: SessionKind::Attach("build".to_string()),
+        ..ClientOpt::default()
+    };
+    let err = run_session_with_cfg::<NoSessionsShell, _>(cfg, opt, NoopShell)
+        .await
+        .unwrap_err();
+    let rejection = err.downcast_ref::<Rejection>().expect("rejection");
+    assert_eq!(RejectReason::SessionNotFound, rejection.reason);
+}
+
+/// Keeps the session slots it's given after its sessions end, like a kept session would
+#[derive(Debug, Default)]
+struct KeepSlotShell {
+    kept: Mutex<Vec<OwnedSemaphorePermit>>,
+}
+
+impl ServerShellProxy for KeepSlotShell {
+    async fn run<W, R>(
+        &self,
+        mut write: FrameWriter<W>,
+        _read: FrameReader<R>,
+        _peer: PublicKey,
+        _shell_cfg: &ShellCfg,
+        _client_opt: ClientOpt,
+        _capabilities: Capabilities,
+        slot: Option<OwnedSemaphorePermit>,
+    ) -> anyhow::Result<()>
+    where
+        W: WriteStream,
+        R: ReadStream,
+    {
+        self.kept.lock().unwrap().extend(slot);
+        write.write_frame(&Frame::Close).await
+    }
+}
+
+#[tokio::test]
+async fn test_kept_session_slot_counts_toward_max_sessions() {
+    let cfg = P2TermdCfg {
+        max_sessions: Some(1),
+        ..P2TermdCfg::default()
+    };
+    let (mut handles, server_task) = start_server::<KeepSlotShell>(cfg, 2);
+    let second = handles.pop().unwrap();
+    let first = handles.pop().unwrap();
+    let opt = ClientOpt::default();
+    p2term_lib::client::runtime::run(first, &opt, NoopShell)
+        .await
+        .unwrap();
+    let err = p2term_lib::client::runtime::run(second, &opt, NoopShell)
+        .await
+        .unwrap_err();
+    let rejection = err.downcast_ref::<Rejection>().expect("rejection");
+    assert_eq!(RejectReason::ServerBusy, rejection.reason);
     server_task.await.unwrap().unwrap();
 }
+
+/// Waits for stdin EOF, then exits as if killed by the next signal
+#[derive(Debug, Default)]
+struct SignalShell;
+
+impl ServerShellProxy for SignalShell {
+    async fn run<W, R>(
+        &self,
+        mut write: FrameWriter<W>,
+        mut read: FrameReader<R>,
+        _peer: PublicKey,
+        _shell_cfg: &ShellCfg,
+        _client_opt: ClientOpt,
+        _capabilities: Capabilities,
+        _slot: Option<OwnedSemaphorePermit>,
+    ) -> anyhow::Result<()>
+    where
+        W: WriteStream,
+        R: ReadStream,
+    {
+        let mut eof = false;
+        let signal = loop {
+            match read.read_frame().await?.context("stream closed early")? {
+                Frame::StdinEof => eof = true,
+                Frame::Signal(signal) if eof => break signal,
+                unexpected => anyhow::bail!("unexpected frame {unexpected:?}"),
+            }
+        };
+        write
+            .write_frame(&Frame::ExitStatus(ExitStatus {
+                code: 0,
+                signal: Some(signal),
+            }))
+            .await?;
+        write.write_frame(&Frame::Close).await
+    }
+}
+
+/// Sends its frames without finishing the stream, then waits for the exit status
+#[derive(Debug)]
+struct SendFramesClient(Vec<Frame>);
+
+impl ClientShellProxy for SendFramesClient {
+    async fn run<W, R>(
+        self,
+        mut write: FrameWriter<W>,
+        mut read: FrameReader<R>,
+        _capabilities: Capabilities,
+    ) -> anyhow::Result<Option<ExitStatus>>
+    where
+        W: WriteStream,
+        R: ReadStream,
+    {
+        for frame in &self.0 {
+            write.write_frame(frame).await?;
+        }
+        let mut status = None;
+        loop {
+            match read.read_frame().await? {
+                Some(Frame::ExitStatus(exit)) => status = Some(exit),
+                Some(Frame::Close) | None => return Ok(status),
+                Some(unexpected) => anyhow::bail!("unexpected frame {unexpected:?}"),
+            }
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_signal_after_stdin_eof() {
+    let client = SendFramesClient(vec![Frame::StdinEof, Frame::Signal(Signal::Int)]);
+    let status = run_session::<SignalShell, _>(ClientOpt::default(), client)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(Some(Signal::Int), status.signal);
+}
@@ -0,0 +1,205 @@
+use p2term_lib::crypto::generate_secret_key;
+use p2term_lib::proto::{AttachMode, RejectReason};
+use p2term_lib::server::config::{PersistenceCfg, SessionAccess};
+use p2term_lib::server::sessions::{Attachments, ClientState, Scrollback, check_access};
+
+#[test]
+fn test_scrollback_reads_clamp_to_what_is_kept() {
+    let mut scrollback = Scrollback::new(8);
+    scrollback.push(b"0123");
+    let mut cursor = 0;
+    assert_eq!((0, b"01".to_vec()), scrollback.read_from(&mut cursor, 2));
+    assert_eq!(2, cursor);
+    assert_eq!((2, b"23".to_vec()), scrollback.read_from(&mut cursor, 64));
+    assert_eq!(4, cursor);
+    // Caught up
+    assert_eq!((4, Vec::new()), scrollback.read_from(&mut cursor, 64));
+    assert_eq!(4, cursor);
+
+    // Drops the oldest output past the capacity, a cursor pointing at it skips ahead
+    scrollback.push(b"456789ab");
+    assert_eq!(4, scrollback.start());
+    assert_eq!(12, scrollback.end());
+    let mut behind = 1;
+    assert_eq!((4, b"4567".to_vec()), scrollback.read_from(&mut behind, 4));
+    assert_eq!(8, behind);
+
+    // A cursor past the end, like a resume offset from a confused client, is pulled back
+    let mut ahead = 100;
+    assert_eq!((12, Vec::new()), scrollback.read_from(&mut ahead, 4));
+    assert_eq!(12, ahead);
+}
+
+#[test]
+fn test_scrollback_keeps_only_the_latest_of_a_large_push() {
+    let mut scrollback = Scrollback::new(4);
+    scrollback.push(b"0123456789");
+    assert_eq!(6, scrollback.start());
+    let mut cursor = 0;
+    assert_eq!((6, b"6789".to_vec()), scrollback.read_from(&mut cursor, 64));
+}
+
+#[test]
+fn test_check_access_per_attach_mode() {
+    let owner = generate_secret_key().public();
+    let writer = generate_secret_key().public();
+    let watcher = generate_secret_key().public();
+    let stranger = generate_secret_key().public();
+    let mut cfg = PersistenceCfg::default();
+    cfg.attach_grants
+        .insert(writer, std::iter::once(owner).collect());
+    cfg.watch_grants
+        .insert(watcher, std::iter::once(owner).collect());
+    let access = |peer, mode| {
+        check_access(Some(owner), |owner| owner, "1", &peer, &cfg, mode)
+            .map(|(_, access)| access)
+            .map_err(|rejection| rejection.reason)
+    };
+    for peer in [owner, writer] {
+        assert_eq!(Ok(SessionAccess::Write), access(peer, AttachMode::Takeover));
+        assert_eq!(Ok(SessionAccess::Write), access(peer, AttachMode::Join));
+        // Watching is read-only whatever the peer could do
+        assert_eq!(Ok(SessionAccess::Watch), access(peer, AttachMode::Watch));
+    }
+    assert_eq!(Ok(SessionAccess::Watch), access(watcher, AttachMode::Watch));
+    assert_eq!(
+        Err(RejectReason::ReadOnly),
+        access(watcher, AttachMode::Takeover)
+    );
+    assert_eq!(
+        Err(RejectReason::ReadOnly),
+        access(watcher, AttachMode::Join)
+    );
+    for mode in [AttachMode::Takeover, AttachMode::Join, AttachMode::Watch] {
+        assert_eq!(Err(RejectReason::SessionNotFound), access(stranger, mode));
+    }
+}
+
+#[test]
+fn test_check_access_hides_unseen_sessions() {
+    let owner = generate_secret_key().public();
+    let stranger = generate_secret_key().public();
+    let cfg = PersistenceCfg::default();
+    let missing = check_access(
+        None::<iroh_base::PublicKey>,
+        |owner| owner,
+        "1",
+        &owner,
+        &cfg,
+        AttachMode::Watch,
+    )
+    .unwrap_err();
+    let unseen = check_access(
+        Some(owner),
+        |owner| owner,
+        "1",
+        &stranger,
+        &cfg,
+        AttachMode::Watch,
+    )
+    .unwrap_err();
+    assert_eq!(missing.reason, unseen.reason);
+    assert_eq!(missing.message, unseen.message);
+}
+
+#[test]
+fn test_watcher_input_never_reaches_the_pty() {
+    let mut attachments = Attachments::default();
+    let owner = attachments.attach(SessionAccess::Write, AttachMode::Join);
+    let watcher = attachments.attach(SessionAccess::Watch, AttachMode::Watch);
+    assert!(attachments.may_write(owner));
+    assert!(!attachments.may_write(watcher));
+    assert_eq!(2, attachments.attached());
+
+    // Taking over replaces the writers, the watcher is left watching
+    let taker = attachments.attach(SessionAccess::Write, AttachMode::Takeover);
+    assert_eq!(Some(ClientState::Replaced), attachments.state(owner));
+    assert!(!attachments.may_write(owner));
+    assert!(attachments.may_write(taker));
+    assert_eq!(
+        Some(ClientState::Attached(SessionAccess::Watch)),
+        attachments.state(watcher)
+    );
+    assert!(!attachments.may_write(watcher));
+
+    // Gone clients don't get to write either
+    assert_eq!(None, attachments.detach(taker));
+    assert!(!attachments.may_write(taker));
+}
+
+#[test]
+fn test_detaching_the_last_client() {
+    let mut attachments = Attachments::default();
+    let first = attachments.attach(SessionAccess::Write, AttachMode::Join);
+    let second = attachments.attach(SessionAccess::Watch, AttachMode::Watch);
+    assert_eq!(None, attachments.detach(first));
+    // Detaching twice does nothing
+    assert_eq!(None, attachments.detach(first));
+    let last = attachments.detach(second).unwrap();
+    assert!(attachments.detached_since(last));
+    let again = attachments.attach(SessionAccess::Write, AttachMode::Join);
+    assert!(!attachments.detached_since(last));
+    let last = attachments.detach(again).unwrap();
+    assert!(attachments.detached_since(last));
+}
+
+#[test]
+fn test_slow_client_of_a_resumable_session_gets_every_byte() {
+    let output: Vec<u8> = (0..64 * 1024_u32).map(|i| (i % 251) as u8).collect();
+    let mut scrollback = Scrollback::new(1024);
+    let mut attachments = Attachments::default();
+    let client = attachments.attach(SessionAccess::Write, AttachMode::Takeover);
+    attachments.progress(client, 0);
+    // Never reads, and isn't waited for
+    let watcher = attachments.attach(SessionAccess::Watch, AttachMode::Watch);
+    attachments.progress(watcher, 0);
+    let mut chunks = output.chunks(300);
+    let mut pending = chunks.next();
+    let mut cursor = 0;
+    let mut received = Vec::new();
+    while received.len() < output.len() {
+        // The pump pushes while there's room, the client reads less than it gets each time
+        while let Some(chunk) =
+            pending.filter(|chunk| scrollback.has_room(chunk.len(), attachments.slowest_writer()))
+        {
+            scrollback.push(chunk);
+            pending = chunks.next();
+        }
+        let (offset, bytes) = scrollback.read_from(&mut cursor, 100);
+        assert_eq!(received.len() as u64, offset, "output was skipped");
+        received.extend(bytes);
+        attachments.progress(client, cursor);
+    }
+    assert_eq!(output, received);
+    assert!(scrollback.start() > 0);
+}
+
+#[test]
+fn test_only_attached_writers_hold_output() {
+    let mut scrollback = Scrollback::new(8);
+    scrollback.push(b"012345");
+    let mut attachments = Attachments::default();
+    let watcher = attachments.attach(SessionAccess::Watch, AttachMode::Watch);
+    attachments.progress(watcher, 0);
+    assert_eq!(None, attachments.slowest_writer());
+    assert!(scrollback.has_room(4, attachments.slowest_writer()));
+
+    let first = attachments.attach(SessionAccess::Write, AttachMode::Join);
+    attachments.progress(first, 2);
+    let second = attachments.attach(SessionAccess::Write, AttachMode::Join);
+    attachments.progress(second, 4);
+    assert_eq!(Some(2), attachments.slowest_writer());
+    assert!(scrollback.has_room(4, attachments.slowest_writer()));
+    assert!(!scrollback.has_room(5, attachments.slowest_writer()));
+
+    // Replaced and detached clients are no longer waited for
+    let taker = attachments.attach(SessionAccess::Write, AttachMode::Takeover);
+    attachments.progress(taker, 6);
+    assert_eq!(Some(6), attachments.slowest_writer());
+    attachments.detach(taker);
+    assert_eq!(None, attachments.slowest_writer());
+
+    // Chunks larger than the scrollback still go in once everything has been read
+    assert!(scrollback.has_room(16, Some(6)));
+    assert!(!scrollback.has_room(16, Some(5)));
+}
@@ -0,0 +1,80 @@
+use p2term_lib::server::config::SessionLimits;
+use p2term_lib::server::timeouts::{Expiry, SessionTimer};
+use std::time::Duration;
+use tokio::time::Instant;
+
+#[tokio::test]
+async fn test_idle_session_is_warned_then_expires() {
+    let limit = Duration::from_millis(100);
+    let timer = SessionTimer::new(SessionLimits {
+        idle_timeout: Some(limit),
+        max_duration: None,
+    });
+    assert!(timer.is_limited());
+    let started = Instant::now();
+    let mut warnings = Vec::new();
+    let expiry = timer.expired(|warning| warnings.push(warning)).await;
+    assert_eq!(Expiry::Idle(limit), expiry);
+    assert!(started.elapsed() >= limit);
+    assert_eq!(1, warnings.len(), "{warnings:?}");
+    assert!(warnings[0].contains("idle"), "{warnings:?}");
+}
+
+#[tokio::test]
+async fn test_activity_postpones_idle_expiry() {
+    let limit = Duration::from_millis(150);
+    let timer = SessionTimer::new(SessionLimits {
+        idle_timeout: Some(limit),
+        max_duration: None,
+    });
+    let started = Instant::now();
+    let mut warnings = 0;
+    let expiry = tokio::select! {
+        expiry = timer.expired(|_| warnings += 1) => expiry,
+        () = async {
+            for _ in 0..4 {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                timer.active();
+            }
+            std::future::pending::<()>().await;
+        } => unreachable!(),
+    };
+    assert_eq!(Expiry::Idle(limit), expiry);
+    // Active until 400ms in, then idle for the full limit
+    assert!(started.elapsed() >= Duration::from_millis(550));
+    // Warned each time activity was close to running out, and once more at the end
+    assert!(warnings >= 2, "{warnings}");
+}
+
+#[tokio::test]
+async fn test_max_duration_ignores_activity() {
+    let limit = Duration::from_millis(200);
+    let timer = SessionTimer::new(SessionLimits {
+        idle_timeout: Some(Duration::from_mins(1)),
+        max_duration: Some(limit),
+    });
+    let started = Instant::now();
+    let expiry = tokio::select! {
+        expiry = timer.expired(|_| {}) => expiry,
+        () = async {
+            loop {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                timer.active();
+            }
+        } => unreachable!(),
+    };
+    assert_eq!(Expiry::MaxDuration(limit), expiry);
+    let elapsed = started.elapsed();
+    assert!(
+        elapsed >= limit && elapsed < Duration::from_secs(5),
+        "{elapsed:?}"
+    );
+}
+
+#[tokio::test]
+async fn test_unlimited_session_never_expires() {
+    let timer = SessionTimer::new(SessionLimits::default());
+    assert!(!timer.is_limited());
+    let expired = tokio::time::timeout(Duration::from_millis(50), timer.expired(|_| {})).await;
+    assert!(expired.is_err());
+}
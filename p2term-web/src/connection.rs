@@ -1,18 +1,28 @@
 use crate::{TermSender, log};
 use anyhow::{Context, bail};
 use iroh::{PublicKey, SecretKey};
+use p2term_lib::client::predict::{PredictMode, Predictor};
+use p2term_lib::client::screen::Screen;
 use p2term_lib::client::server_handle::P2TermServerHandle;
 use p2term_lib::client::shell_proxy::ClientShellProxy;
 use p2term_lib::convert::HexConvert;
 use p2term_lib::error::unpack;
-use p2term_lib::proto::{ClientOpt, DEFAULT_TERM};
+use p2term_lib::proto::codec::{Frame, FrameReader, FrameWriter};
+use p2term_lib::proto::{
+    AttachMode, Capabilities, ClientOpt, DEFAULT_TERM, ExitStatus, SessionKind, TermSize,
+};
 use p2term_lib::streams::{ReadStream, WriteStream};
+use std::cell::RefCell;
 use std::path::PathBuf;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::rc::Rc;
+use std::time::Duration;
 use wasm_bindgen::JsValue;
 use wasm_bindgen_futures::js_sys;
 use wasm_bindgen_futures::js_sys::Uint8Array;
 
+/// The size xterm.js opens with, if none was given
+const DEFAULT_SIZE: (u16, u16) = (80, 24);
+
 #[derive(Debug)]
 pub(crate) struct Term(JsValue);
 
@@ -38,13 +48,40 @@ impl Term {
     }
 }
 
+#[derive(Debug)]
+pub(crate) enum OutboundMessage {
+    Data(String),
+    Resize { cols: u16, rows: u16 },
+}
+
+impl OutboundMessage {
+    fn into_frame(self) -> Frame {
+        match self {
+            Self::Data(data) => Frame::Stdin(data.into_bytes()),
+            Self::Resize { cols, rows } => Frame::Resize(term_size(cols, rows)),
+        }
+    }
+}
+
+fn term_size(cols: u16, rows: u16) -> TermSize {
+    TermSize {
+        rows,
+        cols,
+        ..TermSize::default()
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 pub async fn start_connection(
     term: Term,
     secret_key: &str,
     peer_public_key: &str,
     shell: Option<&str>,
     cwd: Option<&str>,
+    size: Option<(u16, u16)>,
+    attach: Option<(String, AttachMode)>,
     on_error: Option<js_sys::Function>,
+    predict: PredictMode,
 ) -> anyhow::Result<TermSender> {
     let secret_key =
         SecretKey::try_from_hex(secret_key.as_bytes()).context("failed to parse secret key")?;
@@ -52,41 +89,76 @@ pub async fn start_connection(
     let server_handle = P2TermServerHandle::connect(secret_key, pk)
         .await
         .context("failed to connect to server")?;
+    let (session, attach_mode) = attach.map_or(
+        (SessionKind::Shell, AttachMode::default()),
+        |(target, mode)| (SessionKind::Attach(target), mode),
+    );
     let opt = ClientOpt {
+        session,
+        attach_mode,
         shell: shell.map(std::string::ToString::to_string),
         cwd: cwd.map(PathBuf::from),
         // I think this is legit for xterm.js, though not 100% sure
         term: Some(DEFAULT_TERM.to_string()),
+        size: size.map(|(cols, rows)| term_size(cols, rows)),
+        ..ClientOpt::default()
     };
+    // The terminal was just opened, all it shows comes from the session
+    let (cols, rows) = size.unwrap_or(DEFAULT_SIZE);
+    let predictor = Predictor::new(predict, Screen::blank(cols, rows));
     let (send, recv) = tokio::sync::mpsc::channel(128);
     wasm_bindgen_futures::spawn_local(async move {
         let wsp = WebShellProxy {
             term,
             outbound_message_incoming: recv,
+            predictor,
         };
-        if let Err(e) = p2term_lib::client::runtime::run(server_handle, &opt, wsp).await {
-            log(&format!("failed to run shell proxy: {}", unpack(&*e)));
-            if let Some(func) = on_error {
-                let _ = func.call1(
-                    &JsValue::NULL,
-                    &JsValue::from_str(&format!("failed to run shell proxy: {}", unpack(&*e))),
-                );
+        match p2term_lib::client::runtime::run(server_handle, &opt, wsp).await {
+            Ok(status) => {
+                if let Some(func) = on_error {
+                    let _ = func.call1(&JsValue::NULL, &JsValue::from_str(&exit_message(status)));
+                }
+            }
+            Err(e) => {
+                log(&format!("failed to run shell proxy: {}", unpack(&*e)));
+                if let Some(func) = on_error {
+                    let _ = func.call1(
+                        &JsValue::NULL,
+                        &JsValue::from_str(&format!("failed to run shell proxy: {}", unpack(&*e))),
+                    );
+                }
             }
-        } else if let Some(func) = on_error {
-            let _ = func.call1(&JsValue::NULL, &JsValue::from_str("term exited"));
         }
     });
     Ok(TermSender(send))
 }
 
+fn exit_message(status: Option<ExitStatus>) -> String {
+    match status {
+        Some(ExitStatus {
+            signal: Some(signal),
+            ..
+        }) => format!("term exited by signal {signal:?}"),
+        Some(ExitStatus { code, .. }) => format!("term exited with code {code}"),
+        None => "term exited".to_string(),
+    }
+}
+
+/// Typing is echoed locally ahead of the session when echo is slow, see [`Predictor`]
 #[derive(Debug)]
 struct WebShellProxy {
     term: Term,
-    outbound_message_incoming: tokio::sync::mpsc::Receiver<String>,
+    outbound_message_incoming: tokio::sync::mpsc::Receiver<OutboundMessage>,
+    predictor: Predictor,
 }
 
 impl ClientShellProxy for WebShellProxy {
-    async fn run<W, R>(self, mut write: W, mut read: R) -> anyhow::Result<()>
+    async fn run<W, R>(
+        self,
+        mut write: FrameWriter<W>,
+        mut read: FrameReader<R>,
+        capabilities: Capabilities,
+    ) -> anyhow::Result<Option<ExitStatus>>
     where
         W: WriteStream,
         R: ReadStream,
@@ -94,57 +166,116 @@ impl ClientShellProxy for WebShellProxy {
         let Self {
             term,
             mut outbound_message_incoming,
+            predictor,
         } = self;
+        let write_fn = term.writer()?;
+        // Typing and output take turns on the same thread
+        let term = Rc::new(term);
+        let predictor = Rc::new(RefCell::new(predictor));
         let (reader_res_send, mut reader_res_recv) = tokio::sync::oneshot::channel();
-        wasm_bindgen_futures::spawn_local(async move {
-            let mut buf = [0u8; 1024];
-            let write_fn = match term.writer() {
-                Ok(f) => f,
-                Err(e) => {
-                    let _ = reader_res_send.send(e);
-                    return;
-                }
-            };
-            let err = loop {
-                let read_bytes = match read.read(&mut buf).await {
-                    Ok(rb) => rb,
-                    Err(e) => {
-                        break anyhow::anyhow!(
-                            "failed to read from remote terminal: {}",
-                            unpack(&e)
-                        );
-                    }
-                };
-                if read_bytes == 0 {
-                    break anyhow::anyhow!("remote terminal EOF");
-                }
-                if let Err(e) = term.invoke_write(&write_fn, &buf[..read_bytes]) {
-                    break anyhow::anyhow!(
-                        "failed to write remote terminal message to web term: {}",
-                        unpack(&*e)
-                    );
-                }
-            };
-            let _ = reader_res_send.send(err);
+        wasm_bindgen_futures::spawn_local({
+            let term = term.clone();
+            let predictor = predictor.clone();
+            async move {
+                let _ =
+                    reader_res_send.send(proxy_remote_output(&term, &mut read, &predictor).await);
+            }
         });
 
         loop {
+            let expires_at = predictor.borrow().expires_at();
             tokio::select! {
                 res = &mut reader_res_recv => {
                     match res {
-                        Ok(e) => bail!("remote terminal error: {}", unpack(&*e)),
+                        Ok(Ok(status)) => return Ok(status),
+                        Ok(Err(e)) => bail!("remote terminal error: {}", unpack(&*e)),
                         Err(_e) => bail!("remote terminal task failed"),
                     }
                 }
                 next = outbound_message_incoming.recv() => {
                     let Some(next) = next else {
-                        return Ok(());
+                        return Ok(None);
                     };
-                    if let Err(e) = write.write_all(next.as_bytes()).await {
-                        bail!("failed to write to remote terminal: {}", unpack(&e));
+                    let drawn = match &next {
+                        OutboundMessage::Data(data) => {
+                            predictor.borrow_mut().input(data.as_bytes(), now())
+                        }
+                        OutboundMessage::Resize { cols, rows } => {
+                            predictor.borrow_mut().resize(*cols, *rows)
+                        }
+                    };
+                    let skip = matches!(next, OutboundMessage::Resize { .. })
+                        && !capabilities.contains(Capabilities::RESIZE);
+                    if !skip && let Err(e) = write.write_frame(&next.into_frame()).await {
+                        bail!("failed to write to remote terminal: {}", unpack(&*e));
+                    }
+                    if !drawn.is_empty() {
+                        term.invoke_write(&write_fn, &drawn)
+                            .context("failed to write prediction to web term")?;
+                    }
+                }
+                () = expired(expires_at) => {
+                    let drawn = predictor.borrow_mut().tick(now());
+                    if !drawn.is_empty() {
+                        term.invoke_write(&write_fn, &drawn)
+                            .context("failed to write prediction to web term")?;
                     }
                 }
             }
         }
     }
 }
+
+async fn proxy_remote_output<R: ReadStream>(
+    term: &Term,
+    read: &mut FrameReader<R>,
+    predictor: &RefCell<Predictor>,
+) -> anyhow::Result<Option<ExitStatus>> {
+    let write_fn = term.writer()?;
+    let mut exit_status = None;
+    loop {
+        let frame = read
+            .read_frame()
+            .await
+            .context("failed to read from remote terminal")?;
+        match frame {
+            Some(Frame::Stdout(bytes) | Frame::Stderr(bytes)) => {
+                let bytes = predictor.borrow_mut().output(&bytes, now());
+                term.invoke_write(&write_fn, &bytes)
+                    .context("failed to write remote terminal message to web term")?;
+            }
+            Some(Frame::ExitStatus(status)) => exit_status = Some(status),
+            Some(Frame::Close) | None => return Ok(exit_status),
+            Some(_) => {}
+        }
+    }
+}
+
+/// Time for [`Predictor`], from any fixed point
+fn now() -> Duration {
+    Duration::try_from_secs_f64(js_sys::Date::now() / 1000.0).unwrap_or_default()
+}
+
+/// Completes once predictions are due to be taken back, never if there are none
+async fn expired(expires_at: Option<Duration>) {
+    match expires_at {
+        Some(expires_at) => sleep(expires_at.saturating_sub(now())).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Tokio has no timer in the browser, `setTimeout` does it instead
+async fn sleep(duration: Duration) {
+    let promise = js_sys::Promise::new(&mut |resolve, _reject| {
+        let set_timeout = js_sys::Reflect::get(&js_sys::global(), &JsValue::from_str("setTimeout"))
+            .map(js_sys::Function::from);
+        if let Ok(set_timeout) = set_timeout {
+            let _ = set_timeout.call2(
+                &JsValue::NULL,
+                &resolve,
+                &JsValue::from_f64(duration.as_secs_f64() * 1000.0),
+            );
+        }
+    });
+    let _ = wasm_bindgen_futures::JsFuture::from(promise).await;
+}
@@ -1,8 +1,10 @@
 mod connection;
 
-use crate::connection::{Term, start_connection};
+use crate::connection::{OutboundMessage, Term, start_connection};
+use p2term_lib::client::predict::PredictMode;
 use p2term_lib::convert::HexConvert;
 use p2term_lib::error::unpack;
+use p2term_lib::proto::AttachMode;
 use wasm_bindgen::JsValue;
 use wasm_bindgen::prelude::wasm_bindgen;
 use wasm_bindgen_futures::js_sys;
@@ -15,16 +17,23 @@ extern "C" {
 }
 
 #[wasm_bindgen]
-pub struct TermSender(tokio::sync::mpsc::Sender<String>);
+pub struct TermSender(tokio::sync::mpsc::Sender<OutboundMessage>);
 
 #[wasm_bindgen]
 impl TermSender {
     pub async fn on_data(&self, data: &str) -> Result<(), JsValue> {
         self.0
-            .send(data.to_string())
+            .send(OutboundMessage::Data(data.to_string()))
             .await
             .map_err(|e| JsValue::from_str(&format!("failed to send data: {}", unpack(&e))))
     }
+
+    pub async fn resize(&self, cols: u16, rows: u16) -> Result<(), JsValue> {
+        self.0
+            .send(OutboundMessage::Resize { cols, rows })
+            .await
+            .map_err(|e| JsValue::from_str(&format!("failed to send resize: {}", unpack(&e))))
+    }
 }
 
 #[wasm_bindgen]
@@ -34,21 +43,46 @@ pub fn generate_private_key() -> String {
 }
 
 #[wasm_bindgen]
+#[allow(clippy::too_many_arguments)]
 pub async fn connect(
     term: JsValue,
     secret_key: &str,
     public_key: &str,
     shell: Option<String>,
     cwd: Option<String>,
+    cols: Option<u16>,
+    rows: Option<u16>,
     on_error: Option<js_sys::Function>,
+    join: Option<String>,
+    watch: Option<String>,
+    predict: Option<String>,
 ) -> Result<TermSender, JsValue> {
+    let attach = match (join, watch) {
+        (Some(_), Some(_)) => {
+            return Err(JsValue::from_str(
+                "failed to connect: only one of join and watch can be given",
+            ));
+        }
+        (Some(target), None) => Some((target, AttachMode::Join)),
+        (None, Some(target)) => Some((target, AttachMode::Watch)),
+        (None, None) => None,
+    };
+    let predict = predict
+        .as_deref()
+        .map(str::parse::<PredictMode>)
+        .transpose()
+        .map_err(|e| JsValue::from_str(&format!("failed to connect: {}", unpack(&*e))))?
+        .unwrap_or_default();
     start_connection(
         Term::new(term),
         secret_key,
         public_key,
         shell.as_deref(),
         cwd.as_deref(),
+        cols.zip(rows),
+        attach,
         on_error,
+        predict,
     )
     .await
     .map_err(|e| JsValue::from_str(&format!("failed to connect: {}", unpack(&*e))))
@@ -0,0 +1,373 @@
+use anyhow::{Context, bail};
+use iroh::PublicKey;
+use iroh::endpoint::{RecvStream, SendStream};
+use p2term_lib::client::files::RemoteFiles;
+use p2term_lib::convert::HexConvert;
+use p2term_lib::files::{self, PartialFile};
+use p2term_lib::proto::files::{FileAttrs, FileError, FileErrorKind, FileKind, FileMeta};
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+pub type Files = RemoteFiles<SendStream, RecvStream>;
+
+/// How often the progress of a file is redrawn
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Either end of a copy, `peer:path` for a path on a peer, anything else is local
+#[derive(Debug, Clone)]
+pub enum Location {
+    Local(PathBuf),
+    Remote { peer: PublicKey, path: String },
+}
+
+impl FromStr for Location {
+    type Err = std::convert::Infallible;
+
+    fn from_str(location: &str) -> Result<Self, Self::Err> {
+        if let Some((peer, path)) = location.split_once(':')
+            && let Ok(peer) = PublicKey::try_from_hex(peer.as_bytes())
+        {
+            return Ok(Self::Remote {
+                peer,
+                path: path.to_string(),
+            });
+        }
+        Ok(Self::Local(PathBuf::from(location)))
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct CopyOpts {
+    /// Copy directories with everything in them
+    pub recursive: bool,
+    /// Give copies the modes and modification times of what they're copied from
+    pub preserve: bool,
+}
+
+/// Copies `source` to `target` on the peer, or into it if it's a directory.
+/// Symlinks inside copied directories are copied as symlinks, `source` itself is followed
+pub async fn upload(
+    files: &mut Files,
+    source: &Path,
+    target: &str,
+    opts: CopyOpts,
+) -> anyhow::Result<()> {
+    let meta = local_meta(source, true).await?;
+    if meta.kind == FileKind::Dir && !opts.recursive {
+        bail!("{} is a directory, copy it with -r", source.display());
+    }
+    let target = match files.stat(target).await {
+        Ok(existing) if existing.kind == FileKind::Dir => {
+            join_remote(target, &local_name(source).await?)
+        }
+        Ok(_) if meta.kind == FileKind::Dir => {
+            bail!("{target} on the peer is not a directory");
+        }
+        Err(e) if !has_kind(&e, FileErrorKind::NotFound) => return Err(e),
+        _ => target.to_string(),
+    };
+    let mut pending = vec![(source.to_path_buf(), target, meta)];
+    let mut dirs = Vec::new();
+    while let Some((local, remote, meta)) = pending.pop() {
+        match meta.kind {
+            FileKind::Dir => {
+                match files.create_dir(&remote, meta.mode).await {
+                    Err(e) if !has_kind(&e, FileErrorKind::AlreadyExists) => return Err(e),
+                    _ => {}
+                }
+                let mut entries = tokio::fs::read_dir(&local)
+                    .await
+                    .with_context(|| format!("failed to read directory {}", local.display()))?;
+                while let Some(entry) = entries
+                    .next_entry()
+                    .await
+                    .with_context(|| format!("failed to read directory {}", local.display()))?
+                {
+                    let path = entry.path();
+                    let name = entry.file_name().into_string().map_err(|_| {
+                        anyhow::anyhow!(
+                            "{} is not named in utf-8, which files on the peer must be",
+                            path.display()
+                        )
+                    })?;
+                    let meta = local_meta(&path, false).await?;
+                    pending.push((path, join_remote(&remote, &name), meta));
+                }
+                dirs.push((remote, meta));
+            }
+            FileKind::Symlink => {
+                let link_target = tokio::fs::read_link(&local)
+                    .await
+                    .with_context(|| format!("failed to read symlink {}", local.display()))?;
+                let link_target = link_target.to_str().with_context(|| {
+                    format!("{} links to a path that's not utf-8", local.display())
+                })?;
+                files.symlink(&remote, link_target).await?;
+            }
+            FileKind::File => {
+                let mut file = tokio::fs::File::open(&local)
+                    .await
+                    .with_context(|| format!("failed to open {}", local.display()))?;
+                let mut progress = Progress::start(&remote, meta.len);
+                let sent = files
+                    .write_from(&remote, meta.mode, &mut file, |sent| progress.update(sent))
+                    .await?;
+                progress.finish(sent);
+                if opts.preserve {
+                    files.set_attrs(&remote, preserved(&meta)).await?;
+                }
+            }
+            FileKind::Other => skip_other(&local.display().to_string()),
+        }
+    }
+    if opts.preserve {
+        // Writing into directories changes their mtimes, so they're set last, deepest first
+        for (remote, meta) in dirs.into_iter().rev() {
+            files.set_attrs(&remote, preserved(&meta)).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Copies `source` on the peer to `target`, or into it if it's a directory.
+/// Symlinks inside copied directories are copied as symlinks, `source` itself is followed
+pub async fn download(
+    files: &mut Files,
+    source: &str,
+    target: &Path,
+    opts: CopyOpts,
+) -> anyhow::Result<()> {
+    let meta = files.stat(source).await?;
+    if meta.kind == FileKind::Dir && !opts.recursive {
+        bail!("{source} is a directory, copy it with -r");
+    }
+    let target = match tokio::fs::metadata(target).await {
+        Ok(existing) if existing.is_dir() => target.join(remote_name(source)?),
+        Ok(_) if meta.kind == FileKind::Dir => {
+            bail!("{} is not a directory", target.display());
+        }
+        _ => target.to_path_buf(),
+    };
+    let mut pending = vec![(source.to_string(), target, meta)];
+    let mut dirs = Vec::new();
+    while let Some((remote, local, meta)) = pending.pop() {
+        match meta.kind {
+            FileKind::Dir => {
+                create_local_dir(&local, meta.mode).await?;
+                for entry in files.read_dir(&remote).await? {
+                    // The peer names the entries, they mustn't lead out of the directory
+                    let name = remote_name(&entry.name)?;
+                    if name != entry.name {
+                        bail!(
+                            "the peer listed an invalid entry {} in {remote}",
+                            entry.name
+                        );
+                    }
+                    pending.push((join_remote(&remote, name), local.join(name), entry.meta));
+                }
+                dirs.push((local, meta));
+            }
+            FileKind::Symlink => {
+                let link_target = files.read_link(&remote).await?;
+                files::symlink(&local, &link_target)
+                    .await
+                    .with_context(|| format!("failed to create symlink {}", local.display()))?;
+            }
+            FileKind::File => {
+                let mut partial = PartialFile::create(&local, meta.mode)
+                    .await
+                    .with_context(|| format!("failed to create {}", local.display()))?;
+                let mut progress = Progress::start(&local.display().to_string(), meta.len);
+                let received = files
+                    .read_to(&remote, &mut partial, |received| progress.update(received))
+                    .await?;
+                partial
+                    .finish()
+                    .await
+                    .with_context(|| format!("failed to write {}", local.display()))?;
+                progress.finish(received);
+                if opts.preserve {
+                    set_local_attrs(&local, &meta).await?;
+                }
+            }
+            FileKind::Other => skip_other(&remote),
+        }
+    }
+    if opts.preserve {
+        for (local, meta) in dirs.into_iter().rev() {
+            set_local_attrs(&local, &meta).await?;
+        }
+    }
+    Ok(())
+}
+
+pub async fn local_meta(path: &Path, follow: bool) -> anyhow::Result<FileMeta> {
+    let meta = if follow {
+        tokio::fs::metadata(path).await
+    } else {
+        tokio::fs::symlink_metadata(path).await
+    };
+    let meta = meta.with_context(|| format!("failed to read {}", path.display()))?;
+    Ok(FileMeta::from(&meta))
+}
+
+/// Creates a directory to copy into, or merges into one already there
+async fn create_local_dir(path: &Path, mode: u32) -> anyhow::Result<()> {
+    match files::create_dir(path, mode).await {
+        Ok(()) => Ok(()),
+        // Not a symlink, which could lead anywhere
+        Err(e)
+            if e.kind() == std::io::ErrorKind::AlreadyExists
+                && tokio::fs::symlink_metadata(path)
+                    .await
+                    .is_ok_and(|meta| meta.is_dir()) =>
+        {
+            Ok(())
+        }
+        Err(e) => Err(e).with_context(|| format!("failed to create directory {}", path.display())),
+    }
+}
+
+async fn set_local_attrs(path: &Path, meta: &FileMeta) -> anyhow::Result<()> {
+    files::set_attrs(path, preserved(meta))
+        .await
+        .with_context(|| format!("failed to set mode and mtime of {}", path.display()))
+}
+
+pub fn preserved(meta: &FileMeta) -> FileAttrs {
+    FileAttrs {
+        mode: Some(meta.mode),
+        mtime: Some(meta.mtime),
+    }
+}
+
+pub fn skip_other(path: &str) {
+    eprintln!("skipping {path}, only files, directories and symlinks are copied");
+}
+
+pub fn has_kind(e: &anyhow::Error, kind: FileErrorKind) -> bool {
+    e.downcast_ref::<FileError>()
+        .is_some_and(|e| e.kind == kind)
+}
+
+pub fn join_remote(dir: &str, name: &str) -> String {
+    if dir.is_empty() {
+        name.to_string()
+    } else if dir.ends_with('/') {
+        format!("{dir}{name}")
+    } else {
+        format!("{dir}/{name}")
+    }
+}
+
+/// The name a copy of `path` on the peer gets in a local directory
+fn remote_name(path: &str) -> anyhow::Result<&str> {
+    let name = path
+        .trim_end_matches('/')
+        .rsplit('/')
+        .next()
+        .unwrap_or_default();
+    if matches!(name, "" | "." | "..") || name.contains(std::path::MAIN_SEPARATOR) {
+        bail!("can't tell what to name a copy of {path}, give the full target path");
+    }
+    Ok(name)
+}
+
+/// The name a copy of `path` gets in a directory on the peer
+pub async fn local_name(path: &Path) -> anyhow::Result<String> {
+    let resolved = tokio::fs::canonicalize(path)
+        .await
+        .with_context(|| format!("failed to resolve {}", path.display()))?;
+    resolved
+        .file_name()
+        .and_then(|name| name.to_str())
+        .map(str::to_string)
+        .with_context(|| {
+            format!(
+                "can't tell what to name a copy of {}, give the full target path",
+                path.display()
+            )
+        })
+}
+
+/// Shows how far a file has been copied on stderr, if it's a terminal
+pub struct Progress {
+    name: String,
+    len: u64,
+    started: Instant,
+    drawn: Option<Instant>,
+    visible: bool,
+}
+
+impl Progress {
+    pub fn start(name: &str, len: u64) -> Self {
+        let progress = Self {
+            name: name.to_string(),
+            len,
+            started: Instant::now(),
+            drawn: None,
+            visible: std::io::stderr().is_terminal(),
+        };
+        progress.draw(0);
+        progress
+    }
+
+    pub fn update(&mut self, done: u64) {
+        if self
+            .drawn
+            .is_some_and(|drawn| drawn.elapsed() < PROGRESS_INTERVAL)
+        {
+            return;
+        }
+        self.drawn = Some(Instant::now());
+        self.draw(done);
+    }
+
+    pub fn finish(self, done: u64) {
+        if self.visible {
+            self.draw(done);
+            eprintln!();
+        }
+    }
+
+    fn draw(&self, done: u64) {
+        if !self.visible {
+            return;
+        }
+        let percent = done
+            .saturating_mul(100)
+            .checked_div(self.len)
+            .unwrap_or(100);
+        let millis = self.started.elapsed().as_millis().max(1);
+        let rate = u64::try_from(u128::from(done) * 1000 / millis).unwrap_or(u64::MAX);
+        eprint!(
+            "\r\x1b[K{} {}/{} {percent}% {}/s",
+            self.name,
+            human_bytes(done),
+            human_bytes(self.len),
+            human_bytes(rate)
+        );
+    }
+}
+
+pub fn human_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{bytes}B");
+    }
+    let mut scaled = bytes;
+    let mut unit = 0;
+    while scaled >= 1024 * 1024 && unit < UNITS.len() - 1 {
+        scaled /= 1024;
+        unit += 1;
+    }
+    // One decimal, from the remainder of the last division
+    format!(
+        "{}.{}{}",
+        scaled / 1024,
+        scaled % 1024 * 10 / 1024,
+        UNITS[unit]
+    )
+}
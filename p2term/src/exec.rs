@@ -0,0 +1,167 @@
+use crate::shell::{SignalListener, next_signal};
+use anyhow::Context;
+use p2term_lib::backpressure::{HandledOutput, PendingAcks};
+use p2term_lib::client::shell_proxy::ClientShellProxy;
+use p2term_lib::compression::OutputDecoder;
+use p2term_lib::proto::codec::{Frame, FrameReader, FrameWriter};
+use p2term_lib::proto::{Capabilities, ExitStatus};
+use p2term_lib::streams::{ReadStream, WriteStream};
+use std::io::{Read, Write};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// Proxies a session without a pty, local stdio is passed through untouched.
+/// Signals that would end `p2term` are forwarded to the remote command if the server
+/// can deliver them.
+#[derive(Debug)]
+pub struct ExecProxy;
+
+impl ClientShellProxy for ExecProxy {
+    async fn run<W, R>(
+        self,
+        write: FrameWriter<W>,
+        read: FrameReader<R>,
+        capabilities: Capabilities,
+    ) -> anyhow::Result<Option<ExitStatus>>
+    where
+        W: WriteStream,
+        R: ReadStream,
+    {
+        let signals = if capabilities.contains(Capabilities::SIGNAL) {
+            Some(SignalListener::new()?)
+        } else {
+            None
+        };
+        let (handled, acks) = p2term_lib::backpressure::acks(capabilities);
+        tokio::select! {
+            to_child_task = proxy_child_stdin(spawn_stdin_reader(), signals, acks, write) => {
+                to_child_task?;
+                Ok(None)
+            }
+            from_child_task = proxy_child_output(read, handled) => {
+                let exit_status = from_child_task?;
+                if capabilities.contains(Capabilities::EXIT_STATUS) {
+                    Ok(exit_status)
+                } else {
+                    Ok(Some(ExitStatus { code: 0, signal: None }))
+                }
+            }
+        }
+    }
+}
+
+/// Reads stdin on a detached thread, a blocking read can't be cancelled and
+/// would otherwise keep the process alive after the session ends.
+/// The channel closes on EOF.
+fn spawn_stdin_reader() -> tokio::sync::mpsc::Receiver<std::io::Result<Vec<u8>>> {
+    let (send, recv) = tokio::sync::mpsc::channel(16);
+    std::thread::spawn(move || {
+        let mut stdin = std::io::stdin().lock();
+        let mut buf = [0u8; 4096];
+        loop {
+            let res = match stdin.read(&mut buf) {
+                Ok(0) => return,
+                Ok(read_bytes) => Ok(buf[..read_bytes].to_vec()),
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => Err(e),
+            };
+            let failed = res.is_err();
+            if send.blocking_send(res).is_err() || failed {
+                return;
+            }
+        }
+    });
+    recv
+}
+
+async fn proxy_child_stdin<W: AsyncWrite + Unpin>(
+    mut stdin: tokio::sync::mpsc::Receiver<std::io::Result<Vec<u8>>>,
+    mut signals: Option<SignalListener>,
+    mut acks: PendingAcks,
+    mut writer: FrameWriter<W>,
+) -> anyhow::Result<()> {
+    let mut stdin_open = true;
+    let mut finished = false;
+    loop {
+        tokio::select! {
+            chunk = stdin.recv(), if stdin_open => {
+                if let Some(chunk) = chunk {
+                    let chunk = chunk.context("failed to read from stdin")?;
+                    writer
+                        .write_frame(&Frame::Stdin(chunk))
+                        .await
+                        .context("failed to write stdin over stream")?;
+                } else if signals.is_some() || acks.acknowledges() {
+                    stdin_open = false;
+                    writer
+                        .write_frame(&Frame::StdinEof)
+                        .await
+                        .context("failed to write stdin EOF over stream")?;
+                } else {
+                    stdin_open = false;
+                    finished = true;
+                    // Without signals, finishing the stream is how the remote sees stdin EOF
+                    writer.shutdown().await?;
+                }
+            }
+            ack = acks.next() => {
+                writer
+                    .write_frame(&ack)
+                    .await
+                    .context("failed to write output ack over stream")?;
+            }
+            Some(signal) = next_signal(signals.as_mut()) => {
+                writer
+                    .write_frame(&Frame::Signal(signal))
+                    .await
+                    .context("failed to write signal over stream")?;
+            }
+            // Not once the stream has been finished
+            () = writer.keepalive_due(), if !finished => {
+                writer
+                    .write_frame(&Frame::Keepalive)
+                    .await
+                    .context("failed to write keepalive over stream")?;
+            }
+            // The session ends when the remote command does
+            else => std::future::pending().await,
+        }
+    }
+}
+
+async fn proxy_child_output<R: AsyncRead + Unpin>(
+    mut reader: FrameReader<R>,
+    mut handled: HandledOutput,
+) -> anyhow::Result<Option<ExitStatus>> {
+    let mut exit_status = None;
+    let mut decoder = OutputDecoder::new()?;
+    loop {
+        let Some(frame) = reader
+            .read_frame()
+            .await
+            .context("failed to read frame from stream")?
+        else {
+            return Ok(exit_status);
+        };
+        // Acknowledged as it's taken, it's written out before the next is read
+        handled.handled(&frame);
+        let frame = match frame {
+            Frame::CompressedStdout(compressed) => Frame::Stdout(decoder.decode(&compressed)?),
+            frame => frame,
+        };
+        match frame {
+            Frame::Stdout(bytes) => {
+                let mut stdout = std::io::stdout();
+                stdout.write_all(&bytes)?;
+                stdout.flush()?;
+            }
+            Frame::Stderr(bytes) => {
+                let mut stderr = std::io::stderr();
+                stderr.write_all(&bytes)?;
+                stderr.flush()?;
+            }
+            Frame::ExitStatus(status) => exit_status = Some(status),
+            Frame::Close => return Ok(exit_status),
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,436 @@
+use anyhow::{Context, bail};
+use iroh::endpoint::{RecvStream, SendStream};
+use p2term_lib::client::connection::P2TermConnection;
+use p2term_lib::error::unpack;
+use p2term_lib::forward::{ForwardSide, Socket, splice};
+use p2term_lib::proto::codec::{Frame, FrameReader, FrameWriter};
+use p2term_lib::proto::{ClientOpt, ForwardedTcp, ForwardedUnix, SessionKind};
+use std::str::FromStr;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+
+/// `[bind_address:]port:host:hostport` like `ssh -L` and `ssh -R`, connections to the port
+/// listened on at one end are forwarded to `host:hostport` as reached from the other end.
+/// Either end may be a unix socket path instead, any part containing a `/` is taken as one
+#[derive(Debug, Clone)]
+pub struct ForwardSpec {
+    listen: Endpoint,
+    target: Endpoint,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+enum Endpoint {
+    Tcp { host: String, port: u16 },
+    Unix(String),
+}
+
+impl Endpoint {
+    /// The session that has the server connect to this endpoint
+    fn forward_session(&self) -> SessionKind {
+        match self {
+            Endpoint::Tcp { host, port } => SessionKind::ForwardTcp {
+                host: host.clone(),
+                port: *port,
+            },
+            Endpoint::Unix(path) => SessionKind::ForwardUnix { path: path.clone() },
+        }
+    }
+
+    /// The session that has the server listen on this endpoint
+    fn listen_session(&self) -> SessionKind {
+        match self {
+            Endpoint::Tcp { host, port } => SessionKind::ListenTcp {
+                host: host.clone(),
+                port: *port,
+            },
+            Endpoint::Unix(path) => SessionKind::ListenUnix { path: path.clone() },
+        }
+    }
+
+    async fn bind(&self) -> anyhow::Result<Listener> {
+        match self {
+            Endpoint::Tcp { host, port } => Ok(Listener::Tcp(
+                TcpListener::bind((host.as_str(), *port)).await?,
+            )),
+            #[cfg(unix)]
+            Endpoint::Unix(path) => Ok(Listener::Unix(
+                tokio::net::UnixListener::bind(path)?,
+                path.clone(),
+            )),
+            #[cfg(not(unix))]
+            Endpoint::Unix(_) => bail!("unix sockets are not supported on this platform"),
+        }
+    }
+
+    async fn connect(&self) -> anyhow::Result<Box<dyn Socket>> {
+        match self {
+            Endpoint::Tcp { host, port } => {
+                Ok(Box::new(TcpStream::connect((host.as_str(), *port)).await?))
+            }
+            #[cfg(unix)]
+            Endpoint::Unix(path) => Ok(Box::new(tokio::net::UnixStream::connect(path).await?)),
+            #[cfg(not(unix))]
+            Endpoint::Unix(_) => bail!("unix sockets are not supported on this platform"),
+        }
+    }
+}
+
+impl core::fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Endpoint::Tcp { host, port } => write!(f, "{host}:{port}"),
+            Endpoint::Unix(path) => f.write_str(path),
+        }
+    }
+}
+
+/// A `host:port` to bind or connect to
+pub type Address<'a> = (&'a str, u16);
+
+impl ForwardSpec {
+    /// The listening and target `host:port`, for forwards that can't be unix sockets
+    pub fn addresses(&self) -> anyhow::Result<(Address<'_>, Address<'_>)> {
+        match (&self.listen, &self.target) {
+            (
+                Endpoint::Tcp {
+                    host: bind_host,
+                    port: bind_port,
+                },
+                Endpoint::Tcp { host, port },
+            ) => Ok(((bind_host, *bind_port), (host, *port))),
+            _ => bail!("forward {self} can't use unix sockets"),
+        }
+    }
+}
+
+impl FromStr for ForwardSpec {
+    type Err = anyhow::Error;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let parts = split_spec(spec);
+        let (listen, target) = match parts.as_slice() {
+            [listen @ .., path] if is_path(path) => (listen, Endpoint::Unix((*path).to_string())),
+            [listen @ .., host, port] => (
+                listen,
+                Endpoint::Tcp {
+                    host: unbracket(host).to_string(),
+                    port: parse_port(port, spec)?,
+                },
+            ),
+            _ => bail!("forward {spec} is not [bind_address:]port:host:hostport"),
+        };
+        let listen = match listen {
+            [path] if is_path(path) => Endpoint::Unix((*path).to_string()),
+            [port] => Endpoint::Tcp {
+                host: "127.0.0.1".to_string(),
+                port: parse_port(port, spec)?,
+            },
+            [host, port] => Endpoint::Tcp {
+                host: unbracket(host).to_string(),
+                port: parse_port(port, spec)?,
+            },
+            _ => bail!("forward {spec} is not [bind_address:]port:host:hostport"),
+        };
+        Ok(Self { listen, target })
+    }
+}
+
+impl core::fmt::Display for ForwardSpec {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{} -> {}", self.listen, self.target)
+    }
+}
+
+fn is_path(part: &str) -> bool {
+    part.contains('/')
+}
+
+/// Splits on colons outside of brackets, so that ipv6 addresses can be given as `[::1]`
+fn split_spec(spec: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut bracketed = false;
+    for (ind, ch) in spec.char_indices() {
+        match ch {
+            '[' => bracketed = true,
+            ']' => bracketed = false,
+            ':' if !bracketed => {
+                parts.push(&spec[start..ind]);
+                start = ind + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push(&spec[start..]);
+    parts
+}
+
+fn unbracket(host: &str) -> &str {
+    host.strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(host)
+}
+
+fn parse_port(port: &str, spec: &str) -> anyhow::Result<u16> {
+    port.parse()
+        .with_context(|| format!("invalid port {port} in forward {spec}"))
+}
+
+/// A local port or socket bound for forwarding, not yet accepting
+#[derive(Debug)]
+pub struct LocalForwarder {
+    forward: ForwardSpec,
+    listener: Listener,
+}
+
+#[derive(Debug)]
+enum Listener {
+    Tcp(TcpListener),
+    /// Removes the socket file once dropped, so that the path can be bound again
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener, String),
+}
+
+impl Listener {
+    async fn accept(&self) -> std::io::Result<Box<dyn Socket>> {
+        match self {
+            Listener::Tcp(listener) => Ok(Box::new(listener.accept().await?.0)),
+            #[cfg(unix)]
+            Listener::Unix(listener, _) => Ok(Box::new(listener.accept().await?.0)),
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        if let Listener::Unix(_, path) = self {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+impl LocalForwarder {
+    /// Binds the local end, before connecting so that a taken port fails early
+    pub async fn bind(forward: ForwardSpec) -> anyhow::Result<Self> {
+        let listener = forward
+            .listen
+            .bind()
+            .await
+            .with_context(|| format!("failed to listen on {}", forward.listen))?;
+        Ok(Self { forward, listener })
+    }
+
+    /// Forwards every accepted connection over a session of its own, on whichever connection
+    /// `connections` currently holds
+    async fn run(self, connections: watch::Receiver<P2TermConnection>) -> anyhow::Result<()> {
+        loop {
+            let stream = self
+                .listener
+                .accept()
+                .await
+                .with_context(|| format!("failed to accept connection for {}", self.forward))?;
+            let connection = connections.borrow().clone();
+            let forward = self.forward.clone();
+            tokio::spawn(async move {
+                if let Err(e) = forward_connection(&connection, stream, &forward).await {
+                    // The terminal may be in raw mode
+                    eprint!("p2term: forward {forward} failed: {}\r\n", unpack(&*e));
+                }
+            });
+        }
+    }
+}
+
+/// Runs the forwarders until one fails
+pub async fn run_local_forwards(
+    forwarders: Vec<LocalForwarder>,
+    connections: watch::Receiver<P2TermConnection>,
+) -> anyhow::Result<()> {
+    let mut running = tokio::task::JoinSet::new();
+    for forwarder in forwarders {
+        running.spawn(forwarder.run(connections.clone()));
+    }
+    while let Some(joined) = running.join_next().await {
+        joined.context("forwarding task failed")??;
+    }
+    Ok(())
+}
+
+async fn forward_connection(
+    connection: &P2TermConnection,
+    stream: Box<dyn Socket>,
+    forward: &ForwardSpec,
+) -> anyhow::Result<()> {
+    let (write, read) = open_forward(connection, forward.target.forward_session()).await?;
+    splice(stream, write, read, ForwardSide::Client).await
+}
+
+/// Opens a forwarding `session`, returning once the server has connected to its target
+pub async fn open_forward(
+    connection: &P2TermConnection,
+    session: SessionKind,
+) -> anyhow::Result<(FrameWriter<SendStream>, FrameReader<RecvStream>)> {
+    let mut server_handle = connection.open_session().await?;
+    let client_opt = ClientOpt {
+        session,
+        ..ClientOpt::default()
+    };
+    server_handle.handshake(&client_opt).await?;
+    let (send, recv) = server_handle.decompose();
+    let mut read = FrameReader::new(recv);
+    loop {
+        match read
+            .read_frame()
+            .await?
+            .context("server closed the session before connecting")?
+        {
+            Frame::ForwardConnected => return Ok((FrameWriter::new(send), read)),
+            Frame::Stderr(reason) => bail!("{}", String::from_utf8_lossy(&reason)),
+            _ => {}
+        }
+    }
+}
+
+/// Has the server listen for each of `forwards`, carrying the connections it accepts to their
+/// local targets, on whichever connection `connections` holds. The forwards are requested
+/// again when the session resumes on a new connection. Fails if none of them could be set up
+pub async fn run_remote_forwards(
+    forwards: Vec<ForwardSpec>,
+    mut connections: watch::Receiver<P2TermConnection>,
+) -> anyhow::Result<()> {
+    if forwards.is_empty() {
+        return Ok(());
+    }
+    loop {
+        let connection = connections.borrow_and_update().clone();
+        let reconnected = tokio::select! {
+            served = serve_remote_forwards(&connection, &forwards) => {
+                served?;
+                connections.changed().await.is_ok()
+            }
+            Ok(()) = connections.changed() => true,
+        };
+        if !reconnected {
+            return Ok(());
+        }
+    }
+}
+
+/// Serves the remote forwards until the connection closes
+async fn serve_remote_forwards(
+    connection: &P2TermConnection,
+    forwards: &[ForwardSpec],
+) -> anyhow::Result<()> {
+    let mut listening = tokio::task::JoinSet::new();
+    for forward in forwards {
+        match request_listen(connection, forward).await {
+            Ok((send, read)) => {
+                let forward = forward.clone();
+                listening.spawn(async move {
+                    if let Err(e) = wait_for_listen_end(send, read).await {
+                        eprint!(
+                            "p2term: remote forward {forward} failed: {}\r\n",
+                            unpack(&*e)
+                        );
+                    }
+                });
+            }
+            Err(e) => eprint!(
+                "p2term: remote forward {forward} failed: {}\r\n",
+                unpack(&*e)
+            ),
+        }
+    }
+    if listening.is_empty() {
+        bail!("none of the remote forwards could be set up");
+    }
+    while let Some((send, recv)) = connection.accept_stream().await? {
+        let forwards = forwards.to_vec();
+        tokio::spawn(async move {
+            if let Err(e) = forward_accepted(send, recv, &forwards).await {
+                eprint!("p2term: remote forward failed: {}\r\n", unpack(&*e));
+            }
+        });
+    }
+    Ok(())
+}
+
+/// Asks the server to listen for `forward`, it listens for as long as the returned send
+/// stream is kept open, and the returned reader ends if it stops
+async fn request_listen(
+    connection: &P2TermConnection,
+    forward: &ForwardSpec,
+) -> anyhow::Result<(SendStream, FrameReader<RecvStream>)> {
+    let mut server_handle = connection.open_session().await?;
+    let client_opt = ClientOpt {
+        session: forward.listen.listen_session(),
+        ..ClientOpt::default()
+    };
+    server_handle.handshake(&client_opt).await?;
+    let (send, recv) = server_handle.decompose();
+    let mut read = FrameReader::new(recv);
+    loop {
+        match read
+            .read_frame()
+            .await?
+            .context("server closed the session before listening")?
+        {
+            Frame::ForwardListening(port) => {
+                let listening = match &forward.listen {
+                    Endpoint::Tcp { host, .. } => format!("{host}:{port}"),
+                    Endpoint::Unix(path) => path.clone(),
+                };
+                eprint!("remote forwarding {listening} -> {}\r\n", forward.target);
+                return Ok((send, read));
+            }
+            Frame::Stderr(reason) => bail!("{}", String::from_utf8_lossy(&reason)),
+            _ => {}
+        }
+    }
+}
+
+async fn wait_for_listen_end(
+    _send: SendStream,
+    mut read: FrameReader<RecvStream>,
+) -> anyhow::Result<()> {
+    loop {
+        match read.read_frame().await? {
+            None | Some(Frame::Close) => bail!("the server stopped listening"),
+            Some(_) => {}
+        }
+    }
+}
+
+/// Dials the local target of a connection the server accepted for one of `forwards`
+async fn forward_accepted(
+    send: SendStream,
+    recv: RecvStream,
+    forwards: &[ForwardSpec],
+) -> anyhow::Result<()> {
+    let mut write = FrameWriter::new(send);
+    let mut read = FrameReader::new(recv);
+    let (listen, origin) = match read.read_frame().await? {
+        Some(Frame::Forwarded(ForwardedTcp { host, port, origin })) => {
+            (Endpoint::Tcp { host, port }, origin)
+        }
+        Some(Frame::ForwardedUnix(ForwardedUnix { path })) => {
+            (Endpoint::Unix(path), "a unix socket client".to_string())
+        }
+        _ => bail!("server opened a stream that doesn't carry a forwarded connection"),
+    };
+    let forward = forwards
+        .iter()
+        .find(|forward| forward.listen == listen)
+        .with_context(|| format!("server forwarded a connection for unknown {listen}"))?;
+    let stream = match forward.target.connect().await {
+        Ok(stream) => stream,
+        Err(e) => {
+            // Hang up on the server's side of the connection
+            write.write_frame(&Frame::Close).await?;
+            return Err(e)
+                .with_context(|| format!("failed to connect to {} for {origin}", forward.target));
+        }
+    };
+    splice(stream, write, read, ForwardSide::Client).await
+}
@@ -1,16 +1,47 @@
-use crate::shell::ShellProxy;
+use crate::cp::{CopyOpts, Location};
+use crate::exec::ExecProxy;
+use crate::forward::{ForwardSpec, LocalForwarder, run_local_forwards, run_remote_forwards};
+use crate::sessions::SessionListProxy;
+use crate::shell::{Redial, ShellProxy};
+use crate::socks::serve_socks;
+use crate::sync::{Exclude, SyncOpts};
+use crate::udp::UdpForwarder;
+use anyhow::Context;
 use clap::Parser;
+use iroh::endpoint::{RecvStream, SendStream};
 use iroh::{PublicKey, SecretKey};
+use p2term_lib::client::connection::P2TermConnection;
+use p2term_lib::client::files::RemoteFiles;
+use p2term_lib::client::predict::PredictMode;
 use p2term_lib::client::runtime;
 use p2term_lib::client::server_handle::P2TermServerHandle;
 use p2term_lib::convert::HexConvert;
 use p2term_lib::crypto::{any_secret_key, generate_secret_key};
 use p2term_lib::error::unpack;
-use p2term_lib::proto::ClientOpt;
+use p2term_lib::proto::{
+    AttachMode, Capabilities, ClientOpt, Compression, ExitStatus, RejectReason, Rejection,
+    SessionKind,
+};
+use std::io::IsTerminal;
 use std::path::PathBuf;
 use std::process::ExitCode;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::sync::watch;
 
+mod cp;
+mod exec;
+mod forward;
+mod sessions;
+mod sftp;
 mod shell;
+mod socks;
+mod sync;
+mod transfer;
+mod udp;
+
+/// Exit code used when the session failed rather than the remote command, same as `ssh`
+const TRANSPORT_FAILURE: u8 = 255;
 
 #[derive(Debug, clap::Parser)]
 struct Args {
@@ -25,6 +56,52 @@ enum SubCommand {
         #[clap(flatten)]
         args: ConnectArgs,
     },
+    /// Run a single command on a peer, without a pty unless `-t` is given
+    Exec {
+        #[clap(flatten)]
+        args: ExecArgs,
+    },
+    /// List the persistent sessions on a peer that this client may attach to
+    Sessions {
+        #[clap(flatten)]
+        args: SessionsArgs,
+    },
+    /// Forward local tcp ports to targets reached from a peer, like `ssh -L`
+    ForwardLocal {
+        #[clap(flatten)]
+        args: ForwardLocalArgs,
+    },
+    /// Have a peer listen on ports, forwarding connections to local targets, like `ssh -R`
+    ForwardRemote {
+        #[clap(flatten)]
+        args: ForwardRemoteArgs,
+    },
+    /// Forward local udp ports to targets reached from a peer
+    ForwardUdp {
+        #[clap(flatten)]
+        args: ForwardUdpArgs,
+    },
+    /// Run a SOCKS5 proxy whose connections are made from a peer, like `ssh -D`
+    Socks {
+        #[clap(flatten)]
+        args: SocksArgs,
+    },
+    /// Copy files and directories to or from a peer, like `scp`
+    Cp {
+        #[clap(flatten)]
+        args: CpArgs,
+    },
+    /// Make a directory on a peer the same as a local one, sending only what changed,
+    /// like `rsync`
+    Sync {
+        #[clap(flatten)]
+        args: SyncArgs,
+    },
+    /// Browse and change the files on a peer interactively, like `sftp`
+    Sftp {
+        #[clap(flatten)]
+        args: SftpArgs,
+    },
     /// Generate a new keypair for use when making a connection
     GenerateKeys {
         /// Secret key output file
@@ -41,6 +118,253 @@ struct ConnectArgs {
     #[clap(long, short, env = "P2TERM_PEER")]
     peer: String,
 
+    #[clap(flatten)]
+    keys: KeyArgs,
+
+    /// Shell to use on server, must be available on the server
+    #[clap(long, env = "P2TERM_SHELL")]
+    shell: Option<String>,
+
+    /// Cwd for the shell on the server
+    #[clap(long, env = "P2TERM_CWD")]
+    cwd: Option<PathBuf>,
+
+    #[clap(flatten)]
+    pty: PtyArgs,
+
+    /// Keep the session running on the server after disconnecting, so that it can be
+    /// attached to again with `--attach`
+    #[clap(long)]
+    persist: bool,
+
+    /// Name for the persistent session, usable instead of its id with `--attach`
+    #[clap(long, requires = "persist")]
+    name: Option<String>,
+
+    /// Attach to a persistent session by id or name, replaying its recent output.
+    /// Other clients writing to the session are detached
+    #[clap(long, conflicts_with_all = ["persist", "shell", "cwd", "join", "watch"])]
+    attach: Option<String>,
+
+    /// Attach to a persistent session by id or name, writing to it alongside the clients
+    /// already attached
+    #[clap(long, conflicts_with_all = ["persist", "shell", "cwd", "watch"])]
+    join: Option<String>,
+
+    /// Watch a persistent session by id or name read-only, input is discarded
+    #[clap(long, conflicts_with_all = ["persist", "shell", "cwd"])]
+    watch: Option<String>,
+
+    /// Exit when the connection is lost, rather than dialing again and resuming the session
+    #[clap(long)]
+    no_reconnect: bool,
+
+    /// Forward a local port while connected, `[bind_address:]port:host:hostport`,
+    /// see `forward-local`
+    #[clap(short = 'L', long = "local-forward")]
+    local_forwards: Vec<ForwardSpec>,
+
+    /// Have the peer listen on a port while connected, `[bind_address:]port:host:hostport`,
+    /// see `forward-remote`
+    #[clap(short = 'R', long = "remote-forward")]
+    remote_forwards: Vec<ForwardSpec>,
+
+    /// Have the peer compress the shell's output, `zstd` or `none`. Only used if the peer
+    /// allows it too
+    #[clap(long, default_value = "zstd", env = "P2TERM_COMPRESSION")]
+    compression: Compression,
+
+    /// Echo typing locally before the server does, like mosh, `adaptive` (the default if no
+    /// value is given) while echo is slow, `always` or `never`. Only on lines the server
+    /// echoes, and not in full-screen applications
+    #[clap(long, num_args = 0..=1, require_equals = true, default_missing_value = "adaptive", env = "P2TERM_PREDICT")]
+    predict: Option<PredictMode>,
+}
+
+impl ConnectArgs {
+    /// The session to attach to and how, if any
+    fn attach_target(&mut self) -> Option<(String, AttachMode)> {
+        if let Some(target) = self.attach.take() {
+            Some((target, AttachMode::Takeover))
+        } else if let Some(target) = self.join.take() {
+            Some((target, AttachMode::Join))
+        } else {
+            self.watch.take().map(|target| (target, AttachMode::Watch))
+        }
+    }
+}
+
+#[derive(Debug, clap::Parser)]
+struct SessionsArgs {
+    /// The `node id`/`public key` of the peer to list sessions on
+    #[clap(env = "P2TERM_PEER")]
+    peer: String,
+
+    #[clap(flatten)]
+    keys: KeyArgs,
+}
+
+#[derive(Debug, clap::Parser)]
+struct ForwardLocalArgs {
+    /// The `node id`/`public key` of the peer to forward through
+    #[clap(env = "P2TERM_PEER")]
+    peer: String,
+
+    #[clap(flatten)]
+    keys: KeyArgs,
+
+    /// `[bind_address:]port:host:hostport`, connections to the local port are forwarded to
+    /// `host:hostport` as reached from the peer. The bind address defaults to `127.0.0.1`.
+    /// Either end may be a unix socket path instead
+    #[clap(required = true)]
+    forwards: Vec<ForwardSpec>,
+}
+
+#[derive(Debug, clap::Parser)]
+struct ForwardRemoteArgs {
+    /// The `node id`/`public key` of the peer to listen on
+    #[clap(env = "P2TERM_PEER")]
+    peer: String,
+
+    #[clap(flatten)]
+    keys: KeyArgs,
+
+    /// `[bind_address:]port:host:hostport`, connections to the port the peer listens on are
+    /// forwarded to `host:hostport` as reached from this machine.
+    /// The bind address defaults to `127.0.0.1`, port 0 lets the peer pick a free port.
+    /// Either end may be a unix socket path instead
+    #[clap(required = true)]
+    forwards: Vec<ForwardSpec>,
+}
+
+#[derive(Debug, clap::Parser)]
+struct ForwardUdpArgs {
+    /// The `node id`/`public key` of the peer to forward through
+    #[clap(env = "P2TERM_PEER")]
+    peer: String,
+
+    #[clap(flatten)]
+    keys: KeyArgs,
+
+    /// Seconds without datagrams either way after which a flow from a local address ends
+    #[clap(long, default_value_t = 60)]
+    idle_timeout: u64,
+
+    /// `[bind_address:]port:host:hostport`, datagrams to the local port are sent to
+    /// `host:hostport` from the peer, and replies back to their sender.
+    /// The bind address defaults to `127.0.0.1`
+    #[clap(required = true)]
+    forwards: Vec<ForwardSpec>,
+}
+
+#[derive(Debug, clap::Parser)]
+struct SocksArgs {
+    /// The `node id`/`public key` of the peer to make connections from
+    #[clap(env = "P2TERM_PEER")]
+    peer: String,
+
+    #[clap(flatten)]
+    keys: KeyArgs,
+
+    /// Local address to accept SOCKS5 clients on
+    #[clap(long, default_value = "127.0.0.1:1080")]
+    listen: String,
+}
+
+#[derive(Debug, clap::Parser)]
+struct CpArgs {
+    #[clap(flatten)]
+    keys: KeyArgs,
+
+    /// Copy directories with everything in them
+    #[clap(short, long)]
+    recursive: bool,
+
+    /// Keep the modes and modification times of what's copied
+    #[clap(short, long)]
+    preserve: bool,
+
+    /// Directory on the peer that relative paths there start from, defaults to the home
+    /// directory if the peer permits it, or else the first directory it permits
+    #[clap(long, env = "P2TERM_CWD")]
+    cwd: Option<PathBuf>,
+
+    /// What to copy, `peer:path` for a path on the peer
+    source: Location,
+
+    /// Where to copy it, `peer:path` for a path on the peer, into it if it's a directory
+    target: Location,
+}
+
+#[derive(Debug, clap::Parser)]
+struct SyncArgs {
+    #[clap(flatten)]
+    keys: KeyArgs,
+
+    /// Remove what's on the peer but not in the local directory, except what's excluded
+    #[clap(long)]
+    delete: bool,
+
+    /// Leave out entries matching a pattern, on both ends. Patterns without a `/` match
+    /// names anywhere, others paths from the synced directory, `**` matches across `/`
+    #[clap(long)]
+    exclude: Vec<Exclude>,
+
+    /// Compare files by their contents rather than by size and modification time
+    #[clap(short, long)]
+    checksum: bool,
+
+    /// Directory on the peer that relative paths there start from, defaults to the home
+    /// directory if the peer permits it, or else the first directory it permits
+    #[clap(long, env = "P2TERM_CWD")]
+    cwd: Option<PathBuf>,
+
+    /// The local directory, or file, to sync
+    source: PathBuf,
+
+    /// Where to sync it to, as `peer:path`
+    target: Location,
+}
+
+#[derive(Debug, clap::Parser)]
+struct SftpArgs {
+    /// The `node id`/`public key` of the peer to browse the files of
+    #[clap(env = "P2TERM_PEER")]
+    peer: String,
+
+    #[clap(flatten)]
+    keys: KeyArgs,
+
+    /// Directory on the peer to start in, defaults to the home directory if the peer
+    /// permits it, or else the first directory it permits
+    #[clap(long, env = "P2TERM_CWD")]
+    cwd: Option<PathBuf>,
+}
+
+#[derive(Debug, clap::Parser)]
+struct ExecArgs {
+    /// The `node id`/`public key` of the peer to run the command on
+    #[clap(env = "P2TERM_PEER")]
+    peer: String,
+
+    #[clap(flatten)]
+    keys: KeyArgs,
+
+    /// Cwd for the command on the server
+    #[clap(long, env = "P2TERM_CWD")]
+    cwd: Option<PathBuf>,
+
+    #[clap(flatten)]
+    pty: PtyArgs,
+
+    /// The command and its arguments, run as `<shell> -c <command>` with the server's default
+    /// shell, each argument quoted so that it stays one
+    #[clap(required = true, trailing_var_arg = true, allow_hyphen_values = true)]
+    command: Vec<String>,
+}
+
+#[derive(Debug, clap::Parser)]
+struct KeyArgs {
     /// Secret key hex
     #[clap(long, env = "P2TERM_SECRET_KEY_HEX")]
     secret_key_hex: Option<String>,
@@ -48,26 +372,76 @@ struct ConnectArgs {
     /// Secret key file
     #[clap(long, env = "P2TERM_SECRET_KEY_FILE")]
     secret_key_file: Option<PathBuf>,
+}
 
-    /// Shell to use on server, must be available on the server
-    #[clap(long, env = "P2TERM_SHELL")]
-    shell: Option<String>,
+#[derive(Debug, clap::Parser)]
+struct PtyArgs {
+    /// Force allocating a pty on the server
+    #[clap(short = 't', conflicts_with = "no_pty")]
+    force_pty: bool,
 
-    /// Cwd for the shell on the server
-    #[clap(long, env = "P2TERM_CWD")]
-    cwd: Option<PathBuf>,
+    /// Never allocate a pty on the server, stdout and stderr are then kept apart
+    #[clap(short = 'T')]
+    no_pty: bool,
+}
+
+impl PtyArgs {
+    /// Whether the session gets a pty, `default` applies if neither flag was given.
+    /// A pty needs a local terminal to drive it, without one there won't be a pty.
+    fn use_pty(&self, default: bool) -> bool {
+        let wanted = if self.no_pty {
+            false
+        } else {
+            self.force_pty || default
+        };
+        if wanted && !has_terminal() {
+            if self.force_pty {
+                eprintln!("pty will not be allocated without a local terminal");
+            }
+            return false;
+        }
+        wanted
+    }
+}
+
+fn has_terminal() -> bool {
+    std::io::stdin().is_terminal() && std::io::stdout().is_terminal()
 }
 
 #[tokio::main]
 async fn main() -> ExitCode {
     let args = Args::parse();
     match args.subcmd {
-        SubCommand::Connect { args } => match start_connection(args).await {
+        SubCommand::Connect { args } => session_exit_code(start_connection(args).await),
+        SubCommand::Exec { args } => session_exit_code(start_exec(args).await),
+        SubCommand::Sessions { args } => session_exit_code(list_sessions(args).await),
+        SubCommand::ForwardLocal { args } => match start_local_forwards(args).await {
             Ok(()) => ExitCode::SUCCESS,
-            Err(e) => {
-                eprintln!("error: {}", unpack(&*e));
-                ExitCode::FAILURE
-            }
+            Err(e) => report_failure(&e),
+        },
+        SubCommand::ForwardRemote { args } => match start_remote_forwards(args).await {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(e) => report_failure(&e),
+        },
+        SubCommand::ForwardUdp { args } => match start_udp_forwards(args).await {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(e) => report_failure(&e),
+        },
+        SubCommand::Socks { args } => match start_socks(args).await {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(e) => report_failure(&e),
+        },
+        SubCommand::Cp { args } => match start_copy(args).await {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(e) => report_failure(&e),
+        },
+        SubCommand::Sync { args } => match start_sync(args).await {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(e) => report_failure(&e),
+        },
+        SubCommand::Sftp { args } => match start_sftp(args).await {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(e) => report_failure(&e),
         },
         SubCommand::GenerateKeys {
             secret_key_output_file,
@@ -94,19 +468,306 @@ async fn main() -> ExitCode {
     }
 }
 
-async fn start_connection(args: ConnectArgs) -> anyhow::Result<()> {
-    let parsed = parse_args(&args)?;
+fn rejection_hint(rejection: &Rejection) -> &'static str {
+    match rejection.reason {
+        RejectReason::DeniedPeer => {
+            "this client's public key needs to be added to `allowed_peers` in the server's config"
+        }
+        RejectReason::DisallowedShell => {
+            "pick one of the server's allowed shells with `--shell`, or leave it out to use the server's default"
+        }
+        RejectReason::InvalidCwd => "`--cwd` must be an existing directory on the server",
+        RejectReason::ServerBusy => {
+            "the server has reached its `max_sessions` limit, try again later"
+        }
+        RejectReason::VersionMismatch => "upgrade whichever of `p2term` and `p2termd` is older",
+        RejectReason::SessionNotFound => {
+            "list the sessions this client may attach to with `p2term sessions <peer>`"
+        }
+        RejectReason::SessionNameTaken => {
+            "pick another `--name`, or attach to the existing session"
+        }
+        RejectReason::InvalidRequest => "the server can't serve this combination of options",
+        RejectReason::ReadOnly => {
+            "this client may only `--watch` the session, write access needs an `attach_grants` entry"
+        }
+        RejectReason::ForwardDenied => {
+            "the server's `permit_open` (`permit_listen` for remote forwards, with `_unix` for unix sockets) needs to allow this for this client"
+        }
+        RejectReason::FilesDenied => {
+            "the server's `permit_files` needs to list directories for this client, `--cwd` must be inside one of them"
+        }
+    }
+}
+
+fn session_exit_code(res: anyhow::Result<Option<ExitStatus>>) -> ExitCode {
+    match res {
+        Ok(status) => exit_code(status),
+        Err(e) => report_failure(&e),
+    }
+}
+
+fn report_failure(e: &anyhow::Error) -> ExitCode {
+    eprintln!("error: {}", unpack(&**e));
+    if let Some(rejection) = e.downcast_ref::<Rejection>() {
+        eprintln!("hint: {}", rejection_hint(rejection));
+    }
+    ExitCode::from(TRANSPORT_FAILURE)
+}
+
+/// Maps the remote exit status to a local exit code, signals become `128 + signal`
+fn exit_code(status: Option<ExitStatus>) -> ExitCode {
+    let Some(status) = status else {
+        eprintln!("session closed without an exit status");
+        return ExitCode::from(TRANSPORT_FAILURE);
+    };
+    let code = if let Some(signal) = status.signal {
+        128 + signal.number()
+    } else {
+        i32::try_from(status.code).unwrap_or(i32::MAX)
+    };
+    // Exit codes are truncated to 8 bits by the os anyway
+    ExitCode::from(u8::try_from(code & 0xff).unwrap_or(u8::MAX))
+}
+
+async fn start_connection(mut args: ConnectArgs) -> anyhow::Result<Option<ExitStatus>> {
+    let parsed = parse_args(&args.peer, &args.keys)?;
+    let forwarders = bind_local_forwards(std::mem::take(&mut args.local_forwards)).await?;
+    let server_handle = P2TermServerHandle::connect(parsed.secret_key.clone(), parsed.peer).await?;
+    let (connections_send, connections) = watch::channel(
+        server_handle
+            .connection()
+            .context("session is not running on a connection")?
+            .clone(),
+    );
+    let pty = args.pty.use_pty(true);
+    let attach = args.attach_target();
+    if !pty && (args.persist || attach.is_some()) {
+        anyhow::bail!("persistent sessions need a pty, and a local terminal to drive it");
+    }
+    let (session, attach_mode) = attach.map_or(
+        (SessionKind::Shell, AttachMode::default()),
+        |(target, mode)| (SessionKind::Attach(target), mode),
+    );
+    let reconnect = pty && !args.no_reconnect;
+    let client_opt = ClientOpt {
+        shell: args.shell,
+        cwd: args.cwd,
+        persist: args.persist,
+        session_name: args.name,
+        session,
+        attach_mode,
+        resumable: reconnect,
+        capabilities: args
+            .compression
+            .offered(Capabilities::SUPPORTED | Capabilities::KEEPALIVE | Capabilities::OUTPUT_ACK),
+        ..session_opt(pty)
+    };
+    let redial = reconnect.then(|| Redial {
+        secret_key: parsed.secret_key,
+        peer: parsed.peer,
+        term: client_opt.term.clone(),
+        attach_mode,
+        capabilities: client_opt.capabilities,
+        connections: connections_send,
+    });
+    let mut forwarding = tokio::spawn(run_local_forwards(forwarders, connections.clone()));
+    let remote_forwards = args.remote_forwards;
+    let remote_forwarding = tokio::spawn(async move {
+        if let Err(e) = run_remote_forwards(remote_forwards, connections).await {
+            // The shell keeps running without them, the terminal may be in raw mode
+            eprint!("p2term: {}\r\n", unpack(&*e));
+        }
+    });
+    let res = tokio::select! {
+        res = run_session(server_handle, &client_opt, pty, redial, args.predict) => res,
+        Ok(Err(e)) = &mut forwarding => Err(e),
+    };
+    forwarding.abort();
+    remote_forwarding.abort();
+    res
+}
+
+async fn start_local_forwards(args: ForwardLocalArgs) -> anyhow::Result<()> {
+    let parsed = parse_args(&args.peer, &args.keys)?;
+    let forwarders = bind_local_forwards(args.forwards).await?;
+    let connection = P2TermConnection::dial(parsed.secret_key, parsed.peer).await?;
+    let (_connections_send, connections) = watch::channel(connection.clone());
+    tokio::select! {
+        res = run_local_forwards(forwarders, connections) => res,
+        () = connection.closed() => anyhow::bail!("connection to peer {} closed", parsed.peer),
+        res = tokio::signal::ctrl_c() => res.context("failed to listen for ctrl-c"),
+    }
+}
+
+async fn start_remote_forwards(args: ForwardRemoteArgs) -> anyhow::Result<()> {
+    let parsed = parse_args(&args.peer, &args.keys)?;
+    let connection = P2TermConnection::dial(parsed.secret_key, parsed.peer).await?;
+    let (_connections_send, connections) = watch::channel(connection);
+    tokio::select! {
+        res = run_remote_forwards(args.forwards, connections) => {
+            res?;
+            anyhow::bail!("connection to peer {} closed", parsed.peer)
+        }
+        res = tokio::signal::ctrl_c() => res.context("failed to listen for ctrl-c"),
+    }
+}
+
+async fn start_udp_forwards(args: ForwardUdpArgs) -> anyhow::Result<()> {
+    let parsed = parse_args(&args.peer, &args.keys)?;
+    let mut forwarders = Vec::with_capacity(args.forwards.len());
+    for forward in args.forwards {
+        eprintln!("forwarding udp {forward}");
+        forwarders.push(UdpForwarder::bind(forward).await?);
+    }
+    let connection = P2TermConnection::dial(parsed.secret_key, parsed.peer).await?;
+    let idle = Duration::from_secs(args.idle_timeout);
+    let mut running = tokio::task::JoinSet::new();
+    for forwarder in forwarders {
+        running.spawn(forwarder.run(connection.clone(), idle));
+    }
+    tokio::select! {
+        Some(joined) = running.join_next() => joined.context("forwarding task failed")?,
+        () = connection.closed() => anyhow::bail!("connection to peer {} closed", parsed.peer),
+        res = tokio::signal::ctrl_c() => res.context("failed to listen for ctrl-c"),
+    }
+}
+
+async fn start_socks(args: SocksArgs) -> anyhow::Result<()> {
+    let parsed = parse_args(&args.peer, &args.keys)?;
+    let listener = TcpListener::bind(args.listen.as_str())
+        .await
+        .with_context(|| format!("failed to listen on {}", args.listen))?;
+    eprintln!("socks proxy listening on {}", args.listen);
+    let connection = P2TermConnection::dial(parsed.secret_key, parsed.peer).await?;
+    tokio::select! {
+        res = serve_socks(listener, connection.clone()) => res,
+        () = connection.closed() => anyhow::bail!("connection to peer {} closed", parsed.peer),
+        res = tokio::signal::ctrl_c() => res.context("failed to listen for ctrl-c"),
+    }
+}
+
+async fn start_copy(args: CpArgs) -> anyhow::Result<()> {
+    let opts = CopyOpts {
+        recursive: args.recursive,
+        preserve: args.preserve,
+    };
+    match (args.source, args.target) {
+        (Location::Local(source), Location::Remote { peer, path }) => {
+            let mut files = open_files(peer, &args.keys, args.cwd).await?;
+            cp::upload(&mut files, &source, &path, opts).await?;
+            files.close().await
+        }
+        (Location::Remote { peer, path }, Location::Local(target)) => {
+            let mut files = open_files(peer, &args.keys, args.cwd).await?;
+            cp::download(&mut files, &path, &target, opts).await?;
+            files.close().await
+        }
+        (Location::Local(_), Location::Local(_)) => {
+            anyhow::bail!("either the source or the target needs to be on a peer, as `peer:path`")
+        }
+        (Location::Remote { .. }, Location::Remote { .. }) => {
+            anyhow::bail!("copying between two peers is not supported, copy through this machine")
+        }
+    }
+}
+
+async fn start_sync(args: SyncArgs) -> anyhow::Result<()> {
+    let Location::Remote { peer, path } = args.target else {
+        anyhow::bail!("the target needs to be on a peer, as `peer:path`");
+    };
+    let opts = SyncOpts {
+        delete: args.delete,
+        checksum: args.checksum,
+        excludes: args.exclude,
+    };
+    let mut files = open_files(peer, &args.keys, args.cwd).await?;
+    sync::sync(&mut files, &args.source, &path, &opts).await?;
+    files.close().await
+}
+
+async fn start_sftp(args: SftpArgs) -> anyhow::Result<()> {
+    let peer = PublicKey::try_from_hex(args.peer.as_bytes())?;
+    let files = open_files(peer, &args.keys, args.cwd).await?;
+    sftp::run(files).await
+}
+
+async fn open_files(
+    peer: PublicKey,
+    keys: &KeyArgs,
+    cwd: Option<PathBuf>,
+) -> anyhow::Result<RemoteFiles<SendStream, RecvStream>> {
+    let secret_key = any_secret_key(
+        keys.secret_key_hex.as_deref(),
+        keys.secret_key_file.as_deref(),
+    )?;
+    let connection = P2TermConnection::dial(secret_key, peer).await?;
+    RemoteFiles::open(&connection, cwd).await
+}
+
+async fn bind_local_forwards(forwards: Vec<ForwardSpec>) -> anyhow::Result<Vec<LocalForwarder>> {
+    let mut forwarders = Vec::with_capacity(forwards.len());
+    for forward in forwards {
+        eprintln!("forwarding {forward}");
+        forwarders.push(LocalForwarder::bind(forward).await?);
+    }
+    Ok(forwarders)
+}
+
+async fn list_sessions(args: SessionsArgs) -> anyhow::Result<Option<ExitStatus>> {
+    let parsed = parse_args(&args.peer, &args.keys)?;
     let server_handle = P2TermServerHandle::connect(parsed.secret_key, parsed.peer).await?;
+    let client_opt = ClientOpt {
+        session: SessionKind::List,
+        ..session_opt(false)
+    };
+    runtime::run(server_handle, &client_opt, SessionListProxy).await
+}
+
+async fn start_exec(args: ExecArgs) -> anyhow::Result<Option<ExitStatus>> {
+    let parsed = parse_args(&args.peer, &args.keys)?;
+    let server_handle = P2TermServerHandle::connect(parsed.secret_key, parsed.peer).await?;
+    let pty = args.pty.use_pty(false);
+    let client_opt = ClientOpt {
+        cwd: args.cwd,
+        session: SessionKind::Exec(args.command),
+        capabilities: Capabilities::SUPPORTED | Capabilities::KEEPALIVE | Capabilities::OUTPUT_ACK,
+        ..session_opt(pty)
+    };
+    run_session(server_handle, &client_opt, pty, None, None).await
+}
+
+/// Options shared by every session, the terminal is only described if there will be a pty
+fn session_opt(pty: bool) -> ClientOpt {
+    if !pty {
+        return ClientOpt {
+            no_pty: true,
+            ..ClientOpt::default()
+        };
+    }
     #[cfg(unix)]
     let term = std::env::var("TERM").ok();
     #[cfg(not(unix))]
     let term = None;
-    let client_opt = ClientOpt {
-        shell: args.shell,
-        cwd: args.cwd,
+    ClientOpt {
         term,
-    };
-    runtime::run(server_handle, &client_opt, ShellProxy).await
+        size: shell::term_size(),
+        ..ClientOpt::default()
+    }
+}
+
+async fn run_session(
+    server_handle: P2TermServerHandle<SendStream, RecvStream>,
+    client_opt: &ClientOpt,
+    pty: bool,
+    redial: Option<Redial>,
+    predict: Option<PredictMode>,
+) -> anyhow::Result<Option<ExitStatus>> {
+    if pty {
+        runtime::run(server_handle, client_opt, ShellProxy::new(redial, predict)).await
+    } else {
+        runtime::run(server_handle, client_opt, ExecProxy).await
+    }
 }
 
 struct ParsedArgs {
@@ -114,11 +775,11 @@ struct ParsedArgs {
     secret_key: SecretKey,
 }
 
-fn parse_args(args: &ConnectArgs) -> anyhow::Result<ParsedArgs> {
-    let peer = PublicKey::try_from_hex(args.peer.as_bytes())?;
+fn parse_args(peer: &str, keys: &KeyArgs) -> anyhow::Result<ParsedArgs> {
+    let peer = PublicKey::try_from_hex(peer.as_bytes())?;
     let secret_key = any_secret_key(
-        args.secret_key_hex.as_deref(),
-        args.secret_key_file.as_deref(),
+        keys.secret_key_hex.as_deref(),
+        keys.secret_key_file.as_deref(),
     )?;
     Ok(ParsedArgs { peer, secret_key })
 }
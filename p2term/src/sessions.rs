@@ -0,0 +1,88 @@
+use anyhow::Context;
+use p2term_lib::client::shell_proxy::ClientShellProxy;
+use p2term_lib::convert::HexConvert;
+use p2term_lib::proto::codec::{Frame, FrameReader, FrameWriter};
+use p2term_lib::proto::{Capabilities, ExitStatus, SessionInfo};
+use p2term_lib::streams::{ReadStream, WriteStream};
+use std::time::SystemTime;
+
+/// Prints the persistent sessions the server lists for this client
+#[derive(Debug)]
+pub struct SessionListProxy;
+
+impl ClientShellProxy for SessionListProxy {
+    async fn run<W, R>(
+        self,
+        _write: FrameWriter<W>,
+        mut read: FrameReader<R>,
+        _capabilities: Capabilities,
+    ) -> anyhow::Result<Option<ExitStatus>>
+    where
+        W: WriteStream,
+        R: ReadStream,
+    {
+        loop {
+            let frame = read
+                .read_frame()
+                .await
+                .context("failed to read frame from stream")?
+                .context("server closed the stream without listing sessions")?;
+            if let Frame::SessionList(sessions) = frame {
+                print_sessions(&sessions);
+                return Ok(Some(ExitStatus {
+                    code: 0,
+                    signal: None,
+                }));
+            }
+        }
+    }
+}
+
+fn print_sessions(sessions: &[SessionInfo]) {
+    if sessions.is_empty() {
+        println!("No sessions");
+        return;
+    }
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs());
+    println!(
+        "{:<6} {:<16} {:<10} {:<9} {:<8} COMMAND",
+        "ID", "NAME", "OWNER", "AGE", "ATTACHED"
+    );
+    for session in sessions {
+        let mut owner = session.owner.to_hex();
+        owner.truncate(8);
+        println!(
+            "{:<6} {:<16} {:<10} {:<9} {:<8} {}",
+            session.id,
+            session.name.as_deref().unwrap_or("-"),
+            owner,
+            format_age(now.saturating_sub(session.created_at)),
+            if session.attached { "yes" } else { "no" },
+            session.command.join(" "),
+        );
+    }
+}
+
+fn format_age(secs: u64) -> String {
+    match secs {
+        0..60 => format!("{secs}s"),
+        60..3600 => format!("{}m", secs / 60),
+        3600..86400 => format!("{}h{}m", secs / 3600, secs % 3600 / 60),
+        _ => format!("{}d{}h", secs / 86400, secs % 86400 / 3600),
+    }
+}
+
+/// Tells the user how to get back to a persistent session they're attached to
+pub fn session_notice(session: &SessionInfo) -> String {
+    let name = session
+        .name
+        .as_deref()
+        .map(|name| format!(" ({name})"))
+        .unwrap_or_default();
+    format!(
+        "[p2term] persistent session {}{name}, reattach with `p2term connect --attach {}`\r\n",
+        session.id, session.id
+    )
+}
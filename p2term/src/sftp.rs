@@ -0,0 +1,375 @@
+use crate::cp::{self, CopyOpts, Files};
+use anyhow::{Context, bail};
+use p2term_lib::error::unpack;
+use p2term_lib::proto::files::{FileAttrs, FileKind, FileMeta, Timestamp};
+use std::io::{BufRead, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+
+const HELP: &str = "\
+cd [path]                          change the directory on the peer, back to the starting one without a path
+chmod <mode> <path>...             set the octal permission bits of paths on the peer
+get [-r] [-p] <path> [local-path]  copy from the peer, into the local directory without a local path
+help                               show this help
+lcd <local-path>                   change the local directory
+lpwd                               print the local directory
+ls [path]                          list a directory on the peer
+mkdir <path>...                    create directories on the peer
+put [-r] [-p] <local-path> [path]  copy to the peer, into the directory on the peer without a path
+pwd                                print the directory on the peer
+rename <from> <to>                 move an entry on the peer, also `mv`
+rm <path>...                       remove files or symlinks on the peer
+rmdir <path>...                    remove empty directories on the peer
+stat <path>...                     show the metadata of paths on the peer
+exit                               end the session, also `quit` or end of input
+
+`-r` copies directories with everything in them, `-p` keeps modes and modification times";
+
+/// Browses the files on the peer with commands read line by line from stdin, until `exit`
+/// or the end of input. Without a terminal the first failing command ends the session
+pub async fn run(files: Files) -> anyhow::Result<()> {
+    let interactive = std::io::stdin().is_terminal();
+    let mut sftp = Sftp::start(files).await?;
+    let mut lines = spawn_line_reader();
+    loop {
+        if interactive {
+            print!("sftp:{}> ", sftp.cwd);
+            std::io::stdout()
+                .flush()
+                .context("failed to write prompt")?;
+        }
+        let Some(line) = lines.recv().await else {
+            break;
+        };
+        let line = line.context("failed to read command from stdin")?;
+        match sftp.run_line(&line).await {
+            Ok(Flow::Continue) => {}
+            Ok(Flow::Exit) => break,
+            Err(e) if interactive => eprintln!("error: {}", unpack(&*e)),
+            Err(e) => return Err(e).with_context(|| format!("failed to run `{line}`")),
+        }
+    }
+    sftp.files.close().await
+}
+
+enum Flow {
+    Continue,
+    Exit,
+}
+
+struct Sftp {
+    files: Files,
+    /// Absolute, as resolved by the peer
+    cwd: String,
+    start: String,
+    local_cwd: PathBuf,
+}
+
+impl Sftp {
+    async fn start(mut files: Files) -> anyhow::Result<Self> {
+        let start = files.real_path(".").await?;
+        let local_cwd =
+            std::env::current_dir().context("failed to get the local working directory")?;
+        Ok(Self {
+            files,
+            cwd: start.clone(),
+            start,
+            local_cwd,
+        })
+    }
+
+    async fn run_line(&mut self, line: &str) -> anyhow::Result<Flow> {
+        let words = split_words(line)?;
+        let Some((command, args)) = words.split_first() else {
+            return Ok(Flow::Continue);
+        };
+        match command.as_str() {
+            "exit" | "quit" | "bye" => return Ok(Flow::Exit),
+            "help" | "?" => println!("{HELP}"),
+            "pwd" => println!("{}", self.cwd),
+            "lpwd" => println!("{}", self.local_cwd.display()),
+            "cd" => self.cd(args.first().map(String::as_str)).await?,
+            "lcd" => self.lcd(single(args, "lcd <local-path>")?).await?,
+            "ls" => self.ls(args.first().map_or(".", String::as_str)).await?,
+            "stat" => {
+                for path in at_least_one(args, "stat <path>...")? {
+                    self.stat(path).await?;
+                }
+            }
+            "get" => self.get(args).await?,
+            "put" => self.put(args).await?,
+            "mkdir" => {
+                for path in at_least_one(args, "mkdir <path>...")? {
+                    self.files.create_dir(&self.remote(path), 0o777).await?;
+                }
+            }
+            "rm" => {
+                for path in at_least_one(args, "rm <path>...")? {
+                    self.files.remove(&self.remote(path)).await?;
+                }
+            }
+            "rmdir" => {
+                for path in at_least_one(args, "rmdir <path>...")? {
+                    self.files.remove_dir(&self.remote(path)).await?;
+                }
+            }
+            "rename" | "mv" => {
+                let [from, to] = args else {
+                    bail!("usage: rename <from> <to>");
+                };
+                self.files
+                    .rename(&self.remote(from), &self.remote(to))
+                    .await?;
+            }
+            "chmod" => self.chmod(args).await?,
+            unknown => bail!("unknown command `{unknown}`, see `help`"),
+        }
+        Ok(Flow::Continue)
+    }
+
+    async fn cd(&mut self, path: Option<&str>) -> anyhow::Result<()> {
+        let Some(path) = path else {
+            self.cwd.clone_from(&self.start);
+            return Ok(());
+        };
+        let resolved = self.files.real_path(&self.remote(path)).await?;
+        if self.files.stat(&resolved).await?.kind != FileKind::Dir {
+            bail!("{path} is not a directory");
+        }
+        self.cwd = resolved;
+        Ok(())
+    }
+
+    async fn lcd(&mut self, path: &str) -> anyhow::Result<()> {
+        let resolved = tokio::fs::canonicalize(self.local_cwd.join(path))
+            .await
+            .with_context(|| format!("failed to resolve {path}"))?;
+        if !resolved.is_dir() {
+            bail!("{path} is not a directory");
+        }
+        self.local_cwd = resolved;
+        Ok(())
+    }
+
+    async fn ls(&mut self, path: &str) -> anyhow::Result<()> {
+        let remote = self.remote(path);
+        let meta = self.files.stat(&remote).await?;
+        if meta.kind != FileKind::Dir {
+            println!("{}", list_line(path, &meta));
+            return Ok(());
+        }
+        for entry in self.files.read_dir(&remote).await? {
+            println!("{}", list_line(&entry.name, &entry.meta));
+        }
+        Ok(())
+    }
+
+    async fn stat(&mut self, path: &str) -> anyhow::Result<()> {
+        let meta = self.files.stat(&self.remote(path)).await?;
+        println!("{path}");
+        println!("  kind:     {}", kind_name(meta.kind));
+        println!("  size:     {}", meta.len);
+        println!("  mode:     {:04o} ({})", meta.mode, mode_string(&meta));
+        println!("  modified: {} UTC", format_mtime(meta.mtime));
+        Ok(())
+    }
+
+    async fn get(&mut self, args: &[String]) -> anyhow::Result<()> {
+        let (opts, paths) = copy_args(args)?;
+        let (source, target) = match paths.as_slice() {
+            [source] => (self.remote(source), self.local_cwd.clone()),
+            [source, target] => (self.remote(source), self.local(target)),
+            _ => bail!("usage: get [-r] [-p] <path> [local-path]"),
+        };
+        cp::download(&mut self.files, &source, &target, opts).await
+    }
+
+    async fn put(&mut self, args: &[String]) -> anyhow::Result<()> {
+        let (opts, paths) = copy_args(args)?;
+        let (source, target) = match paths.as_slice() {
+            [source] => (self.local(source), self.cwd.clone()),
+            [source, target] => (self.local(source), self.remote(target)),
+            _ => bail!("usage: put [-r] [-p] <local-path> [path]"),
+        };
+        cp::upload(&mut self.files, &source, &target, opts).await
+    }
+
+    async fn chmod(&mut self, args: &[String]) -> anyhow::Result<()> {
+        let Some((mode, paths)) = args.split_first() else {
+            bail!("usage: chmod <mode> <path>...");
+        };
+        let mode = u32::from_str_radix(mode, 8)
+            .ok()
+            .filter(|mode| *mode <= 0o7777)
+            .with_context(|| format!("{mode} is not an octal mode like 644"))?;
+        let attrs = FileAttrs {
+            mode: Some(mode),
+            mtime: None,
+        };
+        for path in at_least_one(paths, "chmod <mode> <path>...")? {
+            self.files.set_attrs(&self.remote(path), attrs).await?;
+        }
+        Ok(())
+    }
+
+    /// A path on the peer, relative ones start from the current directory there
+    fn remote(&self, path: &str) -> String {
+        if path.starts_with('/') {
+            path.to_string()
+        } else {
+            cp::join_remote(&self.cwd, path)
+        }
+    }
+
+    fn local(&self, path: &str) -> PathBuf {
+        self.local_cwd.join(Path::new(path))
+    }
+}
+
+fn single<'a>(args: &'a [String], usage: &str) -> anyhow::Result<&'a str> {
+    match args {
+        [arg] => Ok(arg),
+        _ => bail!("usage: {usage}"),
+    }
+}
+
+fn at_least_one<'a>(args: &'a [String], usage: &str) -> anyhow::Result<&'a [String]> {
+    if args.is_empty() {
+        bail!("usage: {usage}");
+    }
+    Ok(args)
+}
+
+/// Splits `-r` and `-p`, or both as `-rp`, from the paths of `get` and `put`
+fn copy_args(args: &[String]) -> anyhow::Result<(CopyOpts, Vec<&str>)> {
+    let mut opts = CopyOpts {
+        recursive: false,
+        preserve: false,
+    };
+    let mut paths = Vec::with_capacity(2);
+    for arg in args {
+        match arg.strip_prefix('-') {
+            Some(flags) if !flags.is_empty() => {
+                for flag in flags.chars() {
+                    match flag {
+                        'r' => opts.recursive = true,
+                        'p' => opts.preserve = true,
+                        _ => bail!("unknown flag -{flag}"),
+                    }
+                }
+            }
+            _ => paths.push(arg.as_str()),
+        }
+    }
+    Ok((opts, paths))
+}
+
+/// Splits a command line on whitespace, keeping what's quoted with `'` or `"` together,
+/// a `\` escapes the next character outside of `'`
+fn split_words(line: &str) -> anyhow::Result<Vec<String>> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut quote = None;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some(open), c) if c == open => quote = None,
+            (None | Some('"'), '\\') => {
+                let escaped = chars.next().context("line ends in an escape")?;
+                word.get_or_insert_default().push(escaped);
+            }
+            (None, '\'' | '"') => {
+                quote = Some(c);
+                word.get_or_insert_default();
+            }
+            (None, c) if c.is_whitespace() => words.extend(word.take()),
+            (_, c) => word.get_or_insert_default().push(c),
+        }
+    }
+    if quote.is_some() {
+        bail!("line ends inside a quote");
+    }
+    words.extend(word);
+    Ok(words)
+}
+
+/// Reads stdin line by line on a detached thread, like the stdin of `exec`.
+/// The channel closes on EOF
+fn spawn_line_reader() -> tokio::sync::mpsc::Receiver<std::io::Result<String>> {
+    let (send, recv) = tokio::sync::mpsc::channel(1);
+    std::thread::spawn(move || {
+        for line in std::io::stdin().lock().lines() {
+            let failed = line.is_err();
+            if send.blocking_send(line).is_err() || failed {
+                return;
+            }
+        }
+    });
+    recv
+}
+
+fn list_line(name: &str, meta: &FileMeta) -> String {
+    format!(
+        "{} {:>12} {} {name}",
+        mode_string(meta),
+        meta.len,
+        format_mtime(meta.mtime)
+    )
+}
+
+fn kind_name(kind: FileKind) -> &'static str {
+    match kind {
+        FileKind::File => "file",
+        FileKind::Dir => "directory",
+        FileKind::Symlink => "symlink",
+        FileKind::Other => "other",
+    }
+}
+
+/// Like `ls -l`, `drwxr-xr-x`
+fn mode_string(meta: &FileMeta) -> String {
+    let mut mode = String::with_capacity(10);
+    mode.push(match meta.kind {
+        FileKind::File => '-',
+        FileKind::Dir => 'd',
+        FileKind::Symlink => 'l',
+        FileKind::Other => '?',
+    });
+    for shift in [6, 3, 0] {
+        let bits = meta.mode >> shift;
+        mode.push(if bits & 0o4 == 0 { '-' } else { 'r' });
+        mode.push(if bits & 0o2 == 0 { '-' } else { 'w' });
+        mode.push(if bits & 0o1 == 0 { '-' } else { 'x' });
+    }
+    mode
+}
+
+/// `YYYY-MM-DD HH:MM` in UTC
+fn format_mtime(mtime: Timestamp) -> String {
+    let days = mtime.secs.div_euclid(86400);
+    let secs_of_day = mtime.secs.rem_euclid(86400);
+    let (year, month, day) = civil_from_days(days);
+    format!(
+        "{year:04}-{month:02}-{day:02} {:02}:{:02}",
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60
+    )
+}
+
+/// The date `days` after the unix epoch, from <http://howardhinnant.github.io/date_algorithms.html>
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
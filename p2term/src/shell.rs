@@ -1,17 +1,53 @@
+use crate::transfer::{InputRequest, Transfers};
 use anyhow::Context;
+use iroh::endpoint::{RecvStream, SendStream};
+use iroh::{PublicKey, SecretKey};
+use p2term_lib::backpressure::{HandledOutput, PendingAcks};
+use p2term_lib::client::connection::P2TermConnection;
+use p2term_lib::client::predict::{PredictMode, Predictor};
+use p2term_lib::client::screen::Screen;
+use p2term_lib::client::server_handle::P2TermServerHandle;
 use p2term_lib::client::shell_proxy::ClientShellProxy;
+use p2term_lib::compression::OutputDecoder;
+use p2term_lib::error::unpack;
+use p2term_lib::proto::codec::{Frame, FrameReader, FrameWriter};
+use p2term_lib::proto::{
+    AttachMode, Capabilities, ClientOpt, ExitStatus, Rejection, ResumeToken, SessionKind, Signal,
+    TermSize,
+};
 use p2term_lib::streams::{ReadStream, WriteStream};
 use std::io::Read;
 use std::io::{Stdout, Write};
-use std::time::Duration;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
 use termion::raw::{IntoRawMode, RawTerminal};
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::{mpsc, watch};
 
+/// Proxies a session with a pty, the local terminal is put in raw mode.
+/// Transfers started from inside the session are handled locally, see [`Transfers`].
+/// If the server hands out a resume token and redialing is enabled, a lost connection is
+/// dialed again and the session resumed, replaying the output missed in between.
+/// With predictions, typing is echoed locally ahead of the server, see [`Predictor`].
 #[derive(Debug)]
-pub struct ShellProxy;
+pub struct ShellProxy {
+    redial: Option<Redial>,
+    predict: Option<PredictMode>,
+}
+
+impl ShellProxy {
+    pub fn new(redial: Option<Redial>, predict: Option<PredictMode>) -> Self {
+        Self { redial, predict }
+    }
+}
 
 impl ClientShellProxy for ShellProxy {
-    async fn run<W, R>(self, write: W, read: R) -> anyhow::Result<()>
+    async fn run<W, R>(
+        self,
+        write: FrameWriter<W>,
+        read: FrameReader<R>,
+        capabilities: Capabilities,
+    ) -> anyhow::Result<Option<ExitStatus>>
     where
         W: WriteStream,
         R: ReadStream,
@@ -21,56 +57,506 @@ impl ClientShellProxy for ShellProxy {
             "/bin/bash".to_string()
         });
         eprintln!("Spawning shell: {shell}");
-        let term_raw = std::io::stdout()
-            .into_raw_mode()
-            .context("Failed to enter raw mode")?;
+        let mut resize = if capabilities.contains(Capabilities::RESIZE) {
+            Some(ResizeListener::new()?)
+        } else {
+            None
+        };
+        let mut term = LocalTerm {
+            stdin: termion::async_stdin(),
+            stdout_raw: std::io::stdout()
+                .into_raw_mode()
+                .context("Failed to enter raw mode")?,
+            transfers: Transfers::default(),
+            echo: self.predict.map(LocalEcho::new),
+        };
+        let mut resume = ResumeState::default();
+        let mut end = proxy_connection(
+            &mut term,
+            resize.as_mut(),
+            &mut resume,
+            write,
+            read,
+            capabilities,
+        )
+        .await?;
+        loop {
+            let lost = match end {
+                ConnectionEnd::Exited(status) => return Ok(status),
+                ConnectionEnd::Lost(e) => e,
+            };
+            let (Some(redial), Some(token)) = (self.redial.as_ref(), resume.token) else {
+                return Err(lost);
+            };
+            status_line(&format!(
+                "connection lost: {}, reconnecting... (Ctrl-C to give up)",
+                unpack(&*lost)
+            ))?;
+            let Some((server_handle, capabilities)) =
+                redial.resume(&mut term.stdin, token, resume.offset).await?
+            else {
+                return Err(lost.context("gave up reconnecting"));
+            };
+            status_line("reconnected")?;
+            if let Some(echo) = term.echo.as_ref() {
+                // Status lines were written since it last saw the terminal
+                echo.forget()?;
+            }
+            let (write, read) = server_handle.into_frames();
+            end = proxy_connection(
+                &mut term,
+                resize.as_mut(),
+                &mut resume,
+                write,
+                read,
+                capabilities,
+            )
+            .await?;
+        }
+    }
+}
 
-        tokio::select! {
-            to_child_task = proxy_child_stdin(termion::async_stdin(), write) => {
-                to_child_task?;
+/// What's needed to dial the server again and resume the session
+#[derive(Debug)]
+pub struct Redial {
+    pub secret_key: SecretKey,
+    pub peer: PublicKey,
+    pub term: Option<String>,
+    pub attach_mode: AttachMode,
+    /// Offered again when resuming, compression included
+    pub capabilities: Capabilities,
+    /// Where forwards learn about the connection the session resumed on
+    pub connections: watch::Sender<P2TermConnection>,
+}
+
+/// Delay before the first redial, doubled after every failed attempt
+const REDIAL_BACKOFF_START: Duration = Duration::from_millis(500);
+const REDIAL_BACKOFF_MAX: Duration = Duration::from_secs(15);
+
+impl Redial {
+    /// Dials until the session is resumed, `None` if the user gave up.
+    /// Stops with an error if the server rejects resuming, the session is gone then.
+    async fn resume(
+        &self,
+        stdin: &mut termion::AsyncReader,
+        token: ResumeToken,
+        offset: u64,
+    ) -> anyhow::Result<Option<(P2TermServerHandle<SendStream, RecvStream>, Capabilities)>> {
+        let mut backoff = REDIAL_BACKOFF_START;
+        loop {
+            tokio::select! {
+                res = self.try_resume(token, offset) => match res {
+                    Ok(resumed) => return Ok(Some(resumed)),
+                    Err(e) if e.downcast_ref::<Rejection>().is_some() => return Err(e),
+                    Err(e) => status_line(&format!(
+                        "failed to reconnect: {}, retrying in {backoff:?}",
+                        unpack(&*e)
+                    ))?,
+                },
+                () = wait_for_interrupt(stdin) => return Ok(None),
             }
-            from_child_task = proxy_child_stdout(read, term_raw) => {
-                from_child_task?;
+            tokio::select! {
+                () = tokio::time::sleep(backoff) => {}
+                () = wait_for_interrupt(stdin) => return Ok(None),
+            }
+            backoff = (backoff * 2).min(REDIAL_BACKOFF_MAX);
+        }
+    }
+
+    async fn try_resume(
+        &self,
+        token: ResumeToken,
+        offset: u64,
+    ) -> anyhow::Result<(P2TermServerHandle<SendStream, RecvStream>, Capabilities)> {
+        let mut server_handle =
+            P2TermServerHandle::connect(self.secret_key.clone(), self.peer).await?;
+        let client_opt = ClientOpt {
+            term: self.term.clone(),
+            size: term_size(),
+            session: SessionKind::Resume { token, offset },
+            attach_mode: self.attach_mode,
+            capabilities: self.capabilities,
+            ..ClientOpt::default()
+        };
+        let capabilities = server_handle.handshake(&client_opt).await?;
+        if let Some(connection) = server_handle.connection() {
+            self.connections.send_replace(connection.clone());
+        }
+        Ok((server_handle, capabilities))
+    }
+}
+
+/// Completes when the user presses Ctrl-C, other input is dropped since there's nowhere to
+/// send it
+async fn wait_for_interrupt(stdin: &mut termion::AsyncReader) {
+    let mut buf = [0u8; 256];
+    loop {
+        match stdin.read(&mut buf) {
+            Ok(0) => tokio::time::sleep(Duration::from_millis(50)).await,
+            Ok(read_bytes) if buf[..read_bytes].contains(&CTRL_C) => return,
+            Ok(_) => {}
+            Err(_) => return,
+        }
+    }
+}
+
+const CTRL_C: u8 = 0x03;
+
+/// Prints a status line from `p2term` itself, the terminal is in raw mode
+pub fn status_line(msg: &str) -> anyhow::Result<()> {
+    let mut stderr = std::io::stderr();
+    write!(stderr, "\r\n[p2term] {msg}\r\n")?;
+    stderr.flush()?;
+    Ok(())
+}
+
+struct LocalTerm {
+    stdin: termion::AsyncReader,
+    stdout_raw: RawTerminal<Stdout>,
+    transfers: Transfers,
+    echo: Option<LocalEcho>,
+}
+
+/// Predictions shared by both directions of a session
+struct LocalEcho {
+    predictor: Mutex<Predictor>,
+    started: Instant,
+}
+
+impl LocalEcho {
+    fn new(mode: PredictMode) -> Self {
+        let (cols, rows) = local_size();
+        Self {
+            // Whatever the terminal showed before isn't known
+            predictor: Mutex::new(Predictor::new(mode, Screen::new(cols, rows))),
+            started: Instant::now(),
+        }
+    }
+
+    fn predictor(&self) -> MutexGuard<'_, Predictor> {
+        self.predictor
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+
+    /// Draws what typing is predicted to do
+    fn input(&self, bytes: &[u8]) -> anyhow::Result<()> {
+        let drawn = self.predictor().input(bytes, self.started.elapsed());
+        write_stdout(&drawn)
+    }
+
+    /// Output for the terminal, with predictions taken back before and drawn again after
+    fn output(&self, bytes: &[u8]) -> Vec<u8> {
+        self.predictor().output(bytes, self.started.elapsed())
+    }
+
+    /// Takes back predictions the server hasn't confirmed in time
+    fn tick(&self) -> anyhow::Result<()> {
+        let drawn = self.predictor().tick(self.started.elapsed());
+        write_stdout(&drawn)
+    }
+
+    fn resize(&self, size: TermSize) -> anyhow::Result<()> {
+        let drawn = self.predictor().resize(size.cols, size.rows);
+        write_stdout(&drawn)
+    }
+
+    /// Something other than the session wrote to the terminal
+    fn forget(&self) -> anyhow::Result<()> {
+        let (cols, rows) = local_size();
+        let drawn = self.predictor().resize(cols, rows);
+        write_stdout(&drawn)
+    }
+}
+
+/// The local terminal's `(cols, rows)`, a common default if it can't be told
+fn local_size() -> (u16, u16) {
+    termion::terminal_size().unwrap_or((80, 24))
+}
+
+/// Output that goes through predictions if there are any
+fn echoed(echo: Option<&LocalEcho>, bytes: Vec<u8>) -> Vec<u8> {
+    match echo {
+        Some(echo) => echo.output(&bytes),
+        None => bytes,
+    }
+}
+
+fn write_stdout(bytes: &[u8]) -> anyhow::Result<()> {
+    if bytes.is_empty() {
+        return Ok(());
+    }
+    let mut stdout = std::io::stdout();
+    stdout.write_all(bytes)?;
+    stdout.flush()?;
+    Ok(())
+}
+
+/// Where the session's output is at, for resuming without missing or repeating any
+#[derive(Debug, Default)]
+struct ResumeState {
+    token: Option<ResumeToken>,
+    offset: u64,
+}
+
+/// Why proxying over one connection stopped, local failures are errors instead
+enum ConnectionEnd {
+    Exited(Option<ExitStatus>),
+    /// The connection failed, the session may still be resumed
+    Lost(anyhow::Error),
+}
+
+async fn proxy_connection<W: AsyncWrite + Unpin, R: AsyncRead + Unpin>(
+    term: &mut LocalTerm,
+    resize: Option<&mut ResizeListener>,
+    resume: &mut ResumeState,
+    write: FrameWriter<W>,
+    read: FrameReader<R>,
+    capabilities: Capabilities,
+) -> anyhow::Result<ConnectionEnd> {
+    let (requests, mut requested) = mpsc::channel(1);
+    let (handled, acks) = p2term_lib::backpressure::acks(capabilities);
+    let echo = term.echo.as_ref();
+    let end = tokio::select! {
+        to_child_task = proxy_child_stdin(&mut term.stdin, resize, &mut requested, acks, echo, write) => to_child_task?,
+        from_child_task = proxy_child_stdout(read, &mut term.stdout_raw, &mut term.transfers, &requests, resume, handled, echo) => from_child_task?,
+    };
+    if matches!(end, ConnectionEnd::Exited(_)) && !capabilities.contains(Capabilities::EXIT_STATUS)
+    {
+        // The server can't tell how the shell exited, a clean close is all we get
+        return Ok(ConnectionEnd::Exited(Some(ExitStatus {
+            code: 0,
+            signal: None,
+        })));
+    }
+    Ok(end)
+}
+
+/// The current size of the local terminal, if it can be determined
+pub fn term_size() -> Option<TermSize> {
+    let (cols, rows) = termion::terminal_size().ok()?;
+    let (pixel_width, pixel_height) = termion::terminal_size_pixels().unwrap_or((0, 0));
+    Some(TermSize {
+        rows,
+        cols,
+        pixel_width,
+        pixel_height,
+    })
+}
+
+struct ResizeListener {
+    #[cfg(unix)]
+    winch: tokio::signal::unix::Signal,
+}
+
+impl ResizeListener {
+    #[cfg(unix)]
+    fn new() -> anyhow::Result<Self> {
+        let winch = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::window_change())
+            .context("failed to add signal handler for SIGWINCH")?;
+        Ok(Self { winch })
+    }
+
+    #[cfg(not(unix))]
+    #[allow(clippy::unnecessary_wraps)]
+    fn new() -> anyhow::Result<Self> {
+        Ok(Self {})
+    }
+
+    #[cfg(unix)]
+    async fn next(&mut self) -> Option<TermSize> {
+        loop {
+            self.winch.recv().await?;
+            if let Some(size) = term_size() {
+                return Some(size);
             }
         }
-        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    #[allow(clippy::unused_async)]
+    async fn next(&mut self) -> Option<TermSize> {
+        std::future::pending().await
+    }
+}
+
+/// Catches the signals that would end a non-interactive session locally, so that they
+/// can be forwarded to the remote process group instead
+pub struct SignalListener {
+    #[cfg(unix)]
+    int: tokio::signal::unix::Signal,
+    #[cfg(unix)]
+    term: tokio::signal::unix::Signal,
+    #[cfg(unix)]
+    hup: tokio::signal::unix::Signal,
+    #[cfg(unix)]
+    quit: tokio::signal::unix::Signal,
+}
+
+impl SignalListener {
+    #[cfg(unix)]
+    pub fn new() -> anyhow::Result<Self> {
+        use tokio::signal::unix::{SignalKind, signal};
+        Ok(Self {
+            int: signal(SignalKind::interrupt())
+                .context("failed to add signal handler for SIGINT")?,
+            term: signal(SignalKind::terminate())
+                .context("failed to add signal handler for SIGTERM")?,
+            hup: signal(SignalKind::hangup()).context("failed to add signal handler for SIGHUP")?,
+            quit: signal(SignalKind::quit()).context("failed to add signal handler for SIGQUIT")?,
+        })
+    }
+
+    #[cfg(not(unix))]
+    #[allow(clippy::unnecessary_wraps)]
+    pub fn new() -> anyhow::Result<Self> {
+        Ok(Self {})
+    }
+
+    #[cfg(unix)]
+    async fn next(&mut self) -> Option<Signal> {
+        tokio::select! {
+            Some(()) = self.int.recv() => Some(Signal::Int),
+            Some(()) = self.term.recv() => Some(Signal::Term),
+            Some(()) = self.hup.recv() => Some(Signal::Hup),
+            Some(()) = self.quit.recv() => Some(Signal::Quit),
+            else => None,
+        }
+    }
+
+    #[cfg(not(unix))]
+    #[allow(clippy::unused_async)]
+    async fn next(&mut self) -> Option<Signal> {
+        std::future::pending().await
+    }
+}
+
+/// The next signal to forward, never completes without a listener
+pub async fn next_signal(signals: Option<&mut SignalListener>) -> Option<Signal> {
+    match signals {
+        Some(signals) => signals.next().await,
+        None => std::future::pending().await,
+    }
+}
+
+async fn next_resize(resize: Option<&mut ResizeListener>) -> Option<TermSize> {
+    match resize {
+        Some(resize) => resize.next().await,
+        None => std::future::pending().await,
     }
 }
 
 async fn proxy_child_stdin<W: AsyncWrite + Unpin>(
-    mut this_stdin: termion::AsyncReader,
-    mut writer: W,
-) -> anyhow::Result<()> {
+    this_stdin: &mut termion::AsyncReader,
+    mut resize: Option<&mut ResizeListener>,
+    requested: &mut mpsc::Receiver<InputRequest>,
+    mut acks: PendingAcks,
+    echo: Option<&LocalEcho>,
+    mut writer: FrameWriter<W>,
+) -> anyhow::Result<ConnectionEnd> {
     let mut buf = [0u8; 4096];
     loop {
         let read_bytes = this_stdin
             .read(&mut buf)
             .context("failed to read from stdin")?;
-        if read_bytes > 0 {
-            writer
-                .write_all(&buf[..read_bytes])
-                .await
-                .context("failed to write bytes from term over stream")?;
+        let frame = if read_bytes > 0 {
+            Frame::Stdin(buf[..read_bytes].to_vec())
         } else {
-            tokio::time::sleep(Duration::from_millis(10)).await;
+            if let Some(echo) = echo {
+                echo.tick()?;
+            }
+            tokio::select! {
+                () = tokio::time::sleep(Duration::from_millis(10)) => continue,
+                Some(size) = next_resize(resize.as_deref_mut()) => {
+                    if let Some(echo) = echo {
+                        echo.resize(size)?;
+                    }
+                    Frame::Resize(size)
+                }
+                ack = acks.next() => ack,
+                () = writer.keepalive_due() => Frame::Keepalive,
+                Some(request) = requested.recv() => {
+                    if let Err(e) = crate::transfer::serve(request, this_stdin, &mut acks, &mut writer).await {
+                        return Ok(ConnectionEnd::Lost(e));
+                    }
+                    continue;
+                }
+            }
+        };
+        if let Err(e) = writer.write_frame(&frame).await {
+            return Ok(ConnectionEnd::Lost(
+                e.context("failed to write to the session over stream"),
+            ));
+        }
+        if let (Some(echo), Frame::Stdin(typed)) = (echo, &frame) {
+            echo.input(typed)?;
         }
     }
 }
 
 async fn proxy_child_stdout<R: AsyncRead + Unpin>(
-    mut reader: R,
-    mut stdout_raw: RawTerminal<Stdout>,
-) -> anyhow::Result<()> {
-    let mut buf = [0u8; 4096];
+    mut reader: FrameReader<R>,
+    stdout_raw: &mut RawTerminal<Stdout>,
+    transfers: &mut Transfers,
+    requests: &mpsc::Sender<InputRequest>,
+    resume: &mut ResumeState,
+    mut handled: HandledOutput,
+    echo: Option<&LocalEcho>,
+) -> anyhow::Result<ConnectionEnd> {
+    let mut exit_status = None;
+    // Every connection carries a compression stream of its own
+    let mut decoder = OutputDecoder::new()?;
     loop {
-        let read_bytes = reader
-            .read(&mut buf)
-            .await
-            .context("failed to read bytes from stream")?;
-        if read_bytes == 0 {
-            return Ok(());
+        let frame = match reader.read_frame().await {
+            Ok(Some(frame)) => {
+                // Acknowledged as it's taken, it's written out before the next is read
+                handled.handled(&frame);
+                frame
+            }
+            Ok(None) if resume.token.is_some() => {
+                return Ok(ConnectionEnd::Lost(anyhow::anyhow!(
+                    "stream closed before the session ended"
+                )));
+            }
+            Ok(None) => return Ok(ConnectionEnd::Exited(exit_status)),
+            Err(e) => {
+                return Ok(ConnectionEnd::Lost(
+                    e.context("failed to read frame from stream"),
+                ));
+            }
+        };
+        let frame = match frame {
+            Frame::CompressedStdout(compressed) => match decoder.decode(&compressed) {
+                Ok(bytes) => Frame::Stdout(bytes),
+                Err(e) => return Ok(ConnectionEnd::Lost(e)),
+            },
+            frame => frame,
+        };
+        match frame {
+            Frame::Stdout(bytes) => {
+                resume.offset += bytes.len() as u64;
+                // Predicted echo goes around the scanner, typing a transfer's escape
+                // sequence ahead of the server mustn't start one
+                transfers
+                    .output(&bytes, stdout_raw, requests, |bytes| echoed(echo, bytes))
+                    .await?;
+            }
+            Frame::Stderr(bytes) => {
+                let mut stderr = std::io::stderr();
+                stderr.write_all(&echoed(echo, bytes))?;
+                stderr.flush()?;
+            }
+            Frame::Session(session) => {
+                let notice = crate::sessions::session_notice(&session).into_bytes();
+                let mut stderr = std::io::stderr();
+                stderr.write_all(&echoed(echo, notice))?;
+                stderr.flush()?;
+            }
+            Frame::ResumeToken(token) => resume.token = Some(token),
+            Frame::OutputOffset(offset) => resume.offset = offset,
+            Frame::ExitStatus(status) => exit_status = Some(status),
+            Frame::Close => return Ok(ConnectionEnd::Exited(exit_status)),
+            _ => {}
         }
-        stdout_raw.write_all(&buf[..read_bytes])?;
-        stdout_raw.flush()?;
     }
 }
@@ -0,0 +1,141 @@
+use crate::forward::open_forward;
+use anyhow::{Context, bail};
+use p2term_lib::client::connection::P2TermConnection;
+use p2term_lib::error::unpack;
+use p2term_lib::forward::{ForwardSide, splice};
+use p2term_lib::proto::{RejectReason, Rejection, SessionKind};
+use std::net::{Ipv4Addr, Ipv6Addr};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+const SOCKS_VERSION: u8 = 5;
+const METHOD_NO_AUTH: u8 = 0;
+const METHOD_NONE_ACCEPTABLE: u8 = 0xff;
+const CMD_CONNECT: u8 = 1;
+const ATYP_IPV4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+
+const REP_SUCCEEDED: u8 = 0;
+const REP_GENERAL_FAILURE: u8 = 1;
+const REP_NOT_ALLOWED: u8 = 2;
+const REP_COMMAND_NOT_SUPPORTED: u8 = 7;
+const REP_ADDRESS_TYPE_NOT_SUPPORTED: u8 = 8;
+
+/// Serves SOCKS5 clients on `listener`, each `CONNECT` is forwarded over a session of its own
+/// and dialed by the peer, so names are resolved on the peer's side as with `socks5h`
+pub async fn serve_socks(
+    listener: TcpListener,
+    connection: P2TermConnection,
+) -> anyhow::Result<()> {
+    loop {
+        let (stream, addr) = listener
+            .accept()
+            .await
+            .context("failed to accept socks connection")?;
+        let connection = connection.clone();
+        tokio::spawn(async move {
+            if let Err(e) = serve_socks_client(&connection, stream).await {
+                eprintln!(
+                    "p2term: socks connection from {addr} failed: {}",
+                    unpack(&*e)
+                );
+            }
+        });
+    }
+}
+
+async fn serve_socks_client(
+    connection: &P2TermConnection,
+    mut stream: TcpStream,
+) -> anyhow::Result<()> {
+    let (host, port) = read_connect_request(&mut stream).await?;
+    let session = SessionKind::ForwardTcp {
+        host: host.clone(),
+        port,
+    };
+    match open_forward(connection, session).await {
+        Ok((write, read)) => {
+            reply(&mut stream, REP_SUCCEEDED).await?;
+            splice(stream, write, read, ForwardSide::Client).await
+        }
+        Err(e) => {
+            reply(&mut stream, reply_code(&e)).await?;
+            Err(e).with_context(|| format!("failed to connect to {host}:{port}"))
+        }
+    }
+}
+
+/// Negotiates no authentication and reads the destination of a `CONNECT` request,
+/// other requests are answered with a failure
+async fn read_connect_request(stream: &mut TcpStream) -> anyhow::Result<(String, u16)> {
+    let mut greeting = [0u8; 2];
+    stream
+        .read_exact(&mut greeting)
+        .await
+        .context("failed to read socks greeting")?;
+    if greeting[0] != SOCKS_VERSION {
+        bail!("unsupported socks version {}", greeting[0]);
+    }
+    let mut methods = vec![0u8; usize::from(greeting[1])];
+    stream
+        .read_exact(&mut methods)
+        .await
+        .context("failed to read socks authentication methods")?;
+    if !methods.contains(&METHOD_NO_AUTH) {
+        stream
+            .write_all(&[SOCKS_VERSION, METHOD_NONE_ACCEPTABLE])
+            .await?;
+        bail!("socks client requires authentication, which isn't supported");
+    }
+    stream.write_all(&[SOCKS_VERSION, METHOD_NO_AUTH]).await?;
+    // Version, command, reserved, address type
+    let mut request = [0u8; 4];
+    stream
+        .read_exact(&mut request)
+        .await
+        .context("failed to read socks request")?;
+    if request[1] != CMD_CONNECT {
+        reply(stream, REP_COMMAND_NOT_SUPPORTED).await?;
+        bail!("unsupported socks command {}", request[1]);
+    }
+    let host = match request[3] {
+        ATYP_IPV4 => {
+            let mut octets = [0u8; 4];
+            stream.read_exact(&mut octets).await?;
+            Ipv4Addr::from(octets).to_string()
+        }
+        ATYP_DOMAIN => {
+            let len = stream.read_u8().await?;
+            let mut name = vec![0u8; usize::from(len)];
+            stream.read_exact(&mut name).await?;
+            String::from_utf8(name).context("socks destination is not a valid host name")?
+        }
+        ATYP_IPV6 => {
+            let mut octets = [0u8; 16];
+            stream.read_exact(&mut octets).await?;
+            Ipv6Addr::from(octets).to_string()
+        }
+        atyp => {
+            reply(stream, REP_ADDRESS_TYPE_NOT_SUPPORTED).await?;
+            bail!("unsupported socks address type {atyp}");
+        }
+    };
+    let port = stream.read_u16().await?;
+    Ok((host, port))
+}
+
+/// The bound address is left unspecified, it's the peer's and of no use to the client
+async fn reply(stream: &mut TcpStream, code: u8) -> anyhow::Result<()> {
+    stream
+        .write_all(&[SOCKS_VERSION, code, 0, ATYP_IPV4, 0, 0, 0, 0, 0, 0])
+        .await
+        .context("failed to reply to socks client")
+}
+
+fn reply_code(error: &anyhow::Error) -> u8 {
+    match error.downcast_ref::<Rejection>() {
+        Some(rejection) if rejection.reason == RejectReason::ForwardDenied => REP_NOT_ALLOWED,
+        _ => REP_GENERAL_FAILURE,
+    }
+}
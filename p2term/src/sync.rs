@@ -0,0 +1,412 @@
+use crate::cp::{self, Files, Progress};
+use anyhow::{Context, bail};
+use p2term_lib::client::files::Signature;
+use p2term_lib::proto::files::{FileAttrs, FileErrorKind, FileKind, FileMeta};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Files are compared and patched in blocks this long
+const BLOCK_LEN: u32 = 64 * 1024;
+
+#[derive(Debug)]
+pub struct SyncOpts {
+    /// Remove what's on the peer but not here
+    pub delete: bool,
+    /// Compare files by their contents rather than by size and modification time
+    pub checksum: bool,
+    pub excludes: Vec<Exclude>,
+}
+
+impl SyncOpts {
+    fn excluded(&self, rel: &str, name: &str, is_dir: bool) -> bool {
+        self.excludes
+            .iter()
+            .any(|exclude| exclude.matches(rel, name, is_dir))
+    }
+}
+
+/// An `--exclude` pattern. Without a `/` it's matched against the names of entries anywhere
+/// in the tree, with one against their paths from the synced directory. A trailing `/`
+/// only matches directories. `*` and `?` match within a name, `**` across names
+#[derive(Debug, Clone)]
+pub struct Exclude {
+    pattern: Vec<char>,
+    whole_path: bool,
+    dirs_only: bool,
+}
+
+impl FromStr for Exclude {
+    type Err = anyhow::Error;
+
+    fn from_str(pattern: &str) -> Result<Self, Self::Err> {
+        let dirs_only = pattern.ends_with('/');
+        let trimmed = pattern.trim_end_matches('/');
+        let whole_path = trimmed.contains('/');
+        let trimmed = trimmed.trim_start_matches('/');
+        if trimmed.is_empty() {
+            bail!("exclude pattern {pattern} matches nothing");
+        }
+        Ok(Self {
+            pattern: trimmed.chars().collect(),
+            whole_path,
+            dirs_only,
+        })
+    }
+}
+
+impl Exclude {
+    fn matches(&self, rel: &str, name: &str, is_dir: bool) -> bool {
+        if self.dirs_only && !is_dir {
+            return false;
+        }
+        let text: Vec<char> = if self.whole_path { rel } else { name }.chars().collect();
+        glob(&self.pattern, &text)
+    }
+}
+
+fn glob(pattern: &[char], text: &[char]) -> bool {
+    match pattern {
+        [] => text.is_empty(),
+        ['*', '*', rest @ ..] => (0..=text.len()).any(|skip| glob(rest, &text[skip..])),
+        ['*', rest @ ..] => (0..=text.len())
+            .take_while(|&skip| !text[..skip].contains(&'/'))
+            .any(|skip| glob(rest, &text[skip..])),
+        ['?', rest @ ..] => text
+            .split_first()
+            .is_some_and(|(c, text)| *c != '/' && glob(rest, text)),
+        [c, rest @ ..] => text
+            .split_first()
+            .is_some_and(|(t, text)| t == c && glob(rest, text)),
+    }
+}
+
+#[derive(Debug, Default)]
+struct Stats {
+    files: u64,
+    updated: u64,
+    /// Bytes of the updated files
+    len: u64,
+    sent: u64,
+    deleted: u64,
+}
+
+/// Makes `target` on the peer the same as `source`, the contents of a directory are
+/// synced into `target`, a file is synced into it if it's a directory. Files that differ
+/// are patched with only the blocks that changed, resuming from an interrupted sync
+pub async fn sync(
+    files: &mut Files,
+    source: &Path,
+    target: &str,
+    opts: &SyncOpts,
+) -> anyhow::Result<()> {
+    let meta = cp::local_meta(source, true).await?;
+    let existing = remote_meta(files, target).await?;
+    let mut stats = Stats::default();
+    match meta.kind {
+        FileKind::Dir => sync_tree(files, source, target, meta, existing, opts, &mut stats).await?,
+        FileKind::File => {
+            if existing.is_some_and(|existing| existing.kind == FileKind::Dir) {
+                let target = cp::join_remote(target, &cp::local_name(source).await?);
+                let existing = remote_meta(files, &target).await?;
+                sync_file(files, source, &target, &meta, existing, opts, &mut stats).await?;
+            } else {
+                sync_file(files, source, target, &meta, existing, opts, &mut stats).await?;
+            }
+        }
+        FileKind::Symlink | FileKind::Other => {
+            bail!("{} is not a file or directory", source.display());
+        }
+    }
+    eprintln!(
+        "{} files, {} updated, sent {} of {}, {} deleted",
+        stats.files,
+        stats.updated,
+        cp::human_bytes(stats.sent),
+        cp::human_bytes(stats.len),
+        stats.deleted
+    );
+    Ok(())
+}
+
+/// A directory to sync the entries of
+struct PendingDir {
+    local: PathBuf,
+    remote: String,
+    /// From the synced directory, to match excludes against
+    rel: String,
+    /// Whether it was on the peer before, if not there's nothing in it to compare with
+    existed: bool,
+}
+
+async fn sync_tree(
+    files: &mut Files,
+    source: &Path,
+    target: &str,
+    meta: FileMeta,
+    existing: Option<FileMeta>,
+    opts: &SyncOpts,
+    stats: &mut Stats,
+) -> anyhow::Result<()> {
+    match existing {
+        Some(existing) if existing.kind != FileKind::Dir => {
+            bail!("{target} on the peer is not a directory");
+        }
+        Some(_) => {}
+        None => files.create_dir(target, meta.mode).await?,
+    }
+    let mut pending = vec![PendingDir {
+        local: source.to_path_buf(),
+        remote: target.to_string(),
+        rel: String::new(),
+        existed: existing.is_some(),
+    }];
+    let mut dirs = vec![(target.to_string(), meta)];
+    while let Some(dir) = pending.pop() {
+        let mut remote_entries: HashMap<String, FileMeta> = if dir.existed {
+            files
+                .read_dir(&dir.remote)
+                .await?
+                .into_iter()
+                .map(|entry| (entry.name, entry.meta))
+                .collect()
+        } else {
+            HashMap::new()
+        };
+        for (name, meta) in read_local_dir(&dir.local, &dir.rel, opts).await? {
+            let local = dir.local.join(&name);
+            let remote = cp::join_remote(&dir.remote, &name);
+            let rel = join_rel(&dir.rel, &name);
+            let mut existing = remote_entries.remove(&name);
+            // Whatever's there of another kind makes way, directories only with `--delete`
+            if let Some(other) = existing
+                && other.kind != meta.kind
+                && (other.kind == FileKind::Dir || meta.kind == FileKind::Dir)
+            {
+                replace(files, &remote, other.kind, opts, stats).await?;
+                existing = None;
+            }
+            match meta.kind {
+                FileKind::Dir => {
+                    if existing.is_none() {
+                        files.create_dir(&remote, meta.mode).await?;
+                    }
+                    pending.push(PendingDir {
+                        local,
+                        remote: remote.clone(),
+                        rel,
+                        existed: existing.is_some(),
+                    });
+                    dirs.push((remote, meta));
+                }
+                FileKind::File => {
+                    sync_file(files, &local, &remote, &meta, existing, opts, stats).await?;
+                }
+                FileKind::Symlink => sync_symlink(files, &local, &remote, existing, stats).await?,
+                FileKind::Other => cp::skip_other(&local.display().to_string()),
+            }
+        }
+        if opts.delete {
+            for (name, meta) in remote_entries {
+                let rel = join_rel(&dir.rel, &name);
+                if !opts.excluded(&rel, &name, meta.kind == FileKind::Dir) {
+                    let remote = cp::join_remote(&dir.remote, &name);
+                    remove_tree(files, &remote, meta.kind, stats).await?;
+                }
+            }
+        }
+    }
+    // Syncing into directories changes their mtimes, so they're set last, deepest first
+    for (remote, meta) in dirs.into_iter().rev() {
+        files.set_attrs(&remote, cp::preserved(&meta)).await?;
+    }
+    Ok(())
+}
+
+async fn sync_file(
+    files: &mut Files,
+    local: &Path,
+    remote: &str,
+    meta: &FileMeta,
+    existing: Option<FileMeta>,
+    opts: &SyncOpts,
+    stats: &mut Stats,
+) -> anyhow::Result<()> {
+    stats.files += 1;
+    let same_file =
+        existing.filter(|existing| existing.kind == FileKind::File && existing.len == meta.len);
+    if let Some(existing) = same_file
+        && !opts.checksum
+        && existing.mtime.secs == meta.mtime.secs
+    {
+        return keep_file(files, remote, meta, &existing).await;
+    }
+    let signature = files.signature(remote, BLOCK_LEN).await?;
+    if let Some(existing) = same_file
+        && !signature.resumed
+        && has_contents(local, &signature).await?
+    {
+        return keep_file(files, remote, meta, &existing).await;
+    }
+    let mut file = tokio::fs::File::open(local)
+        .await
+        .with_context(|| format!("failed to open {}", local.display()))?;
+    let mut progress = Progress::start(remote, meta.len);
+    let sent = files
+        .patch_from(remote, meta.mode, meta.len, &signature, &mut file, |done| {
+            progress.update(done);
+        })
+        .await?;
+    progress.finish(meta.len);
+    files.set_attrs(remote, cp::preserved(meta)).await?;
+    stats.updated += 1;
+    stats.len += meta.len;
+    stats.sent += sent;
+    Ok(())
+}
+
+/// Leaves the contents of a file that's up to date, only fixing up its attributes
+async fn keep_file(
+    files: &mut Files,
+    remote: &str,
+    meta: &FileMeta,
+    existing: &FileMeta,
+) -> anyhow::Result<()> {
+    let attrs = FileAttrs {
+        mode: (existing.mode != meta.mode).then_some(meta.mode),
+        mtime: (existing.mtime != meta.mtime).then_some(meta.mtime),
+    };
+    if attrs != FileAttrs::default() {
+        files.set_attrs(remote, attrs).await?;
+    }
+    Ok(())
+}
+
+/// Whether the file at `local` is made of the blocks of `signature`
+async fn has_contents(local: &Path, signature: &Signature) -> anyhow::Result<bool> {
+    let mut file = tokio::fs::File::open(local)
+        .await
+        .with_context(|| format!("failed to open {}", local.display()))?;
+    signature
+        .matches(&mut file)
+        .await
+        .with_context(|| format!("failed to read {}", local.display()))
+}
+
+async fn sync_symlink(
+    files: &mut Files,
+    local: &Path,
+    remote: &str,
+    existing: Option<FileMeta>,
+    stats: &mut Stats,
+) -> anyhow::Result<()> {
+    let link_target = tokio::fs::read_link(local)
+        .await
+        .with_context(|| format!("failed to read symlink {}", local.display()))?;
+    let link_target = link_target
+        .to_str()
+        .with_context(|| format!("{} links to a path that's not utf-8", local.display()))?;
+    if existing.is_some_and(|existing| existing.kind == FileKind::Symlink)
+        && files.read_link(remote).await? == link_target
+    {
+        return Ok(());
+    }
+    files.symlink(remote, link_target).await?;
+    stats.updated += 1;
+    Ok(())
+}
+
+/// Clears what's at `remote` for an entry of another kind
+async fn replace(
+    files: &mut Files,
+    remote: &str,
+    kind: FileKind,
+    opts: &SyncOpts,
+    stats: &mut Stats,
+) -> anyhow::Result<()> {
+    if kind == FileKind::Dir && !opts.delete {
+        bail!("{remote} is a directory on the peer, sync with --delete to replace it");
+    }
+    remove_tree(files, remote, kind, stats).await
+}
+
+/// Removes `remote`, with everything in it if it's a directory
+async fn remove_tree(
+    files: &mut Files,
+    remote: &str,
+    kind: FileKind,
+    stats: &mut Stats,
+) -> anyhow::Result<()> {
+    if kind != FileKind::Dir {
+        files.remove(remote).await?;
+        stats.deleted += 1;
+        return Ok(());
+    }
+    // Directories come up again to be removed once they've been emptied
+    let mut pending = vec![(remote.to_string(), false)];
+    while let Some((dir, emptied)) = pending.pop() {
+        if emptied {
+            files.remove_dir(&dir).await?;
+            stats.deleted += 1;
+            continue;
+        }
+        pending.push((dir.clone(), true));
+        for entry in files.read_dir(&dir).await? {
+            let path = cp::join_remote(&dir, &entry.name);
+            if entry.meta.kind == FileKind::Dir {
+                pending.push((path, false));
+            } else {
+                files.remove(&path).await?;
+                stats.deleted += 1;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The entries of `dir` that aren't excluded, by name
+async fn read_local_dir(
+    dir: &Path,
+    rel: &str,
+    opts: &SyncOpts,
+) -> anyhow::Result<Vec<(String, FileMeta)>> {
+    let mut read_dir = tokio::fs::read_dir(dir)
+        .await
+        .with_context(|| format!("failed to read directory {}", dir.display()))?;
+    let mut entries = Vec::new();
+    while let Some(entry) = read_dir
+        .next_entry()
+        .await
+        .with_context(|| format!("failed to read directory {}", dir.display()))?
+    {
+        let path = entry.path();
+        let name = entry.file_name().into_string().map_err(|_| {
+            anyhow::anyhow!(
+                "{} is not named in utf-8, which files on the peer must be",
+                path.display()
+            )
+        })?;
+        let meta = cp::local_meta(&path, false).await?;
+        if !opts.excluded(&join_rel(rel, &name), &name, meta.kind == FileKind::Dir) {
+            entries.push((name, meta));
+        }
+    }
+    entries.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+    Ok(entries)
+}
+
+async fn remote_meta(files: &mut Files, path: &str) -> anyhow::Result<Option<FileMeta>> {
+    match files.stat(path).await {
+        Ok(meta) => Ok(Some(meta)),
+        Err(e) if cp::has_kind(&e, FileErrorKind::NotFound) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn join_rel(rel: &str, name: &str) -> String {
+    if rel.is_empty() {
+        name.to_string()
+    } else {
+        format!("{rel}/{name}")
+    }
+}
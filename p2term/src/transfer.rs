@@ -0,0 +1,423 @@
+use crate::cp::human_bytes;
+use crate::shell::status_line;
+use anyhow::Context;
+use p2term_lib::backpressure::PendingAcks;
+use p2term_lib::client::inband::{
+    OutputEvent, OutputScanner, UPLOAD_ABORT, UPLOAD_ACCEPT, UPLOAD_END, UPLOAD_FORMAT_RAW,
+    UPLOAD_LINE_LEN, ZMODEM_CANCEL, upload_lines,
+};
+use p2term_lib::files::PartialFile;
+use p2term_lib::proto::codec::{Frame, FrameWriter};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use termion::event::Key;
+use termion::input::TermRead;
+use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::{mpsc, oneshot};
+
+/// Lines of an upload sent at a time
+const UPLOAD_LINES_PER_FRAME: usize = 64;
+
+/// Handles the transfers a session's output starts, see [`p2term_lib::client::inband`].
+/// Kept across reconnects, since resuming picks up the output exactly where it was
+#[derive(Debug, Default)]
+pub struct Transfers {
+    scanner: OutputScanner,
+    download: Option<Download>,
+}
+
+/// A download being saved, there's none if the user didn't want it or saving it failed
+#[derive(Debug)]
+struct Download {
+    partial: PartialFile,
+    path: PathBuf,
+    size: Option<u64>,
+    received: u64,
+    /// Whether the user agreed to replace what's at `path`
+    overwrite: bool,
+}
+
+/// Where the user wants a download saved
+#[derive(Debug)]
+pub struct SaveAs {
+    path: PathBuf,
+    /// Whether the user agreed to replace what's there
+    overwrite: bool,
+}
+
+/// What the output of a session needs from its input side, which has local stdin and
+/// writes to the session
+#[derive(Debug)]
+pub enum InputRequest {
+    /// Ask the user where to save a download, `None` to skip it
+    Save {
+        name: String,
+        size: Option<u64>,
+        answer: oneshot::Sender<Option<SaveAs>>,
+    },
+    /// Ask the user which file to upload, and type it into the session
+    Upload { format: Option<String> },
+    /// Make `sz` or `rz` give up
+    CancelZmodem,
+}
+
+impl Transfers {
+    /// Writes output to the terminal, except for transfers, which are handled locally.
+    /// Only the session's own output is scanned, `shown` turns what isn't a transfer into
+    /// what the terminal gets
+    pub async fn output<O: Write>(
+        &mut self,
+        bytes: &[u8],
+        stdout: &mut O,
+        requests: &mpsc::Sender<InputRequest>,
+        shown: impl Fn(Vec<u8>) -> Vec<u8>,
+    ) -> anyhow::Result<()> {
+        for event in self.scanner.scan(bytes) {
+            if !matches!(event, OutputEvent::Output(_)) {
+                // Whatever came before goes out before status lines and prompts
+                stdout.flush()?;
+            }
+            match event {
+                OutputEvent::Output(bytes) => stdout.write_all(&shown(bytes))?,
+                OutputEvent::Download { name, size } => {
+                    self.download = start_download(name, size, requests).await?;
+                }
+                OutputEvent::DownloadData(data) => self.write_download(&data).await?,
+                OutputEvent::DownloadEnd => self.finish_download().await?,
+                OutputEvent::DownloadFailed(reason) => {
+                    if let Some(Download { path, .. }) = self.download.take() {
+                        status_line(&format!("failed to receive {}: {reason}", path.display()))?;
+                    }
+                }
+                OutputEvent::UploadRequest { format } => {
+                    // The input side is gone only once the session is
+                    let _ = requests.send(InputRequest::Upload { format }).await;
+                }
+                OutputEvent::Zmodem => {
+                    status_line(
+                        "ZMODEM isn't supported, cancelling it. \
+                         Send files with an OSC 1337 File= sequence instead, see the readme",
+                    )?;
+                    let _ = requests.send(InputRequest::CancelZmodem).await;
+                }
+            }
+        }
+        stdout.flush()?;
+        Ok(())
+    }
+
+    async fn write_download(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        let Some(Download {
+            partial,
+            path,
+            received,
+            ..
+        }) = &mut self.download
+        else {
+            return Ok(());
+        };
+        if let Err(e) = partial.write_all(data).await {
+            status_line(&format!("failed to write {}: {e}", path.display()))?;
+            self.download = None;
+            return Ok(());
+        }
+        *received += data.len() as u64;
+        Ok(())
+    }
+
+    async fn finish_download(&mut self) -> anyhow::Result<()> {
+        let Some(Download {
+            partial,
+            path,
+            size,
+            received,
+            overwrite,
+        }) = self.download.take()
+        else {
+            return Ok(());
+        };
+        if !overwrite && tokio::fs::symlink_metadata(&path).await.is_ok() {
+            status_line(&format!(
+                "not saving {}, something was put there during the download",
+                path.display()
+            ))?;
+            return Ok(());
+        }
+        if let Some(size) = size
+            && size != received
+        {
+            status_line(&format!(
+                "failed to receive {}: expected {size} bytes, got {received}",
+                path.display()
+            ))?;
+            return Ok(());
+        }
+        match partial.finish().await {
+            Ok(()) => status_line(&format!(
+                "saved {} ({})",
+                path.display(),
+                human_bytes(received)
+            )),
+            Err(e) => status_line(&format!("failed to write {}: {e}", path.display())),
+        }
+    }
+}
+
+async fn start_download(
+    name: Option<String>,
+    size: Option<u64>,
+    requests: &mpsc::Sender<InputRequest>,
+) -> anyhow::Result<Option<Download>> {
+    // The name comes from the session, only the last component of it is used
+    let name = name
+        .as_deref()
+        .and_then(|name| Path::new(name).file_name())
+        .and_then(|name| name.to_str())
+        .unwrap_or("download")
+        .to_string();
+    let (answer, answered) = oneshot::channel();
+    let request = InputRequest::Save {
+        name: name.clone(),
+        size,
+        answer,
+    };
+    if requests.send(request).await.is_err() {
+        return Ok(None);
+    }
+    let Some(SaveAs { path, overwrite }) = answered.await.ok().flatten() else {
+        status_line(&format!("skipped {name}"))?;
+        return Ok(None);
+    };
+    match PartialFile::create(&path, 0o644).await {
+        Ok(partial) => Ok(Some(Download {
+            partial,
+            path,
+            size,
+            received: 0,
+            overwrite,
+        })),
+        Err(e) => {
+            status_line(&format!("failed to create {}: {e}", path.display()))?;
+            Ok(None)
+        }
+    }
+}
+
+/// Serves a request of the output side, failing only if the session can't be written to
+pub async fn serve<W: AsyncWrite + Unpin>(
+    request: InputRequest,
+    stdin: &mut termion::AsyncReader,
+    acks: &mut PendingAcks,
+    writer: &mut FrameWriter<W>,
+) -> anyhow::Result<()> {
+    match request {
+        InputRequest::Save { name, size, answer } => {
+            let size = size.map(|size| format!(" ({})", human_bytes(size)));
+            let question = format!(
+                "receiving {name}{}, save as (Ctrl-C to skip):",
+                size.unwrap_or_default()
+            );
+            // The session picked the name, a dotfile like .bashrc isn't suggested
+            let suggested = if name.starts_with('.') { "" } else { &name };
+            let path = prompt(stdin, &question, suggested).await?;
+            let save_as = match path.filter(|path| !path.is_empty()) {
+                Some(path) => save_as(stdin, PathBuf::from(path), &name).await?,
+                None => None,
+            };
+            let _ = answer.send(save_as);
+            Ok(())
+        }
+        InputRequest::Upload { format } => {
+            if format
+                .as_deref()
+                .is_some_and(|format| format != UPLOAD_FORMAT_RAW)
+            {
+                status_line(&format!(
+                    "can't upload as {}, only as {UPLOAD_FORMAT_RAW}",
+                    format.unwrap_or_default()
+                ))?;
+                return type_in(writer, UPLOAD_ABORT).await;
+            }
+            let path = prompt(stdin, "upload which file (Ctrl-C to cancel):", "").await?;
+            match path.filter(|path| !path.is_empty()) {
+                Some(path) => upload(Path::new(&path), acks, writer).await,
+                None => type_in(writer, UPLOAD_ABORT).await,
+            }
+        }
+        InputRequest::CancelZmodem => type_in(writer, ZMODEM_CANCEL).await,
+    }
+}
+
+async fn upload<W: AsyncWrite + Unpin>(
+    path: &Path,
+    acks: &mut PendingAcks,
+    writer: &mut FrameWriter<W>,
+) -> anyhow::Result<()> {
+    let mut file = match tokio::fs::File::open(path).await {
+        Ok(file) => file,
+        Err(e) => {
+            status_line(&format!("failed to open {}: {e}", path.display()))?;
+            return type_in(writer, UPLOAD_ABORT).await;
+        }
+    };
+    type_in(writer, UPLOAD_ACCEPT).await?;
+    let mut buf = vec![0u8; UPLOAD_LINE_LEN * UPLOAD_LINES_PER_FRAME];
+    let mut sent = 0u64;
+    loop {
+        let filled = match read_full(&mut file, &mut buf).await {
+            Ok(filled) => filled,
+            Err(e) => {
+                status_line(&format!(
+                    "failed to read {}: {e}, the copy in the session is incomplete",
+                    path.display()
+                ))?;
+                break;
+            }
+        };
+        // The session's output keeps being acknowledged, it may be echoing the upload
+        if let Some(ack) = acks.take() {
+            writer
+                .write_frame(&ack)
+                .await
+                .context("failed to write to the session over stream")?;
+        }
+        if filled > 0 {
+            type_in(writer, &upload_lines(&buf[..filled])).await?;
+            sent += filled as u64;
+        }
+        if filled < buf.len() {
+            status_line(&format!(
+                "uploaded {} ({})",
+                path.display(),
+                human_bytes(sent)
+            ))?;
+            break;
+        }
+    }
+    type_in(writer, UPLOAD_END).await
+}
+
+/// Reads until `buf` is full or the file ends, so that every line but the last is full
+async fn read_full(file: &mut tokio::fs::File, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match file.read(&mut buf[filled..]).await? {
+            0 => break,
+            read_bytes => filled += read_bytes,
+        }
+    }
+    Ok(filled)
+}
+
+async fn type_in<W: AsyncWrite + Unpin>(
+    writer: &mut FrameWriter<W>,
+    bytes: &[u8],
+) -> anyhow::Result<()> {
+    writer
+        .write_frame(&Frame::Stdin(bytes.to_vec()))
+        .await
+        .context("failed to write to the session over stream")
+}
+
+/// Where to save a download the user answered `path` for, into it if it's a directory.
+/// Replacing a file takes asking again, `None` if the user doesn't want that
+async fn save_as(
+    stdin: &mut termion::AsyncReader,
+    mut path: PathBuf,
+    name: &str,
+) -> anyhow::Result<Option<SaveAs>> {
+    if tokio::fs::metadata(&path)
+        .await
+        .is_ok_and(|meta| meta.is_dir())
+    {
+        path.push(name);
+    }
+    if tokio::fs::symlink_metadata(&path).await.is_err() {
+        return Ok(Some(SaveAs {
+            path,
+            overwrite: false,
+        }));
+    }
+    let question = format!("{} exists, replace it? [y/N]", path.display());
+    Ok(confirm(stdin, &question).await?.then_some(SaveAs {
+        path,
+        overwrite: true,
+    }))
+}
+
+/// Drops what was typed before a prompt was drawn, it was meant for the session rather
+/// than as an answer
+fn discard_typeahead(stdin: &mut termion::AsyncReader) -> anyhow::Result<()> {
+    let mut buf = [0u8; 4096];
+    while std::io::Read::read(stdin, &mut buf).context("failed to read from stdin")? > 0 {}
+    Ok(())
+}
+
+/// Asks a yes or no question on the terminal in raw mode, answered by a single key
+async fn confirm(stdin: &mut termion::AsyncReader, question: &str) -> anyhow::Result<bool> {
+    discard_typeahead(stdin)?;
+    let mut stderr = std::io::stderr();
+    write!(stderr, "\r\n[p2term] {question} ")?;
+    stderr.flush()?;
+    loop {
+        if let Some(key) = (&mut *stdin).keys().next() {
+            let yes = matches!(
+                key.context("failed to read from stdin")?,
+                Key::Char('y' | 'Y')
+            );
+            write!(stderr, "{}\r\n", if yes { "y" } else { "n" })?;
+            stderr.flush()?;
+            return Ok(yes);
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+}
+
+/// Asks for a line on the terminal in raw mode, starting out as `answer`.
+/// `None` if the user pressed Ctrl-C
+async fn prompt(
+    stdin: &mut termion::AsyncReader,
+    question: &str,
+    answer: &str,
+) -> anyhow::Result<Option<String>> {
+    discard_typeahead(stdin)?;
+    let mut answer = answer.to_string();
+    let mut stderr = std::io::stderr();
+    write!(stderr, "\r\n[p2term] {question} {answer}")?;
+    stderr.flush()?;
+    loop {
+        let mut pressed = false;
+        for key in (&mut *stdin).keys() {
+            pressed = true;
+            match key.context("failed to read from stdin")? {
+                Key::Char('\n' | '\r') => {
+                    write!(stderr, "\r\n")?;
+                    return Ok(Some(answer));
+                }
+                Key::Ctrl('c') => {
+                    write!(stderr, "\r\n")?;
+                    return Ok(None);
+                }
+                Key::Backspace if !answer.is_empty() => {
+                    answer.pop();
+                    write!(stderr, "\x08 \x08")?;
+                }
+                Key::Ctrl('u') => {
+                    for _ in answer.drain(..) {
+                        write!(stderr, "\x08 \x08")?;
+                    }
+                }
+                Key::Char(c) if !c.is_control() => {
+                    answer.push(c);
+                    write!(stderr, "{c}")?;
+                }
+                _ => {}
+            }
+        }
+        stderr.flush()?;
+        if !pressed {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+}
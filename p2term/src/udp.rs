@@ -0,0 +1,158 @@
+use crate::forward::{ForwardSpec, open_forward};
+use anyhow::{Context, bail};
+use p2term_lib::client::connection::P2TermConnection;
+use p2term_lib::error::unpack;
+use p2term_lib::proto::SessionKind;
+use p2term_lib::proto::codec::Frame;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+
+/// Room for the largest udp payload a local client can send
+const UDP_BUF_LEN: usize = 64 * 1024;
+
+/// Datagrams queued per flow while it's being set up before further ones are dropped
+const FLOW_QUEUE: usize = 128;
+
+/// A local udp port bound for forwarding, not yet receiving
+#[derive(Debug)]
+pub struct UdpForwarder {
+    forward: ForwardSpec,
+    socket: Arc<UdpSocket>,
+}
+
+impl UdpForwarder {
+    /// Binds the local port, before connecting so that a taken port fails early
+    pub async fn bind(forward: ForwardSpec) -> anyhow::Result<Self> {
+        let (bind, _target) = forward.addresses()?;
+        let socket = UdpSocket::bind(bind)
+            .await
+            .with_context(|| format!("failed to bind udp {}:{}", bind.0, bind.1))?;
+        Ok(Self {
+            forward,
+            socket: Arc::new(socket),
+        })
+    }
+
+    /// Forwards the datagrams of every address sending to the port as a flow of its own,
+    /// which ends once no datagram has passed either way for `idle`
+    pub async fn run(self, connection: P2TermConnection, idle: Duration) -> anyhow::Result<()> {
+        let mut flows: HashMap<SocketAddr, mpsc::Sender<Vec<u8>>> = HashMap::new();
+        let mut buf = vec![0u8; UDP_BUF_LEN];
+        loop {
+            let (len, source) =
+                self.socket.recv_from(&mut buf).await.with_context(|| {
+                    format!("failed to receive for udp forward {}", self.forward)
+                })?;
+            if flows.get(&source).is_none_or(mpsc::Sender::is_closed) {
+                flows.retain(|_, payloads| !payloads.is_closed());
+                flows.insert(source, self.start_flow(&connection, source, idle));
+            }
+            // Like a full socket buffer, the datagram is lost
+            let _ = flows[&source].try_send(buf[..len].to_vec());
+        }
+    }
+
+    fn start_flow(
+        &self,
+        connection: &P2TermConnection,
+        source: SocketAddr,
+        idle: Duration,
+    ) -> mpsc::Sender<Vec<u8>> {
+        let (send, recv) = mpsc::channel(FLOW_QUEUE);
+        let flow = Flow {
+            connection: connection.clone(),
+            socket: self.socket.clone(),
+            source,
+            idle,
+        };
+        let forward = self.forward.clone();
+        tokio::spawn(async move {
+            if let Err(e) = flow.run(&forward, recv).await {
+                eprintln!(
+                    "p2term: udp forward {forward} from {source} failed: {}",
+                    unpack(&*e)
+                );
+            }
+        });
+        send
+    }
+}
+
+/// The datagrams between one local address and the target
+struct Flow {
+    connection: P2TermConnection,
+    socket: Arc<UdpSocket>,
+    source: SocketAddr,
+    idle: Duration,
+}
+
+impl Flow {
+    async fn run(
+        self,
+        forward: &ForwardSpec,
+        mut payloads: mpsc::Receiver<Vec<u8>>,
+    ) -> anyhow::Result<()> {
+        let (_bind, (host, port)) = forward.addresses()?;
+        let mut flow = self.connection.open_flow();
+        let session = SessionKind::ForwardUdp {
+            host: host.to_string(),
+            port,
+            flow: flow.flow(),
+        };
+        let (mut write, mut read) = open_forward(&self.connection, session).await?;
+        // Polled across iterations, reading a frame can't be cancelled halfway
+        let server_done = async {
+            loop {
+                match read.read_frame().await? {
+                    None | Some(Frame::Close) => return anyhow::Ok(()),
+                    Some(Frame::Stderr(reason)) => bail!("{}", String::from_utf8_lossy(&reason)),
+                    Some(_) => {}
+                }
+            }
+        };
+        tokio::pin!(server_done);
+        let idle_timeout = tokio::time::sleep(self.idle);
+        tokio::pin!(idle_timeout);
+        loop {
+            tokio::select! {
+                payload = payloads.recv() => {
+                    let Some(payload) = payload else {
+                        break;
+                    };
+                    self.send(&payload, flow.flow())?;
+                }
+                Some(datagram) = flow.recv() => {
+                    self.socket
+                        .send_to(&datagram, self.source)
+                        .await
+                        .with_context(|| format!("failed to send to {}", self.source))?;
+                }
+                done = &mut server_done => return done,
+                () = &mut idle_timeout => break,
+            }
+            idle_timeout.as_mut().reset(Instant::now() + self.idle);
+        }
+        write.write_frame(&Frame::Close).await
+    }
+
+    fn send(&self, payload: &[u8], flow: u32) -> anyhow::Result<()> {
+        let max_payload = self
+            .connection
+            .max_datagram_payload()
+            .context("the server doesn't take datagrams")?;
+        if payload.len() > max_payload {
+            eprintln!(
+                "p2term: dropping {} byte datagram from {}, at most {max_payload} bytes fit",
+                payload.len(),
+                self.source
+            );
+            return Ok(());
+        }
+        self.connection.send_datagram(flow, payload)
+    }
+}
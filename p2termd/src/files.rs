@@ -0,0 +1,600 @@
+use anyhow::{Context, bail};
+use iroh_base::PublicKey;
+use p2term_lib::files::{self, PartialFile};
+use p2term_lib::proto::codec::{Frame, FrameReader, FrameWriter};
+use p2term_lib::proto::files::{
+    CHUNK_LEN, DirEntry, ENTRIES_PER_BATCH, FileAttrs, FileError, FileErrorKind, FileMeta,
+    FileRequest, FileResponse,
+};
+use p2term_lib::server::config::FileCfg;
+use p2term_lib::streams::{ReadStream, WriteStream};
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+mod sync;
+
+/// Serves the requests of a files session until the client closes it, the policy has
+/// already let the session start in `cwd`
+pub async fn serve<W, R>(
+    mut write: FrameWriter<W>,
+    mut read: FrameReader<R>,
+    peer: &PublicKey,
+    cfg: &FileCfg,
+    cwd: Option<&Path>,
+) -> anyhow::Result<()>
+where
+    W: WriteStream,
+    R: ReadStream,
+{
+    let roots = Roots::resolve_permitted(cfg.roots(peer), cwd).await;
+    tracing::info!("serving files to peer={peer} from {}", roots.base.display());
+    while let Some(frame) = read.read_frame().await? {
+        match frame {
+            Frame::FileRequest(request) => {
+                serve_request(&roots, request, &mut write, &mut read).await?;
+            }
+            Frame::Close => break,
+            Frame::Keepalive => {}
+            unexpected => {
+                tracing::debug!("ignoring unexpected frame from client: {unexpected:?}");
+            }
+        }
+    }
+    write
+        .write_frame(&Frame::Close)
+        .await
+        .context("failed to write close over stream")
+}
+
+/// The directories a peer may access files in, with symlinks resolved.
+///
+/// A path is checked once resolved, then reached again through the directory it's in, held
+/// open, rather than by going down the path again. A directory on the way that's swapped for
+/// a symlink in the meantime, by another session of the peer, can't lead outside then. Only
+/// linux lets a directory be reached through its open file, elsewhere what's opened is
+/// checked again where the platform can tell where that is
+struct Roots {
+    dirs: Vec<PathBuf>,
+    /// Where relative paths start from
+    base: PathBuf,
+}
+
+/// A path checked against the [`Roots`], valid as long as it's kept
+struct Resolved {
+    /// What the path resolved to
+    canonical: PathBuf,
+    dir: OpenDir,
+    /// The entry in `dir`, `None` for the root of the filesystem, which `dir` is then
+    name: Option<OsString>,
+}
+
+impl Resolved {
+    /// Where to reach the entry at
+    fn path(&self) -> PathBuf {
+        match &self.name {
+            Some(name) => self.dir.path.join(name),
+            None => self.dir.path.clone(),
+        }
+    }
+}
+
+/// A directory within the [`Roots`], held open
+struct OpenDir {
+    _file: Option<std::fs::File>,
+    /// Where to reach what's in it at, through the open directory where the platform allows
+    path: PathBuf,
+}
+
+impl Roots {
+    async fn resolve_permitted(permitted: &[PathBuf], cwd: Option<&Path>) -> Self {
+        let mut dirs = Vec::with_capacity(permitted.len());
+        for dir in permitted {
+            match tokio::fs::canonicalize(dir).await {
+                Ok(dir) => dirs.push(dir),
+                Err(e) => tracing::warn!("skipping permitted directory {}: {e}", dir.display()),
+            }
+        }
+        let home = match std::env::home_dir() {
+            Some(home) => tokio::fs::canonicalize(home).await.ok(),
+            None => None,
+        };
+        let base = cwd
+            .map(Path::to_path_buf)
+            .or_else(|| home.filter(|home| dirs.iter().any(|dir| home.starts_with(dir))))
+            .or_else(|| dirs.first().cloned())
+            .unwrap_or_else(|| PathBuf::from("/"));
+        Self { dirs, base }
+    }
+
+    /// Where `path` leads, following symlinks all the way
+    async fn resolve(&self, path: &str) -> Result<Resolved, FileError> {
+        let resolved = tokio::fs::canonicalize(self.base.join(path))
+            .await
+            .map_err(|e| FileError::io(path, &e))?;
+        self.open(path, resolved).await
+    }
+
+    /// Where the entry at `path` is, following symlinks to its directory but not the entry
+    /// itself, so that it can be created, replaced or looked at if it's a symlink
+    async fn resolve_entry(&self, path: &str) -> Result<Resolved, FileError> {
+        let joined = self.base.join(path);
+        let (Some(dir), Some(name)) = (joined.parent(), joined.file_name()) else {
+            return self.resolve(path).await;
+        };
+        let dir = tokio::fs::canonicalize(dir)
+            .await
+            .map_err(|e| FileError::io(path, &e))?;
+        self.open(path, dir.join(name)).await
+    }
+
+    /// Like [`Roots::resolve_entry`], for an entry that's about to be removed or moved,
+    /// which the permitted directories themselves can't be
+    async fn resolve_movable(&self, path: &str) -> Result<Resolved, FileError> {
+        let resolved = self.resolve_entry(path).await?;
+        if self.dirs.contains(&resolved.canonical) {
+            return Err(FileError::new(
+                FileErrorKind::NotPermitted,
+                format!("{path}: a directory this peer may access can't be removed or moved"),
+            ));
+        }
+        Ok(resolved)
+    }
+
+    /// Checks `canonical` and opens the directory it's in
+    async fn open(&self, path: &str, canonical: PathBuf) -> Result<Resolved, FileError> {
+        let canonical = self.check(path, canonical)?;
+        let (dir, name) = match (canonical.parent(), canonical.file_name()) {
+            (Some(dir), Some(name)) => (dir, Some(name.to_os_string())),
+            _ => (canonical.as_path(), None),
+        };
+        // The directory a permitted one is in isn't permitted itself
+        let dir = if name.is_some() && self.dirs.contains(&canonical) {
+            OpenDir {
+                _file: None,
+                path: dir.to_path_buf(),
+            }
+        } else {
+            self.open_dir(path, dir).await?
+        };
+        Ok(Resolved {
+            canonical,
+            dir,
+            name,
+        })
+    }
+
+    /// Opens the directory at `dir`, without following a symlink there
+    async fn open_dir(&self, path: &str, dir: &Path) -> Result<OpenDir, FileError> {
+        if !cfg!(unix) {
+            self.check(path, dir.to_path_buf())?;
+            return Ok(OpenDir {
+                _file: None,
+                path: dir.to_path_buf(),
+            });
+        }
+        let mut options = tokio::fs::OpenOptions::new();
+        options.read(true);
+        #[cfg(unix)]
+        options.custom_flags(libc::O_DIRECTORY | libc::O_NOFOLLOW | libc::O_NONBLOCK);
+        let file = options
+            .open(dir)
+            .await
+            .map_err(|e| FileError::io(path, &e))?;
+        self.check_opened(path, &file)?;
+        let file = file.into_std().await;
+        let path = through(&file, dir);
+        Ok(OpenDir {
+            _file: Some(file),
+            path,
+        })
+    }
+
+    /// Checks where an opened file really is, where the platform can tell
+    fn check_opened(&self, path: &str, file: &tokio::fs::File) -> Result<(), FileError> {
+        match opened_path(file) {
+            Ok(Some(real)) => self.check(path, real).map(drop),
+            Ok(None) => Ok(()),
+            Err(e) => Err(FileError::io(path, &e)),
+        }
+    }
+
+    fn check(&self, path: &str, resolved: PathBuf) -> Result<PathBuf, FileError> {
+        if self.dirs.iter().any(|dir| resolved.starts_with(dir)) {
+            Ok(resolved)
+        } else {
+            Err(FileError::new(
+                FileErrorKind::NotPermitted,
+                format!("{path}: outside the directories this peer may access"),
+            ))
+        }
+    }
+}
+
+/// Where an opened file is, as the kernel has it, `None` where the platform can't tell
+#[cfg(target_os = "linux")]
+fn opened_path(file: &impl std::os::fd::AsRawFd) -> std::io::Result<Option<PathBuf>> {
+    std::fs::read_link(format!("/proc/self/fd/{}", file.as_raw_fd())).map(Some)
+}
+
+#[cfg(target_os = "macos")]
+fn opened_path(file: &impl std::os::fd::AsRawFd) -> std::io::Result<Option<PathBuf>> {
+    use std::os::unix::ffi::OsStrExt;
+    let mut buf = vec![0u8; usize::try_from(libc::PATH_MAX).unwrap_or(1024)];
+    // Safety: `F_GETPATH` writes a nul-terminated path of at most `PATH_MAX` bytes to `buf`
+    if unsafe { libc::fcntl(file.as_raw_fd(), libc::F_GETPATH, buf.as_mut_ptr()) } == -1 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let len = buf.iter().position(|b| *b == 0).unwrap_or(buf.len());
+    Ok(Some(PathBuf::from(std::ffi::OsStr::from_bytes(
+        &buf[..len],
+    ))))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn opened_path<F>(_file: &F) -> std::io::Result<Option<PathBuf>> {
+    Ok(None)
+}
+
+/// Where to reach what's in the open directory `dir`, opened at `path`, at
+#[cfg(target_os = "linux")]
+fn through(dir: &std::fs::File, _path: &Path) -> PathBuf {
+    use std::os::fd::AsRawFd;
+    PathBuf::from(format!("/proc/self/fd/{}", dir.as_raw_fd()))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn through(_dir: &std::fs::File, path: &Path) -> PathBuf {
+    path.to_path_buf()
+}
+
+/// Fails opening symlinks rather than following them, and doesn't wait on fifos
+#[cfg(unix)]
+fn no_follow(options: &mut tokio::fs::OpenOptions) {
+    options.custom_flags(libc::O_NOFOLLOW | libc::O_NONBLOCK);
+}
+
+#[cfg(not(unix))]
+fn no_follow(_options: &mut tokio::fs::OpenOptions) {}
+
+#[cfg(unix)]
+fn is_symlink_error(e: &std::io::Error) -> bool {
+    e.raw_os_error() == Some(libc::ELOOP)
+}
+
+#[cfg(not(unix))]
+fn is_symlink_error(_e: &std::io::Error) -> bool {
+    false
+}
+
+/// For a path that was swapped for a symlink after it was resolved
+fn changed(path: &str) -> FileError {
+    FileError::new(
+        FileErrorKind::NotPermitted,
+        format!("{path}: changed while it was being resolved"),
+    )
+}
+
+async fn serve_request<W, R>(
+    roots: &Roots,
+    request: FileRequest,
+    write: &mut FrameWriter<W>,
+    read: &mut FrameReader<R>,
+) -> anyhow::Result<()>
+where
+    W: WriteStream,
+    R: ReadStream,
+{
+    let res = match request {
+        FileRequest::Stat { path } => stat(roots, &path).await,
+        FileRequest::ReadDir { path } => return send_entries(roots, &path, write).await,
+        FileRequest::ReadLink { path } => read_link(roots, &path).await,
+        FileRequest::Read { path } => return send_file(roots, &path, write).await,
+        FileRequest::Write { path, create_mode } => {
+            receive_file(roots, &path, create_mode, read).await?
+        }
+        FileRequest::CreateDir { path, mode } => create_dir(roots, &path, mode).await,
+        FileRequest::Symlink { path, target } => symlink(roots, &path, &target).await,
+        FileRequest::SetAttrs { path, attrs } => set_attrs(roots, &path, attrs).await,
+        FileRequest::Remove { path } => remove(roots, &path).await,
+        FileRequest::RemoveDir { path } => remove_dir(roots, &path).await,
+        FileRequest::Rename { from, to } => rename(roots, &from, &to).await,
+        FileRequest::RealPath { path } => real_path(roots, &path).await,
+        FileRequest::Signature { path, block_len } => {
+            return sync::send_signature(roots, &path, block_len, write).await;
+        }
+        FileRequest::Patch {
+            path,
+            len,
+            block_len,
+            create_mode,
+        } => sync::receive_patch(roots, &path, len, block_len, create_mode, read).await?,
+        FileRequest::Data(_)
+        | FileRequest::Block { .. }
+        | FileRequest::WriteEnd { .. }
+        | FileRequest::WriteAbort => {
+            bail!("client sent file contents outside of a write");
+        }
+    };
+    respond(write, res).await
+}
+
+async fn respond<W>(
+    write: &mut FrameWriter<W>,
+    res: Result<FileResponse, FileError>,
+) -> anyhow::Result<()>
+where
+    W: WriteStream,
+{
+    let response = res.unwrap_or_else(|e| {
+        tracing::debug!("file request failed: {e}");
+        FileResponse::Error(e)
+    });
+    write
+        .write_frame(&Frame::FileResponse(response))
+        .await
+        .context("failed to write file response")
+}
+
+async fn stat(roots: &Roots, path: &str) -> Result<FileResponse, FileError> {
+    let resolved = roots.resolve(path).await?;
+    let meta = if resolved.name.is_some() {
+        tokio::fs::symlink_metadata(resolved.path()).await
+    } else {
+        tokio::fs::metadata(resolved.path()).await
+    };
+    let meta = meta.map_err(|e| FileError::io(path, &e))?;
+    // It was resolved past symlinks, so it was swapped for one since
+    if meta.is_symlink() {
+        return Err(changed(path));
+    }
+    Ok(FileResponse::Meta(FileMeta::from(&meta)))
+}
+
+async fn create_dir(roots: &Roots, path: &str, mode: u32) -> Result<FileResponse, FileError> {
+    let created = roots.resolve_entry(path).await?;
+    files::create_dir(&created.path(), mode)
+        .await
+        .map_err(|e| FileError::io(path, &e))?;
+    Ok(FileResponse::Done)
+}
+
+async fn symlink(roots: &Roots, path: &str, target: &str) -> Result<FileResponse, FileError> {
+    let link = roots.resolve_entry(path).await?;
+    files::symlink(&link.path(), target)
+        .await
+        .map_err(|e| FileError::io(path, &e))?;
+    Ok(FileResponse::Done)
+}
+
+async fn set_attrs(roots: &Roots, path: &str, attrs: FileAttrs) -> Result<FileResponse, FileError> {
+    let entry = roots.resolve_entry(path).await?;
+    files::set_attrs(&entry.path(), attrs)
+        .await
+        .map_err(|e| FileError::io(path, &e))?;
+    Ok(FileResponse::Done)
+}
+
+async fn remove(roots: &Roots, path: &str) -> Result<FileResponse, FileError> {
+    let entry = roots.resolve_movable(path).await?;
+    let meta = tokio::fs::symlink_metadata(entry.path())
+        .await
+        .map_err(|e| FileError::io(path, &e))?;
+    if meta.is_dir() {
+        return Err(FileError::new(
+            FileErrorKind::Other,
+            format!("{path}: is a directory"),
+        ));
+    }
+    tokio::fs::remove_file(entry.path())
+        .await
+        .map_err(|e| FileError::io(path, &e))?;
+    Ok(FileResponse::Done)
+}
+
+async fn remove_dir(roots: &Roots, path: &str) -> Result<FileResponse, FileError> {
+    let entry = roots.resolve_movable(path).await?;
+    tokio::fs::remove_dir(entry.path())
+        .await
+        .map_err(|e| FileError::io(path, &e))?;
+    Ok(FileResponse::Done)
+}
+
+async fn rename(roots: &Roots, from: &str, to: &str) -> Result<FileResponse, FileError> {
+    let source = roots.resolve_movable(from).await?;
+    let target = roots.resolve_movable(to).await?;
+    tokio::fs::rename(source.path(), target.path())
+        .await
+        .map_err(|e| FileError::io(from, &e))?;
+    Ok(FileResponse::Done)
+}
+
+async fn real_path(roots: &Roots, path: &str) -> Result<FileResponse, FileError> {
+    let resolved = roots.resolve(path).await?.canonical;
+    let resolved = resolved.into_os_string().into_string().map_err(|_| {
+        FileError::new(
+            FileErrorKind::Other,
+            format!("{path}: resolved path is not utf-8"),
+        )
+    })?;
+    Ok(FileResponse::Path(resolved))
+}
+
+async fn read_link(roots: &Roots, path: &str) -> Result<FileResponse, FileError> {
+    let link = roots.resolve_entry(path).await?;
+    let target = tokio::fs::read_link(link.path())
+        .await
+        .map_err(|e| FileError::io(path, &e))?;
+    let target = target.into_os_string().into_string().map_err(|_| {
+        FileError::new(
+            FileErrorKind::Other,
+            format!("{path}: link target is not utf-8"),
+        )
+    })?;
+    Ok(FileResponse::LinkTarget(target))
+}
+
+async fn send_entries<W>(
+    roots: &Roots,
+    path: &str,
+    write: &mut FrameWriter<W>,
+) -> anyhow::Result<()>
+where
+    W: WriteStream,
+{
+    let entries = match read_entries(roots, path).await {
+        Ok(entries) => entries,
+        Err(e) => return respond(write, Err(e)).await,
+    };
+    for batch in entries.chunks(ENTRIES_PER_BATCH) {
+        write
+            .write_frame(&Frame::FileResponse(FileResponse::Entries(batch.to_vec())))
+            .await
+            .context("failed to write directory entries")?;
+    }
+    respond(write, Ok(FileResponse::Done)).await
+}
+
+async fn read_entries(roots: &Roots, path: &str) -> Result<Vec<DirEntry>, FileError> {
+    let resolved = roots.resolve(path).await?;
+    let dir = roots.open_dir(path, &resolved.path()).await?;
+    let mut read_dir = tokio::fs::read_dir(&dir.path)
+        .await
+        .map_err(|e| FileError::io(path, &e))?;
+    let mut entries = Vec::new();
+    while let Some(entry) = read_dir
+        .next_entry()
+        .await
+        .map_err(|e| FileError::io(path, &e))?
+    {
+        let Ok(name) = entry.file_name().into_string() else {
+            tracing::debug!(
+                "skipping entry in {} with a name that's not utf-8",
+                resolved.canonical.display()
+            );
+            continue;
+        };
+        // The entry may be gone already
+        let Ok(meta) = entry.metadata().await else {
+            continue;
+        };
+        entries.push(DirEntry {
+            name,
+            meta: FileMeta::from(&meta),
+        });
+    }
+    entries.sort_unstable_by(|a, b| a.name.cmp(&b.name));
+    Ok(entries)
+}
+
+async fn send_file<W>(roots: &Roots, path: &str, write: &mut FrameWriter<W>) -> anyhow::Result<()>
+where
+    W: WriteStream,
+{
+    let mut file = match open_file(roots, path).await {
+        Ok(file) => file,
+        Err(e) => return respond(write, Err(e)).await,
+    };
+    let mut hasher = blake3::Hasher::new();
+    let mut buf = vec![0u8; CHUNK_LEN];
+    loop {
+        let len = match file.read(&mut buf).await {
+            Ok(0) => break,
+            Ok(len) => len,
+            Err(e) => return respond(write, Err(FileError::io(path, &e))).await,
+        };
+        hasher.update(&buf[..len]);
+        write
+            .write_frame(&Frame::FileResponse(FileResponse::Data(
+                buf[..len].to_vec(),
+            )))
+            .await
+            .context("failed to write file contents")?;
+    }
+    let checksum = *hasher.finalize().as_bytes();
+    respond(write, Ok(FileResponse::ReadDone { checksum })).await
+}
+
+async fn open_file(roots: &Roots, path: &str) -> Result<tokio::fs::File, FileError> {
+    let resolved = roots.resolve(path).await?;
+    let mut options = tokio::fs::OpenOptions::new();
+    options.read(true);
+    no_follow(&mut options);
+    let file = match options.open(resolved.path()).await {
+        Ok(file) => file,
+        Err(e) if is_symlink_error(&e) => return Err(changed(path)),
+        Err(e) => return Err(FileError::io(path, &e)),
+    };
+    roots.check_opened(path, &file)?;
+    let meta = file.metadata().await.map_err(|e| FileError::io(path, &e))?;
+    if !meta.is_file() {
+        return Err(FileError::new(
+            FileErrorKind::Other,
+            format!("{path}: not a regular file"),
+        ));
+    }
+    Ok(file)
+}
+
+/// Takes the contents of a file being written off the stream, the file is only replaced if
+/// they all arrived and match their checksum. Fails only if the session can't go on
+async fn receive_file<R>(
+    roots: &Roots,
+    path: &str,
+    create_mode: u32,
+    read: &mut FrameReader<R>,
+) -> anyhow::Result<Result<FileResponse, FileError>>
+where
+    R: ReadStream,
+{
+    // Kept until the file is in place, which is reached through it
+    let target = roots.resolve_entry(path).await;
+    let mut partial = match &target {
+        Ok(target) => PartialFile::create(&target.path(), create_mode)
+            .await
+            .map_err(|e| FileError::io(path, &e)),
+        Err(e) => Err(e.clone()),
+    };
+    let mut hasher = blake3::Hasher::new();
+    loop {
+        let frame = read
+            .read_frame()
+            .await?
+            .context("client closed the session during a write")?;
+        match frame {
+            Frame::FileRequest(FileRequest::Data(chunk)) => {
+                hasher.update(&chunk);
+                // Once failed, the rest of the contents are only taken off the stream
+                if let Ok(file) = &mut partial
+                    && let Err(e) = file.write_all(&chunk).await
+                {
+                    partial = Err(FileError::io(path, &e));
+                }
+            }
+            Frame::FileRequest(FileRequest::WriteEnd { checksum }) => {
+                let file = match partial {
+                    Ok(file) => file,
+                    Err(e) => return Ok(Err(e)),
+                };
+                if hasher.finalize().as_bytes() != &checksum {
+                    return Ok(Err(FileError::new(
+                        FileErrorKind::Corrupted,
+                        format!("{path}: contents don't match their checksum, left as it was"),
+                    )));
+                }
+                return Ok(file
+                    .finish()
+                    .await
+                    .map(|()| FileResponse::Done)
+                    .map_err(|e| FileError::io(path, &e)));
+            }
+            Frame::FileRequest(FileRequest::WriteAbort) => return Ok(Ok(FileResponse::Done)),
+            Frame::FileRequest(request) => bail!("client sent {request:?} during a write"),
+            Frame::Close => bail!("client closed the session during a write"),
+            Frame::Keepalive => {}
+            unexpected => {
+                tracing::debug!("ignoring unexpected frame from client: {unexpected:?}");
+            }
+        }
+    }
+}
@@ -0,0 +1,427 @@
+//! Patching files with only the blocks that changed, for `p2term sync`.
+//!
+//! A patch is put together in a partial file next to its target, which replaces the target
+//! once complete. An interrupted patch leaves the partial file behind, and signatures of the
+//! target are then taken of what it got so far, so that its blocks aren't sent again.
+use super::{Resolved, Roots, is_symlink_error, no_follow, respond};
+use anyhow::{Context, bail};
+use p2term_lib::proto::codec::{Frame, FrameReader, FrameWriter};
+use p2term_lib::proto::files::{
+    BLOCKS_PER_BATCH, FileError, FileErrorKind, FileRequest, FileResponse, MAX_BLOCK_LEN,
+};
+use p2term_lib::streams::{ReadStream, WriteStream};
+use std::ffi::OsString;
+use std::io::{self, SeekFrom};
+use std::path::{Path, PathBuf};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+pub async fn send_signature<W>(
+    roots: &Roots,
+    path: &str,
+    block_len: u32,
+    write: &mut FrameWriter<W>,
+) -> anyhow::Result<()>
+where
+    W: WriteStream,
+{
+    let (mut basis, block_len) = match signature_basis(roots, path, block_len).await {
+        Ok(opened) => opened,
+        Err(e) => return respond(write, Err(e)).await,
+    };
+    let mut buf = vec![0u8; block_len];
+    let mut batch = Vec::with_capacity(BLOCKS_PER_BATCH);
+    let mut offset = 0u64;
+    loop {
+        let len = match basis.read_at(offset, &mut buf).await {
+            Ok(0) => break,
+            Ok(len) => len,
+            Err(e) => return respond(write, Err(FileError::io(path, &e))).await,
+        };
+        batch.push(*blake3::hash(&buf[..len]).as_bytes());
+        offset += len as u64;
+        if batch.len() == BLOCKS_PER_BATCH {
+            send_batch(write, std::mem::take(&mut batch)).await?;
+        }
+    }
+    if !batch.is_empty() {
+        send_batch(write, batch).await?;
+    }
+    let resumed = basis.partial_len > 0;
+    respond(write, Ok(FileResponse::SignatureEnd { resumed })).await
+}
+
+async fn send_batch<W>(write: &mut FrameWriter<W>, batch: Vec<[u8; 32]>) -> anyhow::Result<()>
+where
+    W: WriteStream,
+{
+    write
+        .write_frame(&Frame::FileResponse(FileResponse::Signature(batch)))
+        .await
+        .context("failed to write signature")
+}
+
+async fn signature_basis(
+    roots: &Roots,
+    path: &str,
+    block_len: u32,
+) -> Result<(Basis, usize), FileError> {
+    let block_len = checked_block_len(path, block_len)?;
+    let target = roots.resolve_entry(path).await?.path();
+    let partial = match partial_path(&target) {
+        Some(partial) => open_regular(&partial).await,
+        None => Ok(None),
+    };
+    let basis = match partial {
+        Ok(partial) => Basis::open(&target, partial).await,
+        Err(e) => Err(e),
+    };
+    let basis = basis.map_err(|e| FileError::io(path, &e))?;
+    basis.check(roots, path)?;
+    Ok((basis, block_len))
+}
+
+/// Takes the blocks of a file being patched off the stream, the file is only replaced if
+/// they all arrived and the result matches its checksum. Fails only if the session can't
+/// go on
+pub async fn receive_patch<R>(
+    roots: &Roots,
+    path: &str,
+    len: u64,
+    block_len: u32,
+    create_mode: u32,
+    read: &mut FrameReader<R>,
+) -> anyhow::Result<Result<FileResponse, FileError>>
+where
+    R: ReadStream,
+{
+    let mut patch = Patch::start(roots, path, len, block_len, create_mode).await;
+    loop {
+        let frame = read
+            .read_frame()
+            .await?
+            .context("client closed the session during a patch")?;
+        match frame {
+            Frame::FileRequest(FileRequest::Block { index, data }) => {
+                // Once failed, the rest of the blocks are only taken off the stream
+                if let Ok(ongoing) = &mut patch
+                    && let Err(e) = ongoing.block(index, &data).await
+                {
+                    patch = Err(e);
+                }
+            }
+            Frame::FileRequest(FileRequest::WriteEnd { checksum }) => {
+                return Ok(match patch {
+                    Ok(patch) => patch.finish(checksum).await.map(|()| FileResponse::Done),
+                    Err(e) => Err(e),
+                });
+            }
+            Frame::FileRequest(FileRequest::WriteAbort) => return Ok(Ok(FileResponse::Done)),
+            Frame::FileRequest(request) => bail!("client sent {request:?} during a patch"),
+            Frame::Close => bail!("client closed the session during a patch"),
+            Frame::Keepalive => {}
+            unexpected => {
+                tracing::debug!("ignoring unexpected frame from client: {unexpected:?}");
+            }
+        }
+    }
+}
+
+/// A file being patched block by block into its partial file
+struct Patch {
+    /// As the client named it
+    path: String,
+    target: Resolved,
+    /// Reached through the target's directory
+    partial: PathBuf,
+    /// Its partial file is also what's written to
+    basis: Basis,
+    len: u64,
+    block_len: usize,
+    /// The first block that hasn't been put in place yet
+    next: u64,
+    hasher: blake3::Hasher,
+    buf: Vec<u8>,
+}
+
+impl Patch {
+    async fn start(
+        roots: &Roots,
+        path: &str,
+        len: u64,
+        block_len: u32,
+        create_mode: u32,
+    ) -> Result<Self, FileError> {
+        let block_len = checked_block_len(path, block_len)?;
+        let target = roots.resolve_entry(path).await?;
+        let partial = partial_path(&target.path()).ok_or_else(|| {
+            FileError::new(
+                FileErrorKind::InvalidRequest,
+                format!("{path}: does not name a file"),
+            )
+        })?;
+        let opened = match open_partial(&partial, create_mode).await {
+            Ok(file) => Basis::open(&target.path(), Some(file)).await,
+            Err(e) => Err(e),
+        };
+        let basis = opened.map_err(|e| FileError::io(path, &e))?;
+        basis.check(roots, path)?;
+        Ok(Self {
+            path: path.to_string(),
+            target,
+            partial,
+            basis,
+            len,
+            block_len,
+            next: 0,
+            hasher: blake3::Hasher::new(),
+            buf: vec![0u8; block_len],
+        })
+    }
+
+    async fn block(&mut self, index: u64, data: &[u8]) -> Result<(), FileError> {
+        if index < self.next || index >= self.block_count() {
+            return Err(self.invalid(&format!("block {index} out of order")));
+        }
+        if data.len() != self.expected_len(index) {
+            return Err(self.invalid(&format!("block {index} is {} bytes long", data.len())));
+        }
+        self.fill_to(index).await?;
+        let offset = self.offset(index);
+        self.write_at(offset, data)
+            .await
+            .map_err(|e| FileError::io(&self.path, &e))?;
+        self.hasher.update(data);
+        self.next = index + 1;
+        Ok(())
+    }
+
+    /// Puts the blocks before `end` that weren't sent in place, from the basis
+    async fn fill_to(&mut self, end: u64) -> Result<(), FileError> {
+        while self.next < end {
+            let index = self.next;
+            let offset = self.offset(index);
+            let expected = self.expected_len(index);
+            let mut block = std::mem::take(&mut self.buf);
+            let res = self.fill_block(offset, &mut block[..expected]).await;
+            self.buf = block;
+            res?;
+            self.next += 1;
+        }
+        Ok(())
+    }
+
+    async fn fill_block(&mut self, offset: u64, block: &mut [u8]) -> Result<(), FileError> {
+        let read = self
+            .basis
+            .read_at(offset, block)
+            .await
+            .map_err(|e| FileError::io(&self.path, &e))?;
+        if read != block.len() {
+            return Err(FileError::new(
+                FileErrorKind::Corrupted,
+                format!(
+                    "{}: changed since its signature was taken, sync it again",
+                    self.path
+                ),
+            ));
+        }
+        // What's already in the partial file is where it belongs
+        if offset + block.len() as u64 > self.basis.partial_len {
+            self.write_at(offset, block)
+                .await
+                .map_err(|e| FileError::io(&self.path, &e))?;
+        }
+        self.hasher.update(block);
+        Ok(())
+    }
+
+    async fn finish(mut self, checksum: [u8; 32]) -> Result<(), FileError> {
+        self.fill_to(self.block_count()).await?;
+        if self.hasher.finalize().as_bytes() != &checksum {
+            // Taken from a basis that changed under it, there's nothing worth keeping
+            let _ = tokio::fs::remove_file(&self.partial).await;
+            return Err(FileError::new(
+                FileErrorKind::Corrupted,
+                format!(
+                    "{}: contents don't match their checksum, left as it was",
+                    self.path
+                ),
+            ));
+        }
+        self.replace_target()
+            .await
+            .map_err(|e| FileError::io(&self.path, &e))
+    }
+
+    async fn replace_target(&self) -> io::Result<()> {
+        let Some(file) = &self.basis.partial else {
+            return Err(io::Error::other("patch lost its partial file"));
+        };
+        file.set_len(self.len).await?;
+        file.sync_all().await?;
+        if let Some(existing) = &self.basis.target {
+            file.set_permissions(existing.metadata().await?.permissions())
+                .await?;
+        }
+        tokio::fs::rename(&self.partial, self.target.path()).await
+    }
+
+    async fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        let Some(file) = self.basis.partial.as_mut() else {
+            return Err(io::Error::other("patch lost its partial file"));
+        };
+        file.seek(SeekFrom::Start(offset)).await?;
+        file.write_all(data).await
+    }
+
+    fn block_count(&self) -> u64 {
+        self.len.div_ceil(self.block_len as u64)
+    }
+
+    fn offset(&self, index: u64) -> u64 {
+        index * self.block_len as u64
+    }
+
+    /// Only the last block may be shorter
+    fn expected_len(&self, index: u64) -> usize {
+        let remaining = self.len - self.offset(index);
+        usize::try_from(remaining).map_or(self.block_len, |remaining| remaining.min(self.block_len))
+    }
+
+    fn invalid(&self, reason: &str) -> FileError {
+        FileError::new(
+            FileErrorKind::InvalidRequest,
+            format!("{}: {reason}", self.path),
+        )
+    }
+}
+
+/// What's being patched, read from the partial file of an interrupted patch as far as it
+/// goes, and from the target past that
+struct Basis {
+    partial: Option<File>,
+    /// How far the partial file went when the basis was opened
+    partial_len: u64,
+    target: Option<File>,
+    target_len: u64,
+}
+
+impl Basis {
+    async fn open(target: &Path, partial: Option<File>) -> io::Result<Self> {
+        let partial_len = match &partial {
+            Some(file) => file.metadata().await?.len(),
+            None => 0,
+        };
+        let target = open_regular(target).await?;
+        let target_len = match &target {
+            Some(file) => file.metadata().await?.len(),
+            None => 0,
+        };
+        Ok(Self {
+            partial,
+            partial_len,
+            target,
+            target_len,
+        })
+    }
+
+    /// Checks that what was opened is where the peer may access it
+    fn check(&self, roots: &Roots, path: &str) -> Result<(), FileError> {
+        for file in [&self.partial, &self.target].into_iter().flatten() {
+            roots.check_opened(path, file)?;
+        }
+        Ok(())
+    }
+
+    fn len(&self) -> u64 {
+        self.partial_len.max(self.target_len)
+    }
+
+    /// Reads as much of `buf` as the basis has from `offset`, returning how much that was
+    async fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let end = self.len().min(offset + buf.len() as u64);
+        if end <= offset {
+            return Ok(0);
+        }
+        let from_partial = self.partial_len.min(end).saturating_sub(offset);
+        let len = usize::try_from(end - offset).map_err(io::Error::other)?;
+        let split = usize::try_from(from_partial).map_err(io::Error::other)?;
+        if split > 0 {
+            read_exact_at(self.partial.as_mut(), offset, &mut buf[..split]).await?;
+        }
+        if split < len {
+            read_exact_at(
+                self.target.as_mut(),
+                offset + from_partial,
+                &mut buf[split..len],
+            )
+            .await?;
+        }
+        Ok(len)
+    }
+}
+
+async fn read_exact_at(file: Option<&mut File>, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+    let Some(file) = file else {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    };
+    file.seek(SeekFrom::Start(offset)).await?;
+    file.read_exact(buf).await?;
+    Ok(())
+}
+
+fn checked_block_len(path: &str, block_len: u32) -> Result<usize, FileError> {
+    if block_len == 0 || block_len > MAX_BLOCK_LEN {
+        return Err(FileError::new(
+            FileErrorKind::InvalidRequest,
+            format!("{path}: blocks must be between 1 and {MAX_BLOCK_LEN} bytes long"),
+        ));
+    }
+    usize::try_from(block_len)
+        .map_err(|e| FileError::new(FileErrorKind::InvalidRequest, e.to_string()))
+}
+
+/// Where a patch of `target` is put together, the same for every patch so that an
+/// interrupted one can be resumed
+fn partial_path(target: &Path) -> Option<PathBuf> {
+    let name = target.file_name()?;
+    let mut partial = OsString::from(".");
+    partial.push(name);
+    partial.push(".p2term-partial");
+    Some(target.with_file_name(partial))
+}
+
+/// Opens the regular file at `path` to read, `None` if there's none there. A symlink isn't
+/// followed, it could lead outside the directories the peer may access
+async fn open_regular(path: &Path) -> io::Result<Option<File>> {
+    let mut options = OpenOptions::new();
+    options.read(true);
+    no_follow(&mut options);
+    let file = match options.open(path).await {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound || is_symlink_error(&e) => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    Ok(file.metadata().await?.is_file().then_some(file))
+}
+
+/// Opens the partial file at `path` to read and write, creating it with `create_mode` less
+/// the umask if it's not there
+async fn open_partial(path: &Path, create_mode: u32) -> io::Result<File> {
+    let mut options = OpenOptions::new();
+    options.read(true).write(true).create(true);
+    #[cfg(unix)]
+    options.mode(create_mode);
+    #[cfg(not(unix))]
+    let _ = create_mode;
+    no_follow(&mut options);
+    let file = options.open(path).await?;
+    if !file.metadata().await?.is_file() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} is not a regular file", path.display()),
+        ));
+    }
+    Ok(file)
+}
@@ -0,0 +1,422 @@
+use anyhow::Context;
+use iroh_base::PublicKey;
+use p2term_lib::datagram::{self, FlowReceiver};
+use p2term_lib::error::unpack;
+use p2term_lib::forward::{ForwardSide, Socket, splice};
+use p2term_lib::proto::codec::{Frame, FrameReader, FrameWriter};
+use p2term_lib::proto::{ForwardedTcp, ForwardedUnix, SessionKind};
+use p2term_lib::server::connection::P2TermServerConnection;
+use p2term_lib::streams::{ReadStream, WriteStream};
+use std::future::Future;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+
+/// How long dialing a forwarding target may take before the session is refused
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Room for the largest udp payload a target can send
+const UDP_BUF_LEN: usize = 64 * 1024;
+
+/// How long a udp flow may go without a datagram either way before the server ends it,
+/// clients end idle flows themselves well before that
+const UDP_IDLE_TIMEOUT: Duration = Duration::from_mins(5);
+
+/// Dials the target of a forwarding session, the policy has already permitted it,
+/// and carries the session's bytes to it
+pub async fn connect<W, R>(
+    write: FrameWriter<W>,
+    read: FrameReader<R>,
+    peer: &PublicKey,
+    session: &SessionKind,
+) -> anyhow::Result<()>
+where
+    W: WriteStream,
+    R: ReadStream,
+{
+    match session {
+        SessionKind::ForwardTcp { host, port } => connect_tcp(write, read, peer, host, *port).await,
+        SessionKind::ForwardUnix { path } => connect_unix(write, read, peer, path).await,
+        _ => anyhow::bail!("session kind doesn't forward"),
+    }
+}
+
+async fn connect_tcp<W, R>(
+    write: FrameWriter<W>,
+    read: FrameReader<R>,
+    peer: &PublicKey,
+    host: &str,
+    port: u16,
+) -> anyhow::Result<()>
+where
+    W: WriteStream,
+    R: ReadStream,
+{
+    let target = format!("{host}:{port}");
+    serve_dialed(write, read, peer, &target, TcpStream::connect((host, port))).await
+}
+
+async fn connect_unix<W, R>(
+    write: FrameWriter<W>,
+    read: FrameReader<R>,
+    peer: &PublicKey,
+    path: &str,
+) -> anyhow::Result<()>
+where
+    W: WriteStream,
+    R: ReadStream,
+{
+    #[cfg(unix)]
+    let dial = tokio::net::UnixStream::connect(path);
+    #[cfg(not(unix))]
+    let dial = async {
+        Err::<TcpStream, _>(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "unix sockets are not supported on this platform",
+        ))
+    };
+    serve_dialed(write, read, peer, path, dial).await
+}
+
+async fn serve_dialed<W, R, S>(
+    mut write: FrameWriter<W>,
+    read: FrameReader<R>,
+    peer: &PublicKey,
+    target: &str,
+    dial: impl Future<Output = std::io::Result<S>>,
+) -> anyhow::Result<()>
+where
+    W: WriteStream,
+    R: ReadStream,
+    S: Socket,
+{
+    let dialed = tokio::time::timeout(CONNECT_TIMEOUT, dial)
+        .await
+        .context("timed out")
+        .and_then(|res| res.context("dial failed"));
+    let stream = match dialed {
+        Ok(stream) => stream,
+        Err(e) => {
+            let reason = format!("failed to connect to {target}: {}", unpack(&*e));
+            tracing::info!("forward for peer={peer} refused, {reason}");
+            return refuse(write, reason).await;
+        }
+    };
+    tracing::info!("forwarding for peer={peer} to {target}");
+    write.write_frame(&Frame::ForwardConnected).await?;
+    splice(stream, write, read, ForwardSide::Server)
+        .await
+        .with_context(|| format!("forward to {target} failed"))
+}
+
+async fn refuse<W: WriteStream>(mut write: FrameWriter<W>, reason: String) -> anyhow::Result<()> {
+    write
+        .write_frame(&Frame::Stderr(reason.into_bytes()))
+        .await?;
+    write.write_frame(&Frame::Close).await
+}
+
+/// Listens on behalf of a remote forwarding session, the policy has already permitted it,
+/// carrying every accepted connection back to the peer until the peer ends the session
+pub async fn listen_tcp<W, R, C>(
+    write: FrameWriter<W>,
+    read: FrameReader<R>,
+    peer: &PublicKey,
+    connection: C,
+    host: String,
+    port: u16,
+) -> anyhow::Result<()>
+where
+    W: WriteStream,
+    R: ReadStream,
+    C: P2TermServerConnection<W, R>,
+{
+    let listener = match TcpListener::bind((host.as_str(), port)).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            let reason = format!("failed to listen on {host}:{port}: {e}");
+            tracing::info!("remote forward for peer={peer} refused, {reason}");
+            return refuse(write, reason).await;
+        }
+    };
+    let bound = listener
+        .local_addr()
+        .context("failed to get listener address")?;
+    let forwarded = |origin| {
+        Frame::Forwarded(ForwardedTcp {
+            host: host.clone(),
+            port,
+            origin,
+        })
+    };
+    serve_listener(
+        write,
+        read,
+        peer,
+        connection,
+        Listener::Tcp(listener),
+        (bound.port(), &bound.to_string()),
+        forwarded,
+    )
+    .await
+}
+
+/// Like [`listen_tcp`] on a unix socket created at `path`, which is removed again once the
+/// peer ends the session
+#[cfg(unix)]
+pub async fn listen_unix<W, R, C>(
+    write: FrameWriter<W>,
+    read: FrameReader<R>,
+    peer: &PublicKey,
+    connection: C,
+    path: String,
+) -> anyhow::Result<()>
+where
+    W: WriteStream,
+    R: ReadStream,
+    C: P2TermServerConnection<W, R>,
+{
+    let listener = match tokio::net::UnixListener::bind(&path) {
+        Ok(listener) => listener,
+        Err(e) => {
+            let reason = format!("failed to listen on {path}: {e}");
+            tracing::info!("remote forward for peer={peer} refused, {reason}");
+            return refuse(write, reason).await;
+        }
+    };
+    let forwarded = |_origin| Frame::ForwardedUnix(ForwardedUnix { path: path.clone() });
+    let served = serve_listener(
+        write,
+        read,
+        peer,
+        connection,
+        Listener::Unix(listener),
+        (0, &path),
+        forwarded,
+    )
+    .await;
+    if let Err(e) = std::fs::remove_file(&path) {
+        tracing::warn!("failed to remove forwarded socket {path}: {}", unpack(&e));
+    }
+    served
+}
+
+#[cfg(not(unix))]
+pub async fn listen_unix<W, R, C>(
+    write: FrameWriter<W>,
+    _read: FrameReader<R>,
+    peer: &PublicKey,
+    _connection: C,
+    path: String,
+) -> anyhow::Result<()>
+where
+    W: WriteStream,
+    R: ReadStream,
+    C: P2TermServerConnection<W, R>,
+{
+    tracing::info!("remote forward to {path} for peer={peer} refused, no unix sockets");
+    refuse(
+        write,
+        "unix sockets are not supported on this platform".to_string(),
+    )
+    .await
+}
+
+enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener),
+}
+
+impl Listener {
+    /// The accepted socket and a description of where it came from
+    async fn accept(&self) -> std::io::Result<(Box<dyn Socket>, String)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, origin) = listener.accept().await?;
+                Ok((Box::new(stream), origin.to_string()))
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener) => {
+                let (stream, _addr) = listener.accept().await?;
+                // Unix socket clients rarely have an address, their user tells more
+                let origin = stream.peer_cred().map_or_else(
+                    |_| "unix socket".to_string(),
+                    |cred| format!("uid {}", cred.uid()),
+                );
+                Ok((Box::new(stream), origin))
+            }
+        }
+    }
+}
+
+/// Reports the listener as bound at `bound`, the port and a description of it, and carries
+/// every connection it accepts back to the peer on a stream starting with the frame
+/// `forwarded` makes of the connection's origin
+async fn serve_listener<W, R, C>(
+    mut write: FrameWriter<W>,
+    mut read: FrameReader<R>,
+    peer: &PublicKey,
+    connection: C,
+    listener: Listener,
+    bound: (u16, &str),
+    forwarded: impl Fn(String) -> Frame,
+) -> anyhow::Result<()>
+where
+    W: WriteStream,
+    R: ReadStream,
+    C: P2TermServerConnection<W, R>,
+{
+    let (bound_port, bound) = bound;
+    tracing::info!("listening on {bound} for peer={peer}");
+    write
+        .write_frame(&Frame::ForwardListening(bound_port))
+        .await?;
+    // Polled across iterations, reading a frame can't be cancelled halfway
+    let peer_done = async {
+        loop {
+            match read.read_frame().await? {
+                None | Some(Frame::Close) => return anyhow::Ok(()),
+                Some(_) => {}
+            }
+        }
+    };
+    tokio::pin!(peer_done);
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (stream, origin) = accepted.context("failed to accept forwarded connection")?;
+                let frame = forwarded(origin.clone());
+                let connection = connection.clone();
+                let peer = *peer;
+                tokio::spawn(async move {
+                    if let Err(e) = forward_accepted(&connection, stream, frame).await {
+                        tracing::info!(
+                            "remote forward from {origin} for peer={peer} failed: {}",
+                            unpack(&*e)
+                        );
+                    }
+                });
+            }
+            done = &mut peer_done => {
+                tracing::info!("stopped listening on {bound} for peer={peer}");
+                return done;
+            }
+        }
+    }
+}
+
+async fn forward_accepted<W, R, C>(
+    connection: &C,
+    stream: Box<dyn Socket>,
+    forwarded: Frame,
+) -> anyhow::Result<()>
+where
+    W: WriteStream,
+    R: ReadStream,
+    C: P2TermServerConnection<W, R>,
+{
+    let (send, recv) = connection.open().await?;
+    let mut write = FrameWriter::new(send);
+    write.write_frame(&forwarded).await?;
+    splice(stream, write, FrameReader::new(recv), ForwardSide::Server).await
+}
+
+/// Sends the datagrams of a udp forwarding session to its target, the policy has already
+/// permitted it, and the target's replies back to the peer on the same flow until the peer
+/// ends the session or the flow has been idle for [`UDP_IDLE_TIMEOUT`]
+pub async fn forward_udp<W, R, C>(
+    mut write: FrameWriter<W>,
+    mut read: FrameReader<R>,
+    peer: &PublicKey,
+    connection: &C,
+    mut flow: FlowReceiver,
+    host: &str,
+    port: u16,
+) -> anyhow::Result<()>
+where
+    W: WriteStream,
+    R: ReadStream,
+    C: P2TermServerConnection<W, R>,
+{
+    let target = format!("{host}:{port}");
+    let socket = match tokio::time::timeout(CONNECT_TIMEOUT, udp_socket(host, port))
+        .await
+        .context("timed out")
+        .and_then(|res| res)
+    {
+        Ok(socket) => socket,
+        Err(e) => {
+            let reason = format!("failed to set up udp to {target}: {}", unpack(&*e));
+            tracing::info!("udp forward for peer={peer} refused, {reason}");
+            return refuse(write, reason).await;
+        }
+    };
+    tracing::info!("forwarding udp for peer={peer} to {target}");
+    write.write_frame(&Frame::ForwardConnected).await?;
+    // Polled across iterations, reading a frame can't be cancelled halfway
+    let peer_done = async {
+        loop {
+            match read.read_frame().await? {
+                None | Some(Frame::Close) => return anyhow::Ok(()),
+                Some(_) => {}
+            }
+        }
+    };
+    tokio::pin!(peer_done);
+    let idle_timeout = tokio::time::sleep(UDP_IDLE_TIMEOUT);
+    tokio::pin!(idle_timeout);
+    let mut buf = vec![0u8; UDP_BUF_LEN];
+    let done = loop {
+        tokio::select! {
+            Some(payload) = flow.recv() => {
+                if let Err(e) = socket.send(&payload).await {
+                    tracing::debug!("failed to send datagram to {target}: {e}");
+                }
+            }
+            received = socket.recv(&mut buf) => match received {
+                Ok(len) => {
+                    let datagram = datagram::encode(flow.flow(), &buf[..len]);
+                    if let Err(e) = connection.send_datagram(datagram) {
+                        tracing::debug!("dropped datagram from {target}: {}", unpack(&*e));
+                    }
+                }
+                // An earlier datagram was refused, like udp the flow carries on
+                Err(e) if e.kind() == std::io::ErrorKind::ConnectionRefused => {
+                    tracing::debug!("datagram to {target} refused");
+                }
+                Err(e) => {
+                    break Err(e).with_context(|| format!("failed to receive from {target}"));
+                }
+            },
+            done = &mut peer_done => break done,
+            () = &mut idle_timeout => {
+                tracing::debug!("udp flow to {target} for peer={peer} went idle");
+                break Ok(());
+            }
+        }
+        idle_timeout
+            .as_mut()
+            .reset(tokio::time::Instant::now() + UDP_IDLE_TIMEOUT);
+    };
+    tracing::info!("stopped forwarding udp for peer={peer} to {target}");
+    done?;
+    write.write_frame(&Frame::Close).await
+}
+
+/// A socket sending to and receiving from only `host:port`
+async fn udp_socket(host: &str, port: u16) -> anyhow::Result<UdpSocket> {
+    let addr = tokio::net::lookup_host((host, port))
+        .await
+        .context("lookup failed")?
+        .next()
+        .context("host has no addresses")?;
+    let local: SocketAddr = if addr.is_ipv4() {
+        (Ipv4Addr::UNSPECIFIED, 0).into()
+    } else {
+        (Ipv6Addr::UNSPECIFIED, 0).into()
+    };
+    let socket = UdpSocket::bind(local).await.context("bind failed")?;
+    socket.connect(addr).await.context("connect failed")?;
+    Ok(socket)
+}
@@ -1,3 +1,5 @@
+mod files;
+mod forward;
 mod observability;
 mod shell;
 
@@ -24,10 +26,10 @@ async fn main() -> anyhow::Result<()> {
     let args = Args::parse();
     setup_observability();
     let router = P2TermRouterImpl::default();
-    run::<P2TermRouterImpl, ShellProxyImpl>(args, router).await
+    run(args, ShellProxyImpl::default(), router).await
 }
 
-async fn run<Router, Shell>(args: Args, router: Router) -> anyhow::Result<()>
+async fn run<Router, Shell>(args: Args, shell: Shell, router: Router) -> anyhow::Result<()>
 where
     Router: P2TermRouter,
     Shell: ServerShellProxy,
@@ -47,6 +49,7 @@ where
     let (shutdown_send, shutdown_recv) = tokio::sync::mpsc::channel(2);
     let mut router_task = tokio::task::spawn(p2term_lib::server::runtime::run::<Router, Shell>(
         config,
+        shell,
         router,
         shutdown_recv,
     ));
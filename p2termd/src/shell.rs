@@ -1,2 +1,5 @@
 pub mod handler;
+mod persistent;
+mod piped;
 mod pty;
+mod signal;
@@ -1,96 +1,372 @@
-use crate::shell::pty::{PtyReader, PtyWriter, subshell_pty_task};
+use crate::files;
+use crate::forward;
+use crate::shell::persistent;
+use crate::shell::persistent::{NewSession, SessionRegistry};
+use crate::shell::piped::run_piped;
+use crate::shell::pty::{
+    PtyChild, PtyKiller, PtyMaster, PtyReader, PtyWriter, SubshellPty, subshell_pty_task,
+};
 use anyhow::Context;
+use iroh_base::PublicKey;
+use p2term_lib::backpressure::{OutputAcks, OutputWindow};
+use p2term_lib::compression::OutputEncoder;
+use p2term_lib::datagram::FlowReceiver;
 use p2term_lib::error::unpack;
-use p2term_lib::proto::ClientOpt;
-use p2term_lib::server::config::ShellCfg;
+use p2term_lib::proto::codec::{Frame, FrameReader, FrameWriter};
+use p2term_lib::proto::{Capabilities, ClientOpt, Rejection, SessionKind};
+use p2term_lib::server::config::{SessionLimits, ShellCfg};
+use p2term_lib::server::connection::P2TermServerConnection;
 use p2term_lib::server::shell_proxy::ServerShellProxy;
+use p2term_lib::server::timeouts::{SessionTimer, notice};
 use p2term_lib::streams::{ReadStream, WriteStream};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::sync::Arc;
+use tokio::sync::{OwnedSemaphorePermit, mpsc};
 
-#[derive(Debug)]
-pub struct ShellProxyImpl;
+/// Output sent in one frame at most, when more of it is waiting
+const OUTPUT_BURST: usize = 64 * 1024;
+
+#[derive(Debug, Default)]
+pub struct ShellProxyImpl {
+    sessions: Arc<SessionRegistry>,
+}
 
 impl ServerShellProxy for ShellProxyImpl {
-    async fn run<W, R>(
-        output_stream: W,
-        input_stream: R,
+    fn admit(
+        &self,
+        peer: &PublicKey,
         shell_cfg: &ShellCfg,
+        client_opt: &ClientOpt,
+    ) -> Result<(), Rejection> {
+        persistent::admit(&self.sessions, peer, shell_cfg, client_opt)
+    }
+
+    async fn listen<W, R, C>(
+        &self,
+        write: FrameWriter<W>,
+        read: FrameReader<R>,
+        peer: PublicKey,
+        connection: C,
+        client_opt: ClientOpt,
+    ) -> anyhow::Result<()>
+    where
+        W: WriteStream,
+        R: ReadStream,
+        C: P2TermServerConnection<W, R>,
+    {
+        match client_opt.session {
+            SessionKind::ListenTcp { host, port } => {
+                forward::listen_tcp(write, read, &peer, connection, host, port).await
+            }
+            SessionKind::ListenUnix { path } => {
+                forward::listen_unix(write, read, &peer, connection, path).await
+            }
+            _ => anyhow::bail!("session kind doesn't listen"),
+        }
+    }
+
+    async fn forward_udp<W, R, C>(
+        &self,
+        write: FrameWriter<W>,
+        read: FrameReader<R>,
+        peer: PublicKey,
+        connection: C,
+        flow: FlowReceiver,
         client_opt: ClientOpt,
     ) -> anyhow::Result<()>
     where
         W: WriteStream,
         R: ReadStream,
+        C: P2TermServerConnection<W, R>,
     {
-        let shell = client_opt
-            .shell
-            .as_deref()
-            .unwrap_or(shell_cfg.default_shell.as_str());
-        let (pty_write, pty_read, mut err_recv) =
-            subshell_pty_task(shell, client_opt.cwd.as_deref(), client_opt.term.as_deref())?;
+        let SessionKind::ForwardUdp { host, port, .. } = client_opt.session else {
+            anyhow::bail!("session kind doesn't forward udp");
+        };
+        forward::forward_udp(write, read, &peer, &connection, flow, &host, port).await
+    }
 
-        let (input_res, output_res) = tokio::join!(
-            proxy_child_stdin(pty_write, input_stream),
-            proxy_child_stdout(pty_read, output_stream)
-        );
-        match (input_res, output_res) {
-            (Ok(()), Ok(())) => {
-                tracing::info!(
-                    "shell session exited normally, both input and output streams closed"
-                );
-                Ok(())
+    async fn run<W, R>(
+        &self,
+        output_stream: FrameWriter<W>,
+        input_stream: FrameReader<R>,
+        peer: PublicKey,
+        shell_cfg: &ShellCfg,
+        client_opt: ClientOpt,
+        capabilities: Capabilities,
+        slot: Option<OwnedSemaphorePermit>,
+    ) -> anyhow::Result<()>
+    where
+        W: WriteStream,
+        R: ReadStream,
+    {
+        let argv = match &client_opt.session {
+            SessionKind::Shell | SessionKind::Exec(_) => shell_cfg
+                .command(&client_opt)
+                .context("session kind doesn't run a command")?,
+            SessionKind::Attach(_) | SessionKind::Resume { .. } | SessionKind::List => {
+                return persistent::serve_existing(
+                    &self.sessions,
+                    output_stream,
+                    input_stream,
+                    &peer,
+                    &shell_cfg.persistence,
+                    client_opt,
+                    capabilities,
+                )
+                .await;
             }
-            (Ok(()), Err(_)) => {
-                tracing::info!("shell session exited by peer leaving");
-                Ok(())
+            SessionKind::ForwardTcp { .. } | SessionKind::ForwardUnix { .. } => {
+                return forward::connect(output_stream, input_stream, &peer, &client_opt.session)
+                    .await;
             }
-            (Err(_), Ok(())) => {
-                tracing::info!("shell session exited by peer pty closing");
-                Ok(())
+            SessionKind::Files => {
+                return files::serve(
+                    output_stream,
+                    input_stream,
+                    &peer,
+                    &shell_cfg.files,
+                    client_opt.cwd.as_deref(),
+                )
+                .await;
             }
-            (Err(e_in), Err(e_out)) => {
-                // Drain thread errors, running in a task so we can wait
-                while let Some(next_err) = err_recv.recv().await {
-                    tracing::warn!("shell session child thread error: {}", unpack(&*next_err));
-                }
-                anyhow::bail!(
-                    "shell session child input/output proxy both failed: in={}, out={}",
-                    unpack(&*e_in),
-                    unpack(&*e_out)
-                );
+            SessionKind::ListenTcp { .. }
+            | SessionKind::ListenUnix { .. }
+            | SessionKind::ForwardUdp { .. } => {
+                anyhow::bail!("listening and udp sessions are served by listen and forward_udp")
+            }
+        };
+        let limits = shell_cfg.timeouts.limits(&peer);
+        if client_opt.no_pty {
+            return run_piped(
+                &argv,
+                client_opt.cwd.as_deref(),
+                output_stream,
+                input_stream,
+                capabilities,
+                limits,
+            )
+            .await;
+        }
+        let pty = subshell_pty_task(
+            &argv,
+            client_opt.cwd.as_deref(),
+            client_opt.term.as_deref(),
+            client_opt.size,
+        )?;
+        if client_opt.persist || client_opt.resumable {
+            return persistent::keep(
+                &self.sessions,
+                pty,
+                NewSession {
+                    command: argv,
+                    owner: peer,
+                    name: client_opt.session_name,
+                    persistent: client_opt.persist,
+                    limits,
+                    slot,
+                },
+                &shell_cfg.persistence,
+                output_stream,
+                input_stream,
+                capabilities,
+            )
+            .await;
+        }
+        serve_pty(pty, output_stream, input_stream, capabilities, limits).await
+    }
+}
+
+/// Carries a session's frames to and from a shell that ends with the session
+async fn serve_pty<W, R>(
+    pty: SubshellPty,
+    output_stream: FrameWriter<W>,
+    input_stream: FrameReader<R>,
+    capabilities: Capabilities,
+    limits: SessionLimits,
+) -> anyhow::Result<()>
+where
+    W: WriteStream,
+    R: ReadStream,
+{
+    let SubshellPty {
+        writer,
+        reader,
+        master,
+        child,
+        killer,
+        errors: mut err_recv,
+    } = pty;
+
+    let timer = SessionTimer::new(limits);
+    // Messages for the user, written by the output side which owns the stream
+    let (notices, notices_recv) = mpsc::channel(4);
+    let (acks, window) = p2term_lib::backpressure::window(capabilities);
+    let (input_res, output_res) = tokio::join!(
+        proxy_child_stdin(writer, master, killer, input_stream, &timer, notices, acks),
+        proxy_child_stdout(
+            reader,
+            child,
+            capabilities,
+            output_stream,
+            &timer,
+            notices_recv,
+            window
+        )
+    );
+    match (input_res, output_res) {
+        (Ok(()), Ok(())) => {
+            tracing::info!("shell session exited normally, both input and output streams closed");
+            Ok(())
+        }
+        (Ok(()), Err(_)) => {
+            tracing::info!("shell session exited by peer leaving");
+            Ok(())
+        }
+        (Err(_), Ok(())) => {
+            tracing::info!("shell session exited by peer pty closing");
+            Ok(())
+        }
+        (Err(e_in), Err(e_out)) => {
+            // Drain thread errors, running in a task so we can wait
+            while let Some(next_err) = err_recv.recv().await {
+                tracing::warn!("shell session child thread error: {}", unpack(&*next_err));
             }
+            anyhow::bail!(
+                "shell session child input/output proxy both failed: in={}, out={}",
+                unpack(&*e_in),
+                unpack(&*e_out)
+            );
         }
     }
 }
 
 async fn proxy_child_stdin<R: ReadStream>(
     child_stdin: PtyWriter,
-    mut input_stream: R,
+    pty_master: PtyMaster,
+    mut killer: PtyKiller,
+    input_stream: FrameReader<R>,
+    timer: &SessionTimer,
+    notices: mpsc::Sender<String>,
+    acks: OutputAcks,
+) -> anyhow::Result<()> {
+    let res = tokio::select! {
+        res = proxy_client_frames(child_stdin, pty_master, input_stream, timer, &acks) => res,
+        expiry = timer.expired(|warning| {
+            // Dropped if the client isn't keeping up with them anyway
+            let _ = notices.try_send(warning);
+        }) => {
+            tracing::info!("shell session {expiry}, hanging up");
+            let _ = notices.try_send(format!("closing session, {expiry}"));
+            Ok(())
+        }
+    };
+    // The client is gone, or the session is over, hang up the shell like a closed
+    // terminal would
+    killer.hangup();
+    res
+}
+
+async fn proxy_client_frames<R: ReadStream>(
+    child_stdin: PtyWriter,
+    pty_master: PtyMaster,
+    mut input_stream: FrameReader<R>,
+    timer: &SessionTimer,
+    acks: &OutputAcks,
 ) -> anyhow::Result<()> {
-    let mut buf = [0u8; 4096];
     loop {
-        let read_bytes = match AsyncReadExt::read(&mut input_stream, &mut buf).await {
-            Ok(0) => return Ok(()),
-            Ok(rb) => rb,
-            Err(e) if e.kind() == std::io::ErrorKind::NotConnected => {
-                return Ok(());
-            }
-            Err(e) => return Err(anyhow::anyhow!("failed to read from stdin: {}", unpack(&e))),
+        let Some(frame) = input_stream.read_frame().await? else {
+            return Ok(());
         };
-        if read_bytes > 0 {
-            child_stdin.write_chunk(&buf[..read_bytes]).await?;
+        match frame {
+            Frame::Stdin(bytes) => {
+                timer.active();
+                child_stdin.write_chunk(&bytes).await?;
+            }
+            Frame::Resize(size) => {
+                if let Err(e) = pty_master.resize(size) {
+                    tracing::warn!("failed to resize pty to {size:?}: {}", unpack(&*e));
+                }
+            }
+            Frame::Signal(signal) => {
+                if let Err(e) = pty_master.signal_foreground(signal) {
+                    tracing::warn!("failed to deliver {signal:?}: {}", unpack(&*e));
+                }
+            }
+            Frame::OutputAck(handled) => acks.ack(handled),
+            Frame::Close => return Ok(()),
+            Frame::Keepalive => {}
+            unexpected => {
+                tracing::debug!("ignoring unexpected frame from client: {unexpected:?}");
+            }
         }
     }
 }
 
-async fn proxy_child_stdout<W>(mut pty_reader: PtyReader, mut write: W) -> anyhow::Result<()>
+async fn proxy_child_stdout<W>(
+    mut pty_reader: PtyReader,
+    child: PtyChild,
+    capabilities: Capabilities,
+    mut write: FrameWriter<W>,
+    timer: &SessionTimer,
+    mut notices: mpsc::Receiver<String>,
+    mut window: OutputWindow,
+) -> anyhow::Result<()>
 where
     W: WriteStream,
 {
+    let mut encoder = OutputEncoder::new(capabilities)?;
     loop {
-        let next = pty_reader.read_bytes().await?;
+        let mut burst = tokio::select! {
+            // A notice sent before hanging up goes out before the shell's exit
+            biased;
+            Some(msg) = notices.recv() => {
+                write
+                    .write_frame(&notice(&msg))
+                    .await
+                    .context("failed to write notice over stream")?;
+                continue;
+            }
+            // Not read while the client is behind, which eventually blocks the shell
+            read = pty_reader.read_bytes(), if window.is_open() => match read {
+                Some(bytes) => bytes,
+                None => break,
+            },
+            () = window.open(), if !window.is_open() => continue,
+            () = write.keepalive_due() => {
+                write
+                    .write_frame(&Frame::Keepalive)
+                    .await
+                    .context("failed to write keepalive over stream")?;
+                continue;
+            }
+        };
+        timer.active();
+        // Output that's already waiting goes out with it, compressed as one burst
+        while burst.len() < OUTPUT_BURST
+            && let Some(next) = pty_reader.try_read_bytes()
+        {
+            burst.extend_from_slice(&next);
+        }
+        let frame = encoder.frame(&burst)?;
         write
-            .write_all(&next)
+            .write_frame(&frame)
             .await
             .context("failed to write bytes from term over stream")?;
+        window.sent(&frame);
+    }
+    match child.wait().await {
+        Ok(status) => {
+            tracing::info!("shell exited with {status:?}");
+            if capabilities.contains(Capabilities::EXIT_STATUS) {
+                write
+                    .write_frame(&Frame::ExitStatus(status))
+                    .await
+                    .context("failed to write exit status over stream")?;
+            }
+        }
+        Err(e) => tracing::warn!("failed to get shell exit status: {}", unpack(&*e)),
     }
+    write
+        .write_frame(&Frame::Close)
+        .await
+        .context("failed to write close over stream")
 }
@@ -0,0 +1,874 @@
+use crate::shell::pty::{PtyChild, PtyKiller, PtyMaster, PtyReader, PtyWriter, SubshellPty};
+use anyhow::Context;
+use iroh_base::PublicKey;
+use p2term_lib::backpressure::{OutputAcks, OutputWindow};
+use p2term_lib::compression::OutputEncoder;
+use p2term_lib::crypto::generate_resume_token;
+use p2term_lib::error::unpack;
+use p2term_lib::proto::codec::{Frame, FrameReader, FrameWriter};
+use p2term_lib::proto::{
+    AttachMode, Capabilities, ClientOpt, ExitStatus, RejectReason, Rejection, ResumeToken,
+    SessionInfo, SessionKind, TermSize,
+};
+use p2term_lib::server::config::{PersistenceCfg, SessionAccess, SessionLimits, ShellCfg};
+use p2term_lib::server::sessions::{Attachments, ClientState, Scrollback, check_access};
+use p2term_lib::server::timeouts::{SessionTimer, notice};
+use p2term_lib::streams::{ReadStream, WriteStream};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, SystemTime};
+use tokio::sync::OwnedSemaphorePermit;
+
+/// Largest chunk of output sent in one frame when catching up on the scrollback
+const OUTPUT_CHUNK: usize = 64 * 1024;
+
+/// Kept sessions by id, sessions remove themselves when their child exits
+#[derive(Default)]
+pub struct SessionRegistry {
+    last_id: AtomicU64,
+    sessions: Mutex<BTreeMap<u64, Arc<PersistentSession>>>,
+}
+
+impl core::fmt::Debug for SessionRegistry {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("SessionRegistry")
+            .field("sessions", &self.sessions().len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl SessionRegistry {
+    fn sessions(&self) -> MutexGuard<'_, BTreeMap<u64, Arc<PersistentSession>>> {
+        self.sessions
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+
+    /// Finds a persistent session by id or name
+    fn find(&self, target: &str) -> Option<Arc<PersistentSession>> {
+        let sessions = self.sessions();
+        let found = if let Ok(id) = target.parse::<u64>() {
+            sessions.get(&id)
+        } else {
+            sessions
+                .values()
+                .find(|session| session.name.as_deref() == Some(target))
+        };
+        found.filter(|session| session.persistent).cloned()
+    }
+
+    /// Finds any kept session by its resume token
+    fn find_by_token(&self, token: &ResumeToken) -> Option<Arc<PersistentSession>> {
+        self.sessions()
+            .values()
+            .find(|session| session.token == *token)
+            .cloned()
+    }
+
+    /// Finds a session `peer` may attach to in `mode`, not telling apart sessions that
+    /// don't exist from ones the peer may not see
+    fn find_attachable(
+        &self,
+        target: &str,
+        peer: &PublicKey,
+        cfg: &PersistenceCfg,
+        mode: AttachMode,
+    ) -> Result<(Arc<PersistentSession>, SessionAccess), Rejection> {
+        check_access(
+            self.find(target),
+            |session| &session.owner,
+            target,
+            peer,
+            cfg,
+            mode,
+        )
+    }
+
+    /// Finds a session to resume, with the same access checks as [`Self::find_attachable`]
+    fn find_resumable(
+        &self,
+        token: &ResumeToken,
+        peer: &PublicKey,
+        cfg: &PersistenceCfg,
+        mode: AttachMode,
+    ) -> Result<(Arc<PersistentSession>, SessionAccess), Rejection> {
+        check_access(
+            self.find_by_token(token),
+            |session| &session.owner,
+            "with that resume token",
+            peer,
+            cfg,
+            mode,
+        )
+    }
+
+    fn name_taken(&self, name: &str) -> bool {
+        self.sessions()
+            .values()
+            .any(|session| session.name.as_deref() == Some(name))
+    }
+
+    fn insert(&self, session: Arc<PersistentSession>) -> Result<(), Rejection> {
+        let mut sessions = self.sessions();
+        if let Some(name) = session.name.as_deref()
+            && sessions.values().any(|s| s.name.as_deref() == Some(name))
+        {
+            return Err(name_taken(name));
+        }
+        sessions.insert(session.id, session);
+        Ok(())
+    }
+
+    fn remove(&self, id: u64) {
+        self.sessions().remove(&id);
+    }
+
+    fn list(&self, peer: &PublicKey, cfg: &PersistenceCfg) -> Vec<SessionInfo> {
+        self.sessions()
+            .values()
+            .filter(|session| session.persistent && cfg.access(peer, &session.owner).is_some())
+            .map(|session| session.info())
+            .collect()
+    }
+}
+
+fn name_taken(name: &str) -> Rejection {
+    Rejection::new(
+        RejectReason::SessionNameTaken,
+        format!("there is already a session named {name}"),
+    )
+}
+
+/// Checks requests involving kept sessions before they're accepted
+pub fn admit(
+    sessions: &SessionRegistry,
+    peer: &PublicKey,
+    shell_cfg: &ShellCfg,
+    client_opt: &ClientOpt,
+) -> Result<(), Rejection> {
+    match &client_opt.session {
+        SessionKind::Attach(target) => {
+            sessions.find_attachable(
+                target,
+                peer,
+                &shell_cfg.persistence,
+                client_opt.attach_mode,
+            )?;
+        }
+        SessionKind::Resume { token, .. } => {
+            sessions.find_resumable(token, peer, &shell_cfg.persistence, client_opt.attach_mode)?;
+        }
+        _ => {}
+    }
+    if let Some(name) = client_opt.session_name.as_deref() {
+        if name.parse::<u64>().is_ok() {
+            return Err(Rejection::new(
+                RejectReason::InvalidRequest,
+                "session names can't be numbers, those are session ids",
+            ));
+        }
+        if sessions.name_taken(name) {
+            return Err(name_taken(name));
+        }
+    }
+    Ok(())
+}
+
+/// Serves the session kinds that use an already kept session
+pub async fn serve_existing<W, R>(
+    sessions: &SessionRegistry,
+    write: FrameWriter<W>,
+    read: FrameReader<R>,
+    peer: &PublicKey,
+    cfg: &PersistenceCfg,
+    client_opt: ClientOpt,
+    capabilities: Capabilities,
+) -> anyhow::Result<()>
+where
+    W: WriteStream,
+    R: ReadStream,
+{
+    let ClientOpt {
+        session,
+        attach_mode,
+        size,
+        ..
+    } = client_opt;
+    match session {
+        SessionKind::Attach(target) => {
+            attach(
+                sessions,
+                &target,
+                attach_mode,
+                peer,
+                cfg,
+                write,
+                read,
+                capabilities,
+                size,
+            )
+            .await
+        }
+        SessionKind::Resume { token, offset } => {
+            resume(
+                sessions,
+                &token,
+                offset,
+                attach_mode,
+                peer,
+                cfg,
+                write,
+                read,
+                capabilities,
+                size,
+            )
+            .await
+        }
+        SessionKind::List => list(sessions, write, peer, cfg).await,
+        SessionKind::Shell
+        | SessionKind::Exec(_)
+        | SessionKind::ForwardTcp { .. }
+        | SessionKind::ListenTcp { .. }
+        | SessionKind::ForwardUnix { .. }
+        | SessionKind::ListenUnix { .. }
+        | SessionKind::ForwardUdp { .. }
+        | SessionKind::Files => {
+            anyhow::bail!("session kind doesn't use a kept session")
+        }
+    }
+}
+
+/// Answers [`SessionKind::List`]
+async fn list<W: WriteStream>(
+    sessions: &SessionRegistry,
+    mut write: FrameWriter<W>,
+    peer: &PublicKey,
+    cfg: &PersistenceCfg,
+) -> anyhow::Result<()> {
+    write
+        .write_frame(&Frame::SessionList(sessions.list(peer, cfg)))
+        .await
+        .context("failed to write session list over stream")?;
+    write
+        .write_frame(&Frame::Close)
+        .await
+        .context("failed to write close over stream")
+}
+
+/// A newly spawned pty to keep
+pub struct NewSession {
+    pub command: Vec<String>,
+    pub owner: PublicKey,
+    pub name: Option<String>,
+    /// Kept when its clients detach, and listed for attaching.
+    /// Otherwise it's only kept for resuming after a lost connection.
+    pub persistent: bool,
+    /// The limits of its owner, held to whoever is attached
+    pub limits: SessionLimits,
+    /// Its count toward the server's `max_sessions`, held until the child exits
+    pub slot: Option<OwnedSemaphorePermit>,
+}
+
+/// Keeps a newly spawned pty running for reattaching or resuming, then attaches the client to it
+pub async fn keep<W, R>(
+    sessions: &Arc<SessionRegistry>,
+    pty: SubshellPty,
+    new: NewSession,
+    cfg: &PersistenceCfg,
+    write: FrameWriter<W>,
+    read: FrameReader<R>,
+    capabilities: Capabilities,
+) -> anyhow::Result<()>
+where
+    W: WriteStream,
+    R: ReadStream,
+{
+    let SubshellPty {
+        writer,
+        reader,
+        master,
+        child,
+        killer,
+        mut errors,
+    } = pty;
+    let NewSession {
+        command,
+        owner,
+        name,
+        persistent,
+        limits,
+        slot,
+    } = new;
+    let session = Arc::new(PersistentSession {
+        id: sessions.last_id.fetch_add(1, Ordering::Relaxed) + 1,
+        token: generate_resume_token(),
+        persistent,
+        name,
+        owner,
+        command,
+        created_at: SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs()),
+        writer,
+        master: Mutex::new(master),
+        killer: Mutex::new(killer),
+        output: Mutex::new(SessionOutput {
+            scrollback: Scrollback::new(cfg.scrollback_bytes),
+            child: ChildState::Running,
+        }),
+        output_changed: tokio::sync::watch::Sender::new(()),
+        read_progress: tokio::sync::watch::Sender::new(()),
+        notices: tokio::sync::broadcast::Sender::new(4),
+        attachments: Mutex::new(Attachments::default()),
+        detached_timeout: cfg.detached_timeout,
+        resume_grace: cfg.resume_grace,
+        timer: SessionTimer::new(limits),
+        _slot: slot,
+    });
+    if let Err(rejection) = sessions.insert(session.clone()) {
+        // Lost a race for the name since admission, the pty goes with the session
+        session.hangup();
+        anyhow::bail!("failed to keep session: {rejection}");
+    }
+    tracing::info!(
+        "started {} session id={} for peer={owner}",
+        if persistent {
+            "persistent"
+        } else {
+            "resumable"
+        },
+        session.id
+    );
+    tokio::spawn(pump_output(
+        sessions.clone(),
+        session.clone(),
+        reader,
+        child,
+    ));
+    if session.timer.is_limited() {
+        tokio::spawn(enforce_limits(session.clone()));
+    }
+    tokio::spawn(async move {
+        while let Some(e) = errors.recv().await {
+            tracing::warn!("persistent session child thread error: {}", unpack(&*e));
+        }
+    });
+    attach_session(
+        session,
+        SessionAccess::Write,
+        AttachMode::Takeover,
+        None,
+        write,
+        read,
+        capabilities,
+        None,
+    )
+    .await
+}
+
+/// Attaches the client to an existing session, see [`SessionKind::Attach`]
+#[allow(clippy::too_many_arguments)]
+async fn attach<W, R>(
+    sessions: &SessionRegistry,
+    target: &str,
+    mode: AttachMode,
+    peer: &PublicKey,
+    cfg: &PersistenceCfg,
+    write: FrameWriter<W>,
+    read: FrameReader<R>,
+    capabilities: Capabilities,
+    size: Option<TermSize>,
+) -> anyhow::Result<()>
+where
+    W: WriteStream,
+    R: ReadStream,
+{
+    let (session, access) = sessions.find_attachable(target, peer, cfg, mode)?;
+    tracing::info!(
+        "peer={peer} attaching to session id={} with {mode:?}",
+        session.id
+    );
+    attach_session(session, access, mode, None, write, read, capabilities, size).await
+}
+
+/// Picks a session up again after a lost connection, see [`SessionKind::Resume`]
+#[allow(clippy::too_many_arguments)]
+async fn resume<W, R>(
+    sessions: &SessionRegistry,
+    token: &ResumeToken,
+    offset: u64,
+    mode: AttachMode,
+    peer: &PublicKey,
+    cfg: &PersistenceCfg,
+    write: FrameWriter<W>,
+    read: FrameReader<R>,
+    capabilities: Capabilities,
+    size: Option<TermSize>,
+) -> anyhow::Result<()>
+where
+    W: WriteStream,
+    R: ReadStream,
+{
+    let (session, access) = sessions.find_resumable(token, peer, cfg, mode)?;
+    tracing::info!(
+        "peer={peer} resuming session id={} from offset={offset}",
+        session.id
+    );
+    attach_session(
+        session,
+        access,
+        mode,
+        Some(offset),
+        write,
+        read,
+        capabilities,
+        size,
+    )
+    .await
+}
+
+struct PersistentSession {
+    id: u64,
+    token: ResumeToken,
+    persistent: bool,
+    name: Option<String>,
+    owner: PublicKey,
+    command: Vec<String>,
+    created_at: u64,
+    writer: PtyWriter,
+    master: Mutex<PtyMaster>,
+    killer: Mutex<PtyKiller>,
+    output: Mutex<SessionOutput>,
+    /// Notified whenever output is added, the session exits, or attachments change
+    output_changed: tokio::sync::watch::Sender<()>,
+    /// Notified whenever a client reads output or attachments change, for the pump
+    /// waiting on the writers to catch up
+    read_progress: tokio::sync::watch::Sender<()>,
+    /// Messages for the users of the attached clients, not kept in the scrollback
+    notices: tokio::sync::broadcast::Sender<String>,
+    attachments: Mutex<Attachments>,
+    detached_timeout: Option<Duration>,
+    resume_grace: Duration,
+    timer: SessionTimer,
+    _slot: Option<OwnedSemaphorePermit>,
+}
+
+struct SessionOutput {
+    scrollback: Scrollback,
+    child: ChildState,
+}
+
+#[derive(Debug, Copy, Clone)]
+enum ChildState {
+    Running,
+    /// The child has exited and all its output is in the scrollback,
+    /// the status is missing if it couldn't be waited for
+    Exited(Option<ExitStatus>),
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex
+        .lock()
+        .unwrap_or_else(std::sync::PoisonError::into_inner)
+}
+
+impl PersistentSession {
+    fn info(&self) -> SessionInfo {
+        SessionInfo {
+            id: self.id,
+            name: self.name.clone(),
+            owner: self.owner,
+            command: self.command.clone(),
+            created_at: self.created_at,
+            attached: lock(&self.attachments).attached() > 0,
+        }
+    }
+
+    /// Attaches a client that reads the output from `cursor` onwards
+    fn attach(&self, access: SessionAccess, mode: AttachMode, cursor: u64) -> u64 {
+        let attachment = {
+            let mut attachments = lock(&self.attachments);
+            let attachment = attachments.attach(access, mode);
+            attachments.progress(attachment, cursor);
+            attachment
+        };
+        // Wakes up the attachments being replaced, if any
+        self.output_changed.send_replace(());
+        self.read_progress.send_replace(());
+        attachment
+    }
+
+    /// Records how far a client has read, letting the pump go on if it was waiting for it
+    fn progress(&self, attachment: u64, cursor: u64) {
+        lock(&self.attachments).progress(attachment, cursor);
+        self.read_progress.send_replace(());
+    }
+
+    fn client_state(&self, attachment: u64) -> Option<ClientState> {
+        lock(&self.attachments).state(attachment)
+    }
+
+    /// Detaches a client, `lost` if it went away without closing the session
+    fn detach(self: &Arc<Self>, attachment: u64, lost: bool) {
+        let detached = lock(&self.attachments).detach(attachment);
+        // The pump doesn't wait for clients that are gone
+        self.read_progress.send_replace(());
+        let Some(last) = detached else {
+            return;
+        };
+        self.output_changed.send_replace(());
+        let timeout = if self.persistent {
+            self.detached_timeout
+        } else if lost {
+            Some(self.resume_grace)
+        } else {
+            // Closed like a regular session, nobody is coming back to resume it
+            tracing::info!("session id={} closed by its client, hanging up", self.id);
+            self.hangup();
+            return;
+        };
+        tracing::info!("session id={} detached, lost={lost}", self.id);
+        let Some(timeout) = timeout else {
+            return;
+        };
+        let session = self.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(timeout).await;
+            // Only if nobody has attached since
+            if lock(&session.attachments).detached_since(last) {
+                tracing::info!(
+                    "session id={} detached for longer than {timeout:?}, hanging up",
+                    session.id
+                );
+                session.hangup();
+            }
+        });
+    }
+
+    fn hangup(&self) {
+        lock(&self.killer).hangup();
+    }
+
+    /// Tells the users of the attached clients, if any
+    fn notify(&self, msg: String) {
+        let _ = self.notices.send(msg);
+    }
+
+    /// Completes once the child has exited and all its output is in the scrollback
+    async fn exited(&self) {
+        let mut changed = self.output_changed.subscribe();
+        while matches!(lock(&self.output).child, ChildState::Running) {
+            // The sender lives as long as the session
+            let _ = changed.changed().await;
+        }
+    }
+
+    /// Completes once `len` more bytes of output fit in the scrollback without dropping
+    /// any that the attached writers haven't read yet
+    async fn room_for(&self, len: usize) {
+        let mut progressed = self.read_progress.subscribe();
+        loop {
+            let keep = lock(&self.attachments).slowest_writer();
+            if lock(&self.output).scrollback.has_room(len, keep) {
+                return;
+            }
+            // The sender lives as long as the session
+            let _ = progressed.changed().await;
+        }
+    }
+
+    /// Output from `cursor` onwards with the offset it starts at, and whether the child has
+    /// exited after producing it.
+    /// The cursor skips ahead if output it points at has been dropped from the scrollback.
+    fn read_output(&self, cursor: &mut u64, limit: usize) -> (u64, Vec<u8>, ChildState) {
+        let output = lock(&self.output);
+        let (offset, bytes) = output.scrollback.read_from(cursor, limit);
+        let child = if *cursor < output.scrollback.end() {
+            // Not exited as far as the reader is concerned, there's output left
+            ChildState::Running
+        } else {
+            output.child
+        };
+        (offset, bytes, child)
+    }
+}
+
+/// Moves pty output into the scrollback until the child exits, then removes the session.
+/// While the attached writers are behind the pty isn't read, so the child blocks on its
+/// output rather than them losing it, detached sessions only keep the latest output
+async fn pump_output(
+    sessions: Arc<SessionRegistry>,
+    session: Arc<PersistentSession>,
+    mut reader: PtyReader,
+    child: PtyChild,
+) {
+    while let Some(bytes) = reader.read_bytes().await {
+        session.timer.active();
+        session.room_for(bytes.len()).await;
+        lock(&session.output).scrollback.push(&bytes);
+        session.output_changed.send_replace(());
+    }
+    let status = match child.wait().await {
+        Ok(status) => Some(status),
+        Err(e) => {
+            tracing::warn!("failed to get session exit status: {}", unpack(&*e));
+            None
+        }
+    };
+    tracing::info!(
+        "persistent session id={} exited with {status:?}",
+        session.id
+    );
+    sessions.remove(session.id);
+    lock(&session.output).child = ChildState::Exited(status);
+    session.output_changed.send_replace(());
+}
+
+/// Hangs up the session once it's over its limits, attached or not
+async fn enforce_limits(session: Arc<PersistentSession>) {
+    let expiry = tokio::select! {
+        expiry = session.timer.expired(|warning| session.notify(warning)) => expiry,
+        () = session.exited() => return,
+    };
+    tracing::info!("session id={} {expiry}, hanging up", session.id);
+    session.notify(format!("closing session {}, {expiry}", session.id));
+    session.hangup();
+}
+
+/// Attaches a client, replaying the scrollback or, when resuming, the output from `resume_from`
+#[allow(clippy::too_many_arguments)]
+async fn attach_session<W, R>(
+    session: Arc<PersistentSession>,
+    access: SessionAccess,
+    mode: AttachMode,
+    resume_from: Option<u64>,
+    write: FrameWriter<W>,
+    read: FrameReader<R>,
+    capabilities: Capabilities,
+    size: Option<TermSize>,
+) -> anyhow::Result<()>
+where
+    W: WriteStream,
+    R: ReadStream,
+{
+    let attachment = session.attach(access, mode, resume_from.unwrap_or(0));
+    // Watchers don't get to change the size for everyone else
+    if access == SessionAccess::Write
+        && let Some(size) = size
+        && let Err(e) = lock(&session.master).resize(size)
+    {
+        tracing::warn!("failed to resize pty to {size:?}: {}", unpack(&*e));
+    }
+    let (acks, window) = p2term_lib::backpressure::window(capabilities);
+    let (input_res, output_res) = tokio::join!(
+        async {
+            // Dropped with the input side, the window stays open once nothing acknowledges
+            let acks = acks;
+            let res = proxy_client_frames(&session, attachment, read, &acks).await;
+            session.detach(attachment, res.is_err());
+            res
+        },
+        proxy_session_output(
+            &session,
+            attachment,
+            capabilities,
+            resume_from,
+            write,
+            window
+        )
+    );
+    match (input_res, output_res) {
+        (Ok(()) | Err(_), Ok(())) | (Ok(()), Err(_)) => Ok(()),
+        (Err(e_in), Err(e_out)) => anyhow::bail!(
+            "persistent session input/output proxy both failed: in={}, out={}",
+            unpack(&*e_in),
+            unpack(&*e_out)
+        ),
+    }
+}
+
+async fn proxy_client_frames<R: ReadStream>(
+    session: &PersistentSession,
+    attachment: u64,
+    mut input_stream: FrameReader<R>,
+    acks: &OutputAcks,
+) -> anyhow::Result<()> {
+    loop {
+        let Some(frame) = input_stream.read_frame().await? else {
+            return Ok(());
+        };
+        if let Frame::OutputAck(handled) = frame {
+            // Watchers acknowledge the output they get too
+            acks.ack(handled);
+            continue;
+        }
+        if !lock(&session.attachments).may_write(attachment) {
+            // Watching, or replaced by another client which now has the pty
+            continue;
+        }
+        match frame {
+            Frame::Stdin(bytes) => {
+                session.timer.active();
+                session.writer.write_chunk(&bytes).await?;
+            }
+            Frame::Resize(size) => {
+                if let Err(e) = lock(&session.master).resize(size) {
+                    tracing::warn!("failed to resize pty to {size:?}: {}", unpack(&*e));
+                }
+            }
+            Frame::Signal(signal) => {
+                if let Err(e) = lock(&session.master).signal_foreground(signal) {
+                    tracing::warn!("failed to deliver {signal:?}: {}", unpack(&*e));
+                }
+            }
+            Frame::Close => return Ok(()),
+            Frame::Keepalive => {}
+            unexpected => {
+                tracing::debug!("ignoring unexpected frame from client: {unexpected:?}");
+            }
+        }
+    }
+}
+
+/// What the client learns about the session before its output
+async fn write_session_start<W: WriteStream>(
+    session: &PersistentSession,
+    attachment: u64,
+    capabilities: Capabilities,
+    resume_from: Option<u64>,
+    write: &mut FrameWriter<W>,
+) -> anyhow::Result<()> {
+    if session.persistent && resume_from.is_none() {
+        write
+            .write_frame(&Frame::Session(session.info()))
+            .await
+            .context("failed to write session info over stream")?;
+    }
+    if capabilities.contains(Capabilities::RESUME) {
+        write
+            .write_frame(&Frame::ResumeToken(session.token))
+            .await
+            .context("failed to write resume token over stream")?;
+    }
+    if session.client_state(attachment) == Some(ClientState::Attached(SessionAccess::Watch)) {
+        write
+            .write_frame(&Frame::Stderr(
+                format!(
+                    "[p2term] watching session {} read-only, input is ignored\r\n",
+                    session.id
+                )
+                .into_bytes(),
+            ))
+            .await
+            .context("failed to write watch notice over stream")?;
+    }
+    Ok(())
+}
+
+async fn proxy_session_output<W: WriteStream>(
+    session: &PersistentSession,
+    attachment: u64,
+    capabilities: Capabilities,
+    resume_from: Option<u64>,
+    mut write: FrameWriter<W>,
+    mut window: OutputWindow,
+) -> anyhow::Result<()> {
+    let mut encoder = OutputEncoder::new(capabilities)?;
+    // Subscribed before reading, so that nothing added after the read is missed
+    let mut changed = session.output_changed.subscribe();
+    let mut notices = session.notices.subscribe();
+    write_session_start(session, attachment, capabilities, resume_from, &mut write).await?;
+    let resumable = capabilities.contains(Capabilities::RESUME);
+    // Starts at the oldest output kept if not resuming, replaying the scrollback
+    let mut cursor = resume_from.unwrap_or(0);
+    // Where the client thinks the next byte of output is
+    let mut client_offset = cursor;
+    loop {
+        // Output the client is too far behind on is left in the scrollback, the pump waits
+        // for writers to read it while watchers skip it once it has been dropped from there
+        let room = window.room();
+        let (offset, bytes, child) = if room > 0 {
+            session.read_output(&mut cursor, room)
+        } else {
+            (cursor, Vec::new(), ChildState::Running)
+        };
+        if !bytes.is_empty() {
+            session.progress(attachment, cursor);
+        }
+        // Compressed, what was read may not have filled the window
+        let more_waiting = room > 0 && bytes.len() == room;
+        if resumable && !bytes.is_empty() && offset != client_offset {
+            write
+                .write_frame(&Frame::OutputOffset(offset))
+                .await
+                .context("failed to write output offset over stream")?;
+        }
+        client_offset = offset + bytes.len() as u64;
+        for chunk in bytes.chunks(OUTPUT_CHUNK) {
+            let frame = encoder.frame(chunk)?;
+            write
+                .write_frame(&frame)
+                .await
+                .context("failed to write bytes from term over stream")?;
+            window.sent(&frame);
+        }
+        if let ChildState::Exited(status) = child {
+            // Telling why the session was hung up, if it was
+            while let Ok(msg) = notices.try_recv() {
+                write
+                    .write_frame(&notice(&msg))
+                    .await
+                    .context("failed to write notice over stream")?;
+            }
+            if let Some(status) = status
+                && capabilities.contains(Capabilities::EXIT_STATUS)
+            {
+                write
+                    .write_frame(&Frame::ExitStatus(status))
+                    .await
+                    .context("failed to write exit status over stream")?;
+            }
+            break;
+        }
+        match session.client_state(attachment) {
+            Some(ClientState::Attached(_)) => {}
+            Some(ClientState::Replaced) => {
+                // The client is still there, but another one has taken over the pty
+                let _ = write
+                    .write_frame(&Frame::Stderr(
+                        format!(
+                            "\r\n[p2term] detached from session {}, it was attached elsewhere\r\n",
+                            session.id
+                        )
+                        .into_bytes(),
+                    ))
+                    .await;
+                break;
+            }
+            // This client has left
+            None => break,
+        }
+        if more_waiting && window.is_open() {
+            continue;
+        }
+        tokio::select! {
+            // The sender lives as long as the session
+            _ = changed.changed(), if window.is_open() => {}
+            () = window.open(), if !window.is_open() => {}
+            // Lagging behind loses only the oldest notices
+            Ok(msg) = notices.recv() => write
+                .write_frame(&notice(&msg))
+                .await
+                .context("failed to write notice over stream")?,
+            () = write.keepalive_due() => write
+                .write_frame(&Frame::Keepalive)
+                .await
+                .context("failed to write keepalive over stream")?,
+        }
+    }
+    write
+        .write_frame(&Frame::Close)
+        .await
+        .context("failed to write close over stream")
+}
@@ -0,0 +1,285 @@
+use crate::shell::signal::signal_process_group;
+use anyhow::Context;
+use p2term_lib::backpressure::{OutputAcks, OutputWindow};
+use p2term_lib::compression::OutputEncoder;
+use p2term_lib::error::unpack;
+use p2term_lib::proto::codec::{Frame, FrameReader, FrameWriter};
+use p2term_lib::proto::{Capabilities, ExitStatus, Signal};
+use p2term_lib::server::config::SessionLimits;
+use p2term_lib::server::timeouts::{SessionTimer, notice};
+use p2term_lib::streams::{ReadStream, WriteStream};
+use std::path::Path;
+use std::process::Stdio;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::process::{Child, ChildStdin};
+
+/// Runs `argv` without a pty, stdout and stderr are sent as separate frames and
+/// stdin is closed when the client sends [`Frame::StdinEof`] or finishes its stream.
+/// The child leads a new process group, which receives the client's signals.
+pub async fn run_piped<W, R>(
+    argv: &[String],
+    cwd: Option<&Path>,
+    output_stream: FrameWriter<W>,
+    input_stream: FrameReader<R>,
+    capabilities: Capabilities,
+    limits: SessionLimits,
+) -> anyhow::Result<()>
+where
+    W: WriteStream,
+    R: ReadStream,
+{
+    let (program, args) = argv.split_first().context("no command to run")?;
+    let mut cmd = tokio::process::Command::new(program);
+    cmd.args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+    #[cfg(unix)]
+    cmd.process_group(0);
+    if let Some(cwd) = cwd {
+        cmd.current_dir(cwd);
+    }
+    let mut child = cmd
+        .spawn()
+        .with_context(|| format!("failed to spawn {program:?}"))?;
+    let stdin = child.stdin.take();
+    let (control_send, control_recv) = tokio::sync::mpsc::channel(16);
+    let timer = SessionTimer::new(limits);
+    let (acks, window) = p2term_lib::backpressure::window(capabilities);
+
+    let (input_res, output_res) = tokio::join!(
+        proxy_child_stdin(stdin, control_send, input_stream, &timer, acks),
+        proxy_child_output(
+            child,
+            control_recv,
+            capabilities,
+            output_stream,
+            &timer,
+            window
+        )
+    );
+    match (input_res, output_res) {
+        (Ok(()), Ok(())) => {
+            tracing::info!("exec session exited normally, both input and output streams closed");
+            Ok(())
+        }
+        (Err(e), Ok(())) => {
+            tracing::info!("exec session exited by peer leaving: {}", unpack(&*e));
+            Ok(())
+        }
+        (Ok(()), Err(e)) => Err(e),
+        (Err(e_in), Err(e_out)) => {
+            anyhow::bail!(
+                "exec session input/output proxy both failed: in={}, out={}",
+                unpack(&*e_in),
+                unpack(&*e_out)
+            );
+        }
+    }
+}
+
+/// Sent from the input proxy to the task owning the child
+enum ChildControl {
+    Signal(Signal),
+    Hangup,
+    /// A message for the user, written to the stream
+    Notice(String),
+}
+
+async fn proxy_child_stdin<R: ReadStream>(
+    child_stdin: Option<ChildStdin>,
+    control: tokio::sync::mpsc::Sender<ChildControl>,
+    input_stream: FrameReader<R>,
+    timer: &SessionTimer,
+    acks: OutputAcks,
+) -> anyhow::Result<()> {
+    let res = tokio::select! {
+        res = proxy_client_frames(child_stdin, &control, input_stream, timer, &acks) => res,
+        expiry = timer.expired(|warning| {
+            // Dropped if the client isn't keeping up with them anyway
+            let _ = control.try_send(ChildControl::Notice(warning));
+        }) => {
+            tracing::info!("exec session {expiry}, killing it");
+            let _ = control
+                .send(ChildControl::Notice(format!("closing session, {expiry}")))
+                .await;
+            // The exit status still goes to the client
+            let _ = control.send(ChildControl::Signal(Signal::Kill)).await;
+            Ok(())
+        }
+    };
+    if res.is_err() {
+        // The client is gone without finishing its stream, nobody is left to read the output
+        let _ = control.send(ChildControl::Hangup).await;
+    }
+    res
+}
+
+async fn proxy_client_frames<R: ReadStream>(
+    mut child_stdin: Option<ChildStdin>,
+    control: &tokio::sync::mpsc::Sender<ChildControl>,
+    mut input_stream: FrameReader<R>,
+    timer: &SessionTimer,
+    acks: &OutputAcks,
+) -> anyhow::Result<()> {
+    loop {
+        // Returning drops the child's stdin, which it sees as EOF
+        let Some(frame) = input_stream.read_frame().await? else {
+            return Ok(());
+        };
+        match frame {
+            Frame::Stdin(bytes) => {
+                timer.active();
+                let Some(stdin) = child_stdin.as_mut() else {
+                    continue;
+                };
+                if let Err(e) = stdin.write_all(&bytes).await {
+                    // The child closed its stdin, it may still be producing output
+                    tracing::debug!("failed to write to child stdin, dropping input: {e}");
+                    child_stdin = None;
+                }
+            }
+            // Dropping stdin is the EOF, the client may still send signals
+            Frame::StdinEof => child_stdin = None,
+            Frame::Signal(signal) => {
+                // Fails only if the child has been waited for, then there's no one to signal
+                let _ = control.send(ChildControl::Signal(signal)).await;
+            }
+            Frame::OutputAck(handled) => acks.ack(handled),
+            Frame::Close => return Ok(()),
+            Frame::Keepalive | Frame::Resize(_) => {}
+            unexpected => {
+                tracing::debug!("ignoring unexpected frame from client: {unexpected:?}");
+            }
+        }
+    }
+}
+
+async fn proxy_child_output<W: WriteStream>(
+    mut child: Child,
+    mut control: tokio::sync::mpsc::Receiver<ChildControl>,
+    capabilities: Capabilities,
+    mut write: FrameWriter<W>,
+    timer: &SessionTimer,
+    mut window: OutputWindow,
+) -> anyhow::Result<()> {
+    let mut stdout = child.stdout.take();
+    let mut stderr = child.stderr.take();
+    let mut stdout_buf = [0u8; 4096];
+    let mut stderr_buf = [0u8; 4096];
+    // Only stdout is compressed, stderr is rarely more than a few lines
+    let mut encoder = OutputEncoder::new(capabilities)?;
+    // Output is drained before waiting, the child may close its pipes and keep running
+    let status = loop {
+        tokio::select! {
+            // The pipes aren't read while the client is behind, which eventually blocks
+            // the child
+            read = read_pipe(stdout.as_mut(), &mut stdout_buf), if window.is_open() => {
+                match read.context("failed to read child stdout")? {
+                    0 => stdout = None,
+                    read_bytes => {
+                        timer.active();
+                        let frame = encoder.frame(&stdout_buf[..read_bytes])?;
+                        write
+                            .write_frame(&frame)
+                            .await
+                            .context("failed to write child stdout over stream")?;
+                        window.sent(&frame);
+                    }
+                }
+            }
+            read = read_pipe(stderr.as_mut(), &mut stderr_buf), if window.is_open() => {
+                match read.context("failed to read child stderr")? {
+                    0 => stderr = None,
+                    read_bytes => {
+                        timer.active();
+                        let frame = Frame::Stderr(stderr_buf[..read_bytes].to_vec());
+                        write
+                            .write_frame(&frame)
+                            .await
+                            .context("failed to write child stderr over stream")?;
+                        window.sent(&frame);
+                    }
+                }
+            }
+            () = window.open(), if !window.is_open() => {}
+            Some(control) = control.recv() => match control {
+                ChildControl::Signal(signal) => signal_child(&child, signal),
+                ChildControl::Notice(msg) => write
+                    .write_frame(&notice(&msg))
+                    .await
+                    .context("failed to write notice over stream")?,
+                ChildControl::Hangup => {
+                    signal_child(&child, Signal::Kill);
+                    if let Err(e) = child.start_kill() {
+                        tracing::debug!("failed to kill exec child: {e}");
+                    }
+                    child.wait().await.context("failed to wait for exec child")?;
+                    return Ok(());
+                }
+            },
+            status = child.wait(), if stdout.is_none() && stderr.is_none() => break status,
+            () = write.keepalive_due() => write
+                .write_frame(&Frame::Keepalive)
+                .await
+                .context("failed to write keepalive over stream")?,
+        }
+    };
+    match status {
+        Ok(status) => {
+            let status = exit_status(status);
+            tracing::info!("exec child exited with {status:?}");
+            if capabilities.contains(Capabilities::EXIT_STATUS) {
+                write
+                    .write_frame(&Frame::ExitStatus(status))
+                    .await
+                    .context("failed to write exit status over stream")?;
+            }
+        }
+        Err(e) => tracing::warn!("failed to get exec child exit status: {e}"),
+    }
+    write
+        .write_frame(&Frame::Close)
+        .await
+        .context("failed to write close over stream")
+}
+
+fn signal_child(child: &Child, signal: Signal) {
+    // The id is gone once the child has been waited for, its group may be reused after that
+    let Some(pid) = child.id() else {
+        return;
+    };
+    let Ok(pgid) = i32::try_from(pid) else {
+        return;
+    };
+    if let Err(e) = signal_process_group(pgid, signal) {
+        tracing::warn!("failed to deliver {signal:?}: {}", unpack(&*e));
+    }
+}
+
+/// Reads from `pipe`, never completing if it has already been closed
+async fn read_pipe<P: AsyncRead + Unpin>(
+    pipe: Option<&mut P>,
+    buf: &mut [u8],
+) -> std::io::Result<usize> {
+    match pipe {
+        Some(pipe) => pipe.read(buf).await,
+        None => std::future::pending().await,
+    }
+}
+
+fn exit_status(status: std::process::ExitStatus) -> ExitStatus {
+    #[cfg(unix)]
+    let signal = {
+        use std::os::unix::process::ExitStatusExt;
+        status.signal().map(Signal::from_number)
+    };
+    #[cfg(not(unix))]
+    let signal: Option<Signal> = None;
+    ExitStatus {
+        // Only missing when killed by a signal, which is reported separately
+        code: status.code().map_or(1, i32::cast_unsigned),
+        signal,
+    }
+}
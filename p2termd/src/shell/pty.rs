@@ -1,8 +1,16 @@
+use crate::shell::signal::{signal_from_description, signal_process_group};
 use anyhow::Context;
-use p2term_lib::proto::DEFAULT_TERM;
-use portable_pty::{CommandBuilder, PtySize};
+use p2term_lib::proto::{DEFAULT_TERM, ExitStatus, Signal, TermSize};
+use portable_pty::{ChildKiller, CommandBuilder, MasterPty, PtySize};
 use std::io::{Read, Write};
 use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Chunks of output read from a pty ahead of the session taking them. Kept short, so that
+/// output the client isn't taking stops the pty from being read, blocking the shell
+/// rather than piling up here
+const OUTPUT_QUEUE: usize = 4;
 
 pub struct PtyWriter {
     pty_sender: tokio::sync::mpsc::Sender<ShellMessage>,
@@ -31,43 +39,135 @@ pub struct PtyReader {
 }
 
 impl PtyReader {
-    pub async fn read_bytes(&mut self) -> anyhow::Result<Vec<u8>> {
-        self.pty_bytes_recv
-            .recv()
+    /// Next chunk of pty output, `None` when the pty has closed
+    pub async fn read_bytes(&mut self) -> Option<Vec<u8>> {
+        self.pty_bytes_recv.recv().await
+    }
+
+    /// A chunk of pty output that's already waiting, if any
+    pub fn try_read_bytes(&mut self) -> Option<Vec<u8>> {
+        self.pty_bytes_recv.try_recv().ok()
+    }
+}
+
+pub struct PtyMaster {
+    master: Box<dyn MasterPty + Send>,
+}
+
+impl PtyMaster {
+    pub fn resize(&self, size: TermSize) -> anyhow::Result<()> {
+        self.master
+            .resize(pty_size(size))
+            .context("failed to resize pty")
+    }
+
+    /// Sends `signal` to the pty's foreground process group, that is the job the shell
+    /// is currently running, or the shell itself
+    #[cfg(unix)]
+    pub fn signal_foreground(&self, signal: Signal) -> anyhow::Result<()> {
+        let pgid = self
+            .master
+            .process_group_leader()
+            .context("pty has no foreground process group")?;
+        signal_process_group(pgid, signal)
+    }
+
+    #[cfg(not(unix))]
+    pub fn signal_foreground(&self, signal: Signal) -> anyhow::Result<()> {
+        signal_process_group(0, signal)
+    }
+}
+
+fn pty_size(size: TermSize) -> PtySize {
+    PtySize {
+        rows: size.rows,
+        cols: size.cols,
+        pixel_width: size.pixel_width,
+        pixel_height: size.pixel_height,
+    }
+}
+
+pub struct PtyChild {
+    exit_recv: tokio::sync::oneshot::Receiver<std::io::Result<portable_pty::ExitStatus>>,
+}
+
+impl PtyChild {
+    pub async fn wait(self) -> anyhow::Result<ExitStatus> {
+        let status = self
+            .exit_recv
             .await
-            .context("failed to receive message from pty receiver")
+            .context("pty child wait thread exited without a status")?
+            .context("failed to wait for pty child")?;
+        Ok(ExitStatus {
+            code: status.exit_code(),
+            signal: status.signal().and_then(signal_from_description),
+        })
     }
 }
 
+pub struct PtyKiller {
+    killer: Box<dyn ChildKiller + Send + Sync>,
+    exited: Arc<AtomicBool>,
+}
+
+impl PtyKiller {
+    /// Hangs up the child, as if its terminal was closed
+    pub fn hangup(&mut self) {
+        // The pid may have been reused after the child was reaped
+        if self.exited.load(Ordering::Acquire) {
+            return;
+        }
+        if let Err(e) = self.killer.kill() {
+            tracing::debug!("failed to hang up pty child: {e}");
+        }
+    }
+}
+
+pub struct SubshellPty {
+    pub writer: PtyWriter,
+    pub reader: PtyReader,
+    pub master: PtyMaster,
+    pub child: PtyChild,
+    pub killer: PtyKiller,
+    pub errors: tokio::sync::mpsc::Receiver<anyhow::Error>,
+}
+
 enum ShellMessage {
     Byte(u8),
     Chunk(Vec<u8>),
 }
 
+/// Spawns `argv` in a new pty, the first element is the program and the rest its arguments
 pub fn subshell_pty_task(
-    shell: &str,
+    argv: &[String],
     cwd: Option<&Path>,
     term: Option<&str>,
-) -> anyhow::Result<(
-    PtyWriter,
-    PtyReader,
-    tokio::sync::mpsc::Receiver<anyhow::Error>,
-)> {
+    size: Option<TermSize>,
+) -> anyhow::Result<SubshellPty> {
+    anyhow::ensure!(!argv.is_empty(), "no command to spawn in pty");
     let pty_sys = portable_pty::native_pty_system();
     let term = term.unwrap_or(DEFAULT_TERM);
-    let mut cmd = CommandBuilder::new(shell);
+    let mut cmd = CommandBuilder::from_argv(argv.iter().map(Into::into).collect());
     cmd.env("TERM", term);
-    cmd.arg("-l");
     if let Some(cwd) = cwd {
         cmd.cwd(cwd);
     }
     let pty = pty_sys
-        .openpty(PtySize::default())
+        .openpty(size.map(pty_size).unwrap_or_default())
         .context("failed to open pty for shell")?;
-    let _child = pty
+    let mut child = pty
         .slave
         .spawn_command(cmd)
-        .context("failed to spawn shell")?;
+        .with_context(|| format!("failed to spawn {:?} in pty", argv[0]))?;
+    let killer = child.clone_killer();
+    let exited = Arc::new(AtomicBool::new(false));
+    let exited_c = exited.clone();
+    let (exit_send, exit_recv) = tokio::sync::oneshot::channel();
+    std::thread::spawn(move || {
+        let status = child.wait();
+        exited_c.store(true, Ordering::Release);
+        let _ = exit_send.send(status);
+    });
     let reader = pty
         .master
         .try_clone_reader()
@@ -84,19 +184,22 @@ pub fn subshell_pty_task(
             let _ = err_c.blocking_send(e);
         }
     });
-    let (pty_sender, pty_bytes_recv) = tokio::sync::mpsc::channel(128);
+    let (pty_sender, pty_bytes_recv) = tokio::sync::mpsc::channel(OUTPUT_QUEUE);
     std::thread::spawn(move || {
         if let Err(e) = subshell_reader_task(&pty_sender, reader) {
             let _ = err_sender.blocking_send(e);
         }
     });
-    Ok((
-        PtyWriter {
+    Ok(SubshellPty {
+        writer: PtyWriter {
             pty_sender: input_to_pty,
         },
-        PtyReader { pty_bytes_recv },
-        err_receiver,
-    ))
+        reader: PtyReader { pty_bytes_recv },
+        master: PtyMaster { master: pty.master },
+        child: PtyChild { exit_recv },
+        killer: PtyKiller { killer, exited },
+        errors: err_receiver,
+    })
 }
 
 fn subshell_writer_task(
@@ -0,0 +1,82 @@
+#[cfg(unix)]
+use anyhow::Context;
+use p2term_lib::proto::Signal;
+
+/// Signal descriptions as libc's `strsignal` gives them in the C locale, glibc's first
+/// where they differ
+#[cfg(unix)]
+const DESCRIPTIONS: &[(&str, i32)] = &[
+    ("Hangup", libc::SIGHUP),
+    ("Interrupt", libc::SIGINT),
+    ("Quit", libc::SIGQUIT),
+    ("Illegal instruction", libc::SIGILL),
+    ("Trace/breakpoint trap", libc::SIGTRAP),
+    ("Aborted", libc::SIGABRT),
+    ("Abort trap", libc::SIGABRT),
+    ("Bus error", libc::SIGBUS),
+    ("Floating point exception", libc::SIGFPE),
+    ("Arithmetic exception", libc::SIGFPE),
+    ("Killed", libc::SIGKILL),
+    ("User defined signal 1", libc::SIGUSR1),
+    ("Segmentation fault", libc::SIGSEGV),
+    ("User defined signal 2", libc::SIGUSR2),
+    ("Broken pipe", libc::SIGPIPE),
+    ("Alarm clock", libc::SIGALRM),
+    ("Terminated", libc::SIGTERM),
+    ("Child exited", libc::SIGCHLD),
+    ("Child process status", libc::SIGCHLD),
+    ("Continued", libc::SIGCONT),
+    ("Stopped (signal)", libc::SIGSTOP),
+    ("Suspended (signal)", libc::SIGSTOP),
+    ("Stopped", libc::SIGTSTP),
+    ("Suspended", libc::SIGTSTP),
+    ("Stopped (tty input)", libc::SIGTTIN),
+    ("Stopped (tty output)", libc::SIGTTOU),
+    ("Urgent I/O condition", libc::SIGURG),
+    ("CPU time limit exceeded", libc::SIGXCPU),
+    ("File size limit exceeded", libc::SIGXFSZ),
+    ("Virtual timer expired", libc::SIGVTALRM),
+    ("Profiling timer expired", libc::SIGPROF),
+    ("Window changed", libc::SIGWINCH),
+    ("Window size changes", libc::SIGWINCH),
+    ("I/O possible", libc::SIGIO),
+    ("Bad system call", libc::SIGSYS),
+];
+
+/// Maps a signal description, as `portable_pty` takes it from `strsignal`, back to its
+/// signal. Unknown signals are described by number, as are all of them on macOS.
+#[cfg(unix)]
+pub fn signal_from_description(description: &str) -> Option<Signal> {
+    if let Some((_, num)) = DESCRIPTIONS.iter().find(|(known, _)| *known == description) {
+        return Some(Signal::from_number(*num));
+    }
+    description
+        .strip_prefix("Signal ")
+        .or_else(|| description.strip_prefix("Unknown signal "))
+        .or_else(|| description.rsplit_once(": ").map(|(_, num)| num))
+        .and_then(|num| num.parse().ok())
+        .map(Signal::from_number)
+}
+
+#[cfg(not(unix))]
+pub fn signal_from_description(_description: &str) -> Option<Signal> {
+    None
+}
+
+/// Sends `signal` to every process in the group led by `pgid`
+#[cfg(unix)]
+pub fn signal_process_group(pgid: i32, signal: Signal) -> anyhow::Result<()> {
+    // 0 and 1 would signal our own group and init respectively
+    anyhow::ensure!(pgid > 1, "refusing to signal process group {pgid}");
+    // Safety: `killpg` has no memory safety requirements
+    if unsafe { libc::killpg(pgid, signal.number()) } != 0 {
+        return Err(std::io::Error::last_os_error())
+            .with_context(|| format!("failed to send {signal:?} to process group {pgid}"));
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn signal_process_group(_pgid: i32, signal: Signal) -> anyhow::Result<()> {
+    anyhow::bail!("can't send {signal:?}, signals are not supported on this platform")
+}